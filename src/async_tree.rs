@@ -1,41 +1,81 @@
+use futures_core::Stream;
+use std::borrow::Borrow;
 use std::io;
+use std::ops::RangeBounds;
 use std::path::Path;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::thread;
-use tokio::sync::{mpsc, oneshot};
+use std::time::Instant;
+use tokio::runtime;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time;
 
-use crate::{MerkleKey, MerkleSearchTree, MerkleValue};
-use blake3::Hash;
+use crate::{Hash, Version};
+use crate::{Error, MerkleKey, MerkleSearchTree, MerkleValue, Result, Snapshot, TreeHasher};
+
+/// A lookup run by the worker against the current tree. It owns its key in whatever
+/// form the caller looks it up by, which need not be `K`.
+type Query<K, V> = Box<dyn FnOnce(&Snapshot<K, V>) + Send + Sync>;
+
+/// Entries sent to an [`EntryStream`] per message.
+const STREAM_CHUNK_LEN: usize = 256;
+
+/// Chunks an [`EntryStream`] may have buffered before its producer waits for the
+/// consumer to catch up.
+const STREAM_CHUNKS_IN_FLIGHT: usize = 4;
+
+type Chunk<K, V> = Result<Vec<(Arc<K>, Arc<V>)>>;
 
 /// Commands sent to the worker thread
-enum Command<K, V> {
+enum Command<K: MerkleKey, V: MerkleValue> {
     Insert {
         key: K,
         value: V,
-        resp: oneshot::Sender<io::Result<()>>,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    InsertMany {
+        items: Vec<(K, V)>,
+        resp: oneshot::Sender<Result<()>>,
     },
     Remove {
         key: K,
-        resp: oneshot::Sender<io::Result<()>>,
+        resp: oneshot::Sender<Result<()>>,
     },
     Get {
         key: K,
-        resp: oneshot::Sender<io::Result<Option<Arc<V>>>>,
+        resp: oneshot::Sender<Result<Option<Arc<V>>>>,
+    },
+    GetMany {
+        keys: Vec<K>,
+        resp: oneshot::Sender<Result<Vec<Option<Arc<V>>>>>,
     },
     Contains {
         key: K,
-        resp: oneshot::Sender<io::Result<bool>>,
+        resp: oneshot::Sender<Result<bool>>,
     },
+    Query(Query<K, V>),
     Commit {
-        resp: oneshot::Sender<io::Result<(u64, Hash)>>,
+        resp: oneshot::Sender<Result<Version>>,
+    },
+    CommitGroup {
+        resp: oneshot::Sender<Result<Version>>,
     },
     Compact {
         path: String,
-        resp: oneshot::Sender<io::Result<()>>,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    Shutdown {
+        resp: oneshot::Sender<Result<()>>,
     },
 }
 
 /// Async wrapper for MerkleSearchTree using a worker thread
+///
+/// Uncommitted changes are committed when the worker stops, either through
+/// [`close`](Self::close) or once every handle has been dropped. Only `close` reports
+/// whether that commit succeeded.
 #[derive(Debug)]
 pub struct AsyncMerkleSearchTree<K, V>
 where
@@ -43,6 +83,7 @@ where
     V: MerkleValue + Send + Sync + 'static,
 {
     tx: mpsc::Sender<Command<K, V>>,
+    commits: watch::Receiver<Hash>,
 }
 
 impl<K, V> Clone for AsyncMerkleSearchTree<K, V>
@@ -53,44 +94,64 @@ where
     fn clone(&self) -> Self {
         Self {
             tx: self.tx.clone(),
+            commits: self.commits.clone(),
         }
     }
 }
 
-impl<K, V> From<MerkleSearchTree<K, V>> for AsyncMerkleSearchTree<K, V>
+impl<K, V, H> From<MerkleSearchTree<K, V, H>> for AsyncMerkleSearchTree<K, V>
 where
     K: MerkleKey + Send + Sync + 'static,
     V: MerkleValue + Send + Sync + 'static,
+    H: TreeHasher + 'static,
 {
-    fn from(mut tree: MerkleSearchTree<K, V>) -> Self {
+    fn from(mut tree: MerkleSearchTree<K, V, H>) -> Self {
         let (tx, mut rx) = mpsc::channel::<Command<K, V>>(128);
+        let commits = tree.subscribe();
 
         thread::spawn(move || {
+            let delay = tree.store.config().group_commit_delay;
+            // Times the wait for more `commit_group` calls. Without it, which only
+            // happens if the runtime fails to start, groups take in the calls already
+            // queued, as with no delay.
+            let timer = if delay.is_zero() {
+                None
+            } else {
+                runtime::Builder::new_current_thread().enable_time().build().ok()
+            };
+            let mut group = Vec::new();
             while let Some(cmd) = rx.blocking_recv() {
-                match cmd {
-                    Command::Insert { key, value, resp } => {
-                        let _ = resp.send(tree.insert(key, value));
-                    }
-                    Command::Remove { key, resp } => {
-                        let _ = resp.send(tree.remove(&key));
-                    }
-                    Command::Get { key, resp } => {
-                        let _ = resp.send(tree.get(&key));
-                    }
-                    Command::Contains { key, resp } => {
-                        let _ = resp.send(tree.contains(&key));
-                    }
-                    Command::Commit { resp } => {
-                        let _ = resp.send(tree.commit());
-                    }
-                    Command::Compact { path, resp } => {
-                        let _ = resp.send(tree.compact(path));
+                let mut shutdown = run(&mut tree, cmd, &mut group);
+                if !group.is_empty() {
+                    // Calls that arrive before the deadline, or that were already
+                    // queued behind the first, share its commit. Other commands run
+                    // as they come in meanwhile.
+                    let deadline = Instant::now() + delay;
+                    while shutdown.is_none() {
+                        let cmd = match &timer {
+                            Some(timer) => timer
+                                .block_on(async {
+                                    time::timeout_at(deadline.into(), rx.recv()).await
+                                })
+                                .ok()
+                                .flatten(),
+                            None => rx.try_recv().ok(),
+                        };
+                        let Some(cmd) = cmd else { break };
+                        shutdown = run(&mut tree, cmd, &mut group);
                     }
+                    commit_group(&mut tree, &mut group);
+                }
+                if let Some(resp) = shutdown {
+                    let _ = resp.send(shut_down(&mut tree));
+                    return;
                 }
             }
+            // Every handle is gone; nobody is left to report a failure to.
+            let _ = shut_down(&mut tree);
         });
 
-        Self { tx }
+        Self { tx, commits }
     }
 }
 
@@ -99,24 +160,24 @@ where
     K: MerkleKey + Send + Sync + 'static,
     V: MerkleValue + Send + Sync + 'static,
 {
-    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
         Ok(MerkleSearchTree::open(path)?.into())
     }
 
     /// Creates a new MST backed by a temporary file.
-    pub fn new_temporary() -> io::Result<Self> {
+    pub fn new_temporary() -> Result<Self> {
         Ok(MerkleSearchTree::new_temporary()?.into())
     }
 
-    /// Helper to try sending a command to the worker and convert errors to io::Result
-    async fn try_send(&self, cmd: Command<K, V>) -> io::Result<()> {
+    /// Helper to try sending a command to the worker and convert errors to `Error::Io`
+    async fn try_send(&self, cmd: Command<K, V>) -> Result<()> {
         self.tx
             .send(cmd)
             .await
-            .map_err(|error| io::Error::new(io::ErrorKind::BrokenPipe, error))
+            .map_err(|error| io::Error::new(io::ErrorKind::BrokenPipe, error).into())
     }
 
-    pub async fn insert(&self, key: K, value: V) -> io::Result<()> {
+    pub async fn insert(&self, key: K, value: V) -> Result<()> {
         let (resp_tx, resp_rx) = oneshot::channel();
         self.try_send(Command::Insert {
             key,
@@ -127,33 +188,160 @@ where
         resp_rx.await.map_err(Self::on_oneshot_error).flatten()
     }
 
-    pub async fn remove(&self, key: K) -> io::Result<()> {
+    /// Inserts a batch of pairs in a single round trip to the worker, which applies
+    /// them with [`MerkleSearchTree::insert_many`].
+    pub async fn insert_many(&self, items: Vec<(K, V)>) -> Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.try_send(Command::InsertMany {
+            items,
+            resp: resp_tx,
+        })
+        .await?;
+        resp_rx.await.map_err(Self::on_oneshot_error).flatten()
+    }
+
+    pub async fn remove(&self, key: K) -> Result<()> {
         let (resp_tx, resp_rx) = oneshot::channel();
         self.try_send(Command::Remove { key, resp: resp_tx })
             .await?;
         resp_rx.await.map_err(Self::on_oneshot_error).flatten()
     }
 
-    pub async fn get(&self, key: K) -> io::Result<Option<Arc<V>>> {
+    /// Retrieves a value by key. The key is moved to the worker, not cloned; callers
+    /// that only hold a borrowed form of it can use [`get_borrowed`](Self::get_borrowed)
+    /// instead of building a `K`.
+    pub async fn get(&self, key: K) -> Result<Option<Arc<V>>> {
         let (resp_tx, resp_rx) = oneshot::channel();
         self.try_send(Command::Get { key, resp: resp_tx }).await?;
         resp_rx.await.map_err(Self::on_oneshot_error).flatten()
     }
 
-    pub async fn contains(&self, key: K) -> io::Result<bool> {
+    /// Looks up a batch of keys in a single round trip to the worker. Values come back
+    /// in the order of `keys`.
+    pub async fn get_many(&self, keys: Vec<K>) -> Result<Vec<Option<Arc<V>>>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.try_send(Command::GetMany {
+            keys,
+            resp: resp_tx,
+        })
+        .await?;
+        resp_rx.await.map_err(Self::on_oneshot_error).flatten()
+    }
+
+    /// Checks if a key exists. Like [`get`](Self::get), this moves the key to the
+    /// worker; see [`contains_borrowed`](Self::contains_borrowed).
+    pub async fn contains(&self, key: K) -> Result<bool> {
         let (resp_tx, resp_rx) = oneshot::channel();
         self.try_send(Command::Contains { key, resp: resp_tx })
             .await?;
         resp_rx.await.map_err(Self::on_oneshot_error).flatten()
     }
 
-    pub async fn commit(&self) -> io::Result<(u64, Hash)> {
+    /// Like [`get`](Self::get), but takes the key in any form `K` can be borrowed as,
+    /// e.g. `&str` for `String` keys. The key is copied once to reach the worker.
+    pub async fn get_borrowed<Q>(&self, key: &Q) -> Result<Option<Arc<V>>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned + ?Sized + 'static,
+        Q::Owned: Send + Sync + 'static,
+    {
+        self.query(key, |snapshot, key| snapshot.get(key)).await
+    }
+
+    /// Like [`contains`](Self::contains), but takes the key in any form `K` can be
+    /// borrowed as.
+    pub async fn contains_borrowed<Q>(&self, key: &Q) -> Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned + ?Sized + 'static,
+        Q::Owned: Send + Sync + 'static,
+    {
+        self.query(key, |snapshot, key| snapshot.contains(key))
+            .await
+    }
+
+    /// Streams every entry in key order, as of when the worker gets to the request.
+    pub async fn iter(&self) -> Result<EntryStream<K, V>> {
+        self.range(..).await
+    }
+
+    /// Streams the entries within `range` in key order, as of when the worker gets to
+    /// the request.
+    ///
+    /// The entries are read from a snapshot by a separate thread, so the stream does
+    /// not hold up other calls on the tree. That thread stays at most a few chunks
+    /// ahead of the consumer, and stops once the stream is dropped.
+    pub async fn range<R>(&self, range: R) -> Result<EntryStream<K, V>>
+    where
+        R: RangeBounds<K> + Send + Sync + 'static,
+    {
+        let (chunk_tx, chunk_rx) = mpsc::channel(STREAM_CHUNKS_IN_FLIGHT);
+        self.try_send(Command::Query(Box::new(move |snapshot| {
+            let snapshot = snapshot.clone();
+            thread::spawn(move || produce_chunks(snapshot, range, chunk_tx));
+        })))
+        .await?;
+        Ok(EntryStream {
+            rx: chunk_rx,
+            chunk: Vec::new().into_iter(),
+        })
+    }
+
+    /// Takes a [`Snapshot`] of the tree as of when the worker gets to the request.
+    ///
+    /// The snapshot reads straight from the shared store, so a burst of lookups on it
+    /// costs no round trips to the worker. Like any snapshot, it does not see later
+    /// writes.
+    pub async fn snapshot(&self) -> Result<Snapshot<K, V>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.try_send(Command::Query(Box::new(move |snapshot| {
+            let _ = resp_tx.send(snapshot.clone());
+        })))
+        .await?;
+        resp_rx.await.map_err(Self::on_oneshot_error)
+    }
+
+    /// Runs `op` on the worker with an owned copy of `key`.
+    async fn query<Q, T>(&self, key: &Q, op: fn(&Snapshot<K, V>, &Q) -> Result<T>) -> Result<T>
+    where
+        Q: ToOwned + ?Sized + 'static,
+        Q::Owned: Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        let key = key.to_owned();
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.try_send(Command::Query(Box::new(move |snapshot| {
+            let _ = resp_tx.send(op(snapshot, key.borrow()));
+        })))
+        .await?;
+        resp_rx.await.map_err(Self::on_oneshot_error).flatten()
+    }
+
+    /// Commits, then compacts on the worker if the tree's
+    /// [`CompactionPolicy`](crate::CompactionPolicy) calls for it. The commit is
+    /// reported before compacting, so only later commands wait for that.
+    pub async fn commit(&self) -> Result<Version> {
         let (resp_tx, resp_rx) = oneshot::channel();
         self.try_send(Command::Commit { resp: resp_tx }).await?;
         resp_rx.await.map_err(Self::on_oneshot_error).flatten()
     }
 
-    pub async fn compact(&self, path: String) -> io::Result<()> {
+    /// Commits along with every other `commit_group` call the worker receives before
+    /// it gets to committing, and resolves once that one commit is done. Concurrent
+    /// writers each waiting for their changes to be durable then share a single sync,
+    /// rather than each paying for one.
+    ///
+    /// The worker holds the first call of a group back for the
+    /// [`group_commit_delay`](crate::OpenOptions::group_commit_delay), running other
+    /// commands meanwhile, then takes in every call queued by then. Every call of a
+    /// group gets the same [`Version`], or the same error.
+    pub async fn commit_group(&self) -> Result<Version> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.try_send(Command::CommitGroup { resp: resp_tx }).await?;
+        resp_rx.await.map_err(Self::on_oneshot_error).flatten()
+    }
+
+    pub async fn compact(&self, path: String) -> Result<()> {
         let (resp_tx, resp_rx) = oneshot::channel();
         self.try_send(Command::Compact {
             path,
@@ -163,7 +351,165 @@ where
         resp_rx.await.map_err(Self::on_oneshot_error).flatten()
     }
 
-    fn on_oneshot_error(recv_error: oneshot::error::RecvError) -> io::Error {
-        io::Error::new(io::ErrorKind::BrokenPipe, recv_error)
+    /// Commits any pending changes and stops the worker, returning once they are
+    /// durable. Calls through other clones of this handle fail with `BrokenPipe`
+    /// afterwards.
+    pub async fn close(self) -> Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.try_send(Command::Shutdown { resp: resp_tx }).await?;
+        resp_rx.await.map_err(Self::on_oneshot_error).flatten()
+    }
+
+    /// Returns a receiver that observes the root hash of every commit that writes a
+    /// new root; see [`MerkleSearchTree::subscribe`].
+    pub fn subscribe(&self) -> watch::Receiver<Hash> {
+        let mut commits = self.commits.clone();
+        commits.mark_unchanged();
+        commits
+    }
+
+    fn on_oneshot_error(recv_error: oneshot::error::RecvError) -> Error {
+        io::Error::new(io::ErrorKind::BrokenPipe, recv_error).into()
+    }
+}
+
+/// Stream of entries returned by [`AsyncMerkleSearchTree::range`] and
+/// [`AsyncMerkleSearchTree::iter`].
+///
+/// Besides implementing [`Stream`], it can be drained with [`next`](Self::next). A
+/// loading error is yielded once, after which the stream ends.
+pub struct EntryStream<K, V> {
+    rx: mpsc::Receiver<Chunk<K, V>>,
+    chunk: std::vec::IntoIter<(Arc<K>, Arc<V>)>,
+}
+
+impl<K, V> EntryStream<K, V> {
+    /// Returns the next entry, or `None` once the range is exhausted.
+    pub async fn next(&mut self) -> Option<Result<(Arc<K>, Arc<V>)>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl<K, V> Stream for EntryStream<K, V> {
+    type Item = Result<(Arc<K>, Arc<V>)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(entry) = self.chunk.next() {
+                return Poll::Ready(Some(Ok(entry)));
+            }
+            match std::task::ready!(self.rx.poll_recv(cx)) {
+                Some(Ok(chunk)) => self.chunk = chunk.into_iter(),
+                Some(Err(e)) => {
+                    self.rx.close();
+                    return Poll::Ready(Some(Err(e)));
+                }
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// Sends the entries of `snapshot` within `range` in chunks, until they run out or
+/// the stream is dropped.
+fn produce_chunks<K: MerkleKey, V: MerkleValue, R: RangeBounds<K>>(
+    snapshot: Snapshot<K, V>,
+    range: R,
+    tx: mpsc::Sender<Chunk<K, V>>,
+) {
+    let mut iter = match snapshot.range(range) {
+        Ok(iter) => iter,
+        Err(e) => {
+            let _ = tx.blocking_send(Err(e));
+            return;
+        }
+    };
+    loop {
+        let chunk = iter
+            .by_ref()
+            .take(STREAM_CHUNK_LEN)
+            .collect::<Result<Vec<_>>>();
+        let last = !matches!(&chunk, Ok(entries) if entries.len() == STREAM_CHUNK_LEN);
+        if chunk.as_ref().is_ok_and(Vec::is_empty) || tx.blocking_send(chunk).is_err() || last {
+            return;
+        }
+    }
+}
+
+/// Runs `cmd` against `tree`. A `commit_group` call joins `group` instead, and a
+/// shutdown is handed back to be run once the group is committed.
+fn run<K: MerkleKey, V: MerkleValue, H: TreeHasher>(
+    tree: &mut MerkleSearchTree<K, V, H>,
+    cmd: Command<K, V>,
+    group: &mut Vec<oneshot::Sender<Result<Version>>>,
+) -> Option<oneshot::Sender<Result<()>>> {
+    match cmd {
+        Command::Insert { key, value, resp } => {
+            let _ = resp.send(tree.insert(key, value));
+        }
+        Command::InsertMany { items, resp } => {
+            let _ = resp.send(tree.insert_many(items));
+        }
+        Command::Remove { key, resp } => {
+            let _ = resp.send(tree.remove(&key));
+        }
+        Command::Get { key, resp } => {
+            let _ = resp.send(tree.get(&key));
+        }
+        Command::GetMany { keys, resp } => {
+            let keys: Vec<&K> = keys.iter().collect();
+            let _ = resp.send(tree.get_many(&keys));
+        }
+        Command::Contains { key, resp } => {
+            let _ = resp.send(tree.contains(&key));
+        }
+        Command::Query(query) => query(&tree.snapshot()),
+        Command::Commit { resp } => {
+            let committed = tree.commit();
+            let compact = committed.is_ok();
+            let _ = resp.send(committed);
+            // After replying, so the caller only waits for the commit. A failed
+            // compaction leaves the tree as it was, to be retried after a later
+            // commit.
+            if compact {
+                let _ = tree.compact_if_due();
+            }
+        }
+        Command::CommitGroup { resp } => group.push(resp),
+        Command::Compact { path, resp } => {
+            let _ = resp.send(tree.compact(path));
+        }
+        Command::Shutdown { resp } => return Some(resp),
+    }
+    None
+}
+
+/// Commits once for every call in `group` and replies to all of them with the same
+/// version or error.
+fn commit_group<K: MerkleKey, V: MerkleValue, H: TreeHasher>(
+    tree: &mut MerkleSearchTree<K, V, H>,
+    group: &mut Vec<oneshot::Sender<Result<Version>>>,
+) {
+    let committed = tree.commit();
+    let compact = committed.is_ok();
+    for resp in group.drain(..) {
+        let _ = resp.send(match &committed {
+            Ok(version) => Ok(*version),
+            Err(err) => Err(err.duplicate()),
+        });
+    }
+    if compact {
+        let _ = tree.compact_if_due();
+    }
+}
+
+/// Final commit of a worker that is about to exit. Read-only trees have nothing to
+/// save.
+fn shut_down<K: MerkleKey, V: MerkleValue, H: TreeHasher>(
+    tree: &mut MerkleSearchTree<K, V, H>,
+) -> Result<()> {
+    if tree.store.config().read_only {
+        return Ok(());
     }
+    tree.commit().map(|_| ())
 }
@@ -0,0 +1,272 @@
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::sync::{Arc, RwLock};
+
+/// What a store keeps its bytes in: a file, or a buffer in memory that behaves like
+/// one.
+///
+/// Handles are cloned so that reads and writes each have their own. Reads are
+/// positional and never move the cursor, so they need no lock; only the writer's
+/// handle is ever seeked.
+pub(crate) enum Backing {
+    File(File),
+    Memory(Memory),
+}
+
+/// A growable buffer shared by every handle cloned from the same backing, each with
+/// a cursor of its own.
+pub(crate) struct Memory {
+    bytes: Arc<RwLock<Vec<u8>>>,
+    pos: u64,
+}
+
+impl Backing {
+    /// An empty buffer in memory.
+    pub(crate) fn memory() -> Self {
+        Self::Memory(Memory {
+            bytes: Default::default(),
+            pos: 0,
+        })
+    }
+
+    /// Another handle to the same bytes, with its own cursor.
+    pub(crate) fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Self::File(file) => Self::File(file.try_clone()?),
+            Self::Memory(memory) => Self::Memory(Memory {
+                bytes: Arc::clone(&memory.bytes),
+                pos: 0,
+            }),
+        })
+    }
+
+    /// The file, for backings that have one.
+    #[cfg(feature = "mmap")]
+    pub(crate) fn as_file(&self) -> Option<&File> {
+        match self {
+            Self::File(file) => Some(file),
+            Self::Memory(_) => None,
+        }
+    }
+
+    /// Takes an exclusive advisory lock on the file, waiting for other processes to
+    /// release theirs. Memory is never shared with another process, so it needs none.
+    pub(crate) fn lock(&self) -> io::Result<Option<FileLock>> {
+        match self {
+            Self::File(file) => {
+                let file = file.try_clone()?;
+                file.lock()?;
+                Ok(Some(FileLock(file)))
+            }
+            Self::Memory(_) => Ok(None),
+        }
+    }
+
+    pub(crate) fn len(&self) -> io::Result<u64> {
+        match self {
+            Self::File(file) => Ok(file.metadata()?.len()),
+            Self::Memory(memory) => Ok(memory.bytes.read().unwrap().len() as u64),
+        }
+    }
+
+    pub(crate) fn set_len(&self, len: u64) -> io::Result<()> {
+        match self {
+            Self::File(file) => file.set_len(len),
+            Self::Memory(memory) => {
+                memory.bytes.write().unwrap().resize(to_usize(len)?, 0);
+                Ok(())
+            }
+        }
+    }
+
+    /// Makes every write so far durable. Memory has nothing to make durable.
+    pub(crate) fn sync_all(&self) -> io::Result<()> {
+        match self {
+            Self::File(file) => file.sync_all(),
+            Self::Memory(_) => Ok(()),
+        }
+    }
+
+    pub(crate) fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        match self {
+            Self::File(file) => read_exact_at(file, buf, offset),
+            Self::Memory(memory) => {
+                if memory.read_at(buf, offset)? < buf.len() {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Reads into `buf` until it is full or the bytes end, and returns the number of
+    /// bytes read.
+    pub(crate) fn read_up_to_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        match self {
+            Self::File(file) => read_up_to_at(file, buf, offset),
+            Self::Memory(memory) => memory.read_at(buf, offset),
+        }
+    }
+
+    pub(crate) fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        match self {
+            Self::File(file) => write_all_at(file, buf, offset),
+            Self::Memory(memory) => memory.write_at(buf, offset),
+        }
+    }
+}
+
+/// An exclusive advisory lock on a file, released when dropped.
+pub(crate) struct FileLock(File);
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+impl Memory {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let bytes = self.bytes.read().unwrap();
+        let start = to_usize(offset)?.min(bytes.len());
+        let read = buf.len().min(bytes.len() - start);
+        buf[..read].copy_from_slice(&bytes[start..start + read]);
+        Ok(read)
+    }
+
+    /// Writes `buf` at `offset`, filling any gap past the end with zeros like a file
+    /// would.
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let start = to_usize(offset)?;
+        let end = start
+            .checked_add(buf.len())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::OutOfMemory))?;
+        let mut bytes = self.bytes.write().unwrap();
+        if bytes.len() < end {
+            bytes.resize(end, 0);
+        }
+        bytes[start..end].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+impl Write for Backing {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::File(file) => file.write(buf),
+            Self::Memory(memory) => {
+                memory.write_at(buf, memory.pos)?;
+                memory.pos += buf.len() as u64;
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::File(file) => file.flush(),
+            Self::Memory(_) => Ok(()),
+        }
+    }
+}
+
+impl Seek for Backing {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Self::File(file) => file.seek(pos),
+            Self::Memory(memory) => {
+                let pos = match pos {
+                    SeekFrom::Start(offset) => Some(offset),
+                    SeekFrom::End(delta) => {
+                        (memory.bytes.read().unwrap().len() as u64).checked_add_signed(delta)
+                    }
+                    SeekFrom::Current(delta) => memory.pos.checked_add_signed(delta),
+                };
+                memory.pos = pos.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset")
+                })?;
+                Ok(memory.pos)
+            }
+        }
+    }
+}
+
+fn to_usize(offset: u64) -> io::Result<usize> {
+    usize::try_from(offset).map_err(|_| io::ErrorKind::OutOfMemory.into())
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn read_up_to_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read_at(&mut buf[read..], offset + read as u64) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(windows)]
+fn read_up_to_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    let mut read = 0;
+    while read < buf.len() {
+        match file.seek_read(&mut buf[read..], offset + read as u64) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
@@ -11,7 +11,7 @@ fn generate_value(i: u64) -> u64 {
 
 /// Helper to populate a tree with `count` items
 fn setup_tree(count: u64) -> MerkleSearchTree<Vec<u8>, u64> {
-    let mut tree = MerkleSearchTree::new_temporary().unwrap();
+    let tree = MerkleSearchTree::new_temporary().unwrap();
     for i in 0..count {
         tree.insert(generate_key(i), generate_value(i)).unwrap();
     }
@@ -21,7 +21,7 @@ fn setup_tree(count: u64) -> MerkleSearchTree<Vec<u8>, u64> {
 #[bench]
 fn insert_into_empty(b: &mut Bencher) {
     b.iter(|| {
-        let mut tree = MerkleSearchTree::new_temporary().unwrap();
+        let tree = MerkleSearchTree::new_temporary().unwrap();
         let key = generate_key(1);
         let val = generate_value(1);
         test::black_box(tree.insert(key, val)).unwrap();
@@ -30,7 +30,7 @@ fn insert_into_empty(b: &mut Bencher) {
 
 #[bench]
 fn insert_into_populated_1k(b: &mut Bencher) {
-    let mut tree = setup_tree(1_000);
+    let tree = setup_tree(1_000);
     let mut i = 1_000;
 
     b.iter(|| {
@@ -43,7 +43,7 @@ fn insert_into_populated_1k(b: &mut Bencher) {
 
 #[bench]
 fn insert_into_populated_10k(b: &mut Bencher) {
-    let mut tree = setup_tree(10_000);
+    let tree = setup_tree(10_000);
     let mut i = 10_000;
 
     b.iter(|| {
@@ -54,6 +54,27 @@ fn insert_into_populated_10k(b: &mut Bencher) {
     });
 }
 
+#[bench]
+fn insert_loop_10k(b: &mut Bencher) {
+    b.iter(|| {
+        let tree = MerkleSearchTree::new_temporary().unwrap();
+        for i in 0..10_000 {
+            tree.insert(generate_key(i), generate_value(i)).unwrap();
+        }
+        test::black_box(tree.root_hash());
+    });
+}
+
+#[bench]
+fn insert_many_10k(b: &mut Bencher) {
+    b.iter(|| {
+        let tree = MerkleSearchTree::new_temporary().unwrap();
+        tree.insert_many((0..10_000).map(|i| (generate_key(i), generate_value(i))))
+            .unwrap();
+        test::black_box(tree.root_hash());
+    });
+}
+
 #[bench]
 fn contains_hit(b: &mut Bencher) {
     let tree = setup_tree(10_000);
@@ -74,6 +95,52 @@ fn get_hit(b: &mut Bencher) {
     });
 }
 
+#[bench]
+fn get_loop_100(b: &mut Bencher) {
+    let tree = setup_tree(10_000);
+    let keys: Vec<Vec<u8>> = (0..100).map(|i| generate_key(i * 97)).collect();
+
+    b.iter(|| {
+        for key in &keys {
+            test::black_box(tree.get(key)).unwrap();
+        }
+    });
+}
+
+#[bench]
+fn get_many_100(b: &mut Bencher) {
+    let tree = setup_tree(10_000);
+    let keys: Vec<Vec<u8>> = (0..100).map(|i| generate_key(i * 97)).collect();
+    let queries: Vec<&Vec<u8>> = keys.iter().collect();
+
+    b.iter(|| {
+        test::black_box(tree.get_many(&queries)).unwrap();
+    });
+}
+
+#[bench]
+fn contains_loop_100(b: &mut Bencher) {
+    let tree = setup_tree(10_000);
+    let keys: Vec<Vec<u8>> = (0..100).map(|i| generate_key(i * 97)).collect();
+
+    b.iter(|| {
+        for key in &keys {
+            test::black_box(tree.contains(key)).unwrap();
+        }
+    });
+}
+
+#[bench]
+fn contains_all_100(b: &mut Bencher) {
+    let tree = setup_tree(10_000);
+    let keys: Vec<Vec<u8>> = (0..100).map(|i| generate_key(i * 97)).collect();
+    let queries: Vec<&Vec<u8>> = keys.iter().collect();
+
+    b.iter(|| {
+        test::black_box(tree.contains_all(&queries)).unwrap();
+    });
+}
+
 #[bench]
 fn contains_miss(b: &mut Bencher) {
     let tree = setup_tree(10_000);
@@ -86,7 +153,7 @@ fn contains_miss(b: &mut Bencher) {
 
 #[bench]
 fn remove_present(b: &mut Bencher) {
-    let mut tree = setup_tree(10_000);
+    let tree = setup_tree(10_000);
     let key = generate_key(99_999);
     let val = generate_value(99_999);
 
@@ -98,7 +165,7 @@ fn remove_present(b: &mut Bencher) {
 
 #[bench]
 fn remove_missing(b: &mut Bencher) {
-    let mut tree = setup_tree(10_000);
+    let tree = setup_tree(10_000);
     let key = generate_key(99_999);
 
     b.iter(|| {
@@ -116,10 +183,194 @@ fn root_hash(b: &mut Bencher) {
 
 #[bench]
 fn flush_no_changes(b: &mut Bencher) {
-    let mut tree = setup_tree(1_000);
+    let tree = setup_tree(1_000);
     tree.commit().unwrap();
 
     b.iter(|| {
         test::black_box(tree.commit()).unwrap();
     });
 }
+
+#[bench]
+fn commit_100k(b: &mut Bencher) {
+    b.iter(|| {
+        let tree = MerkleSearchTree::new_temporary().unwrap();
+        tree.insert_many((0..100_000).map(|i| (generate_key(i), generate_value(i))))
+            .unwrap();
+        tree.commit().unwrap();
+    });
+}
+
+#[bench]
+fn insert_and_commit_4k_values(b: &mut Bencher) {
+    b.iter(|| {
+        let tree = MerkleSearchTree::new_temporary().unwrap();
+        for i in 0..1_000 {
+            tree.insert(generate_key(i), vec![i as u8; 4096]).unwrap();
+        }
+        tree.commit().unwrap();
+    });
+}
+
+/// Changes every tenth of 20k committed entries and commits them through `commit`, so
+/// each commit writes most nodes of the lower levels.
+fn bench_wide_commit(b: &mut Bencher, commit: fn(&MerkleSearchTree<Vec<u8>, u64>)) {
+    let tree = MerkleSearchTree::new_temporary().unwrap();
+    tree.insert_many((0..20_000).map(|i| (generate_key(i), generate_value(i))))
+        .unwrap();
+    tree.commit().unwrap();
+    let mut round = 0;
+    b.iter(|| {
+        round += 1;
+        tree.insert_many((0..20_000).step_by(10).map(|i| (generate_key(i), round)))
+            .unwrap();
+        commit(&tree);
+    });
+}
+
+#[bench]
+fn commit_wide_dirty_set(b: &mut Bencher) {
+    bench_wide_commit(b, |tree| {
+        tree.commit().unwrap();
+    });
+}
+
+#[cfg(feature = "parallel")]
+#[bench]
+fn commit_parallel_wide_dirty_set(b: &mut Bencher) {
+    bench_wide_commit(b, |tree| {
+        tree.commit_parallel().unwrap();
+    });
+}
+
+/// Scans 100k committed entries through a fresh handle each time, so every node is
+/// read from the file rather than the node cache.
+fn bench_cold_scan(b: &mut Bencher, options: OpenOptions<Vec<u8>, u64>) {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let tree = MerkleSearchTree::open(file.path()).unwrap();
+    tree.insert_many((0..100_000).map(|i| (generate_key(i), generate_value(i))))
+        .unwrap();
+    tree.commit().unwrap();
+    drop(tree);
+
+    b.iter(|| {
+        let tree = options.open(file.path()).unwrap();
+        test::black_box(tree.iter().unwrap().count());
+    });
+}
+
+#[bench]
+fn scan_cold_100k(b: &mut Bencher) {
+    bench_cold_scan(b, MerkleSearchTree::builder().cache_capacity(16).clone());
+}
+
+#[cfg(feature = "mmap")]
+#[bench]
+fn scan_cold_100k_mmap(b: &mut Bencher) {
+    bench_cold_scan(
+        b,
+        MerkleSearchTree::builder()
+            .cache_capacity(16)
+            .mmap(true)
+            .clone(),
+    );
+}
+
+fn bench_commits(b: &mut Bencher, policy: SyncPolicy) {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let tree = MerkleSearchTree::open_with_sync_policy(file.path(), policy).unwrap();
+    let mut i = 0;
+    b.iter(|| {
+        i += 1;
+        tree.insert(generate_key(i), generate_value(i)).unwrap();
+        tree.commit().unwrap();
+    });
+}
+
+#[bench]
+fn commit_sync_always(b: &mut Bencher) {
+    bench_commits(b, SyncPolicy::Always);
+}
+
+#[bench]
+fn commit_sync_every_100(b: &mut Bencher) {
+    bench_commits(b, SyncPolicy::EveryN { n: 100 });
+}
+
+#[bench]
+fn commit_sync_never(b: &mut Bencher) {
+    bench_commits(b, SyncPolicy::Never);
+}
+
+fn async_runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+}
+
+#[bench]
+fn async_insert_loop_1k(b: &mut Bencher) {
+    let runtime = async_runtime();
+    b.iter(|| {
+        runtime.block_on(async {
+            let tree = AsyncMerkleSearchTree::new_temporary().unwrap();
+            for i in 0..1_000 {
+                tree.insert(generate_key(i), generate_value(i))
+                    .await
+                    .unwrap();
+            }
+        })
+    });
+}
+
+#[bench]
+fn async_insert_many_1k(b: &mut Bencher) {
+    let runtime = async_runtime();
+    b.iter(|| {
+        runtime.block_on(async {
+            let tree = AsyncMerkleSearchTree::new_temporary().unwrap();
+            let items = (0..1_000)
+                .map(|i| (generate_key(i), generate_value(i)))
+                .collect();
+            tree.insert_many(items).await.unwrap();
+        })
+    });
+}
+
+#[bench]
+fn async_get_loop_100(b: &mut Bencher) {
+    let runtime = async_runtime();
+    let tree: AsyncMerkleSearchTree<_, _> = setup_tree(10_000).into();
+    b.iter(|| {
+        runtime.block_on(async {
+            for i in (0..10_000).step_by(100) {
+                test::black_box(tree.get(generate_key(i)).await.unwrap());
+            }
+        })
+    });
+}
+
+/// Same lookups as `async_get_loop_100`, from keys the caller keeps.
+#[bench]
+fn async_get_borrowed_loop_100(b: &mut Bencher) {
+    let runtime = async_runtime();
+    let tree: AsyncMerkleSearchTree<_, _> = setup_tree(10_000).into();
+    let keys: Vec<Vec<u8>> = (0..10_000).step_by(100).map(generate_key).collect();
+    b.iter(|| {
+        runtime.block_on(async {
+            for key in &keys {
+                test::black_box(tree.get_borrowed(key.as_slice()).await.unwrap());
+            }
+        })
+    });
+}
+
+#[bench]
+fn async_get_many_100(b: &mut Bencher) {
+    let runtime = async_runtime();
+    let tree: AsyncMerkleSearchTree<_, _> = setup_tree(10_000).into();
+    b.iter(|| {
+        let keys = (0..10_000).step_by(100).map(generate_key).collect();
+        runtime.block_on(async { test::black_box(tree.get_many(keys).await.unwrap()) })
+    });
+}
@@ -0,0 +1,101 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use crate::NodeId;
+
+/// Node cache counters since the tree was opened or last compacted, as reported by
+/// [`MerkleSearchTree::cache_metrics`].
+///
+/// [`MerkleSearchTree::cache_metrics`]: crate::MerkleSearchTree::cache_metrics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheMetrics {
+    /// Node loads served from the cache.
+    pub hits: u64,
+    /// Node loads that had to read the file.
+    pub misses: u64,
+    /// Nodes dropped to stay within the cache capacity.
+    pub evictions: u64,
+}
+
+impl CacheMetrics {
+    /// Fraction of node loads served from the cache; 0 if nothing was loaded yet.
+    pub fn hit_rate(&self) -> f64 {
+        let loads = self.hits + self.misses;
+        if loads == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / loads as f64
+    }
+}
+
+/// A least-recently-used map from node offsets to loaded nodes.
+///
+/// Recency is tracked with a monotonically increasing tick; `order` maps each tick
+/// back to its offset so the oldest entry can be found in O(log n).
+pub(crate) struct LruCache<T> {
+    capacity: usize,
+    entries: HashMap<NodeId, (Arc<T>, u64)>,
+    order: BTreeMap<u64, NodeId>,
+    tick: u64,
+}
+
+impl<T> LruCache<T> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns the cached value and marks it as most recently used.
+    pub(crate) fn get(&mut self, id: NodeId) -> Option<Arc<T>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (value, last_used) = self.entries.get_mut(&id)?;
+        self.order.remove(last_used);
+        *last_used = tick;
+        self.order.insert(tick, id);
+        Some(value.clone())
+    }
+
+    pub(crate) fn remove(&mut self, id: NodeId) {
+        if let Some((_, tick)) = self.entries.remove(&id) {
+            self.order.remove(&tick);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    /// Inserts a value, evicting least recently used entries beyond capacity, and
+    /// returns how many were evicted.
+    ///
+    /// Evicting only drops the cache's own `Arc`; callers holding clones keep the
+    /// node alive.
+    pub(crate) fn insert(&mut self, id: NodeId, value: Arc<T>) -> u64 {
+        self.tick += 1;
+        if let Some((_, old_tick)) = self.entries.insert(id, (value, self.tick)) {
+            self.order.remove(&old_tick);
+        }
+        self.order.insert(self.tick, id);
+
+        let mut evicted = 0;
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+            evicted += 1;
+        }
+        evicted
+    }
+}
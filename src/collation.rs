@@ -0,0 +1,115 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::marker::PhantomData;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// An order for keys other than their `Ord`, applied through [`Collated`].
+///
+/// The tree computes each key's level and hash from its encoding, so keys that
+/// compare equal must be identical: a collation that ignores some difference between
+/// keys, such as case, removes it in [`normalize`](Self::normalize).
+pub trait Collation<K> {
+    fn compare(a: &K, b: &K) -> Ordering;
+
+    /// The form a key is stored and hashed in. Keys that compare equal must normalize
+    /// to equal keys.
+    fn normalize(key: K) -> K {
+        key
+    }
+}
+
+/// A key ordered by the collation `C` instead of its own `Ord`.
+///
+/// It is encoded exactly like the key it wraps. Its type name, and with it the
+/// schema fingerprint recorded in the header, includes `C`, so a file written with
+/// one collation fails to open with another unless a schema id is set.
+pub struct Collated<K, C> {
+    key: K,
+    collation: PhantomData<fn() -> C>,
+}
+
+impl<K, C: Collation<K>> Collated<K, C> {
+    /// Wraps `key`, normalized by `C`.
+    pub fn new(key: K) -> Self {
+        Self {
+            key: C::normalize(key),
+            collation: PhantomData,
+        }
+    }
+}
+
+impl<K, C> Collated<K, C> {
+    pub fn get(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_inner(self) -> K {
+        self.key
+    }
+}
+
+impl<K: Clone, C> Clone for Collated<K, C> {
+    fn clone(&self) -> Self {
+        Self {
+            key: self.key.clone(),
+            collation: PhantomData,
+        }
+    }
+}
+
+impl<K: fmt::Debug, C> fmt::Debug for Collated<K, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.key.fmt(f)
+    }
+}
+
+impl<K, C: Collation<K>> PartialEq for Collated<K, C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<K, C: Collation<K>> Eq for Collated<K, C> {}
+
+impl<K, C: Collation<K>> PartialOrd for Collated<K, C> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K, C: Collation<K>> Ord for Collated<K, C> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        C::compare(&self.key, &other.key)
+    }
+}
+
+impl<K: Serialize, C> Serialize for Collated<K, C> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.key.serialize(serializer)
+    }
+}
+
+/// Keys read back were normalized when written, so they are not normalized again.
+impl<'de, K: Deserialize<'de>, C> Deserialize<'de> for Collated<K, C> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self {
+            key: K::deserialize(deserializer)?,
+            collation: PhantomData,
+        })
+    }
+}
+
+/// Orders strings ignoring case, by storing them in lower case.
+#[derive(Debug, Clone, Copy)]
+pub struct CaseInsensitive;
+
+impl Collation<String> for CaseInsensitive {
+    fn compare(a: &String, b: &String) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn normalize(key: String) -> String {
+        key.to_lowercase()
+    }
+}
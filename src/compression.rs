@@ -0,0 +1,74 @@
+use std::borrow::Cow;
+use std::io;
+
+use crate::Error;
+
+/// Codec applied to node payloads when they are written.
+///
+/// The codec is recorded with every node, so nodes written with different settings
+/// can share a file and are always read back correctly. Only files created with
+/// format version 3 or later store compressed nodes; older files keep being written
+/// uncompressed until compacted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// zstd at the given level; 0 selects zstd's default level.
+    #[cfg(feature = "compression")]
+    Zstd(i32),
+}
+
+const TAG_NONE: u8 = 0;
+const TAG_ZSTD: u8 = 1;
+
+/// `codec (1) | uncompressed length (4)`
+pub(crate) const FRAME_HEADER_LEN: usize = 5;
+
+impl Compression {
+    /// Frames `raw` for storage, compressing it if that makes it smaller.
+    pub(crate) fn encode(self, raw: Vec<u8>) -> io::Result<Vec<u8>> {
+        let raw_len = u32::try_from(raw.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "node is too large"))?;
+        let (tag, payload) = match self {
+            Compression::None => (TAG_NONE, raw),
+            #[cfg(feature = "compression")]
+            Compression::Zstd(level) => {
+                let compressed = zstd::bulk::compress(&raw, level)?;
+                if compressed.len() < raw.len() {
+                    (TAG_ZSTD, compressed)
+                } else {
+                    (TAG_NONE, raw)
+                }
+            }
+        };
+
+        let mut framed = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        framed.push(tag);
+        framed.extend_from_slice(&raw_len.to_le_bytes());
+        framed.extend_from_slice(&payload);
+        Ok(framed)
+    }
+
+    /// Undoes `encode`, whichever codec the node was written with, borrowing the
+    /// payload of uncompressed nodes from `framed`.
+    pub(crate) fn decode_borrowed(framed: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+        if framed.len() < FRAME_HEADER_LEN {
+            return Err(Error::Corrupt("node record is too short".to_string()).into());
+        }
+        match framed[0] {
+            TAG_NONE => Ok(Cow::Borrowed(&framed[FRAME_HEADER_LEN..])),
+            #[cfg(feature = "compression")]
+            TAG_ZSTD => {
+                let raw_len = u32::from_le_bytes(framed[1..FRAME_HEADER_LEN].try_into().unwrap());
+                zstd::bulk::decompress(&framed[FRAME_HEADER_LEN..], raw_len as usize)
+                    .map(Cow::Owned)
+            }
+            #[cfg(not(feature = "compression"))]
+            TAG_ZSTD => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "node is zstd-compressed; enable the `compression` feature to read it",
+            )),
+            tag => Err(Error::Corrupt(format!("unknown node codec {tag}")).into()),
+        }
+    }
+}
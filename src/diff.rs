@@ -0,0 +1,81 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::iter::Iter;
+use crate::{Error, MerkleKey, MerkleValue, Result};
+
+/// One way in which other entries differ from those of a tree, as returned by
+/// [`MerkleSearchTree::diff_map`].
+///
+/// Each variant is named after what applying the other entries to the tree would do.
+///
+/// [`MerkleSearchTree::diff_map`]: crate::MerkleSearchTree::diff_map
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference<K, V> {
+    /// The key is missing from the tree.
+    Added { key: Arc<K>, value: Arc<V> },
+    /// The key is only in the tree.
+    Removed { key: Arc<K>, value: Arc<V> },
+    /// The key has the value `old` in the tree and `new` in the other entries.
+    Changed {
+        key: Arc<K>,
+        old: Arc<V>,
+        new: Arc<V>,
+    },
+}
+
+/// Merges the entries of `tree` with those of `map`, both in key order.
+pub(crate) fn diff_map<K, V>(
+    tree: Iter<K, V>,
+    map: &BTreeMap<K, V>,
+) -> Result<Vec<Difference<K, V>>>
+where
+    K: MerkleKey + Clone,
+    V: MerkleValue + Clone,
+{
+    let mut differences = Vec::new();
+    let mut tree = tree.peekable();
+    let mut map = map.iter().peekable();
+    loop {
+        let order = match (tree.peek(), map.peek()) {
+            (None, None) => break,
+            (Some(Err(_)), _) => return Err(tree.next().unwrap().unwrap_err()),
+            (Some(Ok((key, _))), Some((other, _))) => key.as_ref().cmp(other),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+        };
+        match order {
+            Ordering::Less => {
+                let (key, value) = tree.next().unwrap()?;
+                differences.push(Difference::Removed { key, value });
+            }
+            Ordering::Greater => {
+                let (key, value) = map.next().unwrap();
+                differences.push(Difference::Added {
+                    key: Arc::new(key.clone()),
+                    value: Arc::new(value.clone()),
+                });
+            }
+            Ordering::Equal => {
+                let (key, old) = tree.next().unwrap()?;
+                let (_, new) = map.next().unwrap();
+                if !same_value(old.as_ref(), new)? {
+                    differences.push(Difference::Changed {
+                        key,
+                        old,
+                        new: Arc::new(new.clone()),
+                    });
+                }
+            }
+        }
+    }
+    Ok(differences)
+}
+
+/// Values are equal when they serialize, and so hash, the same.
+fn same_value<V: MerkleValue>(a: &V, b: &V) -> Result<bool> {
+    let a = postcard::to_extend(a, Vec::new()).map_err(Error::from)?;
+    let b = postcard::to_extend(b, Vec::new()).map_err(Error::from)?;
+    Ok(a == b)
+}
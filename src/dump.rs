@@ -0,0 +1,107 @@
+use std::io::{self, BufRead, Read, Write};
+
+use crate::iter::Iter;
+use crate::{Error, MerkleKey, MerkleValue, Result};
+
+/// Entries imported per batch by `import_entries`, which commits after each one so
+/// memory use does not grow with the input. Tests use small batches to cover several
+/// of them cheaply.
+pub(crate) const IMPORT_BATCH_LEN: usize = if cfg!(test) { 1000 } else { 64 * 1024 };
+
+/// How [`MerkleSearchTree::export_entries`] writes entries and
+/// [`MerkleSearchTree::import_entries`] reads them back.
+///
+/// [`MerkleSearchTree::export_entries`]: crate::MerkleSearchTree::export_entries
+/// [`MerkleSearchTree::import_entries`]: crate::MerkleSearchTree::import_entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EntryFormat {
+    /// Each entry is its postcard encoding as a `(key, value)` tuple, preceded by the
+    /// length of that encoding as a little-endian `u32`.
+    Postcard,
+    /// Each entry is a `[key, value]` JSON array on a line of its own.
+    #[cfg(feature = "json")]
+    JsonLines,
+}
+
+/// Writes every entry of `iter` to `writer` in `format`, returning how many there were.
+pub(crate) fn export_entries<K, V, W>(
+    iter: Iter<K, V>,
+    writer: W,
+    format: EntryFormat,
+) -> Result<u64>
+where
+    K: MerkleKey,
+    V: MerkleValue,
+    W: Write,
+{
+    let mut writer = io::BufWriter::new(writer);
+    let mut buf = Vec::new();
+    let mut count = 0;
+    for entry in iter {
+        let (key, value) = entry?;
+        match format {
+            EntryFormat::Postcard => {
+                buf.clear();
+                buf = postcard::to_extend(&(&*key, &*value), buf)?;
+                let len = u32::try_from(buf.len())
+                    .map_err(|_| Error::Serialization("entry is longer than 4 GiB".to_string()))?;
+                writer.write_all(&len.to_le_bytes())?;
+                writer.write_all(&buf)?;
+            }
+            #[cfg(feature = "json")]
+            EntryFormat::JsonLines => {
+                serde_json::to_writer(&mut writer, &(&*key, &*value))?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Reads the entries written by `export_entries` one at a time.
+pub(crate) struct EntryReader<R> {
+    reader: io::BufReader<R>,
+    format: EntryFormat,
+    buf: Vec<u8>,
+}
+
+impl<R: Read> EntryReader<R> {
+    pub(crate) fn new(reader: R, format: EntryFormat) -> Self {
+        Self {
+            reader: io::BufReader::new(reader),
+            format,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Reads the next entry, or `None` at the end of the input. Input that ends in the
+    /// middle of an entry is an error.
+    pub(crate) fn read_entry<K: MerkleKey, V: MerkleValue>(&mut self) -> Result<Option<(K, V)>> {
+        match self.format {
+            EntryFormat::Postcard => {
+                if self.reader.fill_buf()?.is_empty() {
+                    return Ok(None);
+                }
+                let mut len = [0u8; 4];
+                self.reader.read_exact(&mut len)?;
+                self.buf.resize(u32::from_le_bytes(len) as usize, 0);
+                self.reader.read_exact(&mut self.buf)?;
+                Ok(Some(postcard::from_bytes(&self.buf)?))
+            }
+            #[cfg(feature = "json")]
+            EntryFormat::JsonLines => loop {
+                self.buf.clear();
+                if self.reader.read_until(b'\n', &mut self.buf)? == 0 {
+                    return Ok(None);
+                }
+                // Tolerates blank lines, such as a trailing one.
+                if !self.buf.trim_ascii().is_empty() {
+                    return Ok(Some(serde_json::from_slice(&self.buf)?));
+                }
+            },
+        }
+    }
+}
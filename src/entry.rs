@@ -0,0 +1,209 @@
+use std::io;
+use std::sync::Arc;
+
+use crate::node::{Link, Node};
+use crate::{Blake3Hasher, MerkleKey, MerkleSearchTree, MerkleValue, Result, TreeHasher};
+
+/// Nodes from the root down to where a lookup ended, each paired with the index it
+/// was left through (for the last node: the key's index or insertion point).
+type Path<K, V> = Vec<(Arc<Node<K, V>>, usize)>;
+
+/// A view into a single key of a [`MerkleSearchTree`], obtained from
+/// [`MerkleSearchTree::entry`].
+///
+/// The entry holds a mutable borrow of the tree together with the root-to-node path
+/// found by the lookup, so a following write reuses that path instead of descending
+/// again. Because of the borrow, the tree cannot be used until the entry is consumed
+/// or dropped. Dropping an entry without writing leaves the tree unchanged.
+pub enum Entry<'a, K: MerkleKey, V: MerkleValue, H: TreeHasher = Blake3Hasher> {
+    Occupied(OccupiedEntry<'a, K, V, H>),
+    Vacant(VacantEntry<'a, K, V, H>),
+}
+
+/// An entry for a key that exists in the tree.
+pub struct OccupiedEntry<'a, K: MerkleKey, V: MerkleValue, H: TreeHasher = Blake3Hasher> {
+    tree: &'a mut MerkleSearchTree<K, V, H>,
+    key: K,
+    path: Path<K, V>,
+}
+
+/// An entry for a key that does not exist in the tree or, in tombstone mode, was removed.
+pub struct VacantEntry<'a, K: MerkleKey, V: MerkleValue, H: TreeHasher = Blake3Hasher> {
+    tree: &'a mut MerkleSearchTree<K, V, H>,
+    key: K,
+    path: Path<K, V>,
+}
+
+impl<'a, K: MerkleKey, V: MerkleValue, H: TreeHasher> Entry<'a, K, V, H> {
+    pub(crate) fn new(tree: &'a mut MerkleSearchTree<K, V, H>, key: K) -> io::Result<Self> {
+        let (path, found) = find(tree, &key)?;
+        // In tombstone mode, a removed key is vacant.
+        let buried = found
+            && tree.tombstones.is_some_and(|tombstones| {
+                let (node, idx) = path.last().expect("path always holds the key's node");
+                tombstones.is_tombstone(&node.values[*idx])
+            });
+        Ok(if found && !buried {
+            Entry::Occupied(OccupiedEntry { tree, key, path })
+        } else {
+            Entry::Vacant(VacantEntry { tree, key, path })
+        })
+    }
+
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(entry) => &entry.key,
+            Entry::Vacant(entry) => &entry.key,
+        }
+    }
+
+    /// Returns the existing value, or inserts `default` if the key is absent.
+    pub fn or_insert(self, default: V) -> Result<Arc<V>> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Returns the existing value, or inserts the result of `default` if the key is
+    /// absent.
+    pub fn or_insert_with(self, default: impl FnOnce() -> V) -> Result<Arc<V>> {
+        match self {
+            Entry::Occupied(entry) => Ok(entry.get().clone()),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// Applies `f` to a copy of the existing value and stores the result. Does nothing
+    /// if the key is absent.
+    pub fn and_modify(self, f: impl FnOnce(&mut V)) -> Result<Self>
+    where
+        V: Clone,
+    {
+        match self {
+            Entry::Occupied(mut entry) => {
+                let mut value = entry.get().as_ref().clone();
+                f(&mut value);
+                entry.insert(value)?;
+                Ok(Entry::Occupied(entry))
+            }
+            vacant => Ok(vacant),
+        }
+    }
+}
+
+impl<K: MerkleKey, V: MerkleValue, H: TreeHasher> OccupiedEntry<'_, K, V, H> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn get(&self) -> &Arc<V> {
+        let (node, idx) = self.path.last().expect("path always holds the key's node");
+        &node.values[*idx]
+    }
+
+    /// Replaces the value, returning the old one.
+    pub fn insert(&mut self, value: V) -> Result<Arc<V>> {
+        let old = self.get().clone();
+        let (node, idx) = self.path.last().expect("path always holds the key's node");
+        // Under a node byte budget, a value of another size can move the key to another
+        // level, which takes a put from the root and a new lookup.
+        if self.tree.level_of_entry(&self.key, &value) != node.level {
+            let key = node.keys[*idx].clone();
+            let root = self.tree.put_entry(&self.path[0].0, key, Arc::new(value))?;
+            self.tree.state.get_mut().unwrap().root = Link::Loaded(root);
+            self.path = find(self.tree, &self.key)?.0;
+            return Ok(old);
+        }
+        let updated = node.with_value::<H>(*idx, Arc::new(value), &self.tree.store);
+        // An identical value leaves the tree as it was.
+        if updated.hash != node.hash {
+            rebuild(self.tree, &mut self.path, updated);
+        }
+        Ok(old)
+    }
+}
+
+impl<K: MerkleKey, V: MerkleValue, H: TreeHasher> VacantEntry<'_, K, V, H> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Inserts the value, producing the same tree as [`MerkleSearchTree::insert`].
+    pub fn insert(mut self, value: V) -> Result<Arc<V>> {
+        let value = Arc::new(value);
+        // A removed key in tombstone mode: its tombstone is on the path, and the value
+        // replaces it.
+        let (node, idx) = self.path.last().expect("a vacant path is never empty");
+        if node.keys.get(*idx).is_some_and(|key| **key == self.key) {
+            let key = node.keys[*idx].clone();
+            let root = self.tree.put_entry(&self.path[0].0, key, value.clone())?;
+            self.tree.state.get_mut().unwrap().root = Link::Loaded(root);
+            return Ok(value);
+        }
+
+        let key_level = self.tree.level_of_entry(&self.key, &value);
+
+        // `put` keeps descending while the key belongs strictly below a non-empty
+        // node; the first node where it stops is where the insertion happens.
+        let depth = self
+            .path
+            .iter()
+            .position(|(node, _)| {
+                key_level >= node.level || (node.keys.is_empty() && node.children.is_empty())
+            })
+            .expect("a vacant path always ends in an empty node");
+        self.path.truncate(depth + 1);
+
+        let updated = self.path[depth].0.put::<H>(
+            Arc::new(self.key),
+            value.clone(),
+            key_level,
+            &self.tree.store,
+        )?;
+        rebuild(self.tree, &mut self.path, updated);
+        Ok(value)
+    }
+}
+
+/// Looks up `key`, returning the path to where the lookup ended and whether the key
+/// was found there.
+fn find<K: MerkleKey, V: MerkleValue, H: TreeHasher>(
+    tree: &MerkleSearchTree<K, V, H>,
+    key: &K,
+) -> io::Result<(Path<K, V>, bool)> {
+    let mut path = Vec::new();
+    let root = tree.state.read().unwrap().root.clone();
+    let mut node = tree.resolve_link(&root)?;
+
+    loop {
+        match node.keys.binary_search_by(|probe| probe.as_ref().cmp(key)) {
+            Ok(idx) => {
+                path.push((node, idx));
+                return Ok((path, true));
+            }
+            Err(idx) if node.children.is_empty() => {
+                path.push((node, idx));
+                return Ok((path, false));
+            }
+            Err(idx) => {
+                let child = tree.resolve_link(&node.children[idx])?;
+                path.push((node, idx));
+                node = child;
+            }
+        }
+    }
+}
+
+/// Puts `node` in place of the last node on `path` and path-copies every ancestor,
+/// then makes the new spine the tree's root.
+fn rebuild<K: MerkleKey, V: MerkleValue, H: TreeHasher>(
+    tree: &mut MerkleSearchTree<K, V, H>,
+    path: &mut Path<K, V>,
+    node: Arc<Node<K, V>>,
+) {
+    let last = path.len() - 1;
+    path[last].0 = node;
+    for i in (0..last).rev() {
+        let child = Link::Loaded(path[i + 1].0.clone());
+        path[i].0 = path[i].0.with_child::<H>(path[i].1, child, &tree.store);
+    }
+    tree.state.get_mut().unwrap().root = Link::Loaded(path[0].0.clone());
+}
@@ -0,0 +1,124 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Result of the tree's operations.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// An error from a tree operation.
+///
+/// Converts to and from [`io::Error`]: an `Error` turned into an `io::Error` comes
+/// back out as the same variant, so it survives passing through code written against
+/// `io::Result`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Reading or writing the file failed, or an argument was invalid.
+    Io(io::Error),
+    /// The file is damaged: a checksum, hash or structural check failed.
+    Corrupt(String),
+    /// The file was written in a format version this build cannot read.
+    VersionMismatch { found: u32, expected: u32 },
+    /// The file was created for other key and value types, or another schema id.
+    SchemaMismatch { found: u64, expected: u64 },
+    /// A key, value or node could not be encoded or decoded.
+    Serialization(String),
+    /// A string, such as a [`Version`](crate::Version), could not be parsed.
+    Parse(String),
+}
+
+impl Error {
+    /// The closest [`io::ErrorKind`]: `InvalidInput` for `Parse`, and `InvalidData`
+    /// for everything else but `Io`.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::Io(error) => error.kind(),
+            Error::Parse(_) => io::ErrorKind::InvalidInput,
+            _ => io::ErrorKind::InvalidData,
+        }
+    }
+
+    /// A copy of the error, for reporting it to several callers. An `Io` error keeps
+    /// its kind and message, but not its source.
+    pub(crate) fn duplicate(&self) -> Error {
+        match self {
+            Error::Io(error) => Error::Io(io::Error::new(error.kind(), error.to_string())),
+            Error::Corrupt(message) => Error::Corrupt(message.clone()),
+            Error::VersionMismatch { found, expected } => Error::VersionMismatch {
+                found: *found,
+                expected: *expected,
+            },
+            Error::SchemaMismatch { found, expected } => Error::SchemaMismatch {
+                found: *found,
+                expected: *expected,
+            },
+            Error::Serialization(message) => Error::Serialization(message.clone()),
+            Error::Parse(message) => Error::Parse(message.clone()),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => error.fmt(f),
+            Error::Corrupt(message) => write!(f, "corrupt database: {message}"),
+            Error::VersionMismatch { found, expected } => write!(
+                f,
+                "unsupported format version {found} (this build writes version {expected})"
+            ),
+            Error::SchemaMismatch { found, expected } => write!(
+                f,
+                "schema mismatch: the file was written with schema {found:#018x}, but was \
+                 opened as {expected:#018x}; use the same key and value types or schema id \
+                 it was created with"
+            ),
+            Error::Serialization(message) => write!(f, "serialization failed: {message}"),
+            Error::Parse(message) => write!(f, "parsing failed: {message}"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        if error.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            let inner = error.into_inner().unwrap();
+            return *inner.downcast::<Error>().unwrap();
+        }
+        Error::Io(error)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Io(error) => error,
+            error => io::Error::new(error.kind(), error),
+        }
+    }
+}
+
+impl From<postcard::Error> for Error {
+    fn from(error: postcard::Error) -> Self {
+        Error::Serialization(error.to_string())
+    }
+}
+
+#[cfg(feature = "json")]
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        match error.io_error_kind() {
+            Some(kind) => Error::Io(io::Error::new(kind, error)),
+            None => Error::Serialization(error.to_string()),
+        }
+    }
+}
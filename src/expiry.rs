@@ -0,0 +1,187 @@
+use std::borrow::Borrow;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::iter::KeysIter;
+use crate::tombstone::unix_millis;
+use crate::{
+    Blake3Hasher, Hash, MerkleKey, MerkleSearchTree, MerkleValue, Result, TreeHasher, Version,
+};
+
+/// A value as stored by an [`ExpiringTree`], with the time it expires at.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Expiring<V> {
+    pub value: V,
+    /// In milliseconds since the Unix epoch; `None` for values that never expire.
+    pub expires_at: Option<u64>,
+}
+
+impl<V> Expiring<V> {
+    /// Whether the value has expired by `now`, in milliseconds since the Unix epoch.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+impl<V> Deref for Expiring<V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.value
+    }
+}
+
+/// A tree whose entries can expire, stored as a [`MerkleSearchTree`] of
+/// [`Expiring`] values.
+///
+/// Expired entries are absent to `get`, `contains` and `len`, but stay in the file,
+/// in the root hash and in everything reached through [`as_tree`](Self::as_tree)
+/// until [`purge_expired`](Self::purge_expired) removes them.
+///
+/// Expiry times are absolute wall-clock times, stored with each value, and are
+/// compared against the tree's clock, [`SystemTime::now`] unless replaced with
+/// [`with_clock`](Self::with_clock). Processes whose clocks disagree disagree about
+/// which entries have expired, and an entry expired but not yet purged comes back
+/// if the clock is set back past its expiry time.
+pub struct ExpiringTree<K: MerkleKey, V: MerkleValue, H: TreeHasher = Blake3Hasher> {
+    tree: MerkleSearchTree<K, Expiring<V>, H>,
+    clock: fn() -> SystemTime,
+}
+
+impl<K: MerkleKey, V: MerkleValue> ExpiringTree<K, V> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::from_tree(MerkleSearchTree::open(path)?))
+    }
+
+    /// Creates a tree backed by a temporary file, which is deleted once closed.
+    pub fn new_temporary() -> Result<Self> {
+        Ok(Self::from_tree(MerkleSearchTree::new_temporary()?))
+    }
+
+    /// Creates a tree held entirely in memory.
+    pub fn new_in_memory() -> Result<Self> {
+        Ok(Self::from_tree(MerkleSearchTree::new_in_memory()?))
+    }
+}
+
+impl<K: MerkleKey, V: MerkleValue, H: TreeHasher> ExpiringTree<K, V, H> {
+    /// Wraps a tree opened some other way, e.g. through [`OpenOptions`].
+    ///
+    /// [`OpenOptions`]: crate::OpenOptions
+    pub fn from_tree(tree: MerkleSearchTree<K, Expiring<V>, H>) -> Self {
+        Self {
+            tree,
+            clock: SystemTime::now,
+        }
+    }
+
+    /// Decides what has expired by `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: fn() -> SystemTime) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn as_tree(&self) -> &MerkleSearchTree<K, Expiring<V>, H> {
+        &self.tree
+    }
+
+    pub fn into_tree(self) -> MerkleSearchTree<K, Expiring<V>, H> {
+        self.tree
+    }
+
+    fn now(&self) -> u64 {
+        unix_millis((self.clock)())
+    }
+
+    /// Inserts a value that never expires.
+    pub fn insert(&self, key: K, value: V) -> Result<()> {
+        self.tree.insert(
+            key,
+            Expiring {
+                value,
+                expires_at: None,
+            },
+        )
+    }
+
+    /// Inserts a value that is absent from `expires_at` on.
+    pub fn insert_with_ttl(&self, key: K, value: V, expires_at: SystemTime) -> Result<()> {
+        self.tree.insert(
+            key,
+            Expiring {
+                value,
+                expires_at: Some(unix_millis(expires_at)),
+            },
+        )
+    }
+
+    /// The value under `key`, unless there is none or it has expired.
+    pub fn get<Q>(&self, key: &Q) -> Result<Option<Arc<Expiring<V>>>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let now = self.now();
+        Ok(self.tree.get(key)?.filter(|value| !value.is_expired(now)))
+    }
+
+    pub fn contains<Q>(&self, key: &Q) -> Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let now = self.now();
+        Ok(self
+            .tree
+            .with_value(key, |value| !value.is_expired(now))?
+            .unwrap_or(false))
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Result<()>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.tree.remove(key)
+    }
+
+    /// Number of entries that have not expired. Reads every value to find out, unlike
+    /// [`MerkleSearchTree::len`].
+    pub fn len(&self) -> Result<u64> {
+        let now = self.now();
+        let mut len = 0;
+        for value in self.tree.values()? {
+            if !value?.is_expired(now) {
+                len += 1;
+            }
+        }
+        Ok(len)
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Iterates over all keys in order, expired ones included.
+    pub fn keys(&self) -> Result<KeysIter<K, Expiring<V>>> {
+        self.tree.keys()
+    }
+
+    /// Removes every entry expired by `now`, and returns how many there were.
+    pub fn purge_expired(&self, now: SystemTime) -> Result<u64> {
+        let now = unix_millis(now);
+        self.tree.retain(|_, value| !value.is_expired(now))
+    }
+
+    pub fn commit(&self) -> Result<Version> {
+        self.tree.commit()
+    }
+
+    pub fn root_hash(&self) -> Hash {
+        self.tree.root_hash()
+    }
+}
@@ -0,0 +1,184 @@
+use crate::hash::{HASH_LEN, Hash};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+use crate::node::{DiskNode, DiskNodeRef, Link, Node};
+use crate::snapshot::Snapshot;
+use crate::store::Store;
+use crate::{Error, MerkleKey, MerkleValue, Result, TreeHasher};
+
+/// Iterator over the nodes of a tree in their portable encoding, as returned by
+/// [`MerkleSearchTree::export_nodes`].
+///
+/// Each item is a node's hash and its postcard encoding, which refers to children by
+/// hash rather than by file offset. Children come before their parents and the root
+/// comes last. Empty nodes are left out. Loading errors are yielded once, after which
+/// the iterator is exhausted.
+///
+/// [`MerkleSearchTree::export_nodes`]: crate::MerkleSearchTree::export_nodes
+pub struct NodeExport<K: MerkleKey, V: MerkleValue> {
+    snapshot: Snapshot<K, V>,
+    /// Path to the node being visited, each with the index of its next child.
+    stack: Vec<(Arc<Node<K, V>>, usize)>,
+}
+
+impl<K: MerkleKey, V: MerkleValue> NodeExport<K, V> {
+    pub(crate) fn new(snapshot: Snapshot<K, V>) -> io::Result<Self> {
+        let mut export = Self {
+            snapshot,
+            stack: Vec::new(),
+        };
+        let root = export.snapshot.root.clone();
+        if !is_empty(&root) {
+            let root = export.resolve(&root)?;
+            export.stack.push((root, 0));
+        }
+        Ok(export)
+    }
+
+    /// Nodes read for an export are not added to the cache.
+    fn resolve(&self, link: &Link<K, V>) -> io::Result<Arc<Node<K, V>>> {
+        match link {
+            Link::Loaded(node) => Ok(node.clone()),
+            Link::Disk { offset, hash, .. } => {
+                self.snapshot.store.load_node_uncached(*offset, *hash)
+            }
+        }
+    }
+}
+
+impl<K: MerkleKey, V: MerkleValue> Iterator for NodeExport<K, V> {
+    type Item = Result<(Hash, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (node, idx) = self.stack.last_mut()?;
+            if let Some(child) = node.children.get(*idx) {
+                *idx += 1;
+                if is_empty(child) {
+                    continue;
+                }
+                let child = child.clone();
+                match self.resolve(&child) {
+                    Ok(child) => self.stack.push((child, 0)),
+                    Err(e) => {
+                        self.stack.clear();
+                        return Some(Err(e.into()));
+                    }
+                }
+                continue;
+            }
+
+            let (node, _) = self.stack.pop()?;
+            let encoded = DiskNodeRef {
+                level: node.level,
+                keys: &node.keys,
+                values: &node.values,
+                children: node.children.iter().map(Link::hash).collect::<Vec<_>>(),
+                hash: node.hash,
+                encoded_values: &node.encoded_values,
+            };
+            return Some(
+                postcard::to_extend(&encoded, Vec::new())
+                    .map(|bytes| (node.hash, bytes))
+                    .map_err(Error::from),
+            );
+        }
+    }
+}
+
+/// Rebuilds the tree rooted at `root` from exported `nodes`, which must list children
+/// before their parents. Children missing from `nodes` are looked up in the tree
+/// behind `existing`. Every node is checked against its hash.
+pub(crate) fn import_nodes<K, V, H, I>(
+    root: Hash,
+    nodes: I,
+    existing: &Link<K, V>,
+    store: &Store<K, V>,
+) -> io::Result<Link<K, V>>
+where
+    K: MerkleKey,
+    V: MerkleValue,
+    H: TreeHasher,
+    I: IntoIterator<Item = (Hash, Vec<u8>)>,
+{
+    let mut imported = HashMap::new();
+    // Built on the first child that was not sent, as it walks the whole local tree.
+    let mut local = None;
+
+    for (hash, bytes) in nodes {
+        let disk: DiskNode<K, V, Hash> = postcard::from_bytes(&bytes).map_err(Error::from)?;
+        let mut children = Vec::with_capacity(disk.children.len());
+        for child in disk.children {
+            children.push(find_node(child, &imported, &mut local, existing, store)?);
+        }
+        let disk = DiskNode {
+            level: disk.level,
+            keys: disk.keys,
+            values: disk.values,
+            children,
+            hash: disk.hash,
+        };
+
+        let node = Node::from_disk(disk, |link| link);
+        if node.hash != hash || node.hash_with::<H>(store.domain_salt()) != hash {
+            return Err(
+                Error::Corrupt(format!("imported node does not match its hash {hash}")).into(),
+            );
+        }
+        imported.insert(hash, Link::Loaded(Arc::new(node)));
+    }
+
+    find_node(root, &imported, &mut local, existing, store)
+}
+
+fn find_node<K: MerkleKey, V: MerkleValue>(
+    hash: Hash,
+    imported: &HashMap<Hash, Link<K, V>>,
+    local: &mut Option<HashMap<Hash, Link<K, V>>>,
+    existing: &Link<K, V>,
+    store: &Store<K, V>,
+) -> io::Result<Link<K, V>> {
+    if hash == Hash::from_bytes([0u8; HASH_LEN]) {
+        return Ok(Link::Loaded(Arc::new(Node::empty(0))));
+    }
+    if let Some(link) = imported.get(&hash) {
+        return Ok(link.clone());
+    }
+    let local = match local {
+        Some(local) => local,
+        None => local.insert(index_nodes(existing, store)?),
+    };
+    local.get(&hash).cloned().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("node {hash} was neither imported nor found locally"),
+        )
+    })
+}
+
+/// Maps the hash of every node below `root` to its link.
+fn index_nodes<K: MerkleKey, V: MerkleValue>(
+    root: &Link<K, V>,
+    store: &Store<K, V>,
+) -> io::Result<HashMap<Hash, Link<K, V>>> {
+    let mut index = HashMap::new();
+    let mut stack = vec![root.clone()];
+    while let Some(link) = stack.pop() {
+        if is_empty(&link) {
+            continue;
+        }
+        let node = match &link {
+            Link::Loaded(node) => node.clone(),
+            Link::Disk { offset, hash, .. } => store.load_node_uncached(*offset, *hash)?,
+        };
+        stack.extend(node.children.iter().cloned());
+        index.insert(link.hash(), link);
+    }
+    Ok(index)
+}
+
+fn is_empty<K: MerkleKey, V: MerkleValue>(link: &Link<K, V>) -> bool {
+    link.hash() == Hash::from_bytes([0u8; HASH_LEN])
+}
@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::NodeId;
+
+/// Smallest leftover worth keeping after carving a node out of a free region: the
+/// 4-byte length prefix plus at least one byte of payload.
+const MIN_REGION_LEN: u64 = 5;
+
+/// Tracks file regions that used to hold nodes no committed root can reach anymore.
+///
+/// Regions orphaned by a commit first sit in `pending`: the previous root is still
+/// kept in the other metadata slot and may be fallen back to after a torn write, so
+/// its nodes must stay intact until the next commit overwrites that slot. Snapshots
+/// taken before the orphaning commit may also still read them.
+///
+/// Since format version 4, every commit saves the list next to its nodes and the
+/// metadata slot points at it, so it survives reopening the file.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub(crate) struct FreeList {
+    /// Reusable regions ordered by `(len, offset)` for best-fit allocation.
+    free: BTreeSet<(u64, NodeId)>,
+    /// Regions not yet reusable, as `(offset, len)`, batched by the generation of the
+    /// commit that orphaned them.
+    pending: Vec<(u64, Vec<(NodeId, u64)>)>,
+    /// Nodes that more than one parent points at because node deduplication reused
+    /// them, with the number of parents beyond the first. Orphaning a parent takes one
+    /// off, and the node is only freed along with its last parent. Records written
+    /// before this field existed decode it from their zero padding, as empty.
+    pub(crate) deduplicated: BTreeMap<NodeId, u32>,
+    /// Where this list was saved, as `(offset, len)`.
+    #[serde(skip)]
+    pub(crate) record: Option<(NodeId, u64)>,
+}
+
+impl FreeList {
+    /// Takes the smallest free region that fits `len` bytes, returning its offset.
+    /// Any leftover stays free.
+    pub(crate) fn allocate(&mut self, len: u64) -> Option<NodeId> {
+        let &(region_len, offset) = self.free.range((len, 0)..).next()?;
+        self.free.remove(&(region_len, offset));
+        if region_len - len >= MIN_REGION_LEN {
+            self.free.insert((region_len - len, offset + len));
+        }
+        Some(offset)
+    }
+
+    /// Applies the commit with `generation`: `orphaned` is held back, and earlier
+    /// batches become reusable unless a snapshot from before them is still alive.
+    /// `oldest_pin` is the generation the oldest live snapshot was taken at.
+    ///
+    /// The store applies this to a copy while preparing the commit and only keeps the
+    /// result once the commit is durable.
+    ///
+    /// Returns the offsets that just became reusable so stale cache entries can be
+    /// dropped.
+    pub(crate) fn retire(
+        &mut self,
+        generation: u64,
+        orphaned: Vec<(NodeId, u64)>,
+        oldest_pin: Option<u64>,
+    ) -> Vec<NodeId> {
+        if !orphaned.is_empty() {
+            self.pending.push((generation, orphaned));
+        }
+
+        let mut released = Vec::new();
+        self.pending.retain(|(orphaned_at, regions)| {
+            // A snapshot pinned at `pin` may read the root of that generation, whose
+            // nodes are orphaned by later commits only.
+            let reusable =
+                *orphaned_at < generation && oldest_pin.is_none_or(|pin| pin >= *orphaned_at);
+            if reusable {
+                for &(offset, len) in regions {
+                    self.free.insert((len, offset));
+                    released.push(offset);
+                }
+            }
+            !reusable
+        });
+        released
+    }
+
+    /// Forgets every free and pending region, and where the list was saved, leaving
+    /// them unused until compaction. The parents of deduplicated nodes stay counted.
+    pub(crate) fn forget_regions(&mut self) {
+        self.free.clear();
+        self.pending.clear();
+        self.record = None;
+    }
+
+    /// Adds `parents` to the ones counted for the node at `offset` beyond its first,
+    /// which may be negative, dropping it once none are left.
+    pub(crate) fn add_parents(&mut self, offset: NodeId, parents: i64) {
+        if parents == 0 {
+            return;
+        }
+        let extra = self.deduplicated.get(&offset).copied().unwrap_or(0);
+        match (i64::from(extra) + parents).clamp(0, u32::MAX.into()) as u32 {
+            0 => self.deduplicated.remove(&offset),
+            extra => self.deduplicated.insert(offset, extra),
+        };
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.free.is_empty() && self.pending.is_empty() && self.deduplicated.is_empty()
+    }
+
+    /// Bytes that are or will become reusable.
+    pub(crate) fn reclaimable_bytes(&self) -> u64 {
+        let free: u64 = self.free.iter().map(|(len, _)| len).sum();
+        let pending: u64 = self
+            .pending
+            .iter()
+            .flat_map(|(_, regions)| regions)
+            .map(|(_, len)| len)
+            .sum();
+        free + pending
+    }
+}
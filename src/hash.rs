@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// Length of a [`Hash`] in bytes.
+pub const HASH_LEN: usize = 32;
+
+/// A node or root hash, as produced by the tree's [`TreeHasher`].
+///
+/// Displayed and parsed as 64 lowercase hex digits. Empty subtrees hash to all
+/// zeros.
+///
+/// [`TreeHasher`]: crate::TreeHasher
+#[derive(
+    Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, std::hash::Hash, Serialize, Deserialize,
+)]
+pub struct Hash([u8; HASH_LEN]);
+
+impl Hash {
+    pub const fn from_bytes(bytes: [u8; HASH_LEN]) -> Self {
+        Self(bytes)
+    }
+
+    pub const fn as_bytes(&self) -> &[u8; HASH_LEN] {
+        &self.0
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl From<[u8; HASH_LEN]> for Hash {
+    fn from(bytes: [u8; HASH_LEN]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<Hash> for [u8; HASH_LEN] {
+    fn from(hash: Hash) -> Self {
+        hash.0
+    }
+}
+
+impl From<blake3::Hash> for Hash {
+    fn from(hash: blake3::Hash) -> Self {
+        Self(*hash.as_bytes())
+    }
+}
+
+impl PartialEq<[u8; HASH_LEN]> for Hash {
+    fn eq(&self, other: &[u8; HASH_LEN]) -> bool {
+        self.0 == *other
+    }
+}
+
+impl AsRef<[u8]> for Hash {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Hash({self})")
+    }
+}
+
+impl FromStr for Hash {
+    type Err = io::Error;
+
+    /// Parses 64 hex digits, in either case.
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid hash {s:?}: expected {} hex digits", 2 * HASH_LEN),
+            )
+        };
+        if s.len() != 2 * HASH_LEN || !s.is_ascii() {
+            return Err(invalid());
+        }
+        let mut bytes = [0u8; HASH_LEN];
+        for (byte, pair) in bytes.iter_mut().zip(s.as_bytes().chunks(2)) {
+            let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(bytes))
+    }
+}
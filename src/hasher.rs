@@ -0,0 +1,49 @@
+/// A hash function producing 32-byte digests, used both for node hashes and for
+/// deriving key levels.
+///
+/// Trees built with different hashers have different shapes and root hashes, so a
+/// file must always be reopened with the hasher it was written with.
+pub trait TreeHasher: Default {
+    /// Feeds more bytes into the hash.
+    fn update(&mut self, bytes: &[u8]);
+
+    /// Returns the digest of everything fed so far.
+    fn finalize(self) -> [u8; 32];
+
+    /// Hashes `bytes` in one call.
+    fn hash(bytes: &[u8]) -> [u8; 32] {
+        let mut hasher = Self::default();
+        hasher.update(bytes);
+        hasher.finalize()
+    }
+}
+
+/// The default hasher, BLAKE3.
+#[derive(Default)]
+pub struct Blake3Hasher(blake3::Hasher);
+
+impl TreeHasher for Blake3Hasher {
+    fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finalize(self) -> [u8; 32] {
+        self.0.finalize().into()
+    }
+}
+
+/// SHA-256, for deployments that require a NIST hash function.
+#[cfg(feature = "sha256")]
+#[derive(Default)]
+pub struct Sha256Hasher(sha2::Sha256);
+
+#[cfg(feature = "sha256")]
+impl TreeHasher for Sha256Hasher {
+    fn update(&mut self, bytes: &[u8]) {
+        sha2::Digest::update(&mut self.0, bytes);
+    }
+
+    fn finalize(self) -> [u8; 32] {
+        sha2::Digest::finalize(self.0).into()
+    }
+}
@@ -0,0 +1,346 @@
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::io;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+
+use crate::node::{Link, Node, NodeKeys, above_range, below_range};
+use crate::snapshot::Snapshot;
+use crate::{MerkleKey, MerkleValue, Result};
+
+/// An in-order iterator over the entries of a tree, or of a range of it.
+///
+/// Nodes are loaded lazily as the iteration reaches them. The iterator holds a
+/// [`Snapshot`] of the root it was created from, so later writes to the tree are not
+/// observed. Loading errors are yielded once, after which the iterator is exhausted.
+pub struct Iter<K: MerkleKey, V: MerkleValue> {
+    snapshot: Snapshot<K, V>,
+    /// Nodes being walked, each with the index of the next key to yield. Everything
+    /// left of that key in the node has already been visited.
+    stack: Vec<(Arc<Node<K, V>>, usize)>,
+    /// Subtree to the right of the last yielded key, descended on the next call.
+    pending: Option<Link<K, V>>,
+    /// The largest key within the range; None if the range holds no keys.
+    last: Option<Arc<K>>,
+}
+
+impl<K: MerkleKey, V: MerkleValue> Iter<K, V> {
+    pub(crate) fn new<Q, R>(snapshot: Snapshot<K, V>, range: &R) -> io::Result<Self>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        Self::with_bounds(
+            snapshot,
+            |k| below_range(k.borrow(), range),
+            |k| above_range(k.borrow(), range),
+        )
+    }
+
+    /// Iterates over the keys that are neither `below` nor `above` the range. Both
+    /// predicates must be monotonic in key order.
+    pub(crate) fn with_bounds(
+        snapshot: Snapshot<K, V>,
+        below: impl Fn(&K) -> bool,
+        above: impl Fn(&K) -> bool,
+    ) -> io::Result<Self> {
+        let root = snapshot.root.clone();
+        let mut iter = Self {
+            snapshot,
+            stack: Vec::new(),
+            pending: None,
+            last: None,
+        };
+
+        // The deepest key at or before the end bound along the search path is the
+        // largest one in range.
+        let mut node = iter.resolve(&root)?;
+        while !node.children.is_empty() {
+            let idx = node.keys.partition_point(|k| !above(k));
+            if idx > 0 {
+                iter.last = Some(node.keys[idx - 1].clone());
+            }
+            node = iter.resolve(&node.children[idx])?;
+        }
+
+        // Seek to the first key at or after the start bound.
+        let mut node = iter.resolve(&root)?;
+        while !node.children.is_empty() {
+            let idx = node.keys.partition_point(|k| below(k));
+            let child = iter.resolve(&node.children[idx])?;
+            iter.stack.push((node, idx));
+            node = child;
+        }
+
+        Ok(iter)
+    }
+
+    fn resolve(&self, link: &Link<K, V>) -> io::Result<Arc<Node<K, V>>> {
+        match link {
+            Link::Loaded(node) => Ok(node.clone()),
+            Link::Disk { offset, hash, .. } => self.snapshot.store.load_node(*offset, *hash),
+        }
+    }
+
+    /// Pushes the leftmost path of the subtree behind `link`.
+    fn descend_leftmost(&mut self, link: &Link<K, V>) -> io::Result<()> {
+        let mut node = self.resolve(link)?;
+        while !node.children.is_empty() {
+            let child = self.resolve(&node.children[0])?;
+            self.stack.push((node, 0));
+            node = child;
+        }
+        Ok(())
+    }
+}
+
+impl<K: MerkleKey, V: MerkleValue> Iterator for Iter<K, V> {
+    type Item = Result<(Arc<K>, Arc<V>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(link) = self.pending.take()
+            && let Err(e) = self.descend_leftmost(&link)
+        {
+            self.stack.clear();
+            return Some(Err(e.into()));
+        }
+
+        while let Some((node, idx)) = self.stack.last_mut() {
+            if *idx == node.keys.len() {
+                self.stack.pop();
+                continue;
+            }
+
+            let key = node.keys[*idx].clone();
+            let value = node.values[*idx].clone();
+            *idx += 1;
+            self.pending = Some(node.children[*idx].clone());
+
+            match self.last.as_ref().map(|last| key.cmp(last)) {
+                Some(Ordering::Less) => {}
+                Some(Ordering::Equal) => {
+                    // Nothing further can be in range; skip loading the rest.
+                    self.stack.clear();
+                    self.pending = None;
+                }
+                _ => {
+                    self.stack.clear();
+                    self.pending = None;
+                    return None;
+                }
+            }
+            return Some(Ok((key, value)));
+        }
+        None
+    }
+}
+
+/// Like [`Iter`], but yields the entries in descending key order, as returned by
+/// [`MerkleSearchTree::iter_rev`] and [`MerkleSearchTree::range_rev`].
+///
+/// [`MerkleSearchTree::iter_rev`]: crate::MerkleSearchTree::iter_rev
+/// [`MerkleSearchTree::range_rev`]: crate::MerkleSearchTree::range_rev
+pub struct RevIter<K: MerkleKey, V: MerkleValue> {
+    snapshot: Snapshot<K, V>,
+    /// Nodes being walked, each with the number of its keys not yet yielded. Everything
+    /// right of the last of those keys has already been visited.
+    stack: Vec<(Arc<Node<K, V>>, usize)>,
+    /// Subtree to the left of the last yielded key, descended on the next call.
+    pending: Option<Link<K, V>>,
+    /// The smallest key within the range; None if the range holds no keys.
+    first: Option<Arc<K>>,
+}
+
+impl<K: MerkleKey, V: MerkleValue> RevIter<K, V> {
+    pub(crate) fn new<Q, R>(snapshot: Snapshot<K, V>, range: &R) -> io::Result<Self>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        let root = snapshot.root.clone();
+        let mut iter = Self {
+            snapshot,
+            stack: Vec::new(),
+            pending: None,
+            first: None,
+        };
+
+        // The deepest key at or after the start bound along the search path is the
+        // smallest one in range.
+        let mut node = iter.resolve(&root)?;
+        while !node.children.is_empty() {
+            let idx = node
+                .keys
+                .partition_point(|k| below_range(k.as_ref().borrow(), range));
+            if let Some(key) = node.keys.get(idx) {
+                iter.first = Some(key.clone());
+            }
+            node = iter.resolve(&node.children[idx])?;
+        }
+
+        // Seek to the last key at or before the end bound.
+        let mut node = iter.resolve(&root)?;
+        while !node.children.is_empty() {
+            let idx = node
+                .keys
+                .partition_point(|k| !above_range(k.as_ref().borrow(), range));
+            let child = iter.resolve(&node.children[idx])?;
+            iter.stack.push((node, idx));
+            node = child;
+        }
+
+        Ok(iter)
+    }
+
+    fn resolve(&self, link: &Link<K, V>) -> io::Result<Arc<Node<K, V>>> {
+        match link {
+            Link::Loaded(node) => Ok(node.clone()),
+            Link::Disk { offset, hash, .. } => self.snapshot.store.load_node(*offset, *hash),
+        }
+    }
+
+    /// Pushes the rightmost path of the subtree behind `link`.
+    fn descend_rightmost(&mut self, link: &Link<K, V>) -> io::Result<()> {
+        let mut node = self.resolve(link)?;
+        while let Some(child) = node.children.last() {
+            let child = self.resolve(child)?;
+            let len = node.keys.len();
+            self.stack.push((node, len));
+            node = child;
+        }
+        Ok(())
+    }
+}
+
+impl<K: MerkleKey, V: MerkleValue> Iterator for RevIter<K, V> {
+    type Item = Result<(Arc<K>, Arc<V>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(link) = self.pending.take()
+            && let Err(e) = self.descend_rightmost(&link)
+        {
+            self.stack.clear();
+            return Some(Err(e.into()));
+        }
+
+        while let Some((node, idx)) = self.stack.last_mut() {
+            if *idx == 0 {
+                self.stack.pop();
+                continue;
+            }
+
+            *idx -= 1;
+            let key = node.keys[*idx].clone();
+            let value = node.values[*idx].clone();
+            self.pending = Some(node.children[*idx].clone());
+
+            match self.first.as_ref().map(|first| key.cmp(first)) {
+                Some(Ordering::Greater) => {}
+                Some(Ordering::Equal) => {
+                    // Nothing further can be in range; skip loading the rest.
+                    self.stack.clear();
+                    self.pending = None;
+                }
+                _ => {
+                    self.stack.clear();
+                    self.pending = None;
+                    return None;
+                }
+            }
+            return Some(Ok((key, value)));
+        }
+        None
+    }
+}
+
+/// An in-order iterator over the keys of a tree, as returned by
+/// [`MerkleSearchTree::keys`].
+///
+/// Nodes that are not cached are read without decoding their values, and are not
+/// added to the cache. Like [`Iter`], it keeps reading the root it was created from.
+///
+/// [`MerkleSearchTree::keys`]: crate::MerkleSearchTree::keys
+pub struct KeysIter<K: MerkleKey, V: MerkleValue> {
+    snapshot: Snapshot<K, V>,
+    stack: Vec<(NodeKeys<K, V>, usize)>,
+    pending: Option<Link<K, V>>,
+}
+
+impl<K: MerkleKey, V: MerkleValue> KeysIter<K, V> {
+    pub(crate) fn new(snapshot: Snapshot<K, V>) -> Self {
+        let pending = Some(snapshot.root.clone());
+        Self {
+            snapshot,
+            stack: Vec::new(),
+            pending,
+        }
+    }
+
+    fn resolve(&self, link: &Link<K, V>) -> io::Result<NodeKeys<K, V>> {
+        match link {
+            Link::Loaded(node) => Ok(NodeKeys::from(&**node)),
+            Link::Disk { offset, hash, .. } => self.snapshot.store.load_keys(*offset, *hash),
+        }
+    }
+
+    /// Pushes the leftmost path of the subtree behind `link`.
+    fn descend_leftmost(&mut self, link: &Link<K, V>) -> io::Result<()> {
+        let mut node = self.resolve(link)?;
+        while let Some(child) = node.children.first() {
+            let child = self.resolve(child)?;
+            self.stack.push((node, 0));
+            node = child;
+        }
+        self.stack.push((node, 0));
+        Ok(())
+    }
+}
+
+impl<K: MerkleKey, V: MerkleValue> Iterator for KeysIter<K, V> {
+    type Item = Result<Arc<K>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(link) = self.pending.take()
+            && let Err(e) = self.descend_leftmost(&link)
+        {
+            self.stack.clear();
+            return Some(Err(e.into()));
+        }
+
+        while let Some((node, idx)) = self.stack.last_mut() {
+            if *idx == node.keys.len() {
+                self.stack.pop();
+                continue;
+            }
+            let key = node.keys[*idx].clone();
+            *idx += 1;
+            self.pending = node.children.get(*idx).cloned();
+            return Some(Ok(key));
+        }
+        None
+    }
+}
+
+/// An in-order iterator over the values of a tree, as returned by
+/// [`MerkleSearchTree::values`].
+///
+/// [`MerkleSearchTree::values`]: crate::MerkleSearchTree::values
+pub struct ValuesIter<K: MerkleKey, V: MerkleValue> {
+    inner: Iter<K, V>,
+}
+
+impl<K: MerkleKey, V: MerkleValue> ValuesIter<K, V> {
+    pub(crate) fn new(inner: Iter<K, V>) -> Self {
+        Self { inner }
+    }
+}
+
+impl<K: MerkleKey, V: MerkleValue> Iterator for ValuesIter<K, V> {
+    type Item = Result<Arc<V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.inner.next()?.map(|(_, value)| value))
+    }
+}
@@ -9,23 +9,90 @@ mod benches;
 #[cfg(test)]
 mod tests;
 
+mod backing;
+mod cache;
+mod collation;
+mod compression;
+mod diff;
+mod dump;
+mod entry;
+mod error;
+mod expiry;
+mod export;
+mod freelist;
+mod hash;
+mod hasher;
+mod iter;
+#[cfg(feature = "mmap")]
+mod mmap;
 mod node;
+mod options;
+mod proof;
+mod set;
+mod snapshot;
+mod stats;
 mod store;
+mod tombstone;
 mod tree;
+mod verify;
+mod version;
+mod walk;
 mod async_tree;
+mod shared_async_tree;
 
-pub use tree::MerkleSearchTree;
-pub use async_tree::AsyncMerkleSearchTree;
+pub use tree::{ConflictPolicy, MerkleSearchTree, Op};
+pub use async_tree::{AsyncMerkleSearchTree, EntryStream};
+pub use shared_async_tree::SharedAsyncMerkleSearchTree;
+pub use cache::CacheMetrics;
+pub use collation::{CaseInsensitive, Collated, Collation};
+pub use compression::Compression;
+pub use diff::Difference;
+pub use dump::EntryFormat;
+pub use entry::{Entry, OccupiedEntry, VacantEntry};
+pub use error::{Error, Result};
+pub use expiry::{Expiring, ExpiringTree};
+pub use export::NodeExport;
+pub use hash::{HASH_LEN, Hash};
+pub use hasher::{Blake3Hasher, TreeHasher};
+pub use iter::{Iter, KeysIter, RevIter, ValuesIter};
+pub use options::OpenOptions;
+#[cfg(feature = "sha256")]
+pub use hasher::Sha256Hasher;
+pub use set::{MerkleSet, SetDiff};
+pub use snapshot::{Checkpoint, Snapshot};
+pub use stats::TreeStats;
+pub use store::{CompactionPolicy, SyncPolicy};
+pub use tombstone::{Tombstone, Tombstoned};
+pub use verify::{Corruption, VerifyReport};
+pub use version::Version;
+pub use walk::{NodeVisitor, Visit};
+pub use proof::{
+    Proof, ProofNode, verify_absence, verify_absence_with, verify_proof, verify_proof_with,
+};
 
 use serde::{Deserialize, Serialize};
 
 pub(crate) type NodeId = u64;
-pub(crate) const PAGE_SIZE: u64 = 4096;
+/// Page size used for new databases unless configured otherwise.
+pub(crate) const DEFAULT_PAGE_SIZE: u64 = 4096;
+/// Average number of children per node used for new databases unless configured
+/// otherwise.
+pub(crate) const DEFAULT_FANOUT: u32 = 16;
 
 /// A trait for types that can serve as keys.
+///
+/// Keys are ordered by their `Ord` implementation, never by their serialized bytes,
+/// so integers and other types sort naturally without any special encoding.
 pub trait MerkleKey: Ord + std::fmt::Debug + Serialize + for<'a> Deserialize<'a> {}
 impl<T> MerkleKey for T where T: Ord + std::fmt::Debug + Serialize + for<'a> Deserialize<'a> {}
 
 /// A trait for types that can serve as values.
+///
+/// Node hashes cover the postcard encoding of each value, the same bytes that are
+/// stored, so a value hashes as whatever it serializes to. A handle to content kept
+/// elsewhere, such as a blob store, can serialize as the content's hash alone and
+/// skip anything it caches with `#[serde(skip)]`: the tree then stores and hashes the
+/// handle without ever resolving it, and equal handles hash equally however much of
+/// their content they hold in memory.
 pub trait MerkleValue: std::fmt::Debug + Serialize + for<'a> Deserialize<'a> {}
 impl<T> MerkleValue for T where T: std::fmt::Debug + Serialize + for<'a> Deserialize<'a> {}
@@ -0,0 +1,71 @@
+use std::fs::File;
+use std::io;
+use std::sync::{Arc, RwLock};
+
+use memmap2::Mmap;
+
+/// A read-only map of a store's file, for reading nodes without a syscall each.
+///
+/// The map covers the file as it was when last mapped. Reads past its end remap the
+/// file first, so nodes appended since are found once they have been flushed, which
+/// is all a disk link can point at anyway.
+///
+/// Mapping is sound as long as no mapped byte changes while a read borrows it, and
+/// the file never shrinks below the map:
+///
+/// - The store only ever grows the file; it is never truncated once opened, and
+///   `compact` writes a new file instead of shrinking this one.
+/// - Bytes are only overwritten when the free list reuses a region, which only holds
+///   nodes unreachable from every root a reader or snapshot can still walk.
+/// - Like every other read path, this one assumes no other process modifies the
+///   file while it is open.
+pub(crate) struct MappedFile {
+    map: RwLock<Arc<Mmap>>,
+}
+
+impl MappedFile {
+    pub(crate) fn new(file: &File) -> io::Result<Self> {
+        Ok(Self {
+            map: RwLock::new(Arc::new(map(file)?)),
+        })
+    }
+
+    /// Calls `f` with the payload of the length-prefixed record at `offset`.
+    pub(crate) fn with_record<R>(
+        &self,
+        file: &File,
+        offset: u64,
+        f: impl FnOnce(&[u8]) -> io::Result<R>,
+    ) -> io::Result<R> {
+        let start = offset as usize + 4;
+        let mut map = self.covering(file, start)?;
+        let len = u32::from_le_bytes(map[start - 4..start].try_into().unwrap()) as usize;
+        if map.len() < start + len {
+            map = self.covering(file, start + len)?;
+        }
+        f(&map[start..start + len])
+    }
+
+    /// The current map, remapped first if it ends before `end`.
+    fn covering(&self, file: &File, end: usize) -> io::Result<Arc<Mmap>> {
+        let map = self.map.read().unwrap().clone();
+        if map.len() >= end {
+            return Ok(map);
+        }
+        let mut current = self.map.write().unwrap();
+        // Another reader may have remapped in the meantime.
+        if current.len() < end {
+            *current = Arc::new(self::map(file)?);
+        }
+        if current.len() < end {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(current.clone())
+    }
+}
+
+fn map(file: &File) -> io::Result<Mmap> {
+    // SAFETY: see `MappedFile`; the store never truncates the file or rewrites bytes
+    // that a reader may still be looking at.
+    unsafe { Mmap::map(file) }
+}
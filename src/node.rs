@@ -1,20 +1,41 @@
-use crate::{MerkleKey, MerkleValue, NodeId, store::Store};
-use blake3::{Hash, OUT_LEN};
+use crate::hash::{HASH_LEN, Hash};
+use crate::{Error, MerkleKey, MerkleValue, NodeId, TreeHasher, store::Store};
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::ser::{self, SerializeSeq, Serializer};
 use serde::{Deserialize, Serialize};
-use std::{borrow::Borrow, io, sync::Arc};
+use std::{
+    borrow::Borrow,
+    fmt, io,
+    marker::PhantomData,
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
+
+/// Highest level a key can have: every bit of its hash zero, at a fan-out of 2.
+pub(crate) const MAX_LEVEL: u32 = HASH_LEN as u32 * 8;
 
 #[derive(Debug)]
 pub enum Link<K: MerkleKey, V: MerkleValue> {
-    Disk { offset: NodeId, hash: Hash },
+    /// `count` is the number of keys in the subtree, when the file records it.
+    Disk {
+        offset: NodeId,
+        hash: Hash,
+        count: Option<u64>,
+    },
     Loaded(Arc<Node<K, V>>),
 }
 
 impl<K: MerkleKey, V: MerkleValue> Clone for Link<K, V> {
     fn clone(&self) -> Self {
         match self {
-            Link::Disk { offset, hash } => Link::Disk {
+            Link::Disk {
+                offset,
+                hash,
+                count,
+            } => Link::Disk {
                 offset: *offset,
                 hash: *hash,
+                count: *count,
             },
             Link::Loaded(node) => Link::Loaded(node.clone()),
         }
@@ -28,6 +49,14 @@ impl<K: MerkleKey, V: MerkleValue> Link<K, V> {
             Link::Loaded(node) => node.hash,
         }
     }
+
+    /// Number of keys in the subtree, if known without loading it.
+    pub fn count(&self) -> Option<u64> {
+        match self {
+            Link::Disk { count, .. } => *count,
+            Link::Loaded(node) => node.count,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -37,6 +66,30 @@ pub struct Node<K: MerkleKey, V: MerkleValue> {
     pub values: Vec<Arc<V>>,
     pub children: Vec<Link<K, V>>,
     pub hash: Hash,
+    /// Number of keys in this subtree; None if a child's count is unknown, which only
+    /// happens below nodes read from a version 1 file.
+    pub count: Option<u64>,
+    /// Encodings of the values, kept from hashing so that writing the node does not
+    /// encode them again. Empty for nodes read from disk; see [`encoding_of`].
+    pub encoded_values: Arc<[EncodedValue<V>]>,
+}
+
+/// The encoding of a value, along with the value it encodes, so that it is never
+/// mistaken for the encoding of a value that replaced it.
+#[derive(Debug)]
+pub struct EncodedValue<V> {
+    value: Arc<V>,
+    bytes: Box<[u8]>,
+}
+
+/// The encoding of `value`, the value at `idx`, if `encoded` still holds it there.
+pub(crate) fn encoding_of<'a, V>(
+    encoded: &'a [EncodedValue<V>],
+    value: &Arc<V>,
+    idx: usize,
+) -> Option<&'a [u8]> {
+    let cached = encoded.get(idx)?;
+    Arc::ptr_eq(&cached.value, value).then_some(&cached.bytes)
 }
 
 impl<K: MerkleKey, V: MerkleValue> Clone for Node<K, V> {
@@ -47,127 +100,464 @@ impl<K: MerkleKey, V: MerkleValue> Clone for Node<K, V> {
             values: self.values.clone(),
             children: self.children.clone(),
             hash: self.hash,
+            count: self.count,
+            encoded_values: self.encoded_values.clone(),
         }
     }
 }
 
+/// Outcome of splitting a subtree around a key, as seen by its parent.
+enum Split<K: MerkleKey, V: MerkleValue> {
+    /// The subtree has no keys.
+    Empty,
+    /// Every key of the subtree is below the split key.
+    Below,
+    /// Every key of the subtree is above the split key.
+    Above,
+    /// The keys below and above the split key, in new nodes where needed.
+    Halves([Link<K, V>; 2]),
+}
+
+impl<K: MerkleKey, V: MerkleValue> Split<K, V> {
+    /// The two halves of the subtree behind `link`, whose root is at `level`. A side
+    /// that keeps every key is `link` itself.
+    fn into_halves(self, link: &Link<K, V>, level: u32) -> [Link<K, V>; 2] {
+        let empty = || Link::Loaded(Arc::new(Node::empty(level)));
+        match self {
+            Split::Empty => [empty(), empty()],
+            Split::Below => [link.clone(), empty()],
+            Split::Above => [empty(), link.clone()],
+            Split::Halves(halves) => halves,
+        }
+    }
+}
+
+/// A child as recorded on disk: offset, hash and the number of keys in its subtree.
+pub type DiskChild = (NodeId, Hash, u64);
+
+/// A child as recorded by format version 1, which did not store subtree sizes.
+pub type LegacyDiskChild = (NodeId, Hash);
+
+/// A child's location and hash, with its subtree size if known.
+pub type ChildMeta = (NodeId, Hash, Option<u64>);
+
 #[derive(Deserialize)]
-pub struct DiskNode<K, V> {
+pub struct DiskNode<K, V, C = DiskChild> {
     pub level: u32,
     pub keys: Vec<K>,
     pub values: Vec<V>,
-    pub children: Vec<(NodeId, Hash)>,
+    pub children: Vec<C>,
     pub hash: Hash,
 }
 
 #[derive(Serialize)]
-pub struct DiskNodeRef<'a, K, V> {
+pub struct DiskNodeRef<'a, K, V, C = DiskChild> {
     pub level: u32,
     pub keys: &'a [Arc<K>],
     pub values: &'a [Arc<V>],
-    pub children: Vec<(NodeId, Hash)>,
+    pub children: Vec<C>,
+    pub hash: Hash,
+    #[serde(skip)]
+    pub encoded_values: &'a [EncodedValue<V>],
+}
+
+impl<'a, K, V, C> DiskNodeRef<'a, K, V, C> {
+    /// The same node with other child entries, e.g. in the layout of another format
+    /// version.
+    pub fn with_children<D>(&self, children: Vec<D>) -> DiskNodeRef<'a, K, V, D> {
+        DiskNodeRef {
+            level: self.level,
+            keys: self.keys,
+            values: self.values,
+            children,
+            hash: self.hash,
+            encoded_values: self.encoded_values,
+        }
+    }
+}
+
+/// Since format version 5, values are stored after everything else, so the keys and
+/// children of a node can be decoded on their own; see [`DiskNodeKeys`].
+///
+/// `KS` is the keys in the encoding of the format version: `Vec<K>`, or
+/// [`FrontCodedKeys`] since version 8.
+#[derive(Deserialize)]
+pub struct ValuesLast<KS, V, C = DiskChild> {
+    pub level: u32,
+    pub keys: KS,
+    pub children: Vec<C>,
     pub hash: Hash,
+    pub values: Vec<V>,
+}
+
+/// Keys as format version 8 stores them. Each key's encoding is written as the length
+/// of the prefix it shares with the previous key's encoding, followed by the rest of
+/// it, so sorted keys with long common prefixes take little more than their
+/// differences.
+pub struct FrontCoded<'a, K>(pub &'a [Arc<K>]);
+
+impl<K: Serialize> Serialize for FrontCoded<'_, K> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+        let mut previous = Vec::new();
+        for key in self.0 {
+            let encoded = postcard::to_extend(&**key, Vec::new())
+                .map_err(<S::Error as ser::Error>::custom)?;
+            let shared = previous
+                .iter()
+                .zip(&encoded)
+                .take_while(|(a, b)| a == b)
+                .count();
+            seq.serialize_element(&(shared, &encoded[shared..]))?;
+            previous = encoded;
+        }
+        seq.end()
+    }
+}
+
+/// Decoded [`FrontCoded`] keys.
+pub struct FrontCodedKeys<K>(pub Vec<K>);
+
+impl<K> From<FrontCodedKeys<K>> for Vec<K> {
+    fn from(keys: FrontCodedKeys<K>) -> Self {
+        keys.0
+    }
+}
+
+impl<'de, K: for<'a> Deserialize<'a>> Deserialize<'de> for FrontCodedKeys<K> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_seq(FrontCodedVisitor(PhantomData))
+    }
+}
+
+struct FrontCodedVisitor<K>(PhantomData<fn() -> K>);
+
+impl<'de, K: for<'a> Deserialize<'a>> Visitor<'de> for FrontCodedVisitor<K> {
+    type Value = FrontCodedKeys<K>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a sequence of front-coded keys")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        // The length comes from the file, so it only bounds the first allocation.
+        let mut keys = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(1024));
+        let mut encoded = Vec::new();
+        while let Some((shared, suffix)) = seq.next_element::<(usize, &'de [u8])>()? {
+            if shared > encoded.len() {
+                return Err(de::Error::custom(
+                    "key shares more bytes than the previous key has",
+                ));
+            }
+            encoded.truncate(shared);
+            encoded.extend_from_slice(suffix);
+            keys.push(postcard::from_bytes(&encoded).map_err(de::Error::custom)?);
+        }
+        Ok(FrontCodedKeys(keys))
+    }
+}
+
+/// Where a value stored out of line is, since format version 7: the offset of its
+/// record, and the length and hash of its encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlobRef {
+    pub offset: NodeId,
+    pub len: u64,
+    pub hash: Hash,
+}
+
+/// A value as format version 7 stores it in a node.
+///
+/// Nodes are written with the encoding of each value already at hand, so an inline
+/// value is written as `StoredValue::Inline(())`, the variant alone, followed by that
+/// encoding.
+#[derive(Serialize, Deserialize)]
+pub enum StoredValue<V> {
+    Inline(V),
+    Blob(BlobRef),
+}
+
+impl<K, V, C> DiskNode<K, V, C> {
+    /// The same node with each value passed through `f`, e.g. to resolve the values
+    /// format version 7 stores out of line.
+    pub fn try_map_values<W>(
+        self,
+        f: impl FnMut(V) -> io::Result<W>,
+    ) -> io::Result<DiskNode<K, W, C>> {
+        Ok(DiskNode {
+            level: self.level,
+            keys: self.keys,
+            values: self.values.into_iter().map(f).collect::<io::Result<_>>()?,
+            children: self.children,
+            hash: self.hash,
+        })
+    }
+}
+
+impl<K, KS: Into<Vec<K>>, V, C> From<ValuesLast<KS, V, C>> for DiskNode<K, V, C> {
+    fn from(node: ValuesLast<KS, V, C>) -> Self {
+        DiskNode {
+            level: node.level,
+            keys: node.keys.into(),
+            values: node.values,
+            children: node.children,
+            hash: node.hash,
+        }
+    }
+}
+
+/// Leading fields of a [`ValuesLast`] node; decoding stops before the values.
+///
+/// `KS` is the encoding of the keys, as in [`ValuesLast`].
+#[derive(Deserialize)]
+pub struct DiskNodeKeys<KS, C = DiskChild> {
+    /// Fields are decoded by position, so the level has to be read past.
+    pub _level: u32,
+    pub keys: KS,
+    pub children: Vec<C>,
+}
+
+/// The keys and children of a node, without its values.
+pub(crate) struct NodeKeys<K: MerkleKey, V: MerkleValue> {
+    pub(crate) keys: Vec<Arc<K>>,
+    pub(crate) children: Vec<Link<K, V>>,
+}
+
+impl<K: MerkleKey, V: MerkleValue> From<&Node<K, V>> for NodeKeys<K, V> {
+    fn from(node: &Node<K, V>) -> Self {
+        NodeKeys {
+            keys: node.keys.clone(),
+            children: node.children.clone(),
+        }
+    }
 }
 
 impl<K: MerkleKey, V: MerkleValue> Node<K, V> {
     pub(crate) fn empty(level: u32) -> Self {
-        let mut node = Self {
+        Self {
             level,
             keys: Vec::new(),
             values: Vec::new(),
             children: Vec::new(),
-            hash: Hash::from_bytes([0u8; OUT_LEN]),
-        };
-        node.rehash();
-        node
+            hash: Hash::from_bytes([0u8; HASH_LEN]),
+            count: Some(0),
+            encoded_values: Arc::default(),
+        }
     }
 
-    pub(crate) fn as_disk_ref(&self) -> DiskNodeRef<'_, K, V> {
+    pub(crate) fn as_disk_ref(&self) -> DiskNodeRef<'_, K, V, ChildMeta> {
         let children_meta = self
             .children
             .iter()
             .map(|c| match c {
-                Link::Disk { offset, hash } => (*offset, *hash),
+                Link::Disk {
+                    offset,
+                    hash,
+                    count,
+                } => (*offset, *hash, *count),
                 Link::Loaded(_) => {
                     panic!("Cannot serialize a node with dirty children! Flush children first.")
                 }
             })
             .collect();
 
+        self.as_disk_ref_with(children_meta)
+    }
+
+    /// Like `as_disk_ref`, but with the on-disk locations of the children given
+    /// explicitly, so a node with loaded children can be written once they are.
+    pub(crate) fn as_disk_ref_with(
+        &self,
+        children: Vec<ChildMeta>,
+    ) -> DiskNodeRef<'_, K, V, ChildMeta> {
+        debug_assert_eq!(children.len(), self.children.len());
         DiskNodeRef {
             level: self.level,
             keys: &self.keys,
             values: &self.values,
-            children: children_meta,
+            children,
             hash: self.hash,
+            encoded_values: &self.encoded_values,
         }
     }
 
-    pub(crate) fn from_disk(disk: DiskNode<K, V>) -> Self {
-        let children = disk
-            .children
-            .into_iter()
-            .map(|(offset, hash)| Link::Disk { offset, hash })
-            .collect();
+    /// Builds a node from its decoded form; `child` turns the format's child entries
+    /// into links.
+    pub(crate) fn from_disk<C>(disk: DiskNode<K, V, C>, child: impl Fn(C) -> Link<K, V>) -> Self {
+        let children: Vec<_> = disk.children.into_iter().map(child).collect();
 
-        let keys = disk.keys.into_iter().map(Arc::new).collect();
+        let keys: Vec<_> = disk.keys.into_iter().map(Arc::new).collect();
         let values = disk.values.into_iter().map(Arc::new).collect();
 
+        let count = Self::sum_counts(&keys, &children);
         Self {
             level: disk.level,
             keys,
             values,
             children,
             hash: disk.hash,
+            count,
+            encoded_values: Arc::default(),
         }
     }
 
-    pub(crate) fn calc_level(key: &K) -> u32 {
-        let mut h = blake3::Hasher::new();
+    /// Returns a copy of this node with `children[idx]` replaced.
+    pub(crate) fn with_child<H: TreeHasher>(
+        &self,
+        idx: usize,
+        child: Link<K, V>,
+        store: &Store<K, V>,
+    ) -> Arc<Node<K, V>> {
+        let mut new_node = self.clone();
+        new_node.children[idx] = child;
+        new_node.rehash::<H>(store);
+        Arc::new(new_node)
+    }
+
+    /// Returns a copy of this node with `values[idx]` replaced.
+    pub(crate) fn with_value<H: TreeHasher>(
+        &self,
+        idx: usize,
+        value: Arc<V>,
+        store: &Store<K, V>,
+    ) -> Arc<Node<K, V>> {
+        let mut new_node = self.clone();
+        new_node.values[idx] = value;
+        new_node.rehash::<H>(store);
+        Arc::new(new_node)
+    }
+
+    /// Level of `key` in a tree with the given fan-out: the number of leading groups
+    /// of `log2(fanout)` zero bits in the hash of its encoding.
+    ///
+    /// The encoding is the postcard one, as for the key in node hashes. Every file
+    /// depends on it: a key at another level than when it was written is not found.
+    ///
+    /// A key reaches level `l` or above with probability `fanout^-l`, and no key goes
+    /// above `256 / log2(fanout)`. A node's children may sit any number of levels
+    /// below it, so even a key at the top level adds one node above the current root
+    /// rather than a tower of them; `put` and `split` recurse as deep as the tree is
+    /// tall, whatever the levels involved.
+    pub(crate) fn calc_level<H: TreeHasher>(key: &K, fanout: u32) -> u32 {
+        let mut h = H::default();
         let key_bytes =
             postcard::to_extend(key, Vec::new()).expect("Failed to serialize key for level calc");
         h.update(&key_bytes);
-        let hash = h.finalize();
-        let bytes = hash.as_bytes();
-        let mut level = 0;
-        for byte in bytes {
-            if *byte == 0 {
-                level += 2;
-            } else {
-                if *byte & 0xF0 == 0 {
-                    level += 1;
-                }
+        let mut zero_bits = 0;
+        for byte in h.finalize() {
+            zero_bits += byte.leading_zeros();
+            if byte != 0 {
                 break;
             }
         }
-        level
+        zero_bits / fanout.trailing_zeros()
     }
 
-    fn rehash(&mut self) {
-        if self.keys.is_empty() && self.children.is_empty() {
-            self.hash = Hash::from_bytes([0u8; OUT_LEN]);
-            return;
-        }
-
-        let mut h = blake3::Hasher::new();
-        h.update(&self.level.to_le_bytes());
-        h.update(&(self.keys.len() as u64).to_le_bytes());
-
-        for (i, child) in self.children.iter().enumerate() {
-            h.update(child.hash().as_bytes());
-            if i < self.keys.len() {
-                let k_bytes = postcard::to_extend(&self.keys[i], Vec::new())
-                    .expect("Failed to serialize key for rehash");
-                h.update(&(k_bytes.len() as u64).to_le_bytes());
-                h.update(&k_bytes);
-
-                let v_bytes = postcard::to_extend(&self.values[i], Vec::with_capacity(4096))
-                    .expect("Failed to serialize value for hashing");
-                h.update(&(v_bytes.len() as u64).to_le_bytes());
-                h.update(&v_bytes);
+    /// Recomputes the hash and key count after the node was modified.
+    ///
+    /// Values the node already had keep their encoding, even if a key was inserted or
+    /// removed next to them; only new values are encoded.
+    fn rehash<H: TreeHasher>(&mut self, store: &Store<K, V>) {
+        let encoded = &self.encoded_values;
+        self.encoded_values = self
+            .values
+            .iter()
+            .enumerate()
+            .map(|(idx, value)| {
+                let bytes = [idx, idx.wrapping_sub(1), idx + 1]
+                    .into_iter()
+                    .find_map(|at| encoding_of(encoded, value, at))
+                    .map_or_else(
+                        || {
+                            postcard::to_extend(&**value, Vec::new())
+                                .expect("Failed to serialize value for hashing")
+                                .into()
+                        },
+                        Box::from,
+                    );
+                EncodedValue {
+                    value: value.clone(),
+                    bytes,
+                }
+            })
+            .collect();
+        self.hash = self.hash_with::<H>(store.domain_salt());
+        self.count = Self::sum_counts(&self.keys, &self.children);
+    }
+
+    fn sum_counts(keys: &[Arc<K>], children: &[Link<K, V>]) -> Option<u64> {
+        children
+            .iter()
+            .try_fold(keys.len() as u64, |sum, child| Some(sum + child.count()?))
+    }
+
+    /// Recomputes this node's hash from its contents, ignoring the stored `hash`.
+    pub(crate) fn hash_with<H: TreeHasher>(&self, salt: Option<&[u8; 32]>) -> Hash {
+        Self::compute_hash::<H>(
+            salt,
+            self.level,
+            self.keys.len(),
+            self.children.iter().map(Link::hash),
+            |i| {
+                let value = &self.values[i];
+                let encoded = encoding_of(&self.encoded_values, value, i);
+                (self.keys[i].as_ref(), value.as_ref(), encoded)
+            },
+        )
+    }
+
+    /// Hashes a node from its parts. Shared by `rehash` and proof verification so
+    /// both always agree on the pre-image. A domain salt, if any, goes first.
+    ///
+    /// `entry` gives each key and value, with the encoding of the value if known.
+    pub(crate) fn compute_hash<'a, H: TreeHasher>(
+        salt: Option<&[u8; 32]>,
+        level: u32,
+        key_count: usize,
+        children: impl ExactSizeIterator<Item = Hash>,
+        entry: impl Fn(usize) -> (&'a K, &'a V, Option<&'a [u8]>),
+    ) -> Hash
+    where
+        K: 'a,
+        V: 'a,
+    {
+        if key_count == 0 && children.len() == 0 {
+            return Hash::from_bytes([0u8; HASH_LEN]);
+        }
+
+        let mut h = H::default();
+        if let Some(salt) = salt {
+            h.update(salt);
+        }
+        h.update(&level.to_le_bytes());
+        h.update(&(key_count as u64).to_le_bytes());
+
+        // One buffer serves every key and value, so hashing a node allocates at most
+        // as often as its largest entry grows it.
+        let mut buf = Vec::new();
+        for (i, child_hash) in children.enumerate() {
+            h.update(child_hash.as_bytes());
+            if i < key_count {
+                let (key, value, encoded) = entry(i);
+                buf.clear();
+                buf = postcard::to_extend(key, buf).expect("Failed to serialize key for rehash");
+                h.update(&(buf.len() as u64).to_le_bytes());
+                h.update(&buf);
+
+                let value = match encoded {
+                    Some(encoded) => encoded,
+                    None => {
+                        buf.clear();
+                        buf = postcard::to_extend(value, buf)
+                            .expect("Failed to serialize value for hashing");
+                        &buf
+                    }
+                };
+                h.update(&(value.len() as u64).to_le_bytes());
+                h.update(value);
             }
         }
-        self.hash = h.finalize();
+        Hash::from_bytes(h.finalize())
     }
 
     pub(crate) fn contains<Q>(&self, key: &Q, store: &Store<K, V>) -> io::Result<bool>
@@ -175,106 +565,162 @@ impl<K: MerkleKey, V: MerkleValue> Node<K, V> {
         K: Borrow<Q>,
         Q: Ord + ?Sized,
     {
-        match self
-            .keys
-            .binary_search_by(|probe| probe.as_ref().borrow().cmp(key))
-        {
-            Ok(_) => Ok(true),
-            Err(idx) => {
-                if self.children.is_empty() {
-                    return Ok(false);
-                }
-                let child = match &self.children[idx] {
-                    Link::Loaded(n) => n.clone(),
-                    Link::Disk { offset, .. } => store.load_node(*offset)?,
-                };
-                child.contains(key, store)
-            }
-        }
+        Ok(self.get(key, store)?.is_some())
     }
 
+    /// Descends in a loop rather than recursively, so lookups use the same stack space
+    /// however tall the tree is.
     pub(crate) fn get<Q>(&self, key: &Q, store: &Store<K, V>) -> io::Result<Option<Arc<V>>>
     where
         K: Borrow<Q>,
         Q: Ord + ?Sized,
     {
-        match self
-            .keys
-            .binary_search_by(|probe| probe.as_ref().borrow().cmp(key))
-        {
-            Ok(idx) => Ok(Some(self.values[idx].clone())),
-            Err(idx) => {
-                if self.children.is_empty() {
-                    return Ok(None);
+        let mut child;
+        let mut node = self;
+        loop {
+            match node
+                .keys
+                .binary_search_by(|probe| probe.as_ref().borrow().cmp(key))
+            {
+                Ok(idx) => return Ok(Some(node.values[idx].clone())),
+                Err(_) if node.children.is_empty() => return Ok(None),
+                Err(idx) => {
+                    child = node.child_node(idx, store)?;
+                    node = &child;
                 }
-                let child = match &self.children[idx] {
-                    Link::Loaded(n) => n.clone(),
-                    Link::Disk { offset, .. } => store.load_node(*offset)?,
-                };
-                child.get(key, store)
             }
         }
     }
 
-    pub(crate) fn put(
+    /// Looks up a sorted batch of `(output slot, key)` queries in one descent, loading
+    /// each child at most once for all queries routed through it.
+    pub(crate) fn get_many<Q>(
         &self,
-        key: Arc<K>,
-        value: Arc<V>,
-        key_level: u32,
-        store: &Arc<Store<K, V>>,
-    ) -> io::Result<Arc<Node<K, V>>> {
-        if key_level > self.level {
-            let [left_child, right_child] = self.split(&key, store)?;
-            let mut new_node = Node {
-                level: key_level,
-                keys: vec![key],
-                values: vec![value],
-                children: vec![Link::Loaded(left_child), Link::Loaded(right_child)],
-                hash: Hash::from_bytes([0u8; OUT_LEN]),
+        queries: &[(usize, &Q)],
+        store: &Store<K, V>,
+        out: &mut [Option<Arc<V>>],
+    ) -> io::Result<()>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut pending = queries;
+        while let Some(&(slot, key)) = pending.first() {
+            let idx = match self
+                .keys
+                .binary_search_by(|probe| probe.as_ref().borrow().cmp(key))
+            {
+                Ok(idx) => {
+                    out[slot] = Some(self.values[idx].clone());
+                    pending = &pending[1..];
+                    continue;
+                }
+                Err(idx) => idx,
             };
-            new_node.rehash();
-            return Ok(Arc::new(new_node));
+
+            // Sorted queries routed to the same child are contiguous.
+            let group_len = pending
+                .iter()
+                .take_while(|(_, key)| {
+                    self.keys
+                        .binary_search_by(|probe| probe.as_ref().borrow().cmp(key))
+                        == Err(idx)
+                })
+                .count();
+            let (group, rest) = pending.split_at(group_len);
+            pending = rest;
+
+            if self.children.is_empty() {
+                continue;
+            }
+            let child = self.child_node(idx, store)?;
+            child.get_many(group, store, out)?;
         }
+        Ok(())
+    }
 
-        if key_level == self.level {
-            let mut new_node = self.clone();
-            match new_node
+    /// Looks up sorted `keys` in one descent like `get_many`, passing the value found
+    /// for each key, if any, to `stop` until it returns true. Returns whether it did.
+    pub(crate) fn lookup_until<Q>(
+        &self,
+        keys: &[&Q],
+        store: &Store<K, V>,
+        stop: &mut impl FnMut(Option<&Arc<V>>) -> bool,
+    ) -> io::Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut pending = keys;
+        while let Some(&key) = pending.first() {
+            let idx = match self
                 .keys
-                .binary_search_by(|probe| probe.as_ref().cmp(&key))
+                .binary_search_by(|probe| probe.as_ref().borrow().cmp(key))
             {
                 Ok(idx) => {
-                    new_node.values[idx] = value;
-                    new_node.rehash();
-                    return Ok(Arc::new(new_node));
-                }
-                Err(idx) => {
-                    let child_to_split = if !new_node.children.is_empty() {
-                        match &new_node.children[idx] {
-                            Link::Loaded(n) => n.clone(),
-                            Link::Disk { offset, .. } => store.load_node(*offset)?,
-                        }
-                    } else {
-                        Arc::new(Node::empty(self.level.saturating_sub(1)))
-                    };
-
-                    let [left_sub, right_sub] = child_to_split.split(&key, store)?;
-                    new_node.keys.insert(idx, key);
-                    new_node.values.insert(idx, value);
-
-                    if new_node.children.is_empty() {
-                        new_node.children.push(Link::Loaded(left_sub));
-                        new_node.children.push(Link::Loaded(right_sub));
-                    } else {
-                        new_node.children[idx] = Link::Loaded(left_sub);
-                        new_node.children.insert(idx + 1, Link::Loaded(right_sub));
+                    if stop(Some(&self.values[idx])) {
+                        return Ok(true);
                     }
-                    new_node.rehash();
-                    return Ok(Arc::new(new_node));
+                    pending = &pending[1..];
+                    continue;
                 }
+                Err(idx) => idx,
+            };
+
+            // The keys routed to the same child are those below the next separator.
+            let group_len = match self.keys.get(idx) {
+                Some(bound) => pending.partition_point(|key| *key < bound.as_ref().borrow()),
+                None => pending.len(),
+            };
+            let (group, rest) = pending.split_at(group_len);
+            pending = rest;
+
+            if self.children.is_empty() {
+                if group.iter().any(|_| stop(None)) {
+                    return Ok(true);
+                }
+                continue;
+            }
+            let child = self.child_node(idx, store)?;
+            if child.lookup_until(group, store, stop)? {
+                return Ok(true);
             }
         }
+        Ok(false)
+    }
 
-        if self.keys.is_empty() && self.children.is_empty() {
+    pub(crate) fn put<H: TreeHasher>(
+        self: &Arc<Self>,
+        key: Arc<K>,
+        value: Arc<V>,
+        key_level: u32,
+        store: &Arc<Store<K, V>>,
+    ) -> io::Result<Arc<Node<K, V>>> {
+        self.put_with::<H>(key, value, key_level, true, store)
+    }
+
+    /// Like `put`, but unless `overwrite` is set, a key that is already present keeps
+    /// its value. `self` is returned as it is exactly when that happens.
+    pub(crate) fn put_with<H: TreeHasher>(
+        self: &Arc<Self>,
+        key: Arc<K>,
+        value: Arc<V>,
+        key_level: u32,
+        overwrite: bool,
+        store: &Arc<Store<K, V>>,
+    ) -> io::Result<Arc<Node<K, V>>> {
+        if key_level > self.level {
+            return Self::put_above::<H>(
+                &Link::Loaded(self.clone()),
+                self,
+                key,
+                value,
+                key_level,
+                store,
+            );
+        }
+
+        if self.keys.is_empty() && self.children.is_empty() && key_level < self.level {
             let mut new_node = Node {
                 level: key_level,
                 keys: vec![key],
@@ -283,39 +729,140 @@ impl<K: MerkleKey, V: MerkleValue> Node<K, V> {
                     Link::Loaded(Arc::new(Node::empty(0))),
                     Link::Loaded(Arc::new(Node::empty(0))),
                 ],
-                hash: Hash::from_bytes([0u8; OUT_LEN]),
+                hash: Hash::from_bytes([0u8; HASH_LEN]),
+                count: None,
+                encoded_values: Arc::default(),
             };
-            new_node.rehash();
+            new_node.rehash::<H>(store);
             return Ok(Arc::new(new_node));
         }
 
-        let mut new_node = self.clone();
-        let idx = match new_node
-            .keys
-            .binary_search_by(|probe| probe.as_ref().cmp(&key))
-        {
-            Ok(i) => {
-                new_node.values[i] = value;
-                new_node.rehash();
-                return Ok(Arc::new(new_node));
+        let idx = match self.keys.binary_search_by(|probe| probe.as_ref().cmp(&key)) {
+            Ok(_) if !overwrite => return Ok(self.clone()),
+            Ok(idx) => {
+                let mut new_node = Node::clone(self);
+                new_node.values[idx] = value;
+                new_node.rehash::<H>(store);
+                return Ok(self.unless_unchanged(new_node));
             }
-            Err(i) => i,
+            Err(idx) => idx,
         };
 
-        let child_node = match &new_node.children[idx] {
-            Link::Loaded(n) => n.clone(),
-            Link::Disk { offset, .. } => store.load_node(*offset)?,
-        };
+        if key_level == self.level {
+            let [left_sub, right_sub] = if self.children.is_empty() {
+                std::array::from_fn(|_| {
+                    Link::Loaded(Arc::new(Node::empty(self.level.saturating_sub(1))))
+                })
+            } else {
+                Self::split_link::<H>(&self.children[idx], &key, store)?
+            };
+
+            // Built at their final size, rather than cloned and then grown.
+            let mut keys = Vec::with_capacity(self.keys.len() + 1);
+            keys.extend_from_slice(&self.keys[..idx]);
+            keys.push(key);
+            keys.extend_from_slice(&self.keys[idx..]);
+            let mut values = Vec::with_capacity(self.values.len() + 1);
+            values.extend_from_slice(&self.values[..idx]);
+            values.push(value);
+            values.extend_from_slice(&self.values[idx..]);
+            let mut children = Vec::with_capacity(self.children.len().max(1) + 1);
+            children.extend_from_slice(&self.children[..idx.min(self.children.len())]);
+            children.push(left_sub);
+            children.push(right_sub);
+            if idx < self.children.len() {
+                children.extend_from_slice(&self.children[idx + 1..]);
+            }
 
-        let new_child = child_node.put(key, value, key_level, store)?;
+            let mut new_node = Node {
+                level: self.level,
+                keys,
+                values,
+                children,
+                hash: Hash::from_bytes([0u8; HASH_LEN]),
+                count: None,
+                encoded_values: Arc::default(),
+            };
+            new_node.rehash::<H>(store);
+            return Ok(Arc::new(new_node));
+        }
+
+        let child_node = self.child_node(idx, store)?;
+        let new_child = if key_level > child_node.level {
+            let link = &self.children[idx];
+            Self::put_above::<H>(link, &child_node, key, value, key_level, store)?
+        } else {
+            child_node.put_with::<H>(key, value, key_level, overwrite, store)?
+        };
+        if Arc::ptr_eq(&new_child, &child_node) {
+            return Ok(self.clone());
+        }
+        // Only copied once the key is known to change something below.
+        let mut new_node = Node::clone(self);
         new_node.children[idx] = Link::Loaded(new_child);
-        new_node.rehash();
+        new_node.rehash::<H>(store);
+        Ok(Arc::new(new_node))
+    }
+
+    /// Keeps `self` when a replaced value left the node as it was, so an identical
+    /// write leaves its path, and the links on disk along it, untouched.
+    fn unless_unchanged(self: &Arc<Self>, updated: Node<K, V>) -> Arc<Node<K, V>> {
+        if updated.hash == self.hash {
+            self.clone()
+        } else {
+            Arc::new(updated)
+        }
+    }
+
+    /// Puts `key` in a new node at `key_level`, above `node`, which `link` points at.
+    fn put_above<H: TreeHasher>(
+        link: &Link<K, V>,
+        node: &Node<K, V>,
+        key: Arc<K>,
+        value: Arc<V>,
+        key_level: u32,
+        store: &Arc<Store<K, V>>,
+    ) -> io::Result<Arc<Node<K, V>>> {
+        let halves = node.split::<H>(&key, store)?.into_halves(link, node.level);
+        let mut new_node = Node {
+            level: key_level,
+            keys: vec![key],
+            values: vec![value],
+            children: halves.into(),
+            hash: Hash::from_bytes([0u8; HASH_LEN]),
+            count: None,
+            encoded_values: Arc::default(),
+        };
+        new_node.rehash::<H>(store);
         Ok(Arc::new(new_node))
     }
 
-    fn split(&self, split_key: &K, store: &Arc<Store<K, V>>) -> io::Result<[Arc<Node<K, V>>; 2]> {
+    /// Splits the subtree behind `link` into the keys below and above `split_key`,
+    /// leaving out `split_key` itself.
+    ///
+    /// A side that receives the whole subtree gets `link` itself, so its nodes are
+    /// neither copied nor written again; the other side is an empty node.
+    pub(crate) fn split_link<H: TreeHasher>(
+        link: &Link<K, V>,
+        split_key: &K,
+        store: &Arc<Store<K, V>>,
+    ) -> io::Result<[Link<K, V>; 2]> {
+        let node = match link {
+            Link::Loaded(node) => node.clone(),
+            Link::Disk { offset, hash, .. } => store.load_node(*offset, *hash)?,
+        };
+        Ok(node
+            .split::<H>(split_key, store)?
+            .into_halves(link, node.level))
+    }
+
+    fn split<H: TreeHasher>(
+        &self,
+        split_key: &K,
+        store: &Arc<Store<K, V>>,
+    ) -> io::Result<Split<K, V>> {
         if self.keys.is_empty() && self.children.is_empty() {
-            return Ok(std::array::from_fn(|_| Arc::new(Node::empty(self.level))));
+            return Ok(Split::Empty);
         }
 
         let idx = match self
@@ -325,60 +872,83 @@ impl<K: MerkleKey, V: MerkleValue> Node<K, V> {
             Ok(i) => i,
             Err(i) => i,
         };
-
-        let left_keys = self.keys[..idx].to_vec();
-        let left_values = self.values[..idx].to_vec();
-
+        // A key's level depends on the key alone, so a key being put above this node
+        // cannot be stored in it. Were it here anyway, e.g. in a file built with
+        // another fan-out, it is left out of both halves, so the copy being put
+        // replaces it rather than duplicating it.
         let right_start = if idx < self.keys.len() && self.keys[idx].as_ref() == split_key {
             idx + 1
         } else {
             idx
         };
-        let right_keys = self.keys[right_start..].to_vec();
-        let right_values = self.values[right_start..].to_vec();
 
         let [mid_left, mid_right] = if idx < self.children.len() {
-            let child = match &self.children[idx] {
-                Link::Loaded(n) => n.clone(),
-                Link::Disk { offset, .. } => store.load_node(*offset)?,
-            };
-            child.split(split_key, store)?
+            let child = self.child_node(idx, store)?;
+            match child.split::<H>(split_key, store)? {
+                Split::Empty | Split::Below if idx == self.keys.len() => return Ok(Split::Below),
+                Split::Empty | Split::Above if right_start == 0 => return Ok(Split::Above),
+                split => split.into_halves(&self.children[idx], child.level),
+            }
+        } else if idx == self.keys.len() {
+            return Ok(Split::Below);
+        } else if right_start == 0 {
+            return Ok(Split::Above);
         } else {
-            std::array::from_fn(|_| Arc::new(Node::empty(0)))
+            std::array::from_fn(|_| Link::Loaded(Arc::new(Node::empty(0))))
         };
 
-        let mut left_children = self.children[..idx].to_vec();
-        left_children.push(Link::Loaded(mid_left));
-        let mut left_node = Node {
-            level: self.level,
-            keys: left_keys,
-            values: left_values,
-            children: left_children,
-            hash: Hash::from_bytes([0u8; OUT_LEN]),
+        // A half without keys at this level is represented by its only child, so the
+        // shape depends on the key set alone and not on the order of operations.
+        let left = if idx == 0 {
+            mid_left
+        } else {
+            let mut left_children = Vec::with_capacity(idx + 1);
+            left_children.extend_from_slice(&self.children[..idx]);
+            left_children.push(mid_left);
+            let mut left_node = Node {
+                level: self.level,
+                keys: self.keys[..idx].to_vec(),
+                values: self.values[..idx].to_vec(),
+                children: left_children,
+                hash: Hash::from_bytes([0u8; HASH_LEN]),
+                count: None,
+                encoded_values: Arc::default(),
+            };
+            left_node.rehash::<H>(store);
+            Link::Loaded(Arc::new(left_node))
         };
-        left_node.rehash();
 
-        let mut right_children = vec![Link::Loaded(mid_right)];
-        if idx + 1 < self.children.len() {
-            right_children.extend_from_slice(&self.children[idx + 1..]);
-        }
-        let mut right_node = Node {
-            level: self.level,
-            keys: right_keys,
-            values: right_values,
-            children: right_children,
-            hash: Hash::from_bytes([0u8; OUT_LEN]),
+        let right = if right_start == self.keys.len() && idx + 1 >= self.children.len() {
+            mid_right
+        } else {
+            let mut right_children = Vec::with_capacity(self.children.len().saturating_sub(idx));
+            right_children.push(mid_right);
+            if idx + 1 < self.children.len() {
+                right_children.extend_from_slice(&self.children[idx + 1..]);
+            }
+            let mut right_node = Node {
+                level: self.level,
+                keys: self.keys[right_start..].to_vec(),
+                values: self.values[right_start..].to_vec(),
+                children: right_children,
+                hash: Hash::from_bytes([0u8; HASH_LEN]),
+                count: None,
+                encoded_values: Arc::default(),
+            };
+            right_node.rehash::<H>(store);
+            Link::Loaded(Arc::new(right_node))
         };
-        right_node.rehash();
 
-        Ok([left_node, right_node].map(Arc::new))
+        Ok(Split::Halves([left, right]))
     }
 
-    pub(crate) fn delete<Q>(
+    /// Removes `key` from this subtree. Returns the new subtree, or None if the key
+    /// was not present.
+    pub(crate) fn delete<H: TreeHasher, Q>(
         &self,
         key: &Q,
         store: &Arc<Store<K, V>>,
-    ) -> io::Result<(Arc<Node<K, V>>, bool)>
+    ) -> io::Result<Option<Link<K, V>>>
     where
         K: Borrow<Q>,
         Q: Ord + ?Sized,
@@ -395,58 +965,276 @@ impl<K: MerkleKey, V: MerkleValue> Node<K, V> {
                 let left_child = new_node.children.remove(idx);
                 let right_child = new_node.children.remove(idx);
 
-                let merged_child = Node::merge(left_child, right_child, store)?;
+                let merged_child = Node::merge::<H>(left_child, right_child, self.level, store)?;
+
+                // A node left without keys collapses into its only child.
+                if new_node.keys.is_empty() {
+                    return Ok(Some(merged_child));
+                }
 
                 new_node.children.insert(idx, merged_child);
 
-                new_node.rehash();
-                Ok((Arc::new(new_node), true))
+                new_node.rehash::<H>(store);
+                Ok(Some(Link::Loaded(Arc::new(new_node))))
             }
             Err(idx) => {
                 if self.children.is_empty() {
-                    return Ok((Arc::new(self.clone()), false));
+                    return Ok(None);
                 }
 
-                let child_link = &self.children[idx];
-                let child_node = match child_link {
-                    Link::Loaded(n) => n.clone(),
-                    Link::Disk { offset, .. } => store.load_node(*offset)?,
+                let child_node = self.child_node(idx, store)?;
+
+                let Some(new_child) = child_node.delete::<H, Q>(key, store)? else {
+                    return Ok(None);
                 };
 
-                let (new_child, deleted) = child_node.delete(key, store)?;
+                Ok(Some(Link::Loaded(
+                    self.with_child::<H>(idx, new_child, store),
+                )))
+            }
+        }
+    }
+
+    /// Removes every key within `range` from this subtree in a single descent.
+    /// Returns the new subtree and the number of keys removed, or None if no key fell
+    /// within the range.
+    pub(crate) fn delete_range<H: TreeHasher, Q, R>(
+        &self,
+        range: &R,
+        store: &Arc<Store<K, V>>,
+    ) -> io::Result<Option<(Link<K, V>, u64)>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        if self.children.is_empty() {
+            return Ok(None);
+        }
+
+        // Keys in `lo..hi` fall within the range.
+        let lo = self
+            .keys
+            .partition_point(|k| below_range(k.as_ref().borrow(), range));
+        let hi = lo + self.keys[lo..].partition_point(|k| !above_range(k.as_ref().borrow(), range));
+
+        if lo == hi {
+            // The range lies entirely between two keys, i.e. within a single child.
+            let child = self.child_node(lo, store)?;
+            let Some((new_child, removed)) = child.delete_range::<H, Q, R>(range, store)? else {
+                return Ok(None);
+            };
+            return Ok(Some((
+                Link::Loaded(self.with_child::<H>(lo, new_child, store)),
+                removed,
+            )));
+        }
+
+        let mut removed = (hi - lo) as u64;
+        for child in &self.children[lo + 1..hi] {
+            removed += Node::count_keys(child, store)?;
+        }
 
-                if !deleted {
-                    return Ok((Arc::new(self.clone()), false));
+        let mut boundary = |idx: usize| -> io::Result<Link<K, V>> {
+            let child = self.child_node(idx, store)?;
+            Ok(match child.delete_range::<H, Q, R>(range, store)? {
+                Some((new_child, count)) => {
+                    removed += count;
+                    new_child
                 }
+                None => self.children[idx].clone(),
+            })
+        };
+        let left = boundary(lo)?;
+        let right = boundary(hi)?;
+        let merged = Node::merge::<H>(left, right, self.level, store)?;
 
-                let mut new_node = self.clone();
-                new_node.children[idx] = Link::Loaded(new_child);
-                new_node.rehash();
-                Ok((Arc::new(new_node), true))
-            }
+        if lo == 0 && hi == self.keys.len() {
+            return Ok(Some((merged, removed)));
         }
+
+        let mut new_node = self.clone();
+        new_node.keys.drain(lo..hi);
+        new_node.values.drain(lo..hi);
+        new_node.children.splice(lo..=hi, [merged]);
+        new_node.rehash::<H>(store);
+        Ok(Some((Link::Loaded(Arc::new(new_node)), removed)))
     }
 
-    fn merge(
-        left: Link<K, V>,
-        right: Link<K, V>,
+    /// Removes the entries for which `keep` returns false, calling it on every entry in
+    /// key order. Children are filtered first; then each removed key's two neighbouring
+    /// children are merged, as `delete` would. Returns None if nothing was removed.
+    pub(crate) fn retain<H: TreeHasher>(
+        &self,
+        keep: &mut impl FnMut(&K, &V) -> bool,
         store: &Arc<Store<K, V>>,
-    ) -> io::Result<Link<K, V>> {
-        let left_node = match &left {
+    ) -> io::Result<Option<(Link<K, V>, u64)>> {
+        if self.children.is_empty() {
+            return Ok(None);
+        }
+
+        let mut removed = 0;
+        let mut children = Vec::with_capacity(self.children.len());
+        let mut kept = Vec::with_capacity(self.keys.len());
+        for idx in 0..self.children.len() {
+            let child = self.child_node(idx, store)?;
+            children.push(match child.retain::<H>(keep, store)? {
+                Some((new_child, count)) => {
+                    removed += count;
+                    new_child
+                }
+                None => self.children[idx].clone(),
+            });
+            if let Some(key) = self.keys.get(idx) {
+                let keep_key = keep(key, &self.values[idx]);
+                removed += u64::from(!keep_key);
+                kept.push(keep_key);
+            }
+        }
+        if removed == 0 {
+            return Ok(None);
+        }
+
+        let mut children = children.into_iter();
+        let mut new_node = Node::empty(self.level);
+        new_node.children.push(children.next().unwrap());
+        for ((key, value), (keep_key, right)) in self
+            .keys
+            .iter()
+            .zip(&self.values)
+            .zip(kept.into_iter().zip(children))
+        {
+            if keep_key {
+                new_node.keys.push(key.clone());
+                new_node.values.push(value.clone());
+                new_node.children.push(right);
+            } else {
+                let left = new_node.children.pop().unwrap();
+                new_node
+                    .children
+                    .push(Node::merge::<H>(left, right, self.level, store)?);
+            }
+        }
+
+        // A node left without keys collapses into its only child.
+        if new_node.keys.is_empty() {
+            return Ok(Some((new_node.children.pop().unwrap(), removed)));
+        }
+        new_node.rehash::<H>(store);
+        Ok(Some((Link::Loaded(Arc::new(new_node)), removed)))
+    }
+
+    fn child_node(&self, idx: usize, store: &Store<K, V>) -> io::Result<Arc<Node<K, V>>> {
+        Self::load_below(&self.children[idx], self.level, store)
+    }
+
+    /// Loads the node behind `link`, a child of a node at `level`.
+    ///
+    /// Levels fall on the way down, and a node that does not lie below its parent is
+    /// corrupt. Checking this as nodes are loaded bounds the depth of every recursion
+    /// over the tree by the level of its root, at most [`MAX_LEVEL`], even if the links
+    /// of a corrupt file form a cycle.
+    fn load_below(link: &Link<K, V>, level: u32, store: &Store<K, V>) -> io::Result<Arc<Self>> {
+        let node = match link {
             Link::Loaded(n) => n.clone(),
-            Link::Disk { offset, .. } => store.load_node(*offset)?,
+            Link::Disk { offset, hash, .. } => store.load_node(*offset, *hash)?,
         };
+        // Empty leaves take the level of their parent when it is already 0.
+        if node.level >= level && !(node.keys.is_empty() && node.children.is_empty()) {
+            return Err(Error::Corrupt(format!(
+                "a node at level {level} has a child at level {}",
+                node.level
+            ))
+            .into());
+        }
+        Ok(node)
+    }
 
-        let right_node = match &right {
+    /// Counts every key in the subtree behind `link`, loading nodes only where the
+    /// count is not recorded.
+    pub(crate) fn count_keys(link: &Link<K, V>, store: &Store<K, V>) -> io::Result<u64> {
+        if let Some(count) = link.count() {
+            return Ok(count);
+        }
+        let node = match link {
             Link::Loaded(n) => n.clone(),
-            Link::Disk { offset, .. } => store.load_node(*offset)?,
+            Link::Disk { offset, hash, .. } => store.load_node(*offset, *hash)?,
         };
+        let mut count = node.keys.len() as u64;
+        for child in &node.children {
+            count += Node::count_keys(child, store)?;
+        }
+        Ok(count)
+    }
+
+    /// Counts the keys of this subtree within `range`. `check_start` and `check_end`
+    /// tell whether keys may lie beyond the respective bound; subtrees entirely inside
+    /// the range are counted from their recorded size without being loaded.
+    pub(crate) fn count_range<Q, R>(
+        &self,
+        range: &R,
+        check_start: bool,
+        check_end: bool,
+        store: &Store<K, V>,
+    ) -> io::Result<u64>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        let start = if check_start {
+            self.keys
+                .partition_point(|k| below_range(k.as_ref().borrow(), range))
+        } else {
+            0
+        };
+        let end = if check_end {
+            self.keys
+                .partition_point(|k| !above_range(k.as_ref().borrow(), range))
+        } else {
+            self.keys.len()
+        };
+        if start > end {
+            // An inverted range holds no keys.
+            return Ok(0);
+        }
+
+        let mut count = (end - start) as u64;
+        if self.children.is_empty() {
+            return Ok(count);
+        }
+        for idx in start..=end {
+            let check_start = check_start && idx == start;
+            let check_end = check_end && idx == end;
+            let link = &self.children[idx];
+            count += match link.count() {
+                Some(n) if !check_start && !check_end => n,
+                _ => self.child_node(idx, store)?.count_range(
+                    range,
+                    check_start,
+                    check_end,
+                    store,
+                )?,
+            };
+        }
+        Ok(count)
+    }
+
+    /// Joins two adjacent subtrees, children of a node at `level`.
+    fn merge<H: TreeHasher>(
+        left: Link<K, V>,
+        right: Link<K, V>,
+        level: u32,
+        store: &Arc<Store<K, V>>,
+    ) -> io::Result<Link<K, V>> {
+        let left_node = Self::load_below(&left, level, store)?;
+        let right_node = Self::load_below(&right, level, store)?;
 
         if left_node.keys.is_empty() && left_node.children.is_empty() {
-            return Ok(Link::Loaded(right_node));
+            return Ok(right);
         }
         if right_node.keys.is_empty() && right_node.children.is_empty() {
-            return Ok(Link::Loaded(left_node));
+            return Ok(left);
         }
 
         if left_node.level > right_node.level {
@@ -454,9 +1242,9 @@ impl<K: MerkleKey, V: MerkleValue> Node<K, V> {
             let last_idx = new_left.children.len() - 1;
             let last_child = new_left.children.remove(last_idx);
 
-            let merged = Node::merge(last_child, right, store)?;
+            let merged = Node::merge::<H>(last_child, right, left_node.level, store)?;
             new_left.children.push(merged);
-            new_left.rehash();
+            new_left.rehash::<H>(store);
 
             return Ok(Link::Loaded(Arc::new(new_left)));
         }
@@ -465,9 +1253,9 @@ impl<K: MerkleKey, V: MerkleValue> Node<K, V> {
             let mut new_right = (*right_node).clone();
             let first_child = new_right.children.remove(0);
 
-            let merged = Node::merge(left, first_child, store)?;
+            let merged = Node::merge::<H>(left, first_child, right_node.level, store)?;
             new_right.children.insert(0, merged);
-            new_right.rehash();
+            new_right.rehash::<H>(store);
 
             return Ok(Link::Loaded(Arc::new(new_right)));
         }
@@ -478,14 +1266,37 @@ impl<K: MerkleKey, V: MerkleValue> Node<K, V> {
         let left_boundary_child = new_node.children.pop().expect("Node should have children");
         let right_boundary_child = right_clone.children.remove(0);
 
-        let merged_boundary = Node::merge(left_boundary_child, right_boundary_child, store)?;
+        let merged_boundary = Node::merge::<H>(
+            left_boundary_child,
+            right_boundary_child,
+            left_node.level,
+            store,
+        )?;
 
         new_node.keys.extend(right_clone.keys);
         new_node.values.extend(right_clone.values);
         new_node.children.push(merged_boundary);
         new_node.children.extend(right_clone.children);
-        new_node.rehash();
+        new_node.rehash::<H>(store);
 
         Ok(Link::Loaded(Arc::new(new_node)))
     }
 }
+
+/// Whether `key` sorts before every key in `range`.
+pub(crate) fn below_range<Q: Ord + ?Sized, R: RangeBounds<Q>>(key: &Q, range: &R) -> bool {
+    match range.start_bound() {
+        Bound::Included(start) => key < start,
+        Bound::Excluded(start) => key <= start,
+        Bound::Unbounded => false,
+    }
+}
+
+/// Whether `key` sorts after every key in `range`.
+pub(crate) fn above_range<Q: Ord + ?Sized, R: RangeBounds<Q>>(key: &Q, range: &R) -> bool {
+    match range.end_bound() {
+        Bound::Included(end) => key > end,
+        Bound::Excluded(end) => key >= end,
+        Bound::Unbounded => false,
+    }
+}
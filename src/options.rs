@@ -0,0 +1,267 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::store::{CompactionPolicy, Store, StoreConfig, SyncPolicy};
+use crate::tombstone::Tombstones;
+use crate::{
+    Blake3Hasher, Compression, MerkleKey, MerkleSearchTree, MerkleValue, Result, Tombstone,
+    TreeHasher,
+};
+
+/// Options for opening a [`MerkleSearchTree`], as returned by
+/// [`MerkleSearchTree::builder`].
+///
+/// Settings that shape the file, the page size and the fan-out, only apply when it
+/// is created; all others apply to the handle being opened.
+///
+/// ```no_run
+/// # use file_mst::{MerkleSearchTree, SyncPolicy};
+/// let tree: MerkleSearchTree<String, u64> = MerkleSearchTree::builder()
+///     .cache_capacity(10_000)
+///     .sync_policy(SyncPolicy::EveryN { n: 100 })
+///     .open("data.mst")?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct OpenOptions<K: MerkleKey, V: MerkleValue, H: TreeHasher = Blake3Hasher> {
+    config: StoreConfig,
+    tombstones: Option<Tombstones<V>>,
+    entries: PhantomData<fn() -> (K, V)>,
+    hasher: PhantomData<fn() -> H>,
+}
+
+impl<K: MerkleKey, V: MerkleValue, H: TreeHasher> OpenOptions<K, V, H> {
+    /// Default options for a tree that hashes with `H`.
+    pub fn new() -> Self {
+        Self {
+            config: StoreConfig::default(),
+            tombstones: None,
+            entries: PhantomData,
+            hasher: PhantomData,
+        }
+    }
+
+    /// Opens the tree without write access. The file is never modified; `insert`,
+    /// `remove`, `commit` and `compact` return `PermissionDenied`.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.config.read_only = read_only;
+        self
+    }
+
+    /// Holds at most `cache_capacity` nodes in the cache, evicting the least recently
+    /// used ones beyond that. Unbounded by default.
+    pub fn cache_capacity(&mut self, cache_capacity: usize) -> &mut Self {
+        self.config.cache_capacity = cache_capacity;
+        self
+    }
+
+    /// Page size of a new file; a power of two of at least 512 bytes. Existing files
+    /// keep the page size they were created with.
+    pub fn page_size(&mut self, page_size: u64) -> &mut Self {
+        self.config.page_size = page_size;
+        self
+    }
+
+    /// Fan-out of a new file; see [`MerkleSearchTree::open_with_fanout`]. Opening an
+    /// existing file with a different fan-out fails.
+    pub fn fanout(&mut self, fanout: u32) -> &mut Self {
+        self.config.fanout = Some(fanout);
+        self
+    }
+
+    /// Salts every node hash of a new file with `salt`, so that it never shares node or
+    /// root hashes with a tree of the same contents but another salt, or none. This
+    /// keeps tenants apart and stops anyone without the salt from precomputing
+    /// subtree hashes. Key levels, and so the shape of the tree, do not depend on it.
+    ///
+    /// The salt is recorded in the header: existing files keep theirs, and opening one
+    /// with a different salt fails. Node hashes only match between trees with the same
+    /// salt, so nodes can only be exported to and imported from such peers. Proofs
+    /// carry the salt, so they verify as usual. The salt must not be all zeros.
+    pub fn domain_salt(&mut self, salt: [u8; 32]) -> &mut Self {
+        self.config.domain_salt = Some(salt);
+        self
+    }
+
+    /// Keeps the nodes of a new file near `budget` bytes even when its values are
+    /// large. An entry whose encoded key and value, times the fan-out, come to more
+    /// than the budget is given the largest smaller fan-out, down to 2, that fits:
+    /// its level is then counted in fewer zero bits, so it sits higher up and splits
+    /// the nodes below it into more, smaller ones. Entries small enough keep the
+    /// fan-out of the file.
+    ///
+    /// An entry's level then depends on its value, so replacing a value with one of
+    /// another size can move the entry, and writes first look up the key to check.
+    /// The budget shapes the tree and its root hash, like the fan-out: it is recorded
+    /// in the header, existing files keep theirs, and opening one with a different
+    /// budget fails.
+    pub fn node_byte_budget(&mut self, budget: NonZeroU32) -> &mut Self {
+        self.config.node_byte_budget = Some(budget);
+        self
+    }
+
+    /// When commits sync to disk; see [`SyncPolicy`].
+    pub fn sync_policy(&mut self, sync_policy: SyncPolicy) -> &mut Self {
+        self.config.sync_policy = sync_policy;
+        self
+    }
+
+    /// When [`MerkleSearchTree::compact_if_due`] compacts the tree; never by default.
+    /// Async trees check it after every commit and compact on their own.
+    pub fn compaction_policy(&mut self, policy: CompactionPolicy) -> &mut Self {
+        self.config.compaction_policy = policy;
+        self
+    }
+
+    /// How long the worker of an async tree holds a
+    /// [`commit_group`](crate::AsyncMerkleSearchTree::commit_group) call back, so that
+    /// more can join it and share its commit and sync. Zero by default, which only
+    /// groups the calls already queued when the worker gets to the first one.
+    pub fn group_commit_delay(&mut self, delay: Duration) -> &mut Self {
+        self.config.group_commit_delay = delay;
+        self
+    }
+
+    /// Checks every node loaded from disk against the hash recorded by its parent,
+    /// failing with `InvalidData` on a mismatch. Each load then recomputes a node
+    /// hash, so reads cost noticeably more CPU.
+    pub fn verify_on_read(&mut self, verify_on_read: bool) -> &mut Self {
+        self.config.verify_on_read = verify_on_read;
+        self
+    }
+
+    /// Size of the buffer that nodes are appended through, 64 KiB by default. A
+    /// larger buffer means fewer system calls for bulk loads; a smaller one, down to 0
+    /// for none at all, saves copying each node for commits of only a few nodes. The
+    /// buffer is always flushed before a commit writes its root pointer.
+    pub fn write_buffer_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.config.write_buffer_capacity = capacity;
+        self
+    }
+
+    /// Writes the root pointer of each commit straight to the file, rather than
+    /// through the write buffer after seeking to it. Node appends stay buffered. Off by
+    /// default; durability is the same either way, as commits sync per the
+    /// [`SyncPolicy`] after writing it.
+    pub fn direct_metadata_writes(&mut self, direct: bool) -> &mut Self {
+        self.config.direct_metadata_writes = direct;
+        self
+    }
+
+    /// Codec for the nodes written through this handle; see [`Compression`].
+    pub fn compression(&mut self, compression: Compression) -> &mut Self {
+        self.config.compression = compression;
+        self
+    }
+
+    /// Points at the stored copy of a live node whenever identical content is written
+    /// again, rather than writing it once more. Mostly saves the many identical empty
+    /// nodes at the bottom of the tree.
+    ///
+    /// A node shared this way is reclaimed once no parent points at it anymore.
+    /// Opening a file walks the committed tree to find the nodes to point at, reading
+    /// the keys and children of each. Only files of format version 6 or later
+    /// deduplicate; the setting is ignored for older ones.
+    pub fn dedup_nodes(&mut self, dedup_nodes: bool) -> &mut Self {
+        self.config.dedup_nodes = dedup_nodes;
+        self
+    }
+
+    /// Stores values whose encoding is longer than `threshold` bytes in records of
+    /// their own, leaving only a reference in their node, so nodes holding large
+    /// values stay within a page. `get` and every other read resolve them as usual,
+    /// and root hashes are the same either way.
+    ///
+    /// A value is written once and reused as long as it is unchanged, but its record
+    /// is never reclaimed; `compact` leaves out those no longer reachable. Only files
+    /// of format version 7 or later store values this way; the setting is ignored
+    /// for older ones.
+    pub fn blob_threshold(&mut self, threshold: usize) -> &mut Self {
+        self.config.blob_threshold = Some(threshold);
+        self
+    }
+
+    /// Reads nodes through a read-only memory map of the file rather than a `pread`
+    /// per node, which mostly pays off for files much larger than the node cache.
+    /// Writes are unaffected; the map grows as the file does.
+    ///
+    /// The file must not be modified by anyone but this process while it is open.
+    #[cfg(feature = "mmap")]
+    pub fn mmap(&mut self, mmap: bool) -> &mut Self {
+        self.config.mmap = mmap;
+        self
+    }
+
+    /// Tags the file with `schema_id` instead of a fingerprint of the key and value
+    /// type names; see [`MerkleSearchTree::open_with_schema_id`].
+    pub fn schema_id(&mut self, schema_id: u64) -> &mut Self {
+        self.config.schema_id = Some(schema_id);
+        self
+    }
+
+    /// Opens the tree at `path`, creating the file unless opening read-only.
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<MerkleSearchTree<K, V, H>> {
+        let mut tree = MerkleSearchTree::from_store(Store::open::<H, _>(path, self.config)?)?;
+        tree.tombstones = self.tombstones;
+        Ok(tree)
+    }
+
+    /// Creates a tree backed by a temporary file, which is deleted once closed.
+    pub fn create_temporary(&self) -> Result<MerkleSearchTree<K, V, H>> {
+        let file = tempfile::tempfile()?;
+        let mut tree = MerkleSearchTree::from_store(Store::new::<H>(file, self.config)?)?;
+        tree.tombstones = self.tombstones;
+        Ok(tree)
+    }
+
+    /// Creates a tree that keeps its nodes in memory instead of a file, so nothing
+    /// outlives it. Committing and compacting work as they do on disk; syncing is a
+    /// no-op, whatever the sync policy.
+    pub fn create_in_memory(&self) -> Result<MerkleSearchTree<K, V, H>> {
+        let mut tree = MerkleSearchTree::from_store(Store::in_memory::<H>(self.config)?)?;
+        tree.tombstones = self.tombstones;
+        Ok(tree)
+    }
+}
+
+impl<K: MerkleKey, V: Tombstone, H: TreeHasher> OpenOptions<K, V, H> {
+    /// Keeps a tombstone under each removed key instead of deleting it; see
+    /// [`Tombstone`]. `get`, `contains`, `get_many` and `with_value` treat such keys as
+    /// absent. Everything else sees the tombstones as the values they are: iteration,
+    /// `len`, proofs and diffs include them. Call
+    /// [`MerkleSearchTree::purge_tombstones`] to delete old ones.
+    ///
+    /// The mode belongs to the handle, not the file; open the file with it every
+    /// time.
+    pub fn tombstones(&mut self, tombstones: bool) -> &mut Self {
+        self.tombstones = tombstones.then(Tombstones::new);
+        self
+    }
+}
+
+impl<K: MerkleKey, V: MerkleValue, H: TreeHasher> Default for OpenOptions<K, V, H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: MerkleKey, V: MerkleValue, H: TreeHasher> Clone for OpenOptions<K, V, H> {
+    fn clone(&self) -> Self {
+        Self {
+            config: self.config,
+            tombstones: self.tombstones,
+            entries: PhantomData,
+            hasher: PhantomData,
+        }
+    }
+}
+
+impl<K: MerkleKey, V: MerkleValue, H: TreeHasher> fmt::Debug for OpenOptions<K, V, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpenOptions")
+            .field("config", &self.config)
+            .finish()
+    }
+}
@@ -0,0 +1,177 @@
+use crate::hash::{HASH_LEN, Hash};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::node::{Link, Node};
+use crate::{Blake3Hasher, MerkleKey, MerkleValue, TreeHasher};
+
+/// One node on the path from the root to the node holding (or missing) a key.
+///
+/// Carries everything `rehash` consumes, so a verifier can recompute the node hash
+/// without access to the store.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProofNode<K, V> {
+    pub level: u32,
+    pub keys: Vec<Arc<K>>,
+    pub values: Vec<Arc<V>>,
+    pub children: Vec<Hash>,
+}
+
+impl<K, V> Clone for ProofNode<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            level: self.level,
+            keys: self.keys.clone(),
+            values: self.values.clone(),
+            children: self.children.clone(),
+        }
+    }
+}
+
+impl<K: MerkleKey, V: MerkleValue> ProofNode<K, V> {
+    pub(crate) fn from_node(node: &Node<K, V>) -> Self {
+        Self {
+            level: node.level,
+            keys: node.keys.clone(),
+            values: node.values.clone(),
+            children: node.children.iter().map(Link::hash).collect(),
+        }
+    }
+}
+
+/// A chain of nodes from the root down to the node holding a key (inclusion) or down
+/// to the empty subtree where the key would live (absence).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Proof<K, V> {
+    pub path: Vec<ProofNode<K, V>>,
+    /// Domain salt of the tree the proof comes from, which every node hash on the path
+    /// starts with. The root hash commits to it, so a proof cannot swap it.
+    pub salt: Option<[u8; 32]>,
+}
+
+impl<K, V> Clone for Proof<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            path: self.path.clone(),
+            salt: self.salt,
+        }
+    }
+}
+
+/// Checks that `proof` shows `key` mapped to `value` in the tree with `root_hash`.
+pub fn verify_proof<K: MerkleKey, V: MerkleValue>(
+    root_hash: Hash,
+    key: &K,
+    value: &V,
+    proof: &Proof<K, V>,
+) -> bool {
+    verify_proof_with::<Blake3Hasher, K, V>(root_hash, key, value, proof)
+}
+
+/// Checks that `proof` shows `key` is absent from the tree with `root_hash`.
+pub fn verify_absence<K: MerkleKey, V: MerkleValue>(
+    root_hash: Hash,
+    key: &K,
+    proof: &Proof<K, V>,
+) -> bool {
+    verify_absence_with::<Blake3Hasher, K, V>(root_hash, key, proof)
+}
+
+/// Like [`verify_proof`], for a tree built with the hasher `H`.
+pub fn verify_proof_with<H: TreeHasher, K: MerkleKey, V: MerkleValue>(
+    root_hash: Hash,
+    key: &K,
+    value: &V,
+    proof: &Proof<K, V>,
+) -> bool {
+    fold_path::<H, K, V>(key, Some(value), proof) == Some(root_hash)
+}
+
+/// Like [`verify_absence`], for a tree built with the hasher `H`.
+pub fn verify_absence_with<H: TreeHasher, K: MerkleKey, V: MerkleValue>(
+    root_hash: Hash,
+    key: &K,
+    proof: &Proof<K, V>,
+) -> bool {
+    fold_path::<H, K, V>(key, None, proof) == Some(root_hash)
+}
+
+/// Recomputes the root hash implied by the path of `proof`, bottom-up. Returns `None`
+/// if the path is malformed or does not lead to `key` the way a lookup would.
+fn fold_path<H: TreeHasher, K: MerkleKey, V: MerkleValue>(
+    key: &K,
+    value: Option<&V>,
+    proof: &Proof<K, V>,
+) -> Option<Hash> {
+    let (path, salt) = (&proof.path, proof.salt.as_ref());
+    let zero = Hash::from_bytes([0u8; HASH_LEN]);
+    let Some((last, ancestors)) = path.split_last() else {
+        // Only the empty tree has no nodes on any lookup path.
+        return value.is_none().then_some(zero);
+    };
+
+    let mut current = match value {
+        Some(value) => {
+            check_shape(last)?;
+            let idx = search(last, key).ok()?;
+            Node::<K, V>::compute_hash::<H>(
+                salt,
+                last.level,
+                last.keys.len(),
+                last.children.iter().copied(),
+                |i| {
+                    if i == idx {
+                        (key, value, None)
+                    } else {
+                        (last.keys[i].as_ref(), last.values[i].as_ref(), None)
+                    }
+                },
+            )
+        }
+        None => {
+            check_shape(last)?;
+            let pos = search(last, key).err()?;
+            if last.children[pos] != zero {
+                return None;
+            }
+            hash_with_child::<H, K, V>(salt, last, pos, zero)
+        }
+    };
+
+    for node in ancestors.iter().rev() {
+        check_shape(node)?;
+        let pos = search(node, key).err()?;
+        current = hash_with_child::<H, K, V>(salt, node, pos, current);
+    }
+
+    Some(current)
+}
+
+fn search<K: MerkleKey, V>(node: &ProofNode<K, V>, key: &K) -> Result<usize, usize> {
+    node.keys.binary_search_by(|probe| probe.as_ref().cmp(key))
+}
+
+/// Every non-empty node has one more child than keys; anything else would let a
+/// forged proof smuggle in entries that `rehash` never covers.
+fn check_shape<K, V>(node: &ProofNode<K, V>) -> Option<()> {
+    (node.values.len() == node.keys.len() && node.children.len() == node.keys.len() + 1)
+        .then_some(())
+}
+
+fn hash_with_child<H: TreeHasher, K: MerkleKey, V: MerkleValue>(
+    salt: Option<&[u8; 32]>,
+    node: &ProofNode<K, V>,
+    pos: usize,
+    child: Hash,
+) -> Hash {
+    Node::<K, V>::compute_hash::<H>(
+        salt,
+        node.level,
+        node.keys.len(),
+        node.children
+            .iter()
+            .enumerate()
+            .map(|(i, hash)| if i == pos { child } else { *hash }),
+        |i| (node.keys[i].as_ref(), node.values[i].as_ref(), None),
+    )
+}
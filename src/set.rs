@@ -0,0 +1,135 @@
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::iter::KeysIter;
+use crate::{Blake3Hasher, Hash, MerkleKey, MerkleSearchTree, Result, TreeHasher, Version};
+
+/// A set of keys, stored as a [`MerkleSearchTree`] whose values are all `()`.
+///
+/// Postcard encodes `()` as no bytes at all, so the values take no space on disk and
+/// each entry hashes as its key followed by an empty value. Everything the set does
+/// not wrap, such as proofs and snapshots, is available on the tree through
+/// [`as_tree`](Self::as_tree).
+pub struct MerkleSet<K: MerkleKey, H: TreeHasher = Blake3Hasher> {
+    tree: MerkleSearchTree<K, (), H>,
+}
+
+/// How another set of keys differs from a [`MerkleSet`], as returned by
+/// [`MerkleSet::diff`]. Both lists are in key order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetDiff<'a, K> {
+    /// Keys only in the other set.
+    pub added: Vec<&'a K>,
+    /// Keys only in the `MerkleSet`.
+    pub removed: Vec<Arc<K>>,
+}
+
+impl<K: MerkleKey> MerkleSet<K> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::from_tree(MerkleSearchTree::open(path)?))
+    }
+
+    /// Creates a set backed by a temporary file, which is deleted once closed.
+    pub fn new_temporary() -> Result<Self> {
+        Ok(Self::from_tree(MerkleSearchTree::new_temporary()?))
+    }
+
+    /// Creates a set held entirely in memory.
+    pub fn new_in_memory() -> Result<Self> {
+        Ok(Self::from_tree(MerkleSearchTree::new_in_memory()?))
+    }
+}
+
+impl<K: MerkleKey, H: TreeHasher> MerkleSet<K, H> {
+    /// Wraps a tree opened some other way, e.g. through [`OpenOptions`].
+    ///
+    /// [`OpenOptions`]: crate::OpenOptions
+    pub fn from_tree(tree: MerkleSearchTree<K, (), H>) -> Self {
+        Self { tree }
+    }
+
+    pub fn as_tree(&self) -> &MerkleSearchTree<K, (), H> {
+        &self.tree
+    }
+
+    pub fn into_tree(self) -> MerkleSearchTree<K, (), H> {
+        self.tree
+    }
+
+    pub fn insert(&self, key: K) -> Result<()> {
+        self.tree.insert(key, ())
+    }
+
+    /// Inserts a batch of keys; see [`MerkleSearchTree::insert_many`].
+    pub fn insert_many<I: IntoIterator<Item = K>>(&self, keys: I) -> Result<()> {
+        self.tree.insert_many(keys.into_iter().map(|key| (key, ())))
+    }
+
+    pub fn contains<Q>(&self, key: &Q) -> Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.tree.contains(key)
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Result<()>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.tree.remove(key)
+    }
+
+    pub fn len(&self) -> Result<u64> {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        self.tree.is_empty()
+    }
+
+    /// Iterates over all keys in order.
+    pub fn iter(&self) -> Result<KeysIter<K, ()>> {
+        self.tree.keys()
+    }
+
+    pub fn commit(&self) -> Result<Version> {
+        self.tree.commit()
+    }
+
+    pub fn root_hash(&self) -> Hash {
+        self.tree.root_hash()
+    }
+
+    /// The keys to add to and remove from this set to make it equal to `other`.
+    pub fn diff<'a>(&self, other: &'a BTreeSet<K>) -> Result<SetDiff<'a, K>> {
+        let mut diff = SetDiff {
+            added: Vec::new(),
+            removed: Vec::new(),
+        };
+        let mut ours = self.iter()?.peekable();
+        let mut theirs = other.iter().peekable();
+        loop {
+            let order = match (ours.peek(), theirs.peek()) {
+                (None, None) => break,
+                (Some(Err(_)), _) => return Err(ours.next().unwrap().unwrap_err()),
+                (Some(Ok(key)), Some(other)) => key.as_ref().cmp(other),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+            };
+            match order {
+                Ordering::Less => diff.removed.push(ours.next().unwrap()?),
+                Ordering::Greater => diff.added.push(theirs.next().unwrap()),
+                Ordering::Equal => {
+                    ours.next();
+                    theirs.next();
+                }
+            }
+        }
+        Ok(diff)
+    }
+}
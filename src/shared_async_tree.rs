@@ -0,0 +1,202 @@
+use crate::{Hash, Version};
+use std::borrow::Borrow;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tokio::task;
+
+use crate::{
+    Blake3Hasher, Error, MerkleKey, MerkleSearchTree, MerkleValue, Result, Snapshot, TreeHasher,
+};
+
+struct Shared<K: MerkleKey, V: MerkleValue, H: TreeHasher> {
+    /// Taken by mutating operations only, for their whole duration.
+    tree: Mutex<MerkleSearchTree<K, V, H>>,
+    /// The tree as of the last completed mutation. Readers clone it under a brief
+    /// lock and never wait for a write in progress.
+    current: Mutex<Snapshot<K, V>>,
+    /// Kept so that subscribing never waits for the tree lock.
+    commits: watch::Receiver<Hash>,
+}
+
+/// Async wrapper for MerkleSearchTree running operations on tokio's blocking pool.
+///
+/// Unlike [`AsyncMerkleSearchTree`], which funnels everything through one worker
+/// thread, reads run concurrently with each other and with writes: `get` and
+/// `contains` see the tree as of the last completed `insert`, `remove`, `commit` or
+/// `compact`. Only the mutating operations are serialized.
+///
+/// Must be used from within a tokio runtime.
+///
+/// [`AsyncMerkleSearchTree`]: crate::AsyncMerkleSearchTree
+pub struct SharedAsyncMerkleSearchTree<K, V, H = Blake3Hasher>
+where
+    K: MerkleKey + Send + Sync + 'static,
+    V: MerkleValue + Send + Sync + 'static,
+    H: TreeHasher + 'static,
+{
+    shared: Arc<Shared<K, V, H>>,
+}
+
+impl<K, V, H> Clone for SharedAsyncMerkleSearchTree<K, V, H>
+where
+    K: MerkleKey + Send + Sync + 'static,
+    V: MerkleValue + Send + Sync + 'static,
+    H: TreeHasher + 'static,
+{
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<K, V, H> From<MerkleSearchTree<K, V, H>> for SharedAsyncMerkleSearchTree<K, V, H>
+where
+    K: MerkleKey + Send + Sync + 'static,
+    V: MerkleValue + Send + Sync + 'static,
+    H: TreeHasher + 'static,
+{
+    fn from(tree: MerkleSearchTree<K, V, H>) -> Self {
+        let current = Mutex::new(tree.snapshot());
+        let commits = tree.subscribe();
+        Self {
+            shared: Arc::new(Shared {
+                tree: Mutex::new(tree),
+                current,
+                commits,
+            }),
+        }
+    }
+}
+
+impl<K, V> SharedAsyncMerkleSearchTree<K, V>
+where
+    K: MerkleKey + Send + Sync + 'static,
+    V: MerkleValue + Send + Sync + 'static,
+{
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(MerkleSearchTree::open(path)?.into())
+    }
+
+    /// Creates a new MST backed by a temporary file.
+    pub fn new_temporary() -> Result<Self> {
+        Ok(MerkleSearchTree::new_temporary()?.into())
+    }
+}
+
+impl<K, V, H> SharedAsyncMerkleSearchTree<K, V, H>
+where
+    K: MerkleKey + Send + Sync + 'static,
+    V: MerkleValue + Send + Sync + 'static,
+    H: TreeHasher + 'static,
+{
+    /// Runs `op` on the blocking pool with exclusive access to the tree, then
+    /// publishes the result for readers.
+    async fn write<T: Send + 'static>(
+        &self,
+        op: impl FnOnce(&mut MerkleSearchTree<K, V, H>) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let shared = self.shared.clone();
+        task::spawn_blocking(move || {
+            let mut tree = shared.tree.lock().unwrap();
+            let result = op(&mut tree);
+            // Published even on error, as a failed operation may have changed the tree.
+            *shared.current.lock().unwrap() = tree.snapshot();
+            result
+        })
+        .await
+        .map_err(Self::on_join_error)?
+    }
+
+    /// Runs `op` on the blocking pool against the latest published snapshot.
+    async fn read<T: Send + 'static>(
+        &self,
+        op: impl FnOnce(&Snapshot<K, V>) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let snapshot = self.shared.current.lock().unwrap().clone();
+        task::spawn_blocking(move || op(&snapshot))
+            .await
+            .map_err(Self::on_join_error)?
+    }
+
+    pub async fn insert(&self, key: K, value: V) -> Result<()> {
+        self.write(move |tree| tree.insert(key, value)).await
+    }
+
+    pub async fn remove(&self, key: K) -> Result<()> {
+        self.write(move |tree| tree.remove(&key)).await
+    }
+
+    /// Retrieves a value by key. The key is moved to the blocking pool, not cloned;
+    /// see [`get_borrowed`](Self::get_borrowed) for looking up a borrowed form of it.
+    pub async fn get(&self, key: K) -> Result<Option<Arc<V>>> {
+        self.read(move |snapshot| snapshot.get(&key)).await
+    }
+
+    /// Checks if a key exists, moving the key like [`get`](Self::get); see
+    /// [`contains_borrowed`](Self::contains_borrowed).
+    pub async fn contains(&self, key: K) -> Result<bool> {
+        self.read(move |snapshot| snapshot.contains(&key)).await
+    }
+
+    /// Like [`get`](Self::get), but takes the key in any form `K` can be borrowed as,
+    /// e.g. `&str` for `String` keys. The key is copied once to reach the blocking
+    /// pool.
+    pub async fn get_borrowed<Q>(&self, key: &Q) -> Result<Option<Arc<V>>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned + ?Sized + 'static,
+        Q::Owned: Send + 'static,
+    {
+        let key = key.to_owned();
+        self.read(move |snapshot| snapshot.get::<Q>(key.borrow()))
+            .await
+    }
+
+    /// Like [`contains`](Self::contains), but takes the key in any form `K` can be
+    /// borrowed as.
+    pub async fn contains_borrowed<Q>(&self, key: &Q) -> Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned + ?Sized + 'static,
+        Q::Owned: Send + 'static,
+    {
+        let key = key.to_owned();
+        self.read(move |snapshot| snapshot.contains::<Q>(key.borrow()))
+            .await
+    }
+
+    /// Commits, then compacts on the blocking pool if the tree's
+    /// [`CompactionPolicy`](crate::CompactionPolicy) calls for it, without waiting for
+    /// that. A failed compaction leaves the tree as it was, to be retried after a
+    /// later commit.
+    pub async fn commit(&self) -> Result<Version> {
+        let version = self.write(|tree| tree.commit()).await?;
+        let shared = self.shared.clone();
+        task::spawn_blocking(move || {
+            let mut tree = shared.tree.lock().unwrap();
+            if let Ok(true) = tree.compact_if_due() {
+                *shared.current.lock().unwrap() = tree.snapshot();
+            }
+        });
+        Ok(version)
+    }
+
+    pub async fn compact(&self, path: String) -> Result<()> {
+        self.write(move |tree| tree.compact(path)).await
+    }
+
+    /// Returns a receiver that observes the root hash of every commit that writes a
+    /// new root; see [`MerkleSearchTree::subscribe`].
+    pub fn subscribe(&self) -> watch::Receiver<Hash> {
+        let mut commits = self.shared.commits.clone();
+        commits.mark_unchanged();
+        commits
+    }
+
+    fn on_join_error(join_error: task::JoinError) -> Error {
+        io::Error::other(join_error).into()
+    }
+}
@@ -0,0 +1,129 @@
+use crate::Hash;
+use std::borrow::Borrow;
+use std::io;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+
+use crate::iter::Iter;
+use crate::node::{Link, Node};
+use crate::store::Store;
+use crate::{MerkleKey, MerkleValue, Result};
+
+/// A read-only view of a tree as it was when [`MerkleSearchTree::snapshot`] was
+/// called.
+///
+/// Nodes are never modified in place, so the snapshot keeps reading the same entries
+/// however the tree changes afterwards, committed or not. Space orphaned by commits
+/// made after a snapshot was taken is not reused while the snapshot is alive; it is
+/// reclaimed by the first commit after the snapshot is dropped.
+///
+/// [`MerkleSearchTree::snapshot`]: crate::MerkleSearchTree::snapshot
+pub struct Snapshot<K: MerkleKey, V: MerkleValue> {
+    pub(crate) root: Link<K, V>,
+    pub(crate) store: Arc<Store<K, V>>,
+    /// Store generation the snapshot was taken at.
+    pinned: u64,
+}
+
+impl<K: MerkleKey, V: MerkleValue> Snapshot<K, V> {
+    pub(crate) fn new(root: Link<K, V>, store: Arc<Store<K, V>>) -> Self {
+        let pinned = store.pin_snapshot();
+        Self {
+            root,
+            store,
+            pinned,
+        }
+    }
+
+    fn root_node(&self) -> io::Result<Arc<Node<K, V>>> {
+        match &self.root {
+            Link::Loaded(node) => Ok(node.clone()),
+            Link::Disk { offset, hash, .. } => self.store.load_node(*offset, *hash),
+        }
+    }
+
+    /// Checks if a key exists in the snapshot.
+    pub fn contains<Q>(&self, key: &Q) -> Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        Ok(self.root_node()?.contains(key, &self.store)?)
+    }
+
+    /// Retrieves a value by key. Returns None if the key does not exist.
+    pub fn get<Q>(&self, key: &Q) -> Result<Option<Arc<V>>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        Ok(self.root_node()?.get(key, &self.store)?)
+    }
+
+    /// Iterates over all entries in key order.
+    pub fn iter(&self) -> Result<Iter<K, V>> {
+        Ok(Iter::new::<K, _>(self.clone(), &..)?)
+    }
+
+    /// Iterates over the entries within `range` in key order.
+    pub fn range<Q, R>(&self, range: R) -> Result<Iter<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        Ok(Iter::new(self.clone(), &range)?)
+    }
+
+    pub fn root_hash(&self) -> Hash {
+        self.root.hash()
+    }
+}
+
+impl<K: MerkleKey, V: MerkleValue> Clone for Snapshot<K, V> {
+    fn clone(&self) -> Self {
+        self.store.repin_snapshot(self.pinned);
+        Self {
+            root: self.root.clone(),
+            store: self.store.clone(),
+            pinned: self.pinned,
+        }
+    }
+}
+
+impl<K: MerkleKey, V: MerkleValue> Drop for Snapshot<K, V> {
+    fn drop(&mut self) {
+        self.store.unpin_snapshot(self.pinned);
+    }
+}
+
+/// The root of a tree between two commits, to go back to with
+/// [`MerkleSearchTree::restore`].
+///
+/// It only holds on to the root, whose nodes are never modified in place, so taking
+/// one costs nothing and writes after it leave it intact. A commit may free nodes it
+/// reaches, so it can no longer be restored once the tree has committed since.
+///
+/// [`MerkleSearchTree::restore`]: crate::MerkleSearchTree::restore
+pub struct Checkpoint<K: MerkleKey, V: MerkleValue> {
+    pub(crate) root: Link<K, V>,
+    pub(crate) store: Arc<Store<K, V>>,
+    /// Store generation the checkpoint was taken at.
+    pub(crate) generation: u64,
+}
+
+impl<K: MerkleKey, V: MerkleValue> Checkpoint<K, V> {
+    pub fn root_hash(&self) -> Hash {
+        self.root.hash()
+    }
+}
+
+impl<K: MerkleKey, V: MerkleValue> Clone for Checkpoint<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            root: self.root.clone(),
+            store: self.store.clone(),
+            generation: self.generation,
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use crate::hash::{HASH_LEN, Hash};
+use std::collections::HashSet;
+use std::io;
+
+use crate::node::Link;
+use crate::store::Store;
+use crate::{MerkleKey, MerkleValue};
+
+/// Shape of a tree, as reported by [`MerkleSearchTree::stats`].
+///
+/// Empty nodes, which only mark the absence of a subtree, are not counted.
+///
+/// [`MerkleSearchTree::stats`]: crate::MerkleSearchTree::stats
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    /// Number of nodes on the longest path from the root; 0 for an empty tree.
+    pub height: usize,
+    pub node_count: u64,
+    pub key_count: u64,
+    /// Nodes only held in memory, which the next `commit` writes.
+    pub loaded_nodes: u64,
+    /// Nodes on disk and unchanged since the last commit.
+    pub disk_nodes: u64,
+}
+
+impl TreeStats {
+    /// Average number of keys per node; 0 for an empty tree.
+    pub fn avg_keys_per_node(&self) -> f64 {
+        if self.node_count == 0 {
+            return 0.0;
+        }
+        self.key_count as f64 / self.node_count as f64
+    }
+
+    /// Walks the whole tree below `root`. Nodes read from disk are not added to the
+    /// cache.
+    pub(crate) fn collect<K: MerkleKey, V: MerkleValue>(
+        root: &Link<K, V>,
+        store: &Store<K, V>,
+    ) -> io::Result<Self> {
+        let empty = Hash::from_bytes([0u8; HASH_LEN]);
+        let mut stats = Self::default();
+        let mut stack = vec![(root.clone(), 1)];
+
+        while let Some((link, depth)) = stack.pop() {
+            if link.hash() == empty {
+                continue;
+            }
+            let node = match &link {
+                Link::Loaded(node) => {
+                    stats.loaded_nodes += 1;
+                    node.clone()
+                }
+                Link::Disk { offset, hash, .. } => {
+                    stats.disk_nodes += 1;
+                    store.load_node_uncached(*offset, *hash)?
+                }
+            };
+
+            stats.node_count += 1;
+            stats.key_count += node.keys.len() as u64;
+            stats.height = stats.height.max(depth);
+            stack.extend(node.children.iter().map(|child| (child.clone(), depth + 1)));
+        }
+        Ok(stats)
+    }
+}
+
+/// Sums the on-disk size of the header page and of every record reachable from
+/// `root`: its nodes, including empty ones, and the blobs holding their values.
+/// Records shared through deduplication count once. Nodes only held in memory take no
+/// space yet, but the nodes on disk below them are counted. Nodes read for the walk
+/// are not added to the cache.
+pub(crate) fn live_bytes<K: MerkleKey, V: MerkleValue>(
+    root: &Link<K, V>,
+    store: &Store<K, V>,
+) -> io::Result<u64> {
+    let empty = Hash::from_bytes([0u8; HASH_LEN]);
+    let mut live = store.config().page_size;
+    let mut counted = HashSet::new();
+    let mut stack = vec![root.clone()];
+
+    while let Some(link) = stack.pop() {
+        let node = match &link {
+            Link::Loaded(node) => node.clone(),
+            Link::Disk { offset, hash, .. } => {
+                if !counted.insert(*offset) {
+                    continue;
+                }
+                live += store.record_len(*offset)?;
+                if *hash == empty {
+                    continue;
+                }
+                for blob in store.blob_offsets(*offset)? {
+                    if counted.insert(blob) {
+                        live += store.record_len(blob)?;
+                    }
+                }
+                store.load_node_uncached(*offset, *hash)?
+            }
+        };
+        stack.extend(node.children.iter().cloned());
+    }
+    Ok(live)
+}
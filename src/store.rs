@@ -1,116 +1,1375 @@
-use blake3::{Hash, OUT_LEN};
+use crate::hash::{HASH_LEN, Hash};
 
+#[cfg(feature = "mmap")]
+use crate::mmap::MappedFile;
 use crate::{
-    MerkleKey, MerkleValue, NodeId, PAGE_SIZE,
-    node::{DiskNode, Node},
+    Compression, DEFAULT_FANOUT, DEFAULT_PAGE_SIZE, Error, MerkleKey, MerkleValue, NodeId,
+    TreeHasher,
+    backing::{Backing, FileLock},
+    cache::{CacheMetrics, LruCache},
+    freelist::FreeList,
+    node::{
+        BlobRef, ChildMeta, DiskChild, DiskNode, DiskNodeKeys, DiskNodeRef, EncodedValue,
+        FrontCoded, FrontCodedKeys, LegacyDiskChild, Link, MAX_LEVEL, Node, NodeKeys, StoredValue,
+        ValuesLast, encoding_of,
+    },
 };
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::{File, OpenOptions};
-use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::Path;
-use std::sync::{Arc, RwLock};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Identifies a file-mst database. Written at the very start of the header page.
+const MAGIC: &[u8; 8] = b"FILEMST\0";
+
+/// On-disk format version. Bump whenever the header or node encoding changes in a
+/// way older readers cannot ignore.
+///
+/// Version 2 records the number of keys below each child link. Version 3 prefixes
+/// every node with its codec, see [`Compression`]. Version 4 saves the free list and
+/// points at it from the metadata slots. Version 5 stores the values of a node after
+/// its other fields, so its keys can be read alone. Version 6 records the fan-out in
+/// the header; older files always use [`DEFAULT_FANOUT`]. Version 7 can store large
+/// values in records of their own; see [`BlobRef`]. Version 8 front-codes the keys
+/// of a node; see [`FrontCoded`]. Version 9 can record a domain salt in the header;
+/// see [`StoreConfig::domain_salt`]. Version 10 can record a node byte budget in the
+/// header; see [`StoreConfig::node_byte_budget`]. Older files are still read and
+/// written in their own format; `compact` upgrades them.
+pub(crate) const FORMAT_VERSION: u32 = 10;
+
+/// Oldest format version this build can open.
+const MIN_FORMAT_VERSION: u32 = 1;
+
+/// Fixed header fields live in the first bytes of page 0; the rest of the page up to
+/// the metadata slots is reserved for future fields.
+///
+/// `magic (8) | format version (4) | page size (4) | schema fingerprint (8) | fan-out (4)
+/// | domain salt (32) | node byte budget (4)`
+///
+/// Files written before the fingerprint existed have zeros there, which skips the
+/// schema check. Likewise, a salt of all zeros or a budget of zero means there is none.
+const HEADER_LEN: usize = 64;
+
+/// Two metadata slots are written alternately, so a torn write can only damage the
+/// slot being written while the previous root stays intact in the other one.
+pub(crate) const SLOT_OFFSETS: [u64; 2] = [256, 320];
+
+/// `generation (8) | root offset (8) | root hash (32) | free list offset (8) | checksum (8)`
+///
+/// Files before version 4 have no free list offset.
+const SLOT_LEN: usize = 64;
+const LEGACY_SLOT_LEN: usize = 56;
+
+/// Room for the free list to grow while its own record is carved out of it: one
+/// `(len, offset)` entry of two varint-encoded `u64`s.
+const FREE_LIST_SLACK: usize = 20;
+
+/// Largest allowed fan-out: a key's level counts leading zero groups of at most a
+/// byte of its hash.
+pub(crate) const MAX_FANOUT: u32 = 256;
+
+/// Smallest allowed page: the header page must fit the fixed fields and both slots.
+pub(crate) const MIN_PAGE_SIZE: u64 = 512;
+
+/// Buffer for node appends unless configured otherwise.
+const DEFAULT_WRITE_BUFFER_CAPACITY: usize = 64 * 1024;
+
+/// When `commit` waits for its writes to reach the disk.
+///
+/// A commit syncs its nodes before writing the root pointer, then syncs the root
+/// pointer. Skipping these syncs makes commits much cheaper, but the operating system
+/// may then write them out late and in any order. Either way, a crash of the process
+/// alone loses nothing that was committed, since the writes already reached the OS.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Every commit is durable once it returns. A power failure or OS crash leaves the
+    /// last commit, or the one before it if the root pointer was torn.
+    #[default]
+    Always,
+    /// Commits are never synced; the OS writes them back whenever it chooses. After a
+    /// power failure or OS crash, any number of recent commits may be lost, and the
+    /// file may be left unreadable if the root pointer reached the disk before the
+    /// nodes it refers to. Suited to bulk loads that can be redone from scratch.
+    Never,
+    /// Only every `n`-th commit is synced, which makes it and every commit before it
+    /// durable. Commits in between carry the same risks as with `Never` until the
+    /// next synced commit.
+    EveryN { n: u64 },
+}
+
+/// When [`MerkleSearchTree::compact_if_due`] compacts a tree, by how much of its file
+/// is garbage: bytes that [`MerkleSearchTree::live_size`] does not count.
+///
+/// [`MerkleSearchTree::compact_if_due`]: crate::MerkleSearchTree::compact_if_due
+/// [`MerkleSearchTree::live_size`]: crate::MerkleSearchTree::live_size
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CompactionPolicy {
+    /// Only explicit calls to `compact` and `compact_in_place` compact.
+    #[default]
+    Never,
+    /// Compacts once more than this fraction of the file is garbage, e.g. `0.5`.
+    GarbageRatio(f64),
+    /// Compacts once more than this many bytes of the file are garbage.
+    GarbageBytes(u64),
+}
+
+/// Tunables fixed when a store is created or opened.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct StoreConfig {
+    pub cache_capacity: usize,
+    /// Opens the file without write access; every mutating call fails.
+    pub read_only: bool,
+    /// Only used when creating a new file; existing files keep the page size recorded
+    /// in their header.
+    pub page_size: u64,
+    /// Recomputes the hash of every node loaded from disk and rejects mismatches.
+    pub verify_on_read: bool,
+    /// Identifies the key and value types stored in the file. Defaults to a
+    /// fingerprint of their type names.
+    pub schema_id: Option<u64>,
+    /// Codec for the nodes this store writes.
+    pub compression: Compression,
+    pub sync_policy: SyncPolicy,
+    pub compaction_policy: CompactionPolicy,
+    /// How long an async tree's worker waits for more `commit_group` calls to join
+    /// one it received, before committing them together.
+    pub group_commit_delay: Duration,
+    /// Fan-out of a new file, [`DEFAULT_FANOUT`] if unset. Existing files keep the
+    /// fan-out recorded in their header, and opening one with a different fan-out
+    /// fails.
+    pub fanout: Option<u32>,
+    /// Reuses the stored copy of a node instead of writing identical content again.
+    /// Only applies to files of format version 6 or later.
+    pub dedup_nodes: bool,
+    /// Size of the buffer node appends go through; 0 writes them straight through.
+    pub write_buffer_capacity: usize,
+    /// Writes metadata slots straight to the file instead of through the buffer.
+    pub direct_metadata_writes: bool,
+    /// Mixed into every node hash of a new file, so trees with different salts never
+    /// share node or root hashes. Existing files keep the salt recorded in their
+    /// header, and opening one with a different salt fails. Only applies to files of
+    /// format version 9 or later.
+    pub domain_salt: Option<[u8; 32]>,
+    /// Values whose encoding is longer than this are written to blobs of their own,
+    /// with only a reference in their node. Only applies to files of format version 7
+    /// or later.
+    pub blob_threshold: Option<usize>,
+    /// Gives entries too large for [`fanout`](Self::fanout) of them to fit in this
+    /// many bytes a smaller fan-out of their own, so nodes of large values stay small.
+    /// Existing files keep the budget recorded in their header, and opening one with a
+    /// different budget fails. Only applies to files of format version 10 or later.
+    pub node_byte_budget: Option<NonZeroU32>,
+    /// Reads nodes through a memory map of the file.
+    #[cfg(feature = "mmap")]
+    pub mmap: bool,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self {
+            cache_capacity: usize::MAX,
+            read_only: false,
+            page_size: DEFAULT_PAGE_SIZE,
+            verify_on_read: false,
+            schema_id: None,
+            compression: Compression::None,
+            sync_policy: SyncPolicy::Always,
+            compaction_policy: CompactionPolicy::Never,
+            group_commit_delay: Duration::ZERO,
+            fanout: None,
+            dedup_nodes: false,
+            write_buffer_capacity: DEFAULT_WRITE_BUFFER_CAPACITY,
+            direct_metadata_writes: false,
+            domain_salt: None,
+            blob_threshold: None,
+            node_byte_budget: None,
+            #[cfg(feature = "mmap")]
+            mmap: false,
+        }
+    }
+}
 
 pub struct Store<K: MerkleKey, V: MerkleValue> {
-    file: RwLock<BufWriter<File>>,
-    cache: RwLock<HashMap<NodeId, Arc<Node<K, V>>>>,
+    /// Handle used for positional reads; never seeked, so readers need no lock.
+    reader: Backing,
+    /// Map of the file that node reads go through instead, when enabled.
+    #[cfg(feature = "mmap")]
+    map: Option<MappedFile>,
+    /// Append path. Only writes, metadata updates and flushes take this lock.
+    writer: Mutex<BufWriter<Backing>>,
+    cache: Mutex<LruCache<Node<K, V>>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    cache_evictions: AtomicU64,
+    config: StoreConfig,
+    /// Format version of the file, which decides how nodes are encoded.
+    version: u32,
+    /// Generation of the most recent valid metadata slot; 0 if none was ever written.
+    generation: AtomicU64,
+    /// Commits written since the last synced one, for `SyncPolicy::EveryN`.
+    unsynced_commits: AtomicU64,
+    /// File length when the live size was last measured for the compaction policy.
+    measured_len: AtomicU64,
+    /// Regions that can be reused. Only replaced once a commit is durable; see
+    /// `prepare_retire`.
+    free_list: Mutex<FreeList>,
+    /// Set during a guarded commit, which appends every record at the end of the file
+    /// rather than taking space from the free list; see `begin_guarded_commit`.
+    append_only: AtomicBool,
+    /// Where the live nodes are, when deduplication is enabled. Rebuilt from the
+    /// committed tree on open; see `index_live_nodes`.
+    node_index: Option<Mutex<NodeIndex>>,
+    /// Blobs this store wrote or read, by the hash of their value, so a value is not
+    /// written again each time its node is. Only kept when values go to blobs.
+    blobs: Option<Mutex<HashMap<Hash, BlobRef>>>,
+    /// Live snapshots, counted by the generation they were taken at. Nodes orphaned
+    /// after the oldest one are not reused.
+    snapshots: Mutex<BTreeMap<u64, usize>>,
+    /// Hashes a node with the tree's hasher; used by `verify_on_read`.
+    node_hash: fn(&Node<K, V>, Option<&[u8; 32]>) -> Hash,
+    /// Hashes an encoded value with the tree's hasher; used for blobs.
+    value_hash: fn(&[u8]) -> Hash,
+    /// Where the file was opened from; `None` for temporary files and memory.
+    path: Option<PathBuf>,
+    /// Set by node writes and cleared by `flush`; metadata must never be written
+    /// while nodes are still unsynced.
+    #[cfg(test)]
+    unsynced_nodes: std::sync::atomic::AtomicBool,
+    /// Number of `sync_all` calls made so far.
+    #[cfg(test)]
+    pub(crate) syncs: AtomicU64,
+    /// Number of reads made to load records, outside of the memory map.
+    #[cfg(test)]
+    pub(crate) reads: AtomicU64,
+    /// Simulates a crash right after a commit synced its nodes: metadata writes are
+    /// silently dropped.
+    #[cfg(test)]
+    pub(crate) drop_metadata_writes: std::sync::atomic::AtomicBool,
+    /// Simulates a failing disk: the node write this many writes from now fails, then
+    /// writes succeed again. Zero fails none.
+    #[cfg(test)]
+    pub(crate) node_writes_until_failure: AtomicU64,
 }
 
 impl<K: MerkleKey, V: MerkleValue> Store<K, V> {
-    pub fn new(file: File) -> Arc<Self> {
-        Arc::new(Self {
-            file: RwLock::new(BufWriter::with_capacity(64 * 1024, file)),
-            cache: RwLock::new(HashMap::new()),
-        })
+    /// Creates a store on top of `file`.
+    ///
+    /// An empty file is initialized with a fresh header page; otherwise the existing
+    /// header is validated and its page size takes precedence over `config`. `H` must
+    /// be the hasher of the tree stored in the file.
+    pub fn new<H: TreeHasher>(file: File, config: StoreConfig) -> io::Result<Arc<Self>> {
+        Self::from_file::<H>(file, config, None)
+    }
+
+    /// Creates a store that keeps its bytes in memory instead of a file. They are gone
+    /// once it is dropped.
+    pub(crate) fn in_memory<H: TreeHasher>(config: StoreConfig) -> io::Result<Arc<Self>> {
+        Self::from_backing::<H>(Backing::memory(), config, None)
+    }
+
+    /// Like `new`, recording `path` as where the file lives.
+    pub(crate) fn from_file<H: TreeHasher>(
+        file: File,
+        config: StoreConfig,
+        path: Option<PathBuf>,
+    ) -> io::Result<Arc<Self>> {
+        Self::from_backing::<H>(Backing::File(file), config, path)
     }
-    pub(crate) fn open<P: AsRef<Path>>(path: P) -> io::Result<Arc<Self>> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(false)
-            .open(path)?;
 
-        if file.metadata()?.len() == 0 {
-            file.set_len(PAGE_SIZE)?;
+    fn from_backing<H: TreeHasher>(
+        mut file: Backing,
+        mut config: StoreConfig,
+        path: Option<PathBuf>,
+    ) -> io::Result<Arc<Self>> {
+        let schema = match config.schema_id {
+            Some(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "schema id must be non-zero",
+                ));
+            }
+            Some(schema_id) => schema_id,
+            None => type_fingerprint::<K, V>(),
+        };
+
+        let version;
+        let file_len = file.len()?;
+        if file_len == 0 {
+            if config.read_only {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "cannot open an empty file read-only",
+                ));
+            }
+            validate_page_size(config.page_size)?;
+            let fanout = config.fanout.unwrap_or(DEFAULT_FANOUT);
+            validate_fanout(fanout)?;
+            if config.domain_salt == Some([0; 32]) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "domain salt must not be all zeros",
+                ));
+            }
+            let header = Header {
+                version: FORMAT_VERSION,
+                page_size: config.page_size as u32,
+                schema,
+                fanout,
+                domain_salt: config.domain_salt,
+                node_byte_budget: config.node_byte_budget,
+            };
+            config.fanout = Some(fanout);
+            file.set_len(config.page_size)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&header.encode())?;
+            version = FORMAT_VERSION;
+        } else {
+            // Anything shorter than the header page was cut off, or is not a database.
+            if file_len < HEADER_LEN as u64 {
+                return Err(Error::Corrupt(format!(
+                    "file is {file_len} bytes long, too short to hold a header"
+                ))
+                .into());
+            }
+            let mut bytes = [0u8; HEADER_LEN];
+            file.read_exact_at(&mut bytes, 0)?;
+            let header = Header::decode(&bytes)?;
+            if file_len < u64::from(header.page_size) {
+                return Err(Error::Corrupt(format!(
+                    "file is {file_len} bytes long, shorter than its {}-byte header page",
+                    header.page_size
+                ))
+                .into());
+            }
+            if header.schema != 0 && header.schema != schema {
+                return Err(Error::SchemaMismatch {
+                    found: header.schema,
+                    expected: schema,
+                }
+                .into());
+            }
+            if let Some(fanout) = config.fanout
+                && fanout != header.fanout
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "fan-out mismatch: the file was created with fan-out {}, but was \
+                         opened with {fanout}",
+                        header.fanout
+                    ),
+                ));
+            }
+            if config
+                .domain_salt
+                .is_some_and(|salt| Some(salt) != header.domain_salt)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "domain salt mismatch: the file was created with another salt, or none",
+                ));
+            }
+            if config
+                .node_byte_budget
+                .is_some_and(|budget| Some(budget) != header.node_byte_budget)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "node byte budget mismatch: the file was created with another budget, \
+                     or none",
+                ));
+            }
+            config.page_size = u64::from(header.page_size);
+            config.fanout = Some(header.fanout);
+            config.domain_salt = header.domain_salt;
+            config.node_byte_budget = header.node_byte_budget;
+            version = header.version;
+        }
+
+        let mut store = Self {
+            #[cfg(feature = "mmap")]
+            map: match file.as_file() {
+                Some(file) if config.mmap => Some(MappedFile::new(file)?),
+                _ => None,
+            },
+            reader: file.try_clone()?,
+            writer: Mutex::new(BufWriter::with_capacity(config.write_buffer_capacity, file)),
+            cache: Mutex::new(LruCache::new(config.cache_capacity)),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            cache_evictions: AtomicU64::new(0),
+            config,
+            version,
+            generation: AtomicU64::new(0),
+            unsynced_commits: AtomicU64::new(0),
+            measured_len: AtomicU64::new(0),
+            free_list: Mutex::new(FreeList::default()),
+            append_only: AtomicBool::new(false),
+            // Older readers would ignore the set of deduplicated nodes and free them.
+            node_index: (config.dedup_nodes && version >= 6 && !config.read_only)
+                .then(Default::default),
+            blobs: (config.blob_threshold.is_some() && version >= 7 && !config.read_only)
+                .then(Default::default),
+            snapshots: Mutex::new(BTreeMap::new()),
+            node_hash: Node::hash_with::<H>,
+            value_hash: |bytes| Hash::from_bytes(H::hash(bytes)),
+            path,
+            #[cfg(test)]
+            unsynced_nodes: Default::default(),
+            #[cfg(test)]
+            syncs: Default::default(),
+            #[cfg(test)]
+            reads: Default::default(),
+            #[cfg(test)]
+            drop_metadata_writes: Default::default(),
+            #[cfg(test)]
+            node_writes_until_failure: Default::default(),
+        };
+        if let Some(slot) = store.read_latest_slot()? {
+            store.generation.store(slot.generation, Ordering::Relaxed);
+            // Read-only stores never allocate, so they skip loading the free list.
+            if slot.free_list != 0 && !store.config.read_only {
+                store.free_list = Mutex::new(store.read_free_list(slot.free_list)?);
+            }
+            if let Some(index) = &store.node_index {
+                store.index_live_nodes(
+                    &mut index.lock().unwrap(),
+                    slot.root_offset,
+                    slot.root_hash,
+                )?;
+            }
         }
+        Ok(Arc::new(store))
+    }
 
-        Ok(Self::new(file))
+    pub(crate) fn open<H: TreeHasher, P: AsRef<Path>>(
+        path: P,
+        config: StoreConfig,
+    ) -> io::Result<Arc<Self>> {
+        let file = if config.read_only {
+            OpenOptions::new().read(true).write(false).open(&path)?
+        } else {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?
+        };
+
+        Self::from_file::<H>(file, config, Some(path.as_ref().to_path_buf()))
     }
 
-    pub(crate) fn write_metadata(&self, root_offset: u64, root_hash: Hash) -> io::Result<()> {
-        let mut writer = self.file.write().unwrap();
-        writer.seek(SeekFrom::Start(0))?;
+    /// The path the file was opened from, unless it is a temporary file.
+    pub(crate) fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Whether the store keeps its bytes in memory rather than in a file.
+    pub(crate) fn is_in_memory(&self) -> bool {
+        matches!(self.reader, Backing::Memory(_))
+    }
+
+    /// The effective configuration, with the page size and fan-out as recorded in the
+    /// file.
+    pub(crate) fn config(&self) -> StoreConfig {
+        self.config
+    }
+
+    /// Salt mixed into the hash of every node in this file, if it has one.
+    pub(crate) fn domain_salt(&self) -> Option<&[u8; 32]> {
+        self.config.domain_salt.as_ref()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn cached_nodes(&self) -> usize {
+        self.cache.lock().unwrap().len()
+    }
 
-        writer.write_all(&root_offset.to_le_bytes())?;
-        writer.write_all(root_hash.as_bytes())?;
+    #[cfg(test)]
+    pub(crate) fn deduplicated_nodes(&self) -> usize {
+        self.free_list.lock().unwrap().deduplicated.len()
+    }
+
+    /// Writes the root pointer into the older of the two metadata slots, along with the
+    /// offset of the saved free list, if any.
+    ///
+    /// The nodes the root points at must already be synced: this write is the commit
+    /// point, after which the new root may be what a reopened file sees.
+    pub(crate) fn write_metadata(
+        &self,
+        root_offset: u64,
+        root_hash: Hash,
+        free_list: Option<NodeId>,
+    ) -> io::Result<()> {
+        #[cfg(test)]
+        {
+            assert!(
+                !self.unsynced_nodes.load(Ordering::Relaxed),
+                "metadata written before the nodes it refers to were synced"
+            );
+            if self.drop_metadata_writes.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+        }
+        self.check_outside_header(root_offset)?;
+        let mut writer = self.writer.lock().unwrap();
+        let generation = self.generation.load(Ordering::Relaxed) + 1;
+
+        let mut slot = [0u8; SLOT_LEN];
+        slot[0..8].copy_from_slice(&generation.to_le_bytes());
+        slot[8..16].copy_from_slice(&root_offset.to_le_bytes());
+        slot[16..48].copy_from_slice(root_hash.as_bytes());
+        let checksum_at = self.slot_len() - 8;
+        if self.version >= 4 {
+            slot[48..56].copy_from_slice(&free_list.unwrap_or(0).to_le_bytes());
+        }
+        let checksum = slot_checksum(&slot[..checksum_at]);
+        slot[checksum_at..checksum_at + 8].copy_from_slice(&checksum);
+
+        let slot_offset = SLOT_OFFSETS[(generation % 2) as usize];
+        if self.config.direct_metadata_writes {
+            // Every other write seeks first, so writing at an offset cannot misplace
+            // them; nothing is buffered here, as the commit flushed before this.
+            writer
+                .get_ref()
+                .write_all_at(&slot[..self.slot_len()], slot_offset)?;
+        } else {
+            writer.seek(SeekFrom::Start(slot_offset))?;
+            writer.write_all(&slot[..self.slot_len()])?;
+        }
+        self.generation.store(generation, Ordering::Relaxed);
         Ok(())
     }
 
+    /// Returns the root pointer from the newest metadata slot with a valid checksum.
     pub(crate) fn read_metadata(&self) -> io::Result<Option<(u64, Hash)>> {
-        let mut writer_guard = self.file.write().unwrap();
-        let file = writer_guard.get_mut();
-        file.seek(SeekFrom::Start(0))?;
-
-        let mut offset_buf = [0u8; 8];
-        file.read_exact(&mut offset_buf)?;
-        let offset = u64::from_le_bytes(offset_buf);
+        Ok(self
+            .read_latest_slot()?
+            .map(|slot| (slot.root_offset, slot.root_hash)))
+    }
 
-        if offset == 0 {
+    /// Reads the root another handle committed last, for a read-only store following
+    /// it. If the generation moved on, the cache is emptied: the other handle may have
+    /// reused the space of cached nodes for new ones.
+    pub(crate) fn reload_metadata(&self) -> io::Result<Option<(u64, Hash)>> {
+        debug_assert!(self.config.read_only);
+        let Some(slot) = self.read_latest_slot()? else {
             return Ok(None);
+        };
+        if self.generation.swap(slot.generation, Ordering::Relaxed) != slot.generation {
+            self.cache.lock().unwrap().clear();
+        }
+        Ok(Some((slot.root_offset, slot.root_hash)))
+    }
+
+    /// Starts a commit that may run alongside other processes committing to the file:
+    /// takes an exclusive advisory lock on it, so the root read by
+    /// [`follow_latest_commit`](Self::follow_latest_commit) stays the newest until the
+    /// metadata is written, and appends every record at the end of the file. Other
+    /// handles may have allocated the regions this store's free list holds, and ones
+    /// that commit without the lock may still be writing into them.
+    pub(crate) fn begin_guarded_commit(&self) -> io::Result<GuardedCommit<'_>> {
+        let lock = self.reader.lock()?;
+        self.append_only.store(true, Ordering::Relaxed);
+        Ok(GuardedCommit {
+            append_only: &self.append_only,
+            _lock: lock,
+        })
+    }
+
+    /// Returns the root pointer of the newest slot. If another handle wrote it, adopts
+    /// its generation, so the next metadata write goes to the other slot, and forgets
+    /// the regions of the free list, cached nodes and the offsets kept for reuse: the
+    /// other handle may be using that space, and its own free list may list nodes the
+    /// uncommitted changes of this one still point at.
+    pub(crate) fn follow_latest_commit(&self) -> io::Result<Option<(u64, Hash)>> {
+        let slot = self.read_latest_slot()?;
+        let root = slot.as_ref().map(|slot| (slot.root_offset, slot.root_hash));
+        let generation = slot.as_ref().map_or(0, |slot| slot.generation);
+        if generation == self.generation.load(Ordering::Relaxed) {
+            return Ok(root);
+        }
+        self.free_list.lock().unwrap().forget_regions();
+        self.generation.store(generation, Ordering::Relaxed);
+        self.cache.lock().unwrap().clear();
+        if let Some(index) = &self.node_index {
+            *index.lock().unwrap() = NodeIndex::default();
+        }
+        if let Some(blobs) = &self.blobs {
+            blobs.lock().unwrap().clear();
         }
+        Ok(root)
+    }
+
+    fn slot_len(&self) -> usize {
+        if self.version >= 4 {
+            SLOT_LEN
+        } else {
+            LEGACY_SLOT_LEN
+        }
+    }
+
+    /// Reads both metadata slots and picks the newest intact one.
+    ///
+    /// Returns `None` for a database that was never committed, and an error if slots
+    /// were written but none of them survived intact.
+    fn read_latest_slot(&self) -> io::Result<Option<Slot>> {
+        // Make sure our own buffered metadata writes are visible to the reader.
+        self.writer.lock().unwrap().flush()?;
+
+        let mut latest: Option<Slot> = None;
+        let mut corrupt = false;
+        let checksum_at = self.slot_len() - 8;
 
-        let mut hash = [0u8; OUT_LEN];
-        file.read_exact(&mut hash)?;
+        for slot_offset in SLOT_OFFSETS {
+            let mut slot = vec![0u8; self.slot_len()];
+            self.reader.read_exact_at(&mut slot, slot_offset)?;
+
+            if slot.iter().all(|b| *b == 0) {
+                continue;
+            }
+            if slot[checksum_at..] != slot_checksum(&slot[..checksum_at]) {
+                corrupt = true;
+                continue;
+            }
+
+            let generation = u64::from_le_bytes(slot[0..8].try_into().unwrap());
+            let root_offset = u64::from_le_bytes(slot[8..16].try_into().unwrap());
+            let mut hash = [0u8; HASH_LEN];
+            hash.copy_from_slice(&slot[16..48]);
+            let free_list = if self.version >= 4 {
+                u64::from_le_bytes(slot[48..56].try_into().unwrap())
+            } else {
+                0
+            };
+
+            // Nodes start after the header page, so a root inside it cannot be real.
+            // Emptiness is an all-zero slot, never a root offset of 0.
+            if root_offset < self.config.page_size {
+                corrupt = true;
+                continue;
+            }
+            if latest.as_ref().is_none_or(|l| generation > l.generation) {
+                latest = Some(Slot {
+                    generation,
+                    root_offset,
+                    root_hash: Hash::from_bytes(hash),
+                    free_list,
+                });
+            }
+        }
 
-        Ok(Some((offset, Hash::from_bytes(hash))))
+        if latest.is_none() && corrupt {
+            return Err(Error::Corrupt(
+                "metadata checksum mismatch: no intact root pointer found".to_string(),
+            )
+            .into());
+        }
+        Ok(latest)
     }
 
     pub(crate) fn flush(&self) -> io::Result<()> {
-        let mut writer = self.file.write().unwrap();
+        self.flush_with(true)
+    }
+
+    /// Flushes buffered writes to the OS, and syncs them to disk if `sync` is set.
+    pub(crate) fn flush_with(&self, sync: bool) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
         writer.flush()?; // Flushes Rust buffer to OS
-        writer.get_ref().sync_all() // Flushes OS buffer to Disk
+        if sync {
+            writer.get_ref().sync_all()?; // Flushes OS buffer to Disk
+            #[cfg(test)]
+            self.syncs.fetch_add(1, Ordering::Relaxed);
+        }
+        #[cfg(test)]
+        self.unsynced_nodes.store(false, Ordering::Relaxed);
+        Ok(())
     }
 
-    pub(crate) fn load_node(&self, offset: NodeId) -> io::Result<Arc<Node<K, V>>> {
-        {
-            let cache = self.cache.read().unwrap();
-            if let Some(node) = cache.get(&offset) {
-                return Ok(node.clone());
+    /// Decides, per the sync policy, whether the commit being written is synced.
+    pub(crate) fn sync_next_commit(&self) -> bool {
+        match self.config.sync_policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::Never => false,
+            SyncPolicy::EveryN { n } => {
+                let commits = self.unsynced_commits.fetch_add(1, Ordering::Relaxed) + 1;
+                if commits >= n {
+                    self.unsynced_commits.store(0, Ordering::Relaxed);
+                    true
+                } else {
+                    false
+                }
             }
         }
+    }
+
+    /// Loads the node at `offset`. `expected` is the hash its parent (or the metadata,
+    /// for the root) recorded for it, checked when `verify_on_read` is set.
+    pub(crate) fn load_node(&self, offset: NodeId, expected: Hash) -> io::Result<Arc<Node<K, V>>> {
+        if let Some(node) = self.cache.lock().unwrap().get(offset) {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(node);
+        }
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
 
-        let mut writer_guard = self.file.write().unwrap();
-        writer_guard.seek(SeekFrom::Start(offset))?;
-        let file = writer_guard.get_mut();
+        let node = self.read_node(offset, expected)?;
+        let evicted = self.cache.lock().unwrap().insert(offset, node.clone());
+        if evicted > 0 {
+            self.cache_evictions.fetch_add(evicted, Ordering::Relaxed);
+        }
+        Ok(node)
+    }
 
-        let mut len_buf = [0u8; 4];
-        file.read_exact(&mut len_buf)?;
-        let len = u32::from_le_bytes(len_buf) as usize;
+    pub(crate) fn cache_metrics(&self) -> CacheMetrics {
+        CacheMetrics {
+            hits: self.cache_hits.load(Ordering::Relaxed),
+            misses: self.cache_misses.load(Ordering::Relaxed),
+            evictions: self.cache_evictions.load(Ordering::Relaxed),
+        }
+    }
 
-        let mut buf = vec![0u8; len];
-        file.read_exact(&mut buf)?;
+    /// Like `load_node`, but a node that is not cached yet is not added to the cache,
+    /// so whole-tree walks do not evict the working set.
+    pub(crate) fn load_node_uncached(
+        &self,
+        offset: NodeId,
+        expected: Hash,
+    ) -> io::Result<Arc<Node<K, V>>> {
+        if let Some(node) = self.cache.lock().unwrap().get(offset) {
+            return Ok(node);
+        }
+        self.read_node(offset, expected)
+    }
 
-        let disk_node: DiskNode<K, V> = postcard::from_bytes(&buf)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    /// Loads the keys and children of the node at `offset`. Unless the node is cached,
+    /// its values are not decoded, and the partial node is not added to the cache.
+    ///
+    /// Files before version 5, and stores that verify reads, load the whole node.
+    pub(crate) fn load_keys(&self, offset: NodeId, expected: Hash) -> io::Result<NodeKeys<K, V>> {
+        if self.version < 5 || self.config.verify_on_read {
+            return Ok(NodeKeys::from(&*self.load_node(offset, expected)?));
+        }
+        if let Some(node) = self.cache.lock().unwrap().get(offset) {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(NodeKeys::from(&*node));
+        }
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
 
-        let node = Arc::new(Node::from_disk(disk_node));
-        self.cache.write().unwrap().insert(offset, node.clone());
+        let (keys, children): (Vec<K>, Vec<DiskChild>) = self.with_payload(offset, |buf| {
+            Ok(if self.version >= 8 {
+                let disk: DiskNodeKeys<FrontCodedKeys<K>> =
+                    postcard::from_bytes(buf).map_err(Error::from)?;
+                (disk.keys.into(), disk.children)
+            } else {
+                let disk: DiskNodeKeys<Vec<K>> = postcard::from_bytes(buf).map_err(Error::from)?;
+                (disk.keys, disk.children)
+            })
+        })?;
+        Ok(NodeKeys {
+            keys: keys.into_iter().map(Arc::new).collect(),
+            children: children
+                .into_iter()
+                .map(|(offset, hash, count)| Link::Disk {
+                    offset,
+                    hash,
+                    count: Some(count),
+                })
+                .collect(),
+        })
+    }
+
+    /// Calls `f` with the record at `offset`, its codec undone.
+    fn with_payload<R>(
+        &self,
+        offset: NodeId,
+        f: impl FnOnce(&[u8]) -> io::Result<R>,
+    ) -> io::Result<R> {
+        #[cfg(feature = "mmap")]
+        if let (Some(map), Some(file)) = (&self.map, self.reader.as_file()) {
+            return map.with_record(file, offset, |framed| {
+                if self.version >= 3 {
+                    f(&Compression::decode_borrowed(framed)?)
+                } else {
+                    f(framed)
+                }
+            });
+        }
+        // Disk links only ever point at flushed data, so reading through the separate
+        // handle never observes a half-buffered node.
+        //
+        // A record that fits a page is padded so it never crosses into the next one,
+        // so reading up to the end of the page gets it whole in one call. Only longer
+        // records, and records reusing freed space, need a second read for the rest.
+        let page_size = self.config.page_size;
+        let mut buf = vec![0u8; (page_size - offset % page_size).max(4) as usize];
+        let read = self.reader.read_up_to_at(&mut buf, offset)?;
+        #[cfg(test)]
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        if read < 4 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let end = 4 + u32::from_le_bytes(buf[..4].try_into().unwrap()) as usize;
+        if end > read {
+            buf.resize(end, 0);
+            self.reader
+                .read_exact_at(&mut buf[read..], offset + read as u64)?;
+            #[cfg(test)]
+            self.reads.fetch_add(1, Ordering::Relaxed);
+        }
+        let framed = &buf[4..end];
+        if self.version >= 3 {
+            f(&Compression::decode_borrowed(framed)?)
+        } else {
+            f(framed)
+        }
+    }
+
+    fn read_node(&self, offset: NodeId, expected: Hash) -> io::Result<Arc<Node<K, V>>> {
+        let node = Arc::new(self.decode_node(offset)?);
+        if self.config.verify_on_read && (self.node_hash)(&node, self.domain_salt()) != expected {
+            return Err(Error::Corrupt(format!(
+                "node at offset {offset} does not match its recorded hash"
+            ))
+            .into());
+        }
         Ok(node)
     }
 
+    /// Reads and decodes the node at `offset`, bypassing the cache and any hash check.
+    pub(crate) fn decode_node(&self, offset: NodeId) -> io::Result<Node<K, V>> {
+        let node = self.with_payload(offset, |buf| self.decode_payload(buf))?;
+        if node.level > MAX_LEVEL {
+            return Err(Error::Corrupt(format!(
+                "node at offset {offset} has level {}, above the highest possible",
+                node.level
+            ))
+            .into());
+        }
+        Ok(node)
+    }
+
+    fn decode_payload(&self, buf: &[u8]) -> io::Result<Node<K, V>> {
+        let node = if self.version == 1 {
+            let disk_node: DiskNode<K, V, LegacyDiskChild> =
+                postcard::from_bytes(buf).map_err(Error::from)?;
+            Node::from_disk(disk_node, |(offset, hash)| Link::Disk {
+                offset,
+                hash,
+                count: None,
+            })
+        } else {
+            let disk_node: DiskNode<K, V> = if self.version >= 7 {
+                let disk_node: DiskNode<K, StoredValue<V>> = if self.version >= 8 {
+                    postcard::from_bytes::<ValuesLast<FrontCodedKeys<K>, StoredValue<V>>>(buf)
+                        .map_err(Error::from)?
+                        .into()
+                } else {
+                    postcard::from_bytes::<ValuesLast<Vec<K>, StoredValue<V>>>(buf)
+                        .map_err(Error::from)?
+                        .into()
+                };
+                disk_node.try_map_values(|value| self.load_value(value))?
+            } else if self.version >= 5 {
+                postcard::from_bytes::<ValuesLast<Vec<K>, V>>(buf)
+                    .map_err(Error::from)?
+                    .into()
+            } else {
+                postcard::from_bytes(buf).map_err(Error::from)?
+            };
+            Node::from_disk(disk_node, |(offset, hash, count)| Link::Disk {
+                offset,
+                hash,
+                count: Some(count),
+            })
+        };
+        Ok(node)
+    }
+
+    /// Offsets of the blobs holding values of the node at `offset`. Only files of
+    /// format version 7 or later have any.
+    pub(crate) fn blob_offsets(&self, offset: NodeId) -> io::Result<Vec<NodeId>> {
+        if self.version < 7 {
+            return Ok(Vec::new());
+        }
+        let values = self.with_payload(offset, |buf| {
+            Ok(if self.version >= 8 {
+                postcard::from_bytes::<ValuesLast<FrontCodedKeys<K>, StoredValue<V>>>(buf)
+                    .map_err(Error::from)?
+                    .values
+            } else {
+                postcard::from_bytes::<ValuesLast<Vec<K>, StoredValue<V>>>(buf)
+                    .map_err(Error::from)?
+                    .values
+            })
+        })?;
+        Ok(values
+            .into_iter()
+            .filter_map(|value| match value {
+                StoredValue::Blob(blob) => Some(blob.offset),
+                StoredValue::Inline(_) => None,
+            })
+            .collect())
+    }
+
+    /// Reads a value that format version 7 stored out of line from its blob.
+    fn load_value(&self, value: StoredValue<V>) -> io::Result<V> {
+        let blob = match value {
+            StoredValue::Inline(value) => return Ok(value),
+            StoredValue::Blob(blob) => blob,
+        };
+        let value = self.with_payload(blob.offset, |bytes| {
+            if bytes.len() as u64 != blob.len || (self.value_hash)(bytes) != blob.hash {
+                return Err(Error::Corrupt(format!(
+                    "blob at offset {} does not match its reference",
+                    blob.offset
+                ))
+                .into());
+            }
+            Ok(postcard::from_bytes(bytes).map_err(Error::from)?)
+        })?;
+        if let Some(blobs) = &self.blobs {
+            blobs.lock().unwrap().insert(blob.hash, blob);
+        }
+        Ok(value)
+    }
+
+    /// The values of a node in their encoding for the values-last layout of format
+    /// version 5, or that of version 7, which may store them out of line. Values whose
+    /// encoding the node kept from hashing are not encoded again; see
+    /// [`encoding_of`].
+    fn encode_values(&self, values: &[Arc<V>], encoded: &[EncodedValue<V>]) -> io::Result<Vec<u8>> {
+        debug_assert!(self.version >= 5);
+        let mut data = postcard::to_extend(&values.len(), Vec::new()).map_err(Error::from)?;
+        let mut buf = Vec::new();
+        for (idx, value) in values.iter().enumerate() {
+            let bytes = match encoding_of(encoded, value, idx) {
+                Some(bytes) => bytes,
+                None => {
+                    buf.clear();
+                    buf = postcard::to_extend(&**value, buf).map_err(Error::from)?;
+                    &buf
+                }
+            };
+            if self.version < 7 {
+                data.extend_from_slice(bytes);
+                continue;
+            }
+            match self.store_blob(bytes)? {
+                Some(blob) => {
+                    data = postcard::to_extend(&StoredValue::<()>::Blob(blob), data)
+                        .map_err(Error::from)?;
+                }
+                None => {
+                    data =
+                        postcard::to_extend(&StoredValue::Inline(()), data).map_err(Error::from)?;
+                    data.extend_from_slice(bytes);
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    /// Writes `bytes`, the encoding of a value, to a blob if it is longer than the blob
+    /// threshold, unless an identical one was already written or read. Returns where
+    /// the blob is, or `None` if the value stays inline.
+    fn store_blob(&self, bytes: &[u8]) -> io::Result<Option<BlobRef>> {
+        let (Some(threshold), Some(blobs)) = (self.config.blob_threshold, &self.blobs) else {
+            return Ok(None);
+        };
+        if bytes.len() <= threshold {
+            return Ok(None);
+        }
+        let hash = (self.value_hash)(bytes);
+        if let Some(&blob) = blobs.lock().unwrap().get(&hash) {
+            return Ok(Some(blob));
+        }
+        let len = bytes.len() as u64;
+        let offset = self.append_record(bytes.to_vec())?;
+        let blob = BlobRef { offset, len, hash };
+        blobs.lock().unwrap().insert(hash, blob);
+        Ok(Some(blob))
+    }
+
+    /// Length of the record at `offset`, including its 4-byte length prefix.
+    pub(crate) fn record_len(&self, offset: NodeId) -> io::Result<u64> {
+        let mut len_buf = [0u8; 4];
+        self.reader.read_exact_at(&mut len_buf, offset)?;
+        Ok(u64::from(u32::from_le_bytes(len_buf)) + 4)
+    }
+
+    /// Current length of the file, including writes not yet flushed.
+    pub(crate) fn file_len(&self) -> io::Result<u64> {
+        self.writer.lock().unwrap().flush()?;
+        self.reader.len()
+    }
+
+    /// Whether the live size is worth measuring again for the compaction policy: only
+    /// once the file has grown by a tenth since it was last measured, as measuring
+    /// reads every reachable node. Records `file_len` as measured if so.
+    pub(crate) fn take_measurement(&self, file_len: u64) -> bool {
+        let measured = self.measured_len.load(Ordering::Relaxed);
+        if measured != 0 && file_len < measured + measured / 10 {
+            return false;
+        }
+        self.measured_len.store(file_len, Ordering::Relaxed);
+        true
+    }
+
+    /// Generation of the last commit, which no commit since opening the store may have
+    /// made yet.
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    /// Registers a snapshot of the current generation and returns that generation.
+    pub(crate) fn pin_snapshot(&self) -> u64 {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        let generation = self.generation.load(Ordering::Relaxed);
+        *snapshots.entry(generation).or_default() += 1;
+        generation
+    }
+
+    /// Registers another snapshot of an already pinned generation.
+    pub(crate) fn repin_snapshot(&self, generation: u64) {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        *snapshots.entry(generation).or_default() += 1;
+    }
+
+    pub(crate) fn unpin_snapshot(&self, generation: u64) {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        if let Some(count) = snapshots.get_mut(&generation) {
+            *count -= 1;
+            if *count == 0 {
+                snapshots.remove(&generation);
+            }
+        }
+    }
+
+    /// Computes the free list as of the next commit, given the nodes that commit
+    /// orphans, and saves it to the file. Nothing becomes reusable until the result is
+    /// passed to `retire_nodes` once the commit is durable.
+    ///
+    /// The saved list must be synced before the metadata slot pointing at it is
+    /// written. If the commit never lands, a reopened file falls back to the previous
+    /// root and the list saved with it, which never marked the nodes this commit
+    /// orphans as free, so reclaimed space cannot be handed out early. The list saved
+    /// by the previous commit is kept until that fallback slot is overwritten, like
+    /// any orphaned node.
+    ///
+    /// `parents` counts, for nodes the commit keeps, the parents it gives them less the
+    /// ones it orphans, beyond the one each node has.
+    pub(crate) fn prepare_retire(
+        &self,
+        orphaned: &[NodeId],
+        parents: &HashMap<NodeId, i64>,
+    ) -> io::Result<Retirement> {
+        let mut regions = Vec::with_capacity(orphaned.len() + 1);
+        for &offset in orphaned {
+            regions.push((offset, self.record_len(offset)?));
+        }
+
+        // Orphaned nodes may still be reclaimed or fallen back to, so they must not be
+        // reused from now on, whether or not this commit completes.
+        if let Some(index) = &self.node_index {
+            let mut index = index.lock().unwrap();
+            for offset in orphaned {
+                index.remove(*offset);
+            }
+        }
+
+        let mut free_list = self.free_list.lock().unwrap().clone();
+        for (&offset, &parents) in parents {
+            free_list.add_parents(offset, parents);
+        }
+        for offset in orphaned {
+            free_list.deduplicated.remove(offset);
+        }
+        regions.extend(free_list.record.take());
+        let snapshots = self.snapshots.lock().unwrap();
+        let oldest_pin = snapshots.keys().next().copied();
+        let generation = self.generation.load(Ordering::Relaxed) + 1;
+        let released = free_list.retire(generation, regions, oldest_pin);
+        drop(snapshots);
+
+        if self.version >= 4 && !free_list.is_empty() {
+            free_list.record = Some(self.write_free_list(&mut free_list)?);
+        }
+        Ok(Retirement {
+            free_list,
+            released,
+        })
+    }
+
+    /// Adopts the free list prepared for a commit that is now durable.
+    pub(crate) fn retire_nodes(&self, retirement: Retirement) {
+        *self.free_list.lock().unwrap() = retirement.free_list;
+        let mut cache = self.cache.lock().unwrap();
+        for offset in retirement.released {
+            cache.remove(offset);
+        }
+    }
+
+    /// Writes `free_list` as `len (4) | payload | checksum (8)` into space taken from
+    /// the list itself, or at the end of the file, and returns the region it occupies.
+    fn write_free_list(&self, free_list: &mut FreeList) -> io::Result<(NodeId, u64)> {
+        let encode =
+            |free_list: &FreeList| postcard::to_extend(free_list, Vec::new()).map_err(Error::from);
+        // Taking the record's region out of the list replaces at most one free region
+        // with its leftover, which can lengthen the encoding by one entry. Regions are
+        // rounded up to a power of two so that the one freed by an earlier commit fits
+        // the next record even as the list grows a little. The payload is padded up to
+        // the reserved length.
+        let region_len = (encode(free_list)?.len() + FREE_LIST_SLACK + 12).next_power_of_two();
+        let payload_len = region_len - 12;
+        let region_len = region_len as u64;
+        let allocated = if self.append_only.load(Ordering::Relaxed) {
+            None
+        } else {
+            free_list.allocate(region_len)
+        };
+        let mut data = encode(free_list)?;
+        data.resize(payload_len, 0);
+        let checksum = slot_checksum(&data);
+        data.extend_from_slice(&checksum);
+
+        let mut writer = self.writer.lock().unwrap();
+        #[cfg(test)]
+        self.unsynced_nodes.store(true, Ordering::Relaxed);
+        let offset = match allocated {
+            Some(offset) => writer.seek(SeekFrom::Start(offset))?,
+            None => writer.seek(SeekFrom::End(0))?,
+        };
+        writer.write_all(&(data.len() as u32).to_le_bytes())?;
+        writer.write_all(&data)?;
+        Ok((offset, region_len))
+    }
+
+    fn read_free_list(&self, offset: NodeId) -> io::Result<FreeList> {
+        let corrupt = || io::Error::from(Error::Corrupt("free list is corrupt".to_string()));
+        let mut len_buf = [0u8; 4];
+        self.reader.read_exact_at(&mut len_buf, offset)?;
+        let len = u32::from_le_bytes(len_buf) as usize;
+        if len < 8 {
+            return Err(corrupt());
+        }
+        let mut data = vec![0u8; len];
+        self.reader.read_exact_at(&mut data, offset + 4)?;
+        let (payload, checksum) = data.split_at(len - 8);
+        if checksum != slot_checksum(payload) {
+            return Err(corrupt());
+        }
+        let mut free_list: FreeList = postcard::from_bytes(payload).map_err(|_| corrupt())?;
+        free_list.record = Some((offset, len as u64 + 4));
+        Ok(free_list)
+    }
+
+    pub(crate) fn reclaimable_bytes(&self) -> u64 {
+        self.free_list.lock().unwrap().reclaimable_bytes()
+    }
+
     pub(crate) fn write_node(&self, node: &Node<K, V>) -> io::Result<NodeId> {
-        let disk_node = node.as_disk_ref();
+        Ok(self.write_disk_node(&node.as_disk_ref(), None)?.0)
+    }
+
+    /// Whether `encode_entries` applies to the nodes this store writes: those of
+    /// format version 5 or later, with every value inline.
+    #[cfg(feature = "parallel")]
+    pub(crate) fn encodes_entries(&self) -> bool {
+        self.version >= 5 && self.blobs.is_none()
+    }
+
+    /// Encodes the keys and values of `node` ahead of writing it, which only depends
+    /// on the node itself, unlike its child entries. See `encodes_entries`.
+    #[cfg(feature = "parallel")]
+    pub(crate) fn encode_entries(&self, node: &Node<K, V>) -> io::Result<EncodedEntries> {
+        debug_assert!(self.encodes_entries());
+        self.encode_entries_of(&node.keys, &node.values, &node.encoded_values)
+    }
+
+    /// The keys and values of a node in the values-last layout, for files of format
+    /// version 5 or later. Values larger than the blob threshold are written to blobs.
+    fn encode_entries_of(
+        &self,
+        keys: &[Arc<K>],
+        values: &[Arc<V>],
+        encoded: &[EncodedValue<V>],
+    ) -> io::Result<EncodedEntries> {
+        let keys = if self.version >= 8 {
+            postcard::to_extend(&FrontCoded(keys), Vec::new())
+        } else {
+            postcard::to_extend(keys, Vec::new())
+        };
+        Ok(EncodedEntries {
+            keys: keys.map_err(Error::from)?,
+            values: self.encode_values(values, encoded)?,
+        })
+    }
+
+    /// Writes a node and returns its offset, and whether it was deduplicated: with
+    /// deduplication enabled, a live node with the same hash is pointed at instead, and
+    /// counted as having one more parent. See `remove_parent` for when it has none.
+    ///
+    /// `entries` are the keys and values of the node if already encoded.
+    pub(crate) fn write_disk_node(
+        &self,
+        disk_node: &DiskNodeRef<'_, K, V, ChildMeta>,
+        entries: Option<EncodedEntries>,
+    ) -> io::Result<(NodeId, bool)> {
+        #[cfg(test)]
+        if self.node_writes_until_failure.fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |left| left.checked_sub(1),
+        ) == Ok(1)
+        {
+            return Err(io::Error::other("simulated write failure"));
+        }
+        let Some(index) = &self.node_index else {
+            return Ok((self.append_node(disk_node, entries)?, false));
+        };
+        let existing = index.lock().unwrap().get(disk_node.hash);
+        if let Some(offset) = existing {
+            let mut free_list = self.free_list.lock().unwrap();
+            *free_list.deduplicated.entry(offset).or_default() += 1;
+            return Ok((offset, true));
+        }
+        let offset = self.append_node(disk_node, entries)?;
+        index.lock().unwrap().insert(disk_node.hash, offset);
+        Ok((offset, false))
+    }
 
-        let data = postcard::to_extend(&disk_node, Vec::with_capacity(4096))
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    /// Takes back the parent a deduplicated write of the node at `offset` counted, when
+    /// that write gave it none: its own parent was deduplicated too, and so points at
+    /// the children already stored for it, or it is the root that was committed before.
+    pub(crate) fn remove_parent(&self, offset: NodeId) {
+        self.free_list.lock().unwrap().add_parents(offset, -1);
+    }
+
+    /// Adds every node the committed root at `offset` reaches to `index`. The index is
+    /// not saved in the file, so opening one rebuilds it this way; only the keys and
+    /// children of each node are read.
+    fn index_live_nodes(
+        &self,
+        index: &mut NodeIndex,
+        offset: NodeId,
+        hash: Hash,
+    ) -> io::Result<()> {
+        let mut stack = vec![(offset, hash)];
+        while let Some((offset, hash)) = stack.pop() {
+            // Identical subtrees are indexed at one of their offsets.
+            if index.get(hash).is_some() {
+                continue;
+            }
+            index.insert(hash, offset);
+            for child in self.load_keys(offset, hash)?.children {
+                if let Link::Disk { offset, hash, .. } = child {
+                    stack.push((offset, hash));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of parents beyond the first that may point at the node at `offset`,
+    /// because deduplication reused it.
+    pub(crate) fn extra_parents(&self, offset: NodeId) -> u32 {
+        let free_list = self.free_list.lock().unwrap();
+        free_list.deduplicated.get(&offset).copied().unwrap_or(0)
+    }
+
+    fn append_node(
+        &self,
+        disk_node: &DiskNodeRef<'_, K, V, ChildMeta>,
+        entries: Option<EncodedEntries>,
+    ) -> io::Result<NodeId> {
+        let encoded = if self.version == 1 {
+            let children = disk_node
+                .children
+                .iter()
+                .map(|&(offset, hash, _)| (offset, hash))
+                .collect::<Vec<LegacyDiskChild>>();
+            postcard::to_extend(&disk_node.with_children(children), Vec::with_capacity(4096))
+        } else {
+            // Counts are only unknown below nodes read from version 1 files.
+            let children = disk_node
+                .children
+                .iter()
+                .map(|&(offset, hash, count)| {
+                    let count = count.ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "cannot write a node without subtree sizes to a version 2 file",
+                        )
+                    })?;
+                    Ok((offset, hash, count))
+                })
+                .collect::<io::Result<Vec<DiskChild>>>()?;
+            let disk_node = disk_node.with_children(children);
+            if self.version >= 5 {
+                let entries = match entries {
+                    Some(entries) => entries,
+                    None => self.encode_entries_of(
+                        disk_node.keys,
+                        disk_node.values,
+                        disk_node.encoded_values,
+                    )?,
+                };
+                // Postcard encodes the fields of a struct one after the other, so the
+                // keys and values are joined with the rest in field order.
+                postcard::to_extend(&disk_node.level, Vec::with_capacity(4096))
+                    .map(|mut data| {
+                        data.extend_from_slice(&entries.keys);
+                        data
+                    })
+                    .and_then(|data| postcard::to_extend(&disk_node.children, data))
+                    .and_then(|data| postcard::to_extend(&disk_node.hash, data))
+                    .map(|mut data| {
+                        data.extend_from_slice(&entries.values);
+                        data
+                    })
+            } else {
+                postcard::to_extend(&disk_node, Vec::with_capacity(4096))
+            }
+        };
+        self.append_record(encoded.map_err(Error::from)?)
+    }
+
+    /// Writes `data` as a length-prefixed record, in its codec for files of version 3
+    /// or later, and returns its offset.
+    fn append_record(&self, mut data: Vec<u8>) -> io::Result<NodeId> {
+        if self.version >= 3 {
+            data = self.config.compression.encode(data)?;
+        }
 
         let node_total_len = (data.len() + 4) as u64;
-        let mut writer = self.file.write().unwrap();
+        let page_size = self.config.page_size;
+        let mut writer = self.writer.lock().unwrap();
+        #[cfg(test)]
+        self.unsynced_nodes.store(true, Ordering::Relaxed);
+
+        let allocated = if self.append_only.load(Ordering::Relaxed) {
+            None
+        } else {
+            self.free_list.lock().unwrap().allocate(node_total_len)
+        };
+        if let Some(offset) = allocated {
+            self.check_outside_header(offset)?;
+            writer.seek(SeekFrom::Start(offset))?;
+            writer.write_all(&(data.len() as u32).to_le_bytes())?;
+            writer.write_all(&data)?;
+            return Ok(offset);
+        }
+
         let mut current_pos = writer.seek(SeekFrom::End(0))?;
 
-        if node_total_len <= PAGE_SIZE {
-            let offset_in_page = current_pos % PAGE_SIZE;
-            let space_remaining = PAGE_SIZE - offset_in_page;
+        if node_total_len <= page_size {
+            let offset_in_page = current_pos % page_size;
+            let space_remaining = page_size - offset_in_page;
 
             if node_total_len > space_remaining {
                 let padding_len = space_remaining as usize;
@@ -121,9 +1380,198 @@ impl<K: MerkleKey, V: MerkleValue> Store<K, V> {
         }
 
         let start_offset = current_pos;
+        self.check_outside_header(start_offset)?;
         writer.write_all(&(data.len() as u32).to_le_bytes())?;
         writer.write_all(&data)?;
 
         Ok(start_offset)
     }
+
+    /// Fails if `offset` lies in the header page, which holds no records: a record
+    /// placed there would overwrite the header or the metadata slots.
+    fn check_outside_header(&self, offset: NodeId) -> io::Result<()> {
+        if offset < self.config.page_size {
+            return Err(Error::Corrupt(format!(
+                "record offset {offset} lies inside the header page"
+            ))
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// The keys and values of a node in their encoding for the values-last layout, as
+/// returned by [`Store::encode_entries`].
+pub(crate) struct EncodedEntries {
+    keys: Vec<u8>,
+    values: Vec<u8>,
+}
+
+/// Offsets of the live nodes, by hash, for deduplication.
+#[derive(Default)]
+struct NodeIndex {
+    by_hash: HashMap<Hash, NodeId>,
+    by_offset: HashMap<NodeId, Hash>,
+}
+
+impl NodeIndex {
+    fn get(&self, hash: Hash) -> Option<NodeId> {
+        self.by_hash.get(&hash).copied()
+    }
+
+    fn insert(&mut self, hash: Hash, offset: NodeId) {
+        self.remove(offset);
+        self.by_hash.insert(hash, offset);
+        self.by_offset.insert(offset, hash);
+    }
+
+    fn remove(&mut self, offset: NodeId) {
+        if let Some(hash) = self.by_offset.remove(&offset) {
+            self.by_hash.remove(&hash);
+        }
+    }
+}
+
+/// A guarded commit in progress, as started by `Store::begin_guarded_commit`. Ends
+/// when dropped, releasing the file lock.
+pub(crate) struct GuardedCommit<'a> {
+    append_only: &'a AtomicBool,
+    _lock: Option<FileLock>,
+}
+
+impl Drop for GuardedCommit<'_> {
+    fn drop(&mut self) {
+        self.append_only.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Free list prepared for a commit by `Store::prepare_retire`.
+pub(crate) struct Retirement {
+    free_list: FreeList,
+    /// Offsets that become reusable, whose cache entries are stale once it is applied.
+    released: Vec<NodeId>,
+}
+
+impl Retirement {
+    /// Offset of the saved free list, for the metadata slot.
+    pub(crate) fn free_list_offset(&self) -> Option<NodeId> {
+        self.free_list.record.map(|(offset, _)| offset)
+    }
+}
+
+/// A decoded metadata slot.
+struct Slot {
+    generation: u64,
+    root_offset: u64,
+    root_hash: Hash,
+    /// Offset of the saved free list; 0 if there is none.
+    free_list: NodeId,
+}
+
+/// The fixed fields at the start of the header page.
+struct Header {
+    version: u32,
+    page_size: u32,
+    /// Zero in files written before schemas were recorded.
+    schema: u64,
+    /// Always [`DEFAULT_FANOUT`] before version 6.
+    fanout: u32,
+    /// Always `None` before version 9.
+    domain_salt: Option<[u8; 32]>,
+    /// Always `None` before version 10.
+    node_byte_budget: Option<NonZeroU32>,
+}
+
+impl Header {
+    fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[0..8].copy_from_slice(MAGIC);
+        bytes[8..12].copy_from_slice(&self.version.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.page_size.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.schema.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.fanout.to_le_bytes());
+        bytes[28..60].copy_from_slice(&self.domain_salt.unwrap_or_default());
+        bytes[60..64].copy_from_slice(&self.node_byte_budget.map_or(0, u32::from).to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8; HEADER_LEN]) -> io::Result<Self> {
+        if &bytes[0..8] != MAGIC {
+            return Err(
+                Error::Corrupt("not a file-mst database (bad magic bytes)".to_string()).into(),
+            );
+        }
+
+        let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        let header = Self {
+            version,
+            page_size: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+            schema: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+            fanout: if version >= 6 {
+                u32::from_le_bytes(bytes[24..28].try_into().unwrap())
+            } else {
+                DEFAULT_FANOUT
+            },
+            domain_salt: if version >= 9 {
+                Some(bytes[28..60].try_into().unwrap()).filter(|salt| *salt != [0; 32])
+            } else {
+                None
+            },
+            node_byte_budget: if version >= 10 {
+                NonZeroU32::new(u32::from_le_bytes(bytes[60..64].try_into().unwrap()))
+            } else {
+                None
+            },
+        };
+        if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&header.version) {
+            return Err(Error::VersionMismatch {
+                found: header.version,
+                expected: FORMAT_VERSION,
+            }
+            .into());
+        }
+        validate_page_size(u64::from(header.page_size))?;
+        validate_fanout(header.fanout)?;
+        Ok(header)
+    }
+}
+
+/// Default schema id: a hash of the key and value type names. Never zero.
+///
+/// Type names are not guaranteed to be stable across compiler versions or module
+/// moves; an explicit schema id avoids depending on them.
+fn type_fingerprint<K, V>() -> u64 {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(std::any::type_name::<K>().as_bytes());
+    hasher.update(&[0]);
+    hasher.update(std::any::type_name::<V>().as_bytes());
+    let hash = hasher.finalize();
+    u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap()).max(1)
+}
+
+fn validate_fanout(fanout: u32) -> io::Result<()> {
+    if !fanout.is_power_of_two() || !(2..=MAX_FANOUT).contains(&fanout) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid fan-out {fanout}: must be a power of two between 2 and {MAX_FANOUT}"),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_page_size(page_size: u64) -> io::Result<()> {
+    if !page_size.is_power_of_two() || page_size < MIN_PAGE_SIZE || page_size > u32::MAX as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "invalid page size {page_size}: must be a power of two between {MIN_PAGE_SIZE} and 2^31"
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn slot_checksum(bytes: &[u8]) -> [u8; 8] {
+    let hash = blake3::hash(bytes);
+    hash.as_bytes()[..8].try_into().unwrap()
 }
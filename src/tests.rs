@@ -26,7 +26,7 @@ fn new_tree_is_empty() {
 
 #[test]
 fn insert_and_contains_basic() {
-    let mut tree = MerkleSearchTree::new_temporary().unwrap();
+    let tree = MerkleSearchTree::new_temporary().unwrap();
     tree.insert(String::from("A"), "ValA".to_string()).unwrap();
     assert!(tree.contains(&String::from("A")).unwrap());
 
@@ -39,7 +39,7 @@ fn insert_and_contains_basic() {
 
 #[test]
 fn insert_update_value() {
-    let mut tree = MerkleSearchTree::new_temporary().unwrap();
+    let tree = MerkleSearchTree::new_temporary().unwrap();
     tree.insert(String::from("A"), "Val1".to_string()).unwrap();
     assert_eq!(
         tree.get(&String::from("A")).unwrap().as_deref(),
@@ -56,7 +56,7 @@ fn insert_update_value() {
 
 #[test]
 fn insert_duplicate_idempotency() {
-    let mut tree = MerkleSearchTree::new_temporary().unwrap();
+    let tree = MerkleSearchTree::new_temporary().unwrap();
 
     tree.insert(String::from("A"), "ValA".to_string()).unwrap();
     let hash1 = tree.root_hash();
@@ -73,9 +73,30 @@ fn insert_duplicate_idempotency() {
     assert_ne!(hash1, hash3, "Tree hash should change when value changes");
 }
 
+#[test]
+fn identical_writes_append_nothing() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let mut tree = MerkleSearchTree::<String, u64>::open(file.path())?;
+    let keys = generate_keys(2000, 70);
+    tree.insert_many(keys.iter().map(|k| (k.clone(), 1)))?;
+    let committed = tree.commit()?;
+    let file_len = std::fs::metadata(file.path())?.len();
+
+    tree.insert(keys[17].clone(), 1)?;
+    tree.insert_many(keys[100..200].iter().map(|k| (k.clone(), 1)))?;
+    tree.transaction(vec![Op::Insert(keys[5].clone(), 1)])?;
+    if let Entry::Occupied(mut entry) = tree.entry(keys[9].clone())? {
+        entry.insert(1)?;
+    }
+    assert!(matches!(tree.state.read().unwrap().root, node::Link::Disk { .. }));
+    assert_eq!(tree.commit()?, committed);
+    assert_eq!(std::fs::metadata(file.path())?.len(), file_len);
+    Ok(())
+}
+
 #[test]
 fn ordering_and_traversal() {
-    let mut tree = MerkleSearchTree::new_temporary().unwrap();
+    let tree = MerkleSearchTree::new_temporary().unwrap();
     let keys = vec!["B", "A", "C", "E", "D"];
 
     for &k in &keys {
@@ -97,12 +118,12 @@ fn deterministic_hashing_order_independence() {
     let mut rng = StdRng::seed_from_u64(37);
     let mut keys: Vec<String> = (0..100).map(|i| format!("k{}", i)).collect();
 
-    let mut tree1 = MerkleSearchTree::new_temporary().unwrap();
+    let tree1 = MerkleSearchTree::new_temporary().unwrap();
     for k in &keys {
         tree1.insert(k.clone(), k.clone()).unwrap();
     }
 
-    let mut tree2 = MerkleSearchTree::new_temporary().unwrap();
+    let tree2 = MerkleSearchTree::new_temporary().unwrap();
     keys.shuffle(&mut rng);
     for k in &keys {
         tree2.insert(k.clone(), k.clone()).unwrap();
@@ -124,7 +145,7 @@ fn large_scale_persistence() {
     let keys = generate_keys(count, 42);
 
     {
-        let mut tree: MerkleSearchTree<String, i32> = MerkleSearchTree::open(&path).unwrap();
+        let tree: MerkleSearchTree<String, i32> = MerkleSearchTree::open(&path).unwrap();
         for (i, k) in keys.iter().enumerate() {
             tree.insert(k.clone(), i as i32).unwrap();
         }
@@ -147,7 +168,7 @@ fn large_scale_persistence() {
 
 #[test]
 fn exhaustive_deletion() -> io::Result<()> {
-    let mut tree = MerkleSearchTree::new_temporary()?;
+    let tree = MerkleSearchTree::new_temporary()?;
     let count = 1000;
     let keys: Vec<String> = (0..count).map(|i| format!("key-{:04}", i)).collect();
 
@@ -198,7 +219,7 @@ fn exhaustive_deletion() -> io::Result<()> {
 
 #[test]
 fn interleaved_operations() -> io::Result<()> {
-    let mut tree = MerkleSearchTree::new_temporary()?;
+    let tree = MerkleSearchTree::new_temporary()?;
     let mut active_keys = HashMap::new();
     let mut rng = StdRng::seed_from_u64(37);
 
@@ -236,7 +257,7 @@ fn interleaved_operations() -> io::Result<()> {
 
 #[test]
 fn boundary_deletions() -> io::Result<()> {
-    let mut tree = MerkleSearchTree::new_temporary()?;
+    let tree = MerkleSearchTree::new_temporary()?;
     let keys = vec!["A", "M", "Z"];
 
     for &k in &keys {
@@ -267,7 +288,7 @@ fn blobs_and_page_boundaries() {
     let path = file.path().to_owned();
     
     // We use Vec<u8> explicitly to represent "Blobs"
-    let mut tree: MerkleSearchTree<String, Vec<u8>> = MerkleSearchTree::open(&path).unwrap();
+    let tree: MerkleSearchTree<String, Vec<u8>> = MerkleSearchTree::open(&path).unwrap();
     let mut rng = StdRng::seed_from_u64(999);
 
     // 2. Prepare Blob Data
@@ -408,3 +429,3919 @@ fn compaction_reduces_file_size_and_preserves_data() {
         assert_eq!(val.as_deref(), Some(&"original-value".to_string()));
     }
 }
+
+#[test]
+fn inclusion_proofs_verify_against_root() -> io::Result<()> {
+    let tree = MerkleSearchTree::new_temporary()?;
+    let keys = generate_keys(500, 7);
+    for (i, k) in keys.iter().enumerate() {
+        tree.insert(k.clone(), i as u64)?;
+    }
+    tree.commit()?;
+    let root = tree.root_hash();
+
+    for (i, k) in keys.iter().enumerate().step_by(25) {
+        let proof = tree.prove(k.as_str())?.expect("key should be provable");
+        assert!(verify_proof(root, k, &(i as u64), &proof));
+        assert!(!verify_proof(root, k, &(i as u64 + 1), &proof));
+        assert!(!verify_absence(root, k, &proof));
+    }
+
+    assert!(tree.prove("missing")?.is_none());
+    Ok(())
+}
+
+#[test]
+fn get_with_proof_returns_the_value_and_a_verifying_proof() -> io::Result<()> {
+    let tree = MerkleSearchTree::new_temporary()?;
+    let keys = generate_keys(500, 62);
+    for (i, k) in keys.iter().enumerate() {
+        tree.insert(k.clone(), i as u64)?;
+    }
+    tree.commit()?;
+    tree.insert(keys[3].clone(), 1000)?;
+    let root = tree.root_hash();
+
+    for k in keys.iter().step_by(25).chain([&keys[3]]) {
+        let (value, proof) = tree.get_with_proof(k.as_str())?.expect("key is present");
+        assert_eq!(Some(&value), tree.get(k.as_str())?.as_ref());
+        assert!(verify_proof(root, k, &*value, &proof));
+        assert_eq!(proof.path.len(), tree.prove(k.as_str())?.unwrap().path.len());
+    }
+    assert!(tree.get_with_proof("missing")?.is_none());
+    Ok(())
+}
+
+#[test]
+fn absence_proofs_verify_against_root() -> io::Result<()> {
+    let tree: MerkleSearchTree<String, u64> = MerkleSearchTree::new_temporary()?;
+    let empty_proof = tree.prove_absence("anything")?.unwrap();
+    assert!(verify_absence(tree.root_hash(), &"anything".to_string(), &empty_proof));
+
+    for (i, k) in generate_keys(500, 8).into_iter().enumerate() {
+        tree.insert(k, i as u64)?;
+    }
+    let root = tree.root_hash();
+
+    let missing = "key-missing".to_string();
+    let proof = tree.prove_absence(missing.as_str())?.unwrap();
+    assert!(verify_absence(root, &missing, &proof));
+    assert!(!verify_proof(root, &missing, &0, &proof));
+
+    // Tampering with any node on the path breaks the chain up to the root.
+    let mut forged = proof.clone();
+    forged.path[0].level += 1;
+    assert!(!verify_absence(root, &missing, &forged));
+    Ok(())
+}
+
+#[test]
+fn insert_many_matches_insert_loop() -> io::Result<()> {
+    let keys = generate_keys(2000, 11);
+
+    let looped = MerkleSearchTree::new_temporary()?;
+    for (i, k) in keys.iter().enumerate() {
+        looped.insert(k.clone(), i)?;
+    }
+    // A duplicate key later in the batch must win, as it would in a loop.
+    looped.insert(keys[0].clone(), usize::MAX)?;
+
+    let mut batch: Vec<(String, usize)> = keys.iter().cloned().zip(0..).collect();
+    batch.push((keys[0].clone(), usize::MAX));
+    let batched = MerkleSearchTree::new_temporary()?;
+    batched.insert_many(batch)?;
+
+    assert_eq!(looped.root_hash(), batched.root_hash());
+    assert_eq!(batched.get(&keys[0])?.as_deref(), Some(&usize::MAX));
+    Ok(())
+}
+
+#[test]
+fn insert_many_keeps_the_last_of_duplicate_keys() -> io::Result<()> {
+    let batched = MerkleSearchTree::new_temporary()?;
+    batched.insert_many([("a", 1), ("a", 2), ("b", 3)].map(|(k, v)| (k.to_string(), v)))?;
+    let single = MerkleSearchTree::new_temporary()?;
+    single.insert("a".to_string(), 2)?;
+    single.insert("b".to_string(), 3)?;
+
+    assert_eq!(batched.get("a")?.as_deref(), Some(&2));
+    assert_eq!(batched.len()?, 2);
+    assert_eq!(batched.root_hash(), single.root_hash());
+    Ok(())
+}
+
+#[test]
+fn extend_follows_its_conflict_policy() -> io::Result<()> {
+    let keys = generate_keys(300, 71);
+    let tree = MerkleSearchTree::<String, u32>::new_temporary()?;
+    tree.insert_many(keys[..200].iter().map(|k| (k.clone(), 0)))?;
+    let batch = || keys[100..].iter().map(|k| (k.clone(), 1));
+
+    let kept = MerkleSearchTree::<String, u32>::new_temporary()?;
+    kept.insert_many(keys[..200].iter().map(|k| (k.clone(), 0)))?;
+    assert_eq!(kept.extend(batch(), ConflictPolicy::KeepExisting)?, 100);
+    assert_eq!(kept.get(keys[150].as_str())?.as_deref(), Some(&0));
+    assert_eq!(kept.get(keys[250].as_str())?.as_deref(), Some(&1));
+    assert_eq!(kept.len()?, 300);
+
+    // Nothing of a failed batch is applied, not even the keys before the conflict.
+    let before = tree.root_hash();
+    let err = tree.extend(batch(), ConflictPolicy::Error).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(tree.root_hash(), before);
+    let fresh = keys[200..].iter().map(|k| (k.clone(), 1));
+    assert_eq!(tree.extend(fresh.clone(), ConflictPolicy::Error)?, 100);
+    let repeated = fresh.clone().chain(fresh.take(1));
+    let err = MerkleSearchTree::new_temporary()?.extend(repeated, ConflictPolicy::Error);
+    assert_eq!(err.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+    // Overwriting counts only the keys that were new.
+    let overwritten = MerkleSearchTree::<String, u32>::new_temporary()?;
+    overwritten.insert_many(keys[..200].iter().map(|k| (k.clone(), 0)))?;
+    assert_eq!(overwritten.extend(batch(), ConflictPolicy::Overwrite)?, 100);
+    let inserted = MerkleSearchTree::<String, u32>::new_temporary()?;
+    inserted.insert_many(keys[..200].iter().map(|k| (k.clone(), 0)).chain(batch()))?;
+    assert_eq!(overwritten.root_hash(), inserted.root_hash());
+    assert_eq!(kept.len()?, overwritten.len()?);
+    assert_ne!(kept.root_hash(), overwritten.root_hash());
+    Ok(())
+}
+
+#[test]
+fn splitting_around_existing_keys_keeps_every_value_once() -> io::Result<()> {
+    let mut keys = generate_keys(3000, 80);
+    keys.sort();
+    let tree = MerkleSearchTree::<String, u32>::new_temporary()?;
+    // A key two levels up splits a node at each level below it on the way in.
+    let high = keys.iter().position(|k| tree.key_level(k) >= 2).expect("a level-2 key");
+    let (below, above) = (keys[high - 1].clone(), keys[high + 1].clone());
+
+    for (i, key) in keys.iter().enumerate().filter(|(i, _)| *i != high) {
+        tree.insert(key.clone(), i as u32)?;
+    }
+    // Split nodes that are on disk as well as loaded ones.
+    tree.commit()?;
+    tree.insert(keys[high].clone(), high as u32)?;
+    // Its neighbours now sit at the edges of the halves the split produced.
+    tree.insert(below.clone(), 1_000_000)?;
+    tree.insert(keys[high].clone(), 2_000_000)?;
+    tree.insert(above.clone(), 3_000_000)?;
+
+    let expected = |i: usize| match i {
+        _ if i == high => 2_000_000,
+        _ if i == high - 1 => 1_000_000,
+        _ if i == high + 1 => 3_000_000,
+        _ => i as u32,
+    };
+    assert_eq!(tree.len()?, keys.len() as u64);
+    let entries: Vec<(String, u32)> = tree
+        .iter()?
+        .map(|entry| entry.map(|(k, v)| ((*k).clone(), *v)))
+        .collect::<Result<_>>()?;
+    let want: Vec<(String, u32)> =
+        keys.iter().enumerate().map(|(i, k)| (k.clone(), expected(i))).collect();
+    assert_eq!(entries, want);
+
+    let rebuilt = MerkleSearchTree::<String, u32>::new_temporary()?;
+    rebuilt.insert_many(want.into_iter().rev())?;
+    assert_eq!(tree.root_hash(), rebuilt.root_hash());
+    assert!(tree.verify()?.is_ok());
+    Ok(())
+}
+
+#[test]
+fn try_insert_gives_the_value_back_for_present_keys() -> io::Result<()> {
+    let keys = generate_keys(200, 72);
+    let tree = MerkleSearchTree::<String, String>::new_temporary()?;
+    for key in &keys[..100] {
+        assert_eq!(tree.try_insert(key.clone(), "first".to_string())?, Ok(()));
+    }
+    let version = tree.commit()?;
+
+    for key in &keys[..100] {
+        let refused = tree.try_insert(key.clone(), "second".to_string())?;
+        assert_eq!(refused, Err("second".to_string()));
+    }
+    // Refused inserts leave the committed root in place, not a copy of it.
+    assert_eq!(tree.root_hash(), version.hash);
+    assert!(!tree.is_dirty());
+
+    for key in &keys[100..] {
+        assert_eq!(tree.try_insert(key.clone(), "first".to_string())?, Ok(()));
+    }
+    assert_eq!(tree.len()?, 200);
+    let expected = MerkleSearchTree::<String, String>::new_temporary()?;
+    expected.insert_many(keys.iter().map(|k| (k.clone(), "first".to_string())))?;
+    assert_eq!(tree.root_hash(), expected.root_hash());
+    Ok(())
+}
+
+#[test]
+fn bounded_cache_stays_within_capacity() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let path = file.path().to_owned();
+    let keys = generate_keys(5000, 21);
+
+    {
+        let tree = MerkleSearchTree::open(&path)?;
+        for (i, k) in keys.iter().enumerate() {
+            tree.insert(k.clone(), i as u64)?;
+        }
+        tree.commit()?;
+    }
+
+    let tree: MerkleSearchTree<String, u64> = MerkleSearchTree::open_with_cache_capacity(&path, 16)?;
+    for (i, k) in keys.iter().enumerate() {
+        assert_eq!(tree.get(k)?.as_deref(), Some(&(i as u64)));
+        assert!(tree.store.cached_nodes() <= 16);
+    }
+    assert!(!tree.contains("missing")?);
+    Ok(())
+}
+
+#[test]
+fn cache_metrics_count_hits_misses_and_evictions() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let keys = generate_keys(2000, 22);
+    let tree = MerkleSearchTree::open(file.path())?;
+    for (i, k) in keys.iter().enumerate() {
+        tree.insert(k.clone(), i as u64)?;
+    }
+    tree.commit()?;
+    drop(tree);
+
+    let tree: MerkleSearchTree<String, u64> = MerkleSearchTree::open(file.path())?;
+    assert_eq!(tree.cache_metrics(), CacheMetrics::default());
+    assert_eq!(tree.cache_metrics().hit_rate(), 0.0);
+    for k in &keys {
+        tree.get(k)?;
+    }
+    let cold = tree.cache_metrics();
+    assert_eq!(cold.misses, tree.store.cached_nodes() as u64);
+    assert_eq!(cold.evictions, 0);
+    for k in &keys {
+        tree.get(k)?;
+    }
+    let warm = tree.cache_metrics();
+    assert_eq!(warm.misses, cold.misses);
+    assert!(warm.hits > cold.hits && warm.hit_rate() > cold.hit_rate());
+
+    let tree = MerkleSearchTree::<String, u64>::open_with_cache_capacity(file.path(), 8)?;
+    for k in &keys {
+        tree.get(k)?;
+    }
+    let metrics = tree.cache_metrics();
+    assert!(metrics.evictions > 0);
+    assert_eq!(metrics.evictions, metrics.misses - tree.store.cached_nodes() as u64);
+    Ok(())
+}
+
+#[test]
+fn cold_node_loads_read_a_record_at_once_unless_it_spans_pages() -> io::Result<()> {
+    use std::sync::atomic::Ordering;
+
+    let files = [tempfile::NamedTempFile::new()?, tempfile::NamedTempFile::new()?];
+    // Pages large enough for every node.
+    let tree = MerkleSearchTree::<u32, u32>::builder()
+        .page_size(64 * 1024)
+        .open(files[0].path())?;
+    tree.insert_many((0..2000).map(|i| (i, i)))?;
+    tree.commit()?;
+    let large = vec![1u8; 3 * DEFAULT_PAGE_SIZE as usize];
+    let tree = MerkleSearchTree::open(files[1].path())?;
+    tree.insert("large".to_string(), large.clone())?;
+    tree.commit()?;
+    drop(tree);
+
+    let tree = MerkleSearchTree::<u32, u32>::open(files[0].path())?;
+    for i in 0..2000 {
+        tree.get(&i)?;
+    }
+    let misses = tree.cache_metrics().misses;
+    assert!(misses > 100);
+    assert_eq!(tree.store.reads.load(Ordering::Relaxed), misses);
+
+    // A node longer than a page takes a second read for the rest.
+    let tree: MerkleSearchTree<String, Vec<u8>> = MerkleSearchTree::open(files[1].path())?;
+    assert_eq!(tree.get("large")?.as_deref(), Some(&large));
+    assert_eq!(tree.cache_metrics().misses, 1);
+    assert_eq!(tree.store.reads.load(Ordering::Relaxed), 2);
+    Ok(())
+}
+
+#[test]
+fn torn_metadata_falls_back_then_errors() -> io::Result<()> {
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+
+    let file = tempfile::NamedTempFile::new()?;
+    let path = file.path().to_owned();
+
+    let (first_root, second_root) = {
+        let tree = MerkleSearchTree::open(&path)?;
+        tree.insert("a".to_string(), 1u32)?;
+        let first = tree.commit()?.hash;
+        tree.insert("b".to_string(), 2u32)?;
+        let second = tree.commit()?.hash;
+        (first, second)
+    };
+
+    let smash = |slot: u64| -> io::Result<()> {
+        let mut raw = OpenOptions::new().write(true).open(&path)?;
+        raw.seek(SeekFrom::Start(slot + 10))?;
+        raw.write_all(&[0xAB; 4])
+    };
+
+    // The second commit went to the second slot written; tearing it must expose the
+    // first root rather than garbage.
+    smash(store::SLOT_OFFSETS[0])?;
+    let tree: MerkleSearchTree<String, u32> = MerkleSearchTree::open(&path)?;
+    assert_ne!(tree.root_hash(), second_root);
+    assert_eq!(tree.root_hash(), first_root);
+    assert!(tree.contains("a")? && !tree.contains("b")?);
+    drop(tree);
+
+    smash(store::SLOT_OFFSETS[1])?;
+    let err = MerkleSearchTree::<String, u32>::open(&path).err().unwrap();
+    assert!(matches!(err, Error::Corrupt(_)), "{err}");
+    Ok(())
+}
+
+#[test]
+fn root_pointers_inside_the_header_page_are_corrupt_not_empty() -> io::Result<()> {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    let file = tempfile::NamedTempFile::new()?;
+    let path = file.path();
+    // An empty tree still commits a root node, placed after the header page.
+    let version = MerkleSearchTree::<String, u32>::open(path)?.commit()?;
+    assert!(version.offset >= DEFAULT_PAGE_SIZE);
+    let reopened = MerkleSearchTree::<String, u32>::open(path)?;
+    assert!(!reopened.is_dirty() && reopened.is_empty()?);
+    drop(reopened);
+
+    // Point the slot of that commit at offset 0, with a checksum that still matches.
+    let mut raw = std::fs::OpenOptions::new().read(true).write(true).open(path)?;
+    let mut slot = [0u8; 64];
+    raw.seek(SeekFrom::Start(store::SLOT_OFFSETS[1]))?;
+    raw.read_exact(&mut slot)?;
+    slot[8..16].copy_from_slice(&0u64.to_le_bytes());
+    let checksum = blake3::hash(&slot[..56]);
+    slot[56..].copy_from_slice(&checksum.as_bytes()[..8]);
+    raw.seek(SeekFrom::Start(store::SLOT_OFFSETS[1]))?;
+    raw.write_all(&slot)?;
+
+    let err = MerkleSearchTree::<String, u32>::open(path).err().unwrap();
+    assert!(matches!(err, Error::Corrupt(_)), "{err}");
+    Ok(())
+}
+
+#[test]
+fn open_rejects_files_shorter_than_the_header_page() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    std::fs::write(file.path(), [0u8; 10])?;
+    let err = MerkleSearchTree::<String, u32>::open(file.path()).err().unwrap();
+    assert!(matches!(err, Error::Corrupt(_)), "{err}");
+    assert!(err.to_string().contains("10 bytes"), "{err}");
+    // Nothing was written to the file.
+    assert_eq!(std::fs::metadata(file.path())?.len(), 10);
+
+    // A valid header whose page was cut short.
+    let file = tempfile::NamedTempFile::new()?;
+    MerkleSearchTree::<String, u32>::open(file.path())?.commit()?;
+    let raw = std::fs::OpenOptions::new().write(true).open(file.path())?;
+    raw.set_len(100)?;
+    let err = MerkleSearchTree::<String, u32>::open(file.path()).err().unwrap();
+    assert!(matches!(err, Error::Corrupt(_)), "{err}");
+    Ok(())
+}
+
+#[test]
+fn open_rejects_foreign_files_and_versions() -> io::Result<()> {
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+
+    let file = tempfile::NamedTempFile::new()?;
+    std::fs::write(file.path(), vec![7u8; 4096])?;
+    let err = MerkleSearchTree::<String, u32>::open(file.path()).err().unwrap();
+    assert!(matches!(err, Error::Corrupt(_)), "{err}");
+
+    let file = tempfile::NamedTempFile::new()?;
+    MerkleSearchTree::<String, u32>::open(file.path())?.commit()?;
+    let mut raw = OpenOptions::new().write(true).open(file.path())?;
+    raw.seek(SeekFrom::Start(8))?;
+    raw.write_all(&(store::FORMAT_VERSION + 1).to_le_bytes())?;
+    let err = MerkleSearchTree::<String, u32>::open(file.path()).err().unwrap();
+    assert!(matches!(
+        err,
+        Error::VersionMismatch { found, expected }
+            if found == store::FORMAT_VERSION + 1 && expected == store::FORMAT_VERSION
+    ));
+    Ok(())
+}
+
+#[test]
+fn page_size_is_persisted_and_validated() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("small-pages.mst");
+    let keys = generate_keys(1000, 5);
+
+    {
+        let tree = MerkleSearchTree::open_with_page_size(&path, 1024)?;
+        for (i, k) in keys.iter().enumerate() {
+            tree.insert(k.clone(), i as u32)?;
+        }
+        tree.commit()?;
+    }
+
+    // Reopening with a different requested size keeps the size from the header.
+    let tree: MerkleSearchTree<String, u32> = MerkleSearchTree::open_with_page_size(&path, 8192)?;
+    assert_eq!(tree.store.config().page_size, 1024);
+    for (i, k) in keys.iter().enumerate() {
+        assert_eq!(tree.get(k)?.as_deref(), Some(&(i as u32)));
+    }
+
+    for bad in [0, 256, 1000] {
+        let err = MerkleSearchTree::<String, u32>::open_with_page_size(dir.path().join(format!("bad-{bad}")), bad)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+    Ok(())
+}
+
+#[test]
+fn read_only_open_never_mutates_the_file() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let path = file.path().to_owned();
+    let keys = generate_keys(1000, 3);
+
+    {
+        let tree = MerkleSearchTree::open(&path)?;
+        for (i, k) in keys.iter().enumerate() {
+            tree.insert(k.clone(), i as u32)?;
+        }
+        tree.commit()?;
+    }
+    let before = std::fs::read(&path)?;
+
+    let mut tree: MerkleSearchTree<String, u32> = MerkleSearchTree::open_read_only(&path)?;
+    for (i, k) in keys.iter().enumerate() {
+        assert_eq!(tree.get(k)?.as_deref(), Some(&(i as u32)));
+    }
+
+    let denied = [
+        tree.insert("new".to_string(), 0).err(),
+        tree.remove(&keys[0]).err(),
+        tree.commit().err(),
+        tree.compact(path.with_extension("compacted")).err(),
+    ];
+    for err in denied {
+        assert_eq!(err.unwrap().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    drop(tree);
+    assert_eq!(std::fs::read(&path)?, before);
+    Ok(())
+}
+
+#[test]
+fn concurrent_readers_share_the_store() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let path = file.path().to_owned();
+    let keys = generate_keys(3000, 17);
+
+    {
+        let tree = MerkleSearchTree::open(&path)?;
+        for (i, k) in keys.iter().enumerate() {
+            tree.insert(k.clone(), i as u64)?;
+        }
+        tree.commit()?;
+    }
+
+    // A small cache keeps the readers going to disk for most lookups.
+    let tree: MerkleSearchTree<String, u64> = MerkleSearchTree::open_with_cache_capacity(&path, 8)?;
+    std::thread::scope(|scope| {
+        for t in 0..8 {
+            let tree = &tree;
+            let keys = &keys;
+            scope.spawn(move || {
+                for (i, k) in keys.iter().enumerate().skip(t).step_by(3) {
+                    assert_eq!(tree.get(k).unwrap().as_deref(), Some(&(i as u64)));
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[test]
+fn get_many_preserves_input_order() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let keys = generate_keys(2000, 31);
+    let tree = MerkleSearchTree::open(file.path())?;
+    for (i, k) in keys.iter().enumerate() {
+        tree.insert(k.clone(), i)?;
+    }
+    tree.commit()?;
+
+    let tree: MerkleSearchTree<String, usize> = MerkleSearchTree::open(file.path())?;
+    let mut queries: Vec<&str> = keys.iter().step_by(37).map(String::as_str).collect();
+    queries.push("missing");
+    queries.push(&keys[0]);
+    let results = tree.get_many(&queries)?;
+
+    assert_eq!(results.len(), queries.len());
+    for (query, result) in queries.iter().zip(&results) {
+        assert_eq!(result.as_deref(), tree.get(*query)?.as_deref());
+    }
+    assert_eq!(results[results.len() - 2], None);
+    Ok(())
+}
+
+#[test]
+fn contains_any_and_all_test_a_batch_in_one_descent() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let keys = generate_keys(2000, 73);
+    let tree = MerkleSearchTree::open(file.path())?;
+    tree.insert_many(keys.iter().map(|k| (k.clone(), 0u32)))?;
+    tree.commit()?;
+
+    let tree: MerkleSearchTree<String, u32> = MerkleSearchTree::open(file.path())?;
+    let present: Vec<&str> = keys.iter().rev().step_by(41).map(String::as_str).collect();
+    let absent = ["missing", "key-", "zzz"];
+    let mixed: Vec<&str> = present.iter().chain(&absent).copied().collect();
+    assert!(tree.contains_all(&present)?);
+    assert!(tree.contains_any(&present)?);
+    assert!(!tree.contains_all(&mixed)?);
+    assert!(tree.contains_any(&mixed)?);
+    assert!(!tree.contains_all(&absent)?);
+    assert!(!tree.contains_any(&absent)?);
+    assert!(tree.contains_all::<str>(&[])?);
+    assert!(!tree.contains_any::<str>(&[])?);
+
+    // Opened cold, a batch loads the same nodes as get_many; contains_any stops early.
+    let open = || MerkleSearchTree::<String, u32>::open(file.path());
+    let batched = open()?;
+    batched.get_many(&present)?;
+    let all = open()?;
+    assert!(all.contains_all(&present)?);
+    assert_eq!(all.cache_metrics().misses, batched.cache_metrics().misses);
+    let any = open()?;
+    assert!(any.contains_any(&present)?);
+    assert!(any.cache_metrics().misses < all.cache_metrics().misses);
+    Ok(())
+}
+
+#[test]
+fn first_and_last_key_value() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let tree = MerkleSearchTree::open(file.path())?;
+    assert!(tree.first_key_value()?.is_none());
+    assert!(tree.last_key_value()?.is_none());
+
+    let mut keys = generate_keys(3000, 44);
+    for (i, k) in keys.iter().enumerate() {
+        tree.insert(k.clone(), i)?;
+    }
+    tree.commit()?;
+    let tree: MerkleSearchTree<String, usize> = MerkleSearchTree::open(file.path())?;
+
+    let min_idx = (0..keys.len()).min_by_key(|&i| &keys[i]).unwrap();
+    let max_idx = (0..keys.len()).max_by_key(|&i| &keys[i]).unwrap();
+    let (k, v) = tree.first_key_value()?.unwrap();
+    assert_eq!((k.as_str(), *v), (keys[min_idx].as_str(), min_idx));
+    let (k, v) = tree.last_key_value()?.unwrap();
+    assert_eq!((k.as_str(), *v), (keys[max_idx].as_str(), max_idx));
+
+    keys.sort();
+    tree.remove(&keys[0])?;
+    assert_eq!(tree.first_key_value()?.unwrap().0.as_str(), keys[1].as_str());
+    Ok(())
+}
+
+#[test]
+fn floor_and_ceil_match_a_scan() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let tree: MerkleSearchTree<u32, u32> = MerkleSearchTree::open(file.path())?;
+    assert!(tree.floor(&5)?.is_none());
+    assert!(tree.ceil(&5)?.is_none());
+
+    let mut rng = StdRng::seed_from_u64(45);
+    let mut keys: Vec<u32> = (0..2_000).map(|_| rng.random_range(1_000..100_000)).collect();
+    keys.sort();
+    keys.dedup();
+    for &k in &keys {
+        tree.insert(k, k / 2)?;
+    }
+    tree.commit()?;
+    let tree: MerkleSearchTree<u32, u32> = MerkleSearchTree::open(file.path())?;
+
+    let mut queries: Vec<u32> = (0..1_000).map(|_| rng.random_range(0..101_000)).collect();
+    queries.extend(keys.iter().step_by(50));
+    queries.extend([0, 999, keys[0], keys[keys.len() - 1], 100_000, u32::MAX]);
+    for q in queries {
+        let floor = keys.iter().rev().find(|&&k| k <= q).map(|&k| (k, k / 2));
+        let ceil = keys.iter().find(|&&k| k >= q).map(|&k| (k, k / 2));
+        assert_eq!(tree.floor(&q)?.map(|(k, v)| (*k, *v)), floor, "floor of {q}");
+        assert_eq!(tree.ceil(&q)?.map(|(k, v)| (*k, *v)), ceil, "ceil of {q}");
+    }
+    Ok(())
+}
+
+#[test]
+fn entry_api_matches_insert() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let keys = generate_keys(1500, 51);
+
+    let via_insert = MerkleSearchTree::new_temporary()?;
+    let mut via_entry = MerkleSearchTree::open(file.path())?;
+    for (i, k) in keys.iter().enumerate() {
+        via_insert.insert(k.clone(), i as u64)?;
+        assert_eq!(*via_entry.entry(k.clone())?.or_insert(i as u64)?, i as u64);
+        if i % 500 == 0 {
+            via_entry.commit()?;
+        }
+    }
+    assert_eq!(via_insert.root_hash(), via_entry.root_hash());
+
+    // Occupied entries keep their value on `or_insert`.
+    assert_eq!(*via_entry.entry(keys[3].clone())?.or_insert(999)?, 3);
+
+    let entry = via_entry.entry(keys[3].clone())?.and_modify(|v| *v += 100)?;
+    assert!(matches!(entry, Entry::Occupied(ref e) if **e.get() == 103));
+    via_insert.insert(keys[3].clone(), 103)?;
+    assert_eq!(via_insert.root_hash(), via_entry.root_hash());
+
+    let missing = via_entry.entry("missing".to_string())?.and_modify(|v| *v = 0)?;
+    assert!(matches!(missing, Entry::Vacant(_)));
+    assert!(!via_entry.contains("missing")?);
+    Ok(())
+}
+
+#[test]
+fn freed_space_is_reused_by_later_commits() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let path = file.path().to_owned();
+    let keys = generate_keys(500, 61);
+
+    let tree = MerkleSearchTree::open(&path)?;
+    for (i, k) in keys.iter().enumerate() {
+        tree.insert(k.clone(), i as u64)?;
+    }
+    tree.commit()?;
+    assert_eq!(tree.reclaimable_bytes(), 0);
+
+    let mut sizes = Vec::new();
+    for round in 1..=20u64 {
+        for k in keys.iter().step_by(50) {
+            tree.insert(k.clone(), round)?;
+        }
+        tree.commit()?;
+        sizes.push(std::fs::metadata(&path)?.len());
+    }
+    assert!(tree.reclaimable_bytes() > 0);
+
+    // Once reuse kicks in, the same amount of churn per round stops growing the file.
+    let early_growth = sizes[4] - sizes[0];
+    let late_growth = sizes[19] - sizes[15];
+    assert!(late_growth < early_growth, "file kept growing: {sizes:?}");
+
+    drop(tree);
+    let tree: MerkleSearchTree<String, u64> = MerkleSearchTree::open(&path)?;
+    for (i, k) in keys.iter().enumerate() {
+        let expected = if i % 50 == 0 { 20 } else { i as u64 };
+        assert_eq!(tree.get(k)?.as_deref(), Some(&expected));
+    }
+    Ok(())
+}
+
+#[test]
+fn free_list_survives_reopening() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let path = file.path().to_owned();
+    let keys = generate_keys(500, 62);
+
+    let churn = |tree: &MerkleSearchTree<String, u64>, round: u64| -> io::Result<()> {
+        for k in keys.iter().step_by(50) {
+            tree.insert(k.clone(), round)?;
+        }
+        tree.commit()?;
+        Ok(())
+    };
+
+    let tree = MerkleSearchTree::open(&path)?;
+    for (i, k) in keys.iter().enumerate() {
+        tree.insert(k.clone(), i as u64)?;
+    }
+    tree.commit()?;
+    for round in 1..=10 {
+        churn(&tree, round)?;
+    }
+    let reclaimable = tree.reclaimable_bytes();
+    assert!(reclaimable > 0);
+    drop(tree);
+
+    // Each session only churns briefly, yet the file stops growing.
+    let mut sizes = Vec::new();
+    for session in 0..10 {
+        let tree = MerkleSearchTree::open(&path)?;
+        if session == 0 {
+            assert_eq!(tree.reclaimable_bytes(), reclaimable);
+        }
+        churn(&tree, 11 + session)?;
+        churn(&tree, 11 + session)?;
+        sizes.push(std::fs::metadata(&path)?.len());
+    }
+    assert_eq!(sizes[9], sizes[5], "file kept growing: {sizes:?}");
+
+    // Read-only opens do not need the free list.
+    let tree = MerkleSearchTree::<String, u64>::open_read_only(&path)?;
+    assert_eq!(tree.reclaimable_bytes(), 0);
+    assert_eq!(tree.get(&keys[0])?.as_deref(), Some(&20));
+    Ok(())
+}
+
+#[test]
+fn crash_before_the_root_pointer_keeps_the_previous_free_list() -> io::Result<()> {
+    use std::sync::atomic::Ordering;
+
+    let file = tempfile::NamedTempFile::new()?;
+    let keys = generate_keys(500, 63);
+    let tree = MerkleSearchTree::open(file.path())?;
+    for (i, k) in keys.iter().enumerate() {
+        tree.insert(k.clone(), i as u64)?;
+    }
+    for round in 0..5 {
+        for k in keys.iter().step_by(25) {
+            tree.insert(k.clone(), round)?;
+        }
+        tree.commit()?;
+    }
+    let committed = tree.commit()?.hash;
+    let reclaimable = tree.reclaimable_bytes();
+
+    // This commit orphans nodes of the committed root and fills free regions, but its
+    // root pointer never lands.
+    for k in keys.iter().step_by(7) {
+        tree.insert(k.clone(), 1_000)?;
+    }
+    tree.store.drop_metadata_writes.store(true, Ordering::Relaxed);
+    tree.commit()?;
+    drop(tree);
+
+    let tree = MerkleSearchTree::<String, u64>::open_with_verification(file.path())?;
+    assert_eq!(tree.root_hash(), committed);
+    assert_eq!(tree.reclaimable_bytes(), reclaimable);
+
+    // Reusing the reloaded free list never overwrites nodes the root still reaches.
+    for round in 0..5 {
+        for k in keys.iter().skip(round).step_by(11) {
+            tree.insert(k.clone(), 2_000)?;
+        }
+        tree.commit()?;
+    }
+    drop(tree);
+    let tree = MerkleSearchTree::<String, u64>::open_with_verification(file.path())?;
+    for (i, k) in keys.iter().enumerate() {
+        let expected = if (0..5).any(|round| i >= round && (i - round) % 11 == 0) {
+            2_000
+        } else if i % 25 == 0 {
+            4
+        } else {
+            i as u64
+        };
+        assert_eq!(tree.get(k)?.as_deref(), Some(&expected));
+    }
+    Ok(())
+}
+
+#[test]
+fn failed_commit_frees_written_nodes_and_can_be_retried() -> io::Result<()> {
+    use std::sync::atomic::Ordering;
+
+    let file = tempfile::NamedTempFile::new()?;
+    let keys = generate_keys(2_000, 64);
+    let tree = MerkleSearchTree::open(file.path())?;
+    tree.insert_many(keys.iter().map(|k| (k.clone(), 0)))?;
+    tree.commit()?;
+    tree.insert_many(keys.iter().step_by(40).map(|k| (k.clone(), 1)))?;
+    let changed = tree.root_hash();
+
+    let loaded_nodes = |tree: &MerkleSearchTree<String, u64>| {
+        let mut stack = vec![tree.state.read().unwrap().root.clone()];
+        let mut loaded = 0;
+        while let Some(link) = stack.pop() {
+            if let node::Link::Loaded(node) = link {
+                stack.extend(node.children.iter().cloned());
+                loaded += 1;
+            }
+        }
+        loaded
+    };
+    let dirty = loaded_nodes(&tree);
+
+    // Nodes written before the failure are dropped and linked from the disk instead.
+    tree.store.node_writes_until_failure.store(dirty as u64 / 2, Ordering::Relaxed);
+    assert!(tree.commit().is_err());
+    assert_eq!(loaded_nodes(&tree), dirty - (dirty / 2 - 1));
+    assert_eq!(tree.root_hash(), changed);
+    assert!(tree.is_dirty());
+
+    // The retry writes the rest, and frees no node the written ones still point at.
+    assert_eq!(tree.commit()?.hash, changed);
+    assert_eq!(loaded_nodes(&tree), 0);
+    for round in 2..5 {
+        tree.insert_many(keys.iter().skip(round).step_by(7).map(|k| (k.clone(), round as u64)))?;
+        tree.commit()?;
+    }
+    drop(tree);
+    let tree = MerkleSearchTree::<String, u64>::open_with_verification(file.path())?;
+    assert!(tree.verify()?.is_ok());
+    for (i, k) in keys.iter().enumerate() {
+        let expected = (2..5).rev().find(|round| i >= *round && (i - round) % 7 == 0);
+        let expected = expected.map_or(if i % 40 == 0 { 1 } else { 0 }, |round| round as u64);
+        assert_eq!(tree.get(k)?.as_deref(), Some(&expected));
+    }
+    Ok(())
+}
+
+#[test]
+fn structure_is_history_independent() -> io::Result<()> {
+    for seed in 0..20u64 {
+        let mut keys = generate_keys(300, seed);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let kept = keys.len() / 3;
+
+        let churned = MerkleSearchTree::new_temporary()?;
+        for k in &keys {
+            churned.insert(k.clone(), 1u8)?;
+        }
+        keys.shuffle(&mut rng);
+        for k in &keys[kept..] {
+            churned.remove(k)?;
+        }
+
+        let fresh = MerkleSearchTree::new_temporary()?;
+        for k in &keys[..kept] {
+            fresh.insert(k.clone(), 1u8)?;
+        }
+        assert_eq!(churned.root_hash(), fresh.root_hash(), "seed {seed}");
+    }
+    Ok(())
+}
+
+#[test]
+fn remove_range_matches_individual_removes() -> io::Result<()> {
+    use std::ops::Bound;
+
+    let mut keys = generate_keys(2000, 71);
+    keys.sort();
+    let bounds: Vec<(Bound<&str>, Bound<&str>)> = vec![
+        (Bound::Included(&keys[100]), Bound::Excluded(&keys[900])),
+        (Bound::Excluded(&keys[0]), Bound::Included(&keys[1])),
+        (Bound::Unbounded, Bound::Included(&keys[1500])),
+        (Bound::Included(&keys[1999]), Bound::Unbounded),
+        (Bound::Included("key-zzz"), Bound::Unbounded),
+        (Bound::Unbounded, Bound::Unbounded),
+    ];
+
+    for range in bounds {
+        let ranged = MerkleSearchTree::new_temporary()?;
+        let looped = MerkleSearchTree::new_temporary()?;
+        for (i, k) in keys.iter().enumerate() {
+            ranged.insert(k.clone(), i)?;
+            looped.insert(k.clone(), i)?;
+        }
+        ranged.commit()?;
+
+        let doomed: Vec<&String> = keys
+            .iter()
+            .filter(|k| std::ops::RangeBounds::<str>::contains(&range, k.as_str()))
+            .collect();
+        for k in &doomed {
+            looped.remove(k.as_str())?;
+        }
+
+        assert_eq!(ranged.remove_range::<str, _>(range)?, doomed.len() as u64);
+        assert_eq!(ranged.root_hash(), looped.root_hash(), "range {range:?}");
+        for k in doomed {
+            assert!(!ranged.contains(k.as_str())?);
+        }
+    }
+    Ok(())
+}
+
+/// BLAKE3 keyed with a fixed key: a distinct hash function for exercising `H`.
+#[derive(Default)]
+struct KeyedHasher(Option<blake3::Hasher>);
+
+impl TreeHasher for KeyedHasher {
+    fn update(&mut self, bytes: &[u8]) {
+        self.0
+            .get_or_insert_with(|| blake3::Hasher::new_keyed(&[7; 32]))
+            .update(bytes);
+    }
+
+    fn finalize(self) -> [u8; 32] {
+        self.0
+            .unwrap_or_else(|| blake3::Hasher::new_keyed(&[7; 32]))
+            .finalize()
+            .into()
+    }
+}
+
+#[test]
+fn default_hasher_output_is_pinned() -> io::Result<()> {
+    let tree = MerkleSearchTree::new_temporary()?;
+    for i in 0..100u32 {
+        tree.insert(format!("key-{i}"), i)?;
+    }
+    assert_eq!(
+        tree.root_hash().to_hex().as_str(),
+        "eaad99eeb1a2621defa037510438414f84615881e65fb2624db4233f9159f7a8"
+    );
+    Ok(())
+}
+
+#[test]
+fn custom_hasher_builds_verifiable_trees() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("keyed.mst");
+    let keys = generate_keys(500, 14);
+
+    let default_tree = MerkleSearchTree::new_temporary()?;
+    let keyed: MerkleSearchTree<String, u32, KeyedHasher> =
+        MerkleSearchTree::open_with_hasher(&path)?;
+    for (i, k) in keys.iter().enumerate() {
+        default_tree.insert(k.clone(), i as u32)?;
+        keyed.insert(k.clone(), i as u32)?;
+    }
+    let root = keyed.commit()?.hash;
+    assert_ne!(root, default_tree.root_hash());
+
+    let reopened: MerkleSearchTree<String, u32, KeyedHasher> =
+        MerkleSearchTree::open_with_hasher(&path)?;
+    assert_eq!(reopened.root_hash(), root);
+
+    let proof = reopened.prove(&keys[42])?.expect("key is present");
+    assert!(verify_proof_with::<KeyedHasher, _, _>(root, &keys[42], &42, &proof));
+    assert!(!verify_proof(root, &keys[42], &42, &proof));
+
+    let absent = "missing".to_string();
+    let proof = reopened.prove_absence(&absent)?.expect("key is absent");
+    assert!(verify_absence_with::<KeyedHasher, _, _>(root, &absent, &proof));
+    Ok(())
+}
+
+#[test]
+fn domain_salt_separates_hashes_of_equal_contents() -> io::Result<()> {
+    let keys = generate_keys(300, 78);
+    let file = tempfile::NamedTempFile::new()?;
+    let path = file.path();
+
+    let open_salted = |salt, path: &std::path::Path| -> Result<MerkleSearchTree<String, u32>> {
+        OpenOptions::new().domain_salt(salt).open(path)
+    };
+    let salted = open_salted([1; 32], path)?;
+    let other_salt: MerkleSearchTree<String, u32> =
+        OpenOptions::new().domain_salt([2; 32]).create_temporary()?;
+    let unsalted = MerkleSearchTree::<String, u32>::new_temporary()?;
+    for tree in [&salted, &other_salt, &unsalted] {
+        tree.insert_many(keys.iter().enumerate().map(|(i, k)| (k.clone(), i as u32)))?;
+    }
+    let root = salted.commit()?.hash;
+    assert_ne!(root, other_salt.root_hash());
+    assert_ne!(root, unsalted.root_hash());
+    assert_ne!(other_salt.root_hash(), unsalted.root_hash());
+
+    // Proofs carry the salt; without it they no longer lead to the root.
+    let mut proof = salted.prove(&keys[7])?.expect("key is present");
+    assert!(verify_proof(root, &keys[7], &7, &proof));
+    proof.salt = None;
+    assert!(!verify_proof(root, &keys[7], &7, &proof));
+    drop(salted);
+
+    // The header keeps the salt, so reopening needs no option and rejects another one.
+    let reopened: MerkleSearchTree<String, u32> =
+        OpenOptions::new().verify_on_read(true).open(path)?;
+    assert_eq!(reopened.root_hash(), root);
+    assert_eq!(reopened.get(&keys[42])?.as_deref(), Some(&42));
+    assert!(reopened.verify()?.is_ok());
+    reopened.insert(keys[0].clone(), 1_000)?;
+    reopened.insert(keys[0].clone(), 0)?;
+    assert_eq!(reopened.root_hash(), root);
+    let err = open_salted([2; 32], path).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    let unsalted_file = tempfile::NamedTempFile::new()?;
+    MerkleSearchTree::<String, u32>::open(unsalted_file.path())?.commit()?;
+    let err = open_salted([1; 32], unsalted_file.path()).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    // Compaction carries the salt over.
+    let compacted = tempfile::NamedTempFile::new()?;
+    let mut reopened = reopened;
+    reopened.compact(compacted.path())?;
+    drop(reopened);
+    let compacted = open_salted([1; 32], compacted.path())?;
+    assert_eq!(compacted.root_hash(), root);
+
+    let fresh = tempfile::NamedTempFile::new()?;
+    let err = open_salted([0; 32], fresh.path()).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    Ok(())
+}
+
+#[test]
+fn node_byte_budget_splits_large_values_into_smaller_nodes() -> io::Result<()> {
+    use std::num::NonZeroU32;
+
+    let keys = generate_keys(2000, 79);
+    let value = |i: usize| vec![i as u8; 1000];
+    let file = tempfile::NamedTempFile::new()?;
+    let budget = NonZeroU32::new(4096).unwrap();
+    let budgeted: MerkleSearchTree<String, Vec<u8>> =
+        OpenOptions::new().node_byte_budget(budget).open(file.path())?;
+    let plain = MerkleSearchTree::<String, Vec<u8>>::new_temporary()?;
+    for tree in [&budgeted, &plain] {
+        tree.insert_many(keys.iter().enumerate().map(|(i, k)| (k.clone(), value(i))))?;
+    }
+
+    // Four 1000-byte entries fit the budget, so nodes hold a quarter as many keys.
+    let (small, large) = (budgeted.stats()?, plain.stats()?);
+    assert!(small.node_count > 2 * large.node_count);
+    assert!(small.avg_keys_per_node() * 2.0 < large.avg_keys_per_node());
+    for (i, key) in keys.iter().enumerate() {
+        assert_eq!(budgeted.get(key)?.as_deref(), Some(&value(i)));
+    }
+    assert!(budgeted.verify()?.is_ok());
+
+    // Shrinking values moves their keys back to the levels of the fan-out; the tree
+    // is the one those entries build from scratch.
+    budgeted.insert_many(keys[..500].iter().map(|k| (k.clone(), vec![1])))?;
+    let rebuilt: MerkleSearchTree<String, Vec<u8>> =
+        OpenOptions::new().node_byte_budget(budget).create_temporary()?;
+    rebuilt.insert_many(keys.iter().enumerate().map(|(i, k)| {
+        (k.clone(), if i < 500 { vec![1] } else { value(i) })
+    }))?;
+    assert_eq!(budgeted.root_hash(), rebuilt.root_hash());
+    let root = budgeted.commit()?.hash;
+    drop(budgeted);
+
+    // The header keeps the budget, so reopening needs no option and rejects another.
+    let mut reopened: MerkleSearchTree<String, Vec<u8>> = MerkleSearchTree::open(file.path())?;
+    assert_eq!(reopened.node_byte_budget(), Some(budget));
+    assert_eq!(reopened.root_hash(), root);
+    let err = OpenOptions::<String, Vec<u8>>::new()
+        .node_byte_budget(NonZeroU32::new(8192).unwrap())
+        .open(file.path())
+        .err()
+        .expect("another budget is rejected");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    // Growing a value back through an entry moves its key up again.
+    reopened.entry(keys[0].clone())?.and_modify(|v| *v = value(0))?;
+    rebuilt.insert(keys[0].clone(), value(0))?;
+    assert_eq!(reopened.root_hash(), rebuilt.root_hash());
+    assert_eq!(reopened.get(&keys[0])?.as_deref(), Some(&value(0)));
+    Ok(())
+}
+
+#[cfg(feature = "sha256")]
+#[test]
+fn sha256_hasher_round_trips() -> io::Result<()> {
+    let tree: MerkleSearchTree<String, u32, Sha256Hasher> =
+        MerkleSearchTree::new_temporary_with_hasher()?;
+    let keys = generate_keys(300, 15);
+    for (i, k) in keys.iter().enumerate() {
+        tree.insert(k.clone(), i as u32)?;
+    }
+    let root = tree.commit()?.hash;
+
+    for (i, k) in keys.iter().enumerate() {
+        let proof = tree.prove(k)?.expect("key is present");
+        assert!(verify_proof_with::<Sha256Hasher, _, _>(root, k, &(i as u32), &proof));
+    }
+    Ok(())
+}
+
+#[test]
+fn iter_and_range_match_btreemap() -> io::Result<()> {
+    use std::collections::BTreeMap;
+    use std::ops::Bound;
+
+    let keys = generate_keys(1500, 15);
+    let tree = MerkleSearchTree::new_temporary()?;
+    let mut expected = BTreeMap::new();
+    for (i, k) in keys.iter().enumerate() {
+        tree.insert(k.clone(), i)?;
+        expected.insert(k.clone(), i);
+    }
+
+    let mut sorted = keys.clone();
+    sorted.sort();
+    let (a, b) = (sorted[200].as_str(), sorted[700].as_str());
+    let ranges: Vec<(Bound<&str>, Bound<&str>)> = vec![
+        (Bound::Unbounded, Bound::Unbounded),
+        (Bound::Included(a), Bound::Excluded(b)),
+        (Bound::Excluded(a), Bound::Included(b)),
+        (Bound::Included("key-8"), Bound::Unbounded),
+        (Bound::Unbounded, Bound::Excluded("key-1")),
+        (Bound::Included(b), Bound::Excluded(a)),
+        (Bound::Excluded(a), Bound::Excluded(a)),
+    ];
+
+    // Once fully in memory, then again after a reopen-like commit.
+    for _ in 0..2 {
+        let all: Vec<(String, usize)> = tree
+            .iter()?
+            .map(|e| e.map(|(k, v)| ((*k).clone(), *v)))
+            .collect::<Result<_>>()?;
+        assert!(all.iter().cloned().eq(expected.clone()));
+
+        for range in &ranges {
+            let got: Vec<String> = tree
+                .range::<str, _>(*range)?
+                .map(|e| e.map(|(k, _)| (*k).clone()))
+                .collect::<Result<_>>()?;
+            let want: Vec<String> = sorted
+                .iter()
+                .filter(|k| std::ops::RangeBounds::<str>::contains(range, k.as_str()))
+                .cloned()
+                .collect();
+            assert_eq!(got, want, "range {range:?}");
+        }
+        tree.commit()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn snapshot_survives_later_writes() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let keys = generate_keys(400, 16);
+
+    let tree = MerkleSearchTree::open(file.path())?;
+    for (i, k) in keys.iter().enumerate() {
+        tree.insert(k.clone(), i as u64)?;
+    }
+    tree.commit()?;
+
+    let snapshot = tree.snapshot();
+    let root = snapshot.root_hash();
+
+    // Rewrite everything several times so freed space would be reused.
+    for round in 1..=5u64 {
+        for k in &keys {
+            tree.insert(k.clone(), 1000 * round)?;
+        }
+        tree.remove(&keys[0])?;
+        tree.insert("new".to_string(), round)?;
+        tree.commit()?;
+    }
+
+    assert_eq!(snapshot.root_hash(), root);
+    assert_ne!(tree.root_hash(), root);
+    assert_eq!(snapshot.get(&keys[1])?.as_deref(), Some(&1));
+    assert!(snapshot.contains(&keys[0])?);
+    assert!(!snapshot.contains("new")?);
+
+    let mut count = 0;
+    for (i, entry) in snapshot.iter()?.enumerate() {
+        let (k, v) = entry?;
+        assert_eq!(keys[*v as usize], *k);
+        count = i + 1;
+    }
+    assert_eq!(count, keys.len());
+
+    // Space held back for the snapshot is reclaimed once it is gone.
+    drop(snapshot);
+    tree.insert("later".to_string(), 0)?;
+    tree.commit()?;
+    let len = std::fs::metadata(file.path())?.len();
+    tree.insert("later".to_string(), 1)?;
+    tree.commit()?;
+    assert_eq!(std::fs::metadata(file.path())?.len(), len);
+    Ok(())
+}
+
+#[test]
+fn clear_empties_the_tree_without_reclaiming_space() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let keys = generate_keys(300, 16);
+
+    let tree = MerkleSearchTree::open(file.path())?;
+    for (i, k) in keys.iter().enumerate() {
+        tree.insert(k.clone(), i as u64)?;
+    }
+    tree.commit()?;
+    let reclaimable = tree.reclaimable_bytes();
+
+    tree.clear()?;
+    assert_eq!(tree.root_hash(), [0u8; 32]);
+    assert!(keys.iter().all(|k| !tree.contains(k).unwrap()));
+    assert!(tree.iter()?.next().is_none());
+
+    let hash = tree.commit()?.hash;
+    assert_eq!(hash, [0u8; 32]);
+    assert_eq!(tree.reclaimable_bytes(), reclaimable);
+    let len = std::fs::metadata(file.path())?.len();
+    drop(tree);
+
+    let tree: MerkleSearchTree<String, u64> = MerkleSearchTree::open(file.path())?;
+    assert_eq!(tree.root_hash(), [0u8; 32]);
+    assert!(!tree.contains(&keys[0])?);
+
+    tree.insert(keys[0].clone(), 7)?;
+    tree.commit()?;
+    assert!(std::fs::metadata(file.path())?.len() > len);
+    Ok(())
+}
+
+#[test]
+fn integer_keys_iterate_in_numeric_order() -> io::Result<()> {
+    fn check<K>(mut keys: Vec<K>) -> io::Result<()>
+    where
+        K: MerkleKey + Copy,
+    {
+        let mut sorted = keys.clone();
+        sorted.sort();
+        keys.shuffle(&mut StdRng::seed_from_u64(17));
+
+        let tree = MerkleSearchTree::new_temporary()?;
+        for &k in &keys {
+            tree.insert(k, ())?;
+        }
+        tree.commit()?;
+
+        let got: Vec<K> = tree
+            .iter()?
+            .map(|e| e.map(|(k, _)| *k))
+            .collect::<Result<_>>()?;
+        assert_eq!(got, sorted);
+
+        let (lo, hi) = (sorted[10], sorted[sorted.len() - 10]);
+        let got: Vec<K> = tree
+            .range(lo..hi)?
+            .map(|e| e.map(|(k, _)| *k))
+            .collect::<Result<_>>()?;
+        assert_eq!(got, sorted[10..sorted.len() - 10]);
+        Ok(())
+    }
+
+    // Values whose little-endian or varint encodings sort differently from numbers.
+    check((0..600u64).map(|i| i * 257).chain([u64::MAX, 1 << 32]).collect())?;
+    check((0..600u32).map(|i| i * 131).chain([u32::MAX]).collect())?;
+    check((-300..300i64).map(|i| i * 1021).chain([i64::MIN, i64::MAX]).collect())?;
+    check((-300..300i32).map(|i| i * 7).chain([i32::MIN, i32::MAX]).collect())?;
+    Ok(())
+}
+
+#[test]
+fn verify_on_read_detects_corrupted_values() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let keys = generate_keys(200, 19);
+
+    let tree = MerkleSearchTree::open(file.path())?;
+    for k in &keys {
+        tree.insert(k.clone(), format!("value of {k}"))?;
+    }
+    tree.insert("target".to_string(), "UNTOUCHED-MARKER".to_string())?;
+    tree.commit()?;
+    drop(tree);
+
+    let tree: MerkleSearchTree<String, String> =
+        MerkleSearchTree::open_with_verification(file.path())?;
+    for k in &keys {
+        assert!(tree.contains(k)?);
+    }
+    drop(tree);
+
+    // Flip one letter of the marker, keeping the node decodable.
+    let mut bytes = std::fs::read(file.path())?;
+    let pos = bytes
+        .windows(16)
+        .position(|w| w == b"UNTOUCHED-MARKER")
+        .expect("marker is on disk");
+    bytes[pos] = b'X';
+    std::fs::write(file.path(), bytes)?;
+
+    let tree: MerkleSearchTree<String, String> = MerkleSearchTree::open(file.path())?;
+    assert_eq!(tree.get("target")?.as_deref().map(String::as_str), Some("XNTOUCHED-MARKER"));
+
+    let tree: MerkleSearchTree<String, String> =
+        MerkleSearchTree::open_with_verification(file.path())?;
+    let err = tree.get("target").unwrap_err();
+    assert!(matches!(err, Error::Corrupt(_)), "{err}");
+    Ok(())
+}
+
+#[test]
+fn scan_prefix_matches_filtering() -> io::Result<()> {
+    use std::ops::Bound;
+
+    let mut rng = StdRng::seed_from_u64(20);
+    let mut keys: Vec<Vec<u8>> = (0..2000)
+        .map(|_| {
+            let len = rng.random_range(0..4);
+            // Few distinct bytes, so prefixes are shared and 0xFF runs are common.
+            (0..len)
+                .map(|_| [0x00, 0x01, 0x7F, 0xFE, 0xFF][rng.random_range(0..5)])
+                .collect()
+        })
+        .collect();
+    keys.sort();
+    keys.dedup();
+
+    let tree = MerkleSearchTree::new_temporary()?;
+    for k in &keys {
+        tree.insert(k.clone(), k.len())?;
+    }
+    tree.commit()?;
+
+    let prefixes: [&[u8]; 7] = [
+        &[],
+        &[0x01],
+        &[0xFF],
+        &[0xFF, 0xFF],
+        &[0x01, 0xFF],
+        &[0xFE],
+        &[0x02],
+    ];
+    for prefix in prefixes {
+        let got: Vec<Vec<u8>> = tree
+            .scan_prefix(prefix)?
+            .map(|e| e.map(|(k, _)| (*k).clone()))
+            .collect::<Result<_>>()?;
+        let want: Vec<Vec<u8>> = keys
+            .iter()
+            .filter(|k| k.starts_with(prefix))
+            .cloned()
+            .collect();
+        assert_eq!(got, want, "prefix {prefix:?}");
+    }
+
+    let tree = MerkleSearchTree::new_temporary()?;
+    for k in generate_keys(500, 20) {
+        tree.insert(k, ())?;
+    }
+    for entry in tree.scan_prefix(b"key-a")? {
+        assert!(entry?.0.starts_with("key-a"));
+    }
+    let bounds = (Bound::Included("key-a"), Bound::Excluded("key-b"));
+    let expected = tree.range::<str, _>(bounds)?.count();
+    assert!(expected > 0);
+    assert_eq!(tree.scan_prefix(b"key-a")?.count(), expected);
+    Ok(())
+}
+
+#[test]
+fn recent_snapshots_do_not_block_reuse() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let keys = generate_keys(500, 21);
+
+    let tree = MerkleSearchTree::open(file.path())?;
+    for (i, k) in keys.iter().enumerate() {
+        tree.insert(k.clone(), i as u64)?;
+    }
+    tree.commit()?;
+
+    // Always keep a snapshot of the latest commit alive, as a reader would.
+    let mut snapshot = tree.snapshot();
+    let mut sizes = Vec::new();
+    for round in 1..=20u64 {
+        for k in keys.iter().step_by(50) {
+            tree.insert(k.clone(), round)?;
+        }
+        tree.commit()?;
+        assert_eq!(snapshot.get(&keys[0])?.as_deref(), Some(&(round - 1)));
+        snapshot = tree.snapshot();
+        sizes.push(std::fs::metadata(file.path())?.len());
+    }
+
+    let early_growth = sizes[4] - sizes[0];
+    let late_growth = sizes[19] - sizes[15];
+    assert!(late_growth < early_growth, "file kept growing: {sizes:?}");
+    Ok(())
+}
+
+#[test]
+fn stats_report_shape_and_dirtiness() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let keys = generate_keys(1000, 22);
+
+    let tree = MerkleSearchTree::open_with_cache_capacity(file.path(), 8)?;
+    assert_eq!(tree.stats()?, TreeStats::default());
+    assert_eq!(tree.stats()?.avg_keys_per_node(), 0.0);
+
+    for (i, k) in keys.iter().enumerate() {
+        tree.insert(k.clone(), i)?;
+    }
+    let dirty = tree.stats()?;
+    assert_eq!(dirty.key_count, 1000);
+    assert_eq!(dirty.loaded_nodes, dirty.node_count);
+    assert_eq!(dirty.disk_nodes, 0);
+    assert!(dirty.height > 1);
+    assert!(dirty.avg_keys_per_node() > 1.0);
+
+    tree.commit()?;
+    let clean = tree.stats()?;
+    assert_eq!(clean.loaded_nodes, 0);
+    assert_eq!(clean.disk_nodes, dirty.node_count);
+    assert_eq!((clean.height, clean.key_count), (dirty.height, dirty.key_count));
+    assert!(tree.store.cached_nodes() <= 8);
+
+    tree.insert(keys[0].clone(), 1000)?;
+    // Only the path down to the updated key is dirty.
+    let touched = tree.stats()?;
+    assert!(touched.loaded_nodes >= 1 && touched.loaded_nodes as usize <= touched.height);
+    Ok(())
+}
+
+#[test]
+fn walk_visits_every_node_in_pre_order() -> io::Result<()> {
+    use std::sync::Arc;
+
+    /// Records the level of each visited node and its keys, going no deeper than
+    /// `below`.
+    struct Collect {
+        below: u32,
+        levels: Vec<u32>,
+        keys: Vec<String>,
+    }
+
+    impl NodeVisitor<String, usize> for Collect {
+        fn visit_node(
+            &mut self,
+            level: u32,
+            keys: &[Arc<String>],
+            values: &[Arc<usize>],
+            child_hashes: &[Hash],
+        ) -> Visit {
+            assert_eq!(keys.len(), values.len());
+            assert!(child_hashes.is_empty() || child_hashes.len() == keys.len() + 1);
+            self.levels.push(level);
+            self.keys.extend(keys.iter().map(|k| k.to_string()));
+            if level > self.below { Visit::Descend } else { Visit::Skip }
+        }
+    }
+
+    let tree = MerkleSearchTree::new_temporary()?;
+    let mut keys = generate_keys(1000, 23);
+    tree.insert_many(keys.iter().cloned().enumerate().map(|(i, k)| (k, i)))?;
+    tree.commit()?;
+    tree.insert(keys[0].clone(), 1000)?;
+
+    let mut all = Collect { below: 0, levels: Vec::new(), keys: Vec::new() };
+    tree.walk(&mut all)?;
+    let stats = tree.stats()?;
+    assert_eq!(all.levels.len() as u64, stats.node_count);
+    // The root, the highest node, comes first.
+    assert_eq!(all.levels[0], all.levels.iter().copied().max().unwrap());
+    all.keys.sort();
+    keys.sort();
+    assert_eq!(all.keys, keys);
+
+    let root_level = all.levels[0];
+    let mut root_only = Collect { below: root_level, levels: Vec::new(), keys: Vec::new() };
+    tree.walk(&mut root_only)?;
+    assert_eq!(root_only.levels, [root_level]);
+    Ok(())
+}
+
+#[test]
+fn reopening_with_other_types_is_rejected() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let tree: MerkleSearchTree<String, i32> = MerkleSearchTree::open(file.path())?;
+    tree.insert("a".to_string(), 1)?;
+    tree.commit()?;
+    drop(tree);
+
+    let err = MerkleSearchTree::<String, String>::open(file.path()).err().unwrap();
+    assert!(matches!(err, Error::SchemaMismatch { found, expected } if found != expected));
+    assert!(err.to_string().contains("schema mismatch"), "{err}");
+    assert!(MerkleSearchTree::<String, i32>::open_read_only(file.path()).is_ok());
+
+    // Files from before schemas were recorded open with any types.
+    let mut bytes = std::fs::read(file.path())?;
+    bytes[16..24].fill(0);
+    std::fs::write(file.path(), bytes)?;
+    assert!(MerkleSearchTree::<String, String>::open_read_only(file.path()).is_ok());
+
+    // An explicit schema id replaces the type fingerprint.
+    let file = tempfile::NamedTempFile::new()?;
+    let tree: MerkleSearchTree<String, i32> =
+        MerkleSearchTree::open_with_schema_id(file.path(), 42)?;
+    tree.insert("a".to_string(), 1)?;
+    tree.commit()?;
+    drop(tree);
+
+    let tree: MerkleSearchTree<String, i32> =
+        MerkleSearchTree::open_with_schema_id(file.path(), 42)?;
+    assert_eq!(tree.get("a")?.as_deref(), Some(&1));
+    assert!(MerkleSearchTree::<String, i32>::open(file.path()).is_err());
+    assert!(MerkleSearchTree::<String, i32>::open_with_schema_id(file.path(), 43).is_err());
+    let err = MerkleSearchTree::<String, i32>::open_with_schema_id(file.path(), 0).err();
+    assert_eq!(err.unwrap().kind(), io::ErrorKind::InvalidInput);
+    Ok(())
+}
+
+#[test]
+fn count_range_matches_filtering() -> io::Result<()> {
+    use std::ops::{Bound, RangeBounds};
+
+    let file = tempfile::NamedTempFile::new()?;
+    let tree: MerkleSearchTree<u32, u32> = MerkleSearchTree::open(file.path())?;
+    assert_eq!(tree.len()?, 0);
+    assert!(tree.is_empty()?);
+    for i in 0..2_000u32 {
+        tree.insert(i * 3, i)?;
+    }
+    assert_eq!(tree.len()?, 2_000);
+    assert!(!tree.is_empty()?);
+    tree.commit()?;
+    tree.remove(&300)?;
+    drop(tree);
+
+    let tree: MerkleSearchTree<u32, u32> = MerkleSearchTree::open(file.path())?;
+    assert_eq!(tree.len()?, 2_000);
+    // The size of the whole tree is recorded in the root.
+    assert_eq!(tree.store.cached_nodes(), 1);
+
+    let keys: Vec<u32> = (0..2_000).map(|i| i * 3).collect();
+    let bounds = [
+        Bound::Unbounded,
+        Bound::Included(0),
+        Bound::Excluded(299),
+        Bound::Included(300),
+        Bound::Excluded(300),
+        Bound::Included(5_997),
+        Bound::Excluded(7_000),
+    ];
+    for &start in &bounds {
+        for &end in &bounds {
+            let range: (Bound<u32>, Bound<u32>) = (start, end);
+            let expected = keys.iter().filter(|&k| range.contains(k)).count() as u64;
+            assert_eq!(tree.count_range(range)?, expected, "{range:?}");
+        }
+    }
+    assert_eq!(tree.count_range(30..=60)?, 11);
+    Ok(())
+}
+
+#[test]
+fn older_format_versions_stay_readable_and_writable() -> io::Result<()> {
+    for version in [1u32, 2, 3, 4, 5, 6, 7, 8, 9] {
+        // A fresh file of that version: header without schema, no committed root yet.
+        let file = tempfile::NamedTempFile::new()?;
+        let mut header = vec![0u8; DEFAULT_PAGE_SIZE as usize];
+        header[0..8].copy_from_slice(b"FILEMST\0");
+        header[8..12].copy_from_slice(&version.to_le_bytes());
+        header[12..16].copy_from_slice(&(DEFAULT_PAGE_SIZE as u32).to_le_bytes());
+        if version >= 6 {
+            header[24..28].copy_from_slice(&DEFAULT_FANOUT.to_le_bytes());
+        }
+        std::fs::write(file.path(), header)?;
+
+        let tree: MerkleSearchTree<u32, u32> = MerkleSearchTree::open(file.path())?;
+        for i in 0..500 {
+            tree.insert(i, i)?;
+        }
+        tree.commit()?;
+        drop(tree);
+
+        let mut tree: MerkleSearchTree<u32, u32> = MerkleSearchTree::open(file.path())?;
+        assert_eq!(tree.get(&42)?.as_deref(), Some(&42));
+        assert_eq!(tree.len()?, 500);
+        assert_eq!(tree.count_range(100..200)?, 100);
+        tree.insert(1_000, 0)?;
+        tree.commit()?;
+        assert_eq!(tree.len()?, 501);
+        let root_hash = tree.root_hash();
+
+        // Compaction writes the current format.
+        let compacted = tempfile::NamedTempFile::new()?;
+        tree.compact(compacted.path())?;
+        drop(tree);
+        let current = store::FORMAT_VERSION.to_le_bytes();
+        assert_eq!(std::fs::read(compacted.path())?[8..12], current);
+
+        let tree: MerkleSearchTree<u32, u32> = MerkleSearchTree::open(compacted.path())?;
+        assert_eq!(tree.root_hash(), root_hash);
+        assert_eq!(tree.len()?, 501);
+        assert_eq!(tree.store.cached_nodes(), 1);
+    }
+    Ok(())
+}
+
+#[test]
+fn front_coded_keys_shrink_nodes_without_changing_hashes() -> io::Result<()> {
+    // Time-ordered UUIDs share most of their leading characters.
+    let keys: Vec<String> = (0..16)
+        .map(|i| format!("018f4c7a-3b2e-7{i:03x}-9c41-5e2f8a6d{:04x}", i * 7))
+        .collect();
+    let fanout = 256u32;
+
+    // The same keys in a file of format version 7, which stores them in full.
+    let old_file = tempfile::NamedTempFile::new()?;
+    let mut header = vec![0u8; DEFAULT_PAGE_SIZE as usize];
+    header[0..8].copy_from_slice(b"FILEMST\0");
+    header[8..12].copy_from_slice(&7u32.to_le_bytes());
+    header[12..16].copy_from_slice(&(DEFAULT_PAGE_SIZE as u32).to_le_bytes());
+    header[24..28].copy_from_slice(&fanout.to_le_bytes());
+    std::fs::write(old_file.path(), header)?;
+    let old: MerkleSearchTree<String, u32> = MerkleSearchTree::open(old_file.path())?;
+
+    let file = tempfile::NamedTempFile::new()?;
+    let tree: MerkleSearchTree<String, u32> = OpenOptions::new().fanout(fanout).open(file.path())?;
+    for (i, key) in keys.iter().enumerate() {
+        old.insert(key.clone(), i as u32)?;
+        tree.insert(key.clone(), i as u32)?;
+    }
+    let old_root = old.commit()?;
+    let root = tree.commit()?;
+    assert_eq!(root.hash, old_root.hash);
+    // Every key fits in the root at this fan-out.
+    assert_eq!(tree.store.load_keys(root.offset, root.hash)?.keys.len(), 16);
+    let (len, old_len) = (
+        tree.store.record_len(root.offset)?,
+        old.store.record_len(old_root.offset)?,
+    );
+    // Each key after the first shares its length and 17 characters with the one
+    // before. Every key, the first included, spends two bytes on the lengths of the
+    // shared prefix and of the rest.
+    assert!(len + 15 * 18 - 16 * 2 <= old_len, "{len} vs {old_len}");
+    drop(tree);
+
+    let tree: MerkleSearchTree<String, u32> = MerkleSearchTree::open(file.path())?;
+    for (i, key) in keys.iter().enumerate() {
+        assert_eq!(tree.get(key)?.as_deref(), Some(&(i as u32)));
+    }
+    assert!(!tree.contains("018f4c7a-3b2e-7000-9c41-5e2f8a6d0001")?);
+    let listed: Vec<String> = tree.keys()?.map(|k| Ok((*k?).clone())).collect::<Result<_>>()?;
+    assert_eq!(listed, keys);
+    Ok(())
+}
+
+#[test]
+fn transaction_applies_all_ops_in_one_commit() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let tree: MerkleSearchTree<String, String> = MerkleSearchTree::open(file.path())?;
+    tree.insert("a".to_string(), "1".to_string())?;
+    tree.insert("b".to_string(), "2".to_string())?;
+    tree.commit()?;
+
+    let ops = vec![
+        Op::Insert("c".to_string(), "3".to_string()),
+        Op::Remove("a".to_string()),
+        Op::Insert("a".to_string(), "4".to_string()),
+        Op::Remove("b".to_string()),
+        Op::Remove("missing".to_string()),
+    ];
+    let hash = tree.transaction(ops)?.hash;
+    drop(tree);
+
+    let tree: MerkleSearchTree<String, String> = MerkleSearchTree::open(file.path())?;
+    assert_eq!(tree.root_hash(), hash);
+    assert_eq!(tree.get("a")?.as_deref().map(String::as_str), Some("4"));
+    assert!(!tree.contains("b")?);
+    assert_eq!(tree.get("c")?.as_deref().map(String::as_str), Some("3"));
+    Ok(())
+}
+
+#[test]
+fn is_dirty_tracks_changes_since_the_last_commit() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let tree = MerkleSearchTree::<String, u32>::open(file.path())?;
+    assert!(!tree.is_dirty());
+    tree.insert("a".to_string(), 1)?;
+    assert!(tree.is_dirty());
+    tree.commit()?;
+    assert!(!tree.is_dirty());
+
+    tree.insert("a".to_string(), 1)?;
+    assert!(!tree.is_dirty());
+    tree.insert("b".to_string(), 2)?;
+    tree.remove("b")?;
+    assert!(!tree.is_dirty());
+    tree.remove("a")?;
+    assert!(tree.is_dirty());
+    drop(tree);
+
+    let tree = MerkleSearchTree::<String, u32>::open(file.path())?;
+    assert!(!tree.is_dirty());
+    assert_eq!(tree.get("a")?.as_deref(), Some(&1));
+    Ok(())
+}
+
+#[test]
+fn failed_transaction_leaves_the_tree_unchanged() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let tree = MerkleSearchTree::open(file.path())?;
+    for k in generate_keys(200, 23) {
+        tree.insert(k.clone(), format!("value of {k}"))?;
+    }
+    tree.insert("target".to_string(), "UNTOUCHED-MARKER".to_string())?;
+    tree.commit()?;
+    drop(tree);
+
+    let mut bytes = std::fs::read(file.path())?;
+    let pos = bytes
+        .windows(16)
+        .position(|w| w == b"UNTOUCHED-MARKER")
+        .expect("marker is on disk");
+    bytes[pos] = b'X';
+    std::fs::write(file.path(), bytes)?;
+
+    let tree: MerkleSearchTree<String, String> =
+        MerkleSearchTree::open_with_verification(file.path())?;
+    let root_hash = tree.root_hash();
+    let ops = vec![
+        Op::Insert("new".to_string(), "value".to_string()),
+        Op::Remove("target".to_string()),
+    ];
+    let err = tree.transaction(ops).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(tree.root_hash(), root_hash);
+    drop(tree);
+
+    let tree: MerkleSearchTree<String, String> = MerkleSearchTree::open(file.path())?;
+    assert_eq!(tree.root_hash(), root_hash);
+    assert!(!tree.contains("new")?);
+    Ok(())
+}
+
+#[test]
+fn with_value_borrows_the_stored_value() -> io::Result<()> {
+    let tree = MerkleSearchTree::new_temporary()?;
+    for (i, k) in generate_keys(300, 29).into_iter().enumerate() {
+        tree.insert(k, vec![7u8; i])?;
+    }
+    tree.insert("blob".to_string(), vec![1u8; 4096])?;
+    tree.commit()?;
+
+    assert_eq!(tree.with_value("blob", Vec::len)?, Some(4096));
+    assert_eq!(tree.with_value("blob", |v| v[..2].to_vec())?, Some(vec![1, 1]));
+    assert_eq!(tree.with_value("missing", Vec::len)?, None);
+    for k in generate_keys(300, 29) {
+        let expected = tree.get(&k)?.map(|v| v.len());
+        assert_eq!(tree.with_value(&k, Vec::len)?, expected);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_nodes_shrink_the_file() -> io::Result<()> {
+    let keys = generate_keys(500, 31);
+    let write = |compression| -> io::Result<u64> {
+        let file = tempfile::NamedTempFile::new()?;
+        let tree = MerkleSearchTree::open_with_compression(file.path(), compression)?;
+        for k in &keys {
+            tree.insert(k.clone(), "abcd".repeat(64))?;
+        }
+        tree.commit()?;
+        drop(tree);
+
+        // Reading does not depend on the codec the tree is opened with.
+        let tree: MerkleSearchTree<String, String> = MerkleSearchTree::open(file.path())?;
+        for k in &keys {
+            assert_eq!(tree.get(k)?.as_deref(), Some(&"abcd".repeat(64)));
+        }
+        Ok(std::fs::metadata(file.path())?.len())
+    };
+
+    let plain = write(Compression::None)?;
+    let compressed = write(Compression::Zstd(0))?;
+    assert!(compressed * 3 < plain, "{compressed} vs {plain} bytes");
+    Ok(())
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_and_plain_nodes_mix_in_one_file() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let tree = MerkleSearchTree::open(file.path())?;
+    for i in 0..300u32 {
+        tree.insert(i, vec![0u8; 100])?;
+    }
+    tree.commit()?;
+    drop(tree);
+
+    let tree = MerkleSearchTree::open_with_compression(file.path(), Compression::Zstd(3))?;
+    for i in 300..600u32 {
+        tree.insert(i, vec![1u8; 100])?;
+    }
+    let hash = tree.commit()?.hash;
+    drop(tree);
+
+    let tree: MerkleSearchTree<u32, Vec<u8>> = MerkleSearchTree::open(file.path())?;
+    assert_eq!(tree.root_hash(), hash);
+    for i in 0..600u32 {
+        let fill = u8::from(i >= 300);
+        assert_eq!(tree.get(&i)?.as_deref(), Some(&vec![fill; 100]));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn mapped_reads_follow_the_file_as_it_grows() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let keys = generate_keys(2000, 61);
+    let tree = MerkleSearchTree::<String, u64>::builder()
+        .mmap(true)
+        .cache_capacity(4)
+        .open(file.path())?;
+    for (i, key) in keys[..1000].iter().enumerate() {
+        tree.insert(key.clone(), i as u64)?;
+    }
+    tree.commit()?;
+
+    // Nodes written after the file was mapped are read through a new map.
+    for (i, key) in keys[1000..].iter().enumerate() {
+        tree.insert(key.clone(), 1000 + i as u64)?;
+    }
+    let hash = tree.commit()?.hash;
+    for (i, key) in keys.iter().enumerate() {
+        assert_eq!(tree.get(key)?.as_deref(), Some(&(i as u64)));
+    }
+    assert_eq!(tree.keys()?.count(), 2000);
+    assert!(tree.verify()?.is_ok());
+    drop(tree);
+
+    let tree = MerkleSearchTree::<String, u64>::builder()
+        .mmap(true)
+        .verify_on_read(true)
+        .open(file.path())?;
+    assert_eq!(tree.root_hash(), hash);
+    assert_eq!(tree.iter()?.count(), 2000);
+    Ok(())
+}
+
+#[test]
+fn crash_before_the_root_pointer_keeps_the_previous_root() -> io::Result<()> {
+    use std::sync::atomic::Ordering;
+
+    let file = tempfile::NamedTempFile::new()?;
+    let keys = generate_keys(300, 37);
+    let tree = MerkleSearchTree::open(file.path())?;
+    for k in &keys[..200] {
+        tree.insert(k.clone(), k.len())?;
+    }
+    let committed = tree.commit()?.hash;
+    let committed_len = std::fs::metadata(file.path())?.len();
+
+    // The next commit syncs its nodes, then "crashes" before the metadata lands.
+    for k in &keys[200..] {
+        tree.insert(k.clone(), k.len())?;
+    }
+    tree.store.drop_metadata_writes.store(true, Ordering::Relaxed);
+    tree.commit()?;
+    drop(tree);
+    assert!(std::fs::metadata(file.path())?.len() > committed_len);
+
+    let tree: MerkleSearchTree<String, usize> = MerkleSearchTree::open(file.path())?;
+    assert_eq!(tree.root_hash(), committed);
+    for k in &keys[..200] {
+        assert_eq!(tree.get(k)?.as_deref(), Some(&k.len()));
+    }
+    assert!(!tree.contains(&keys[250])?);
+    Ok(())
+}
+
+#[test]
+fn write_buffer_settings_keep_commits_visible_and_durable() -> io::Result<()> {
+    let keys = generate_keys(600, 63);
+    for (capacity, direct) in [(0, true), (0, false), (1 << 20, true), (512, false)] {
+        let file = tempfile::NamedTempFile::new()?;
+        let tree = MerkleSearchTree::<String, usize>::builder()
+            .write_buffer_capacity(capacity)
+            .direct_metadata_writes(direct)
+            .sync_policy(SyncPolicy::Never)
+            .open(file.path())?;
+        let mut hashes = Vec::new();
+        for chunk in keys.chunks(200) {
+            tree.insert_many(chunk.iter().map(|k| (k.clone(), k.len())))?;
+            hashes.push(tree.commit()?.hash);
+            // Another handle sees each commit while this one is still open.
+            let other: MerkleSearchTree<String, usize> = MerkleSearchTree::open(file.path())?;
+            assert_eq!(other.root_hash(), *hashes.last().unwrap());
+        }
+        drop(tree);
+
+        let tree: MerkleSearchTree<String, usize> = MerkleSearchTree::open(file.path())?;
+        assert_eq!(tree.root_hash(), hashes[2]);
+        assert_eq!(tree.len()?, 600);
+        assert!(tree.verify()?.is_ok());
+    }
+    Ok(())
+}
+
+#[test]
+fn exported_nodes_rebuild_the_tree() -> io::Result<()> {
+    use std::collections::HashSet;
+
+    let keys = generate_keys(1_000, 41);
+    let source = MerkleSearchTree::new_temporary()?;
+    for k in &keys {
+        source.insert(k.clone(), k.len() as u64)?;
+    }
+    source.commit()?;
+
+    let file = tempfile::NamedTempFile::new()?;
+    let replica: MerkleSearchTree<String, u64> = MerkleSearchTree::open(file.path())?;
+    let nodes = source.export_nodes()?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(nodes.last().map(|(hash, _)| *hash), Some(source.root_hash()));
+    replica.import_nodes(source.root_hash(), nodes.clone())?;
+    assert_eq!(replica.root_hash(), source.root_hash());
+    replica.commit()?;
+    drop(replica);
+
+    let replica: MerkleSearchTree<String, u64> = MerkleSearchTree::open(file.path())?;
+    assert_eq!(replica.root_hash(), source.root_hash());
+    assert_eq!(replica.len()?, 1_000);
+    for k in &keys {
+        assert_eq!(replica.get(k)?.as_deref(), Some(&(k.len() as u64)));
+    }
+
+    // After a few changes, only nodes the replica lacks need to be sent.
+    let known: HashSet<_> = nodes.iter().map(|(hash, _)| *hash).collect();
+    for k in &keys[..5] {
+        source.insert(k.clone(), 0)?;
+    }
+    source.remove(&keys[5])?;
+    let changed: Vec<_> = source
+        .export_nodes()?
+        .filter(|node| node.as_ref().is_ok_and(|(hash, _)| !known.contains(hash)))
+        .collect::<Result<_>>()?;
+    assert!(changed.len() < nodes.len() / 4, "{} of {}", changed.len(), nodes.len());
+    replica.import_nodes(source.root_hash(), changed)?;
+    assert_eq!(replica.root_hash(), source.root_hash());
+    replica.commit()?;
+    assert_eq!(replica.get(&keys[0])?.as_deref(), Some(&0));
+    assert!(!replica.contains(&keys[5])?);
+    assert_eq!(replica.get(&keys[6])?.as_deref(), Some(&(keys[6].len() as u64)));
+    Ok(())
+}
+
+#[test]
+fn importing_corrupt_or_incomplete_nodes_fails() -> io::Result<()> {
+    let source = MerkleSearchTree::new_temporary()?;
+    for k in generate_keys(300, 43) {
+        source.insert(k, 1u8)?;
+    }
+    let mut nodes = source.export_nodes()?.collect::<Result<Vec<_>>>()?;
+    let replica: MerkleSearchTree<String, u8> = MerkleSearchTree::new_temporary()?;
+    replica.insert("local".to_string(), 2)?;
+    let before = replica.root_hash();
+
+    let missing = nodes[1..].to_vec();
+    let err = replica.import_nodes(source.root_hash(), missing).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    let (_, bytes) = nodes.last_mut().unwrap();
+    let last = bytes.len() - 33;
+    bytes[last] ^= 1;
+    let err = replica.import_nodes(source.root_hash(), nodes).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(replica.root_hash(), before);
+    Ok(())
+}
+
+#[test]
+fn writes_from_several_threads_are_serialized() -> io::Result<()> {
+    let tree: MerkleSearchTree<u32, u32> = MerkleSearchTree::new_temporary()?;
+    std::thread::scope(|scope| {
+        for t in 0..4u32 {
+            let tree = &tree;
+            scope.spawn(move || {
+                for i in 0..250 {
+                    tree.insert(t * 1_000 + i, i).unwrap();
+                    if i % 50 == 0 {
+                        tree.commit().unwrap();
+                    }
+                }
+            });
+        }
+        let tree = &tree;
+        scope.spawn(move || {
+            for i in 0..250 {
+                // A key is either absent or fully written.
+                if let Some(value) = tree.get(&i).unwrap() {
+                    assert_eq!(*value, i);
+                }
+            }
+        });
+    });
+    tree.commit()?;
+
+    let sequential = MerkleSearchTree::new_temporary()?;
+    for t in 0..4u32 {
+        for i in 0..250 {
+            sequential.insert(t * 1_000 + i, i)?;
+        }
+    }
+    assert_eq!(tree.len()?, 1_000);
+    assert_eq!(tree.root_hash(), sequential.root_hash());
+    Ok(())
+}
+
+#[test]
+fn sync_policy_decides_which_commits_are_synced() -> io::Result<()> {
+    use std::sync::atomic::Ordering;
+
+    let policies = [
+        (SyncPolicy::Always, 6),
+        (SyncPolicy::Never, 0),
+        (SyncPolicy::EveryN { n: 3 }, 2),
+    ];
+    for (policy, synced_commits) in policies {
+        let file = tempfile::NamedTempFile::new()?;
+        let tree = MerkleSearchTree::open_with_sync_policy(file.path(), policy)?;
+        for i in 0..6u32 {
+            tree.insert(i, i)?;
+            tree.commit()?;
+        }
+        // Unchanged trees are not committed again.
+        tree.commit()?;
+        // Each synced commit syncs its nodes, then its root pointer.
+        assert_eq!(tree.store.syncs.load(Ordering::Relaxed), 2 * synced_commits, "{policy:?}");
+        drop(tree);
+
+        let tree: MerkleSearchTree<u32, u32> = MerkleSearchTree::open(file.path())?;
+        assert_eq!(tree.len()?, 6);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn concurrent_group_commits_share_syncs() -> io::Result<()> {
+    use std::collections::HashSet;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    let tree: MerkleSearchTree<u64, u64> = OpenOptions::new()
+        .group_commit_delay(Duration::from_millis(50))
+        .create_temporary()?;
+    let store = tree.store.clone();
+    let tree = AsyncMerkleSearchTree::from(tree);
+    let writers = 64;
+    let tasks: Vec<_> = (0..writers)
+        .map(|i| {
+            let tree = tree.clone();
+            tokio::spawn(async move {
+                tree.insert(i, i).await?;
+                tree.commit_group().await
+            })
+        })
+        .collect();
+    let mut versions = HashSet::new();
+    for task in tasks {
+        versions.insert(task.await.unwrap()?);
+    }
+
+    // Each commit syncs its nodes, then its root pointer.
+    let syncs = store.syncs.load(Ordering::Relaxed);
+    assert_eq!(syncs, 2 * versions.len() as u64);
+    assert!(syncs < writers / 4, "{syncs} syncs for {writers} writers");
+    // The last group took in every insert.
+    let last = *versions.iter().max().unwrap();
+    assert_eq!(tree.commit().await?, last);
+    for i in 0..writers {
+        assert_eq!(tree.get(i).await?.as_deref(), Some(&i));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn group_commit_delay_keeps_running_other_commands() -> io::Result<()> {
+    use std::time::{Duration, Instant};
+
+    let delay = Duration::from_secs(2);
+    let tree: MerkleSearchTree<u64, u64> =
+        OpenOptions::new().group_commit_delay(delay).create_temporary()?;
+    let tree = AsyncMerkleSearchTree::from(tree);
+    let start = Instant::now();
+    let group = tokio::spawn({
+        let tree = tree.clone();
+        async move { tree.commit_group().await }
+    });
+    tree.insert(1, 1).await?;
+    assert_eq!(tree.get(1).await?.as_deref(), Some(&1));
+    assert!(start.elapsed() < delay / 2, "commands waited for the group");
+    assert!(!group.is_finished());
+    // The insert made during the delay is part of the group's commit.
+    let version = group.await.unwrap()?;
+    assert!(start.elapsed() >= delay);
+    assert_eq!(tree.commit().await?, version);
+    Ok(())
+}
+
+#[tokio::test]
+async fn failed_group_commit_reports_the_same_error_to_every_call() -> io::Result<()> {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    let tree: MerkleSearchTree<u64, u64> = OpenOptions::new()
+        .group_commit_delay(Duration::from_millis(200))
+        .create_temporary()?;
+    tree.store.node_writes_until_failure.store(1, Ordering::Relaxed);
+    let tree = AsyncMerkleSearchTree::from(tree);
+    tree.insert(1, 1).await?;
+    let calls: Vec<_> = (0..4)
+        .map(|_| {
+            let tree = tree.clone();
+            tokio::spawn(async move { tree.commit_group().await })
+        })
+        .collect();
+    for call in calls {
+        match call.await.unwrap() {
+            Err(Error::Io(err)) => {
+                assert_eq!(err.kind(), io::ErrorKind::Other);
+                assert_eq!(err.to_string(), "simulated write failure");
+            }
+            other => panic!("unexpected result {other:?}"),
+        }
+    }
+    // The failure was injected once; the retry goes through.
+    tree.commit_group().await?;
+    assert_eq!(tree.get(1).await?.as_deref(), Some(&1));
+    Ok(())
+}
+
+#[test]
+fn keys_skip_values_and_the_cache() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let mut keys = generate_keys(2000, 64);
+    let tree = MerkleSearchTree::open(file.path())?;
+    for k in &keys[..1000] {
+        tree.insert(k.clone(), vec![0u8; 100])?;
+    }
+    tree.commit()?;
+    // Uncommitted nodes are listed too.
+    for k in &keys[1000..] {
+        tree.insert(k.clone(), vec![1u8; 100])?;
+    }
+    keys.sort();
+    let listed: Vec<_> = tree.keys()?.collect::<Result<_>>()?;
+    assert!(listed.iter().map(|k| k.as_str()).eq(keys.iter().map(String::as_str)));
+    tree.commit()?;
+    drop(tree);
+
+    let tree: MerkleSearchTree<String, Vec<u8>> = MerkleSearchTree::open(file.path())?;
+    let listed: Vec<_> = tree.keys()?.collect::<Result<_>>()?;
+    assert!(listed.iter().map(|k| k.as_str()).eq(keys.iter().map(String::as_str)));
+    assert_eq!(tree.store.cached_nodes(), 0);
+    assert!(tree.cache_metrics().misses > 0);
+
+    // Cached nodes are used as they are.
+    tree.get(&keys[0])?;
+    let hits = tree.cache_metrics().hits;
+    assert_eq!(tree.keys()?.count(), keys.len());
+    assert!(tree.cache_metrics().hits > hits);
+
+    let tree = MerkleSearchTree::<String, Vec<u8>>::open_with_verification(file.path())?;
+    assert_eq!(tree.keys()?.count(), keys.len());
+    assert!(MerkleSearchTree::<String, Vec<u8>>::new_temporary()?.keys()?.next().is_none());
+    Ok(())
+}
+
+#[test]
+fn retain_matches_individual_removals() -> io::Result<()> {
+    let keys = generate_keys(2000, 65);
+    let file = tempfile::NamedTempFile::new()?;
+    let tree = MerkleSearchTree::open(file.path())?;
+    let expected = MerkleSearchTree::new_temporary()?;
+    for (i, k) in keys.iter().enumerate() {
+        tree.insert(k.clone(), i as u64)?;
+        expected.insert(k.clone(), i as u64)?;
+    }
+    tree.commit()?;
+    let tree: MerkleSearchTree<String, u64> = MerkleSearchTree::open(file.path())?;
+    let root_hash = tree.root_hash();
+
+    assert_eq!(tree.retain(|_, _| true)?, 0);
+    assert_eq!(tree.root_hash(), root_hash);
+
+    let mut seen = Vec::new();
+    let removed = tree.retain(|k, v| {
+        seen.push(k.clone());
+        v % 2 == 0
+    })?;
+    assert_eq!(removed, 1000);
+    let mut sorted = keys.clone();
+    sorted.sort();
+    assert_eq!(seen, sorted);
+
+    for (i, k) in keys.iter().enumerate() {
+        if i % 2 == 1 {
+            expected.remove(k)?;
+        }
+    }
+    assert_eq!(tree.root_hash(), expected.root_hash());
+    assert_eq!(tree.len()?, 1000);
+    assert!(tree.iter()?.all(|e| e.is_ok_and(|(_, v)| *v % 2 == 0)));
+
+    tree.commit()?;
+    drop(tree);
+    let tree: MerkleSearchTree<String, u64> = MerkleSearchTree::open(file.path())?;
+    assert_eq!(tree.root_hash(), expected.root_hash());
+    assert_eq!(tree.retain(|_, _| false)?, 1000);
+    assert_eq!(tree.root_hash(), [0u8; 32]);
+    Ok(())
+}
+
+#[test]
+fn str_lookups_on_string_keys() -> io::Result<()> {
+    use std::ops::Bound;
+
+    let tree: MerkleSearchTree<String, u32> = MerkleSearchTree::new_temporary()?;
+    for (i, k) in ["apple", "banana", "cherry"].into_iter().enumerate() {
+        tree.insert(k.to_string(), i as u32)?;
+    }
+
+    // None of these lookups construct a `String`.
+    assert!(tree.contains("banana")?);
+    assert!(!tree.contains("durian")?);
+    assert_eq!(tree.get("cherry")?.as_deref(), Some(&2));
+    assert_eq!(tree.with_value("apple", |v| v + 10)?, Some(10));
+    let values = tree.get_many(&["cherry", "fig", "apple"])?;
+    let values: Vec<_> = values.iter().map(|v| v.as_deref()).collect();
+    assert_eq!(values, [Some(&2), None, Some(&0)]);
+    assert_eq!(tree.floor("blueberry")?.unwrap().0.as_str(), "banana");
+    assert_eq!(tree.ceil("blueberry")?.unwrap().0.as_str(), "cherry");
+    let range: (Bound<&str>, Bound<&str>) = (Bound::Included("b"), Bound::Excluded("c"));
+    assert_eq!(tree.range::<str, _>(range)?.count(), 1);
+    assert_eq!(tree.count_range::<str, _>(range)?, 1);
+    assert!(tree.prove("apple")?.is_some());
+
+    tree.remove("apple")?;
+    assert!(!tree.contains("apple")?);
+    assert!(tree.snapshot().contains("banana")?);
+    Ok(())
+}
+
+#[test]
+fn values_and_into_entries_round_trip() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    assert!(MerkleSearchTree::<String, u64>::open(file.path())?.into_entries()?.is_empty());
+
+    let keys = generate_keys(1500, 66);
+    let mut expected: Vec<(String, u64)> =
+        keys.iter().enumerate().map(|(i, k)| (k.clone(), i as u64)).collect();
+    let tree = MerkleSearchTree::open(file.path())?;
+    tree.insert_many(expected.clone())?;
+    tree.commit()?;
+    drop(tree);
+    expected.sort();
+
+    let tree: MerkleSearchTree<String, u64> = MerkleSearchTree::open(file.path())?;
+    let values: Vec<_> = tree.values()?.map(|v| v.map(|v| *v)).collect::<Result<_>>()?;
+    assert_eq!(values, expected.iter().map(|(_, v)| *v).collect::<Vec<_>>());
+    assert_eq!(tree.into_entries()?, expected);
+
+    // Entries still shared with a snapshot are copied, and uncommitted ones included.
+    let tree: MerkleSearchTree<String, u64> = MerkleSearchTree::open(file.path())?;
+    tree.insert("zzz".to_string(), 7)?;
+    let snapshot = tree.snapshot();
+    let entries = tree.into_entries()?;
+    assert_eq!(entries.len(), expected.len() + 1);
+    assert_eq!(entries.last(), Some(&("zzz".to_string(), 7)));
+    assert_eq!(snapshot.get("zzz")?.as_deref(), Some(&7));
+    Ok(())
+}
+
+#[test]
+fn verify_reports_the_first_corrupted_node() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let keys = generate_keys(500, 67);
+    let tree = MerkleSearchTree::open(file.path())?;
+    for k in &keys {
+        tree.insert(k.clone(), format!("value of {k}"))?;
+    }
+    tree.insert("target".to_string(), "UNTOUCHED-MARKER".to_string())?;
+    let report = tree.verify()?;
+    assert_eq!(report.nodes_checked, 0);
+    tree.commit()?;
+
+    let report = tree.verify()?;
+    assert!(report.is_ok());
+    // Empty nodes are stored too, and checked like any other.
+    assert!(report.nodes_checked > tree.stats()?.node_count);
+    drop(tree);
+
+    // Flip one letter of the marker, keeping the node decodable.
+    let mut bytes = std::fs::read(file.path())?;
+    let pos = bytes
+        .windows(16)
+        .position(|w| w == b"UNTOUCHED-MARKER")
+        .expect("marker is on disk");
+    bytes[pos] = b'X';
+    std::fs::write(file.path(), &bytes)?;
+
+    let tree: MerkleSearchTree<String, String> = MerkleSearchTree::open(file.path())?;
+    let report = tree.verify()?;
+    assert!(matches!(report.first_error, Some(Corruption::HashMismatch { .. })));
+    drop(tree);
+
+    // Cutting the file short leaves links pointing past its end.
+    let file_len = bytes.len() as u64;
+    std::fs::OpenOptions::new().write(true).open(file.path())?.set_len(file_len - 64)?;
+    let tree: MerkleSearchTree<String, String> = MerkleSearchTree::open(file.path())?;
+    let report = tree.verify()?;
+    assert!(matches!(report.first_error, Some(Corruption::OutOfBounds { .. })));
+    Ok(())
+}
+
+#[test]
+fn fanout_sets_node_size_and_is_fixed_at_creation() -> io::Result<()> {
+    let keys = generate_keys(3000, 68);
+    let build = |path: &std::path::Path, fanout| -> io::Result<(Hash, TreeStats)> {
+        let tree = MerkleSearchTree::<String, u64>::open_with_fanout(path, fanout)?;
+        tree.insert_many(keys.iter().map(|k| (k.clone(), 1)))?;
+        tree.commit()?;
+        Ok((tree.root_hash(), tree.stats()?))
+    };
+
+    let files: Vec<_> = (0..3).map(|_| tempfile::NamedTempFile::new()).collect::<Result<_, _>>()?;
+    let (_, thin) = build(files[0].path(), 4)?;
+    let (default_hash, default) = build(files[1].path(), DEFAULT_FANOUT)?;
+    let (_, fat) = build(files[2].path(), 64)?;
+    assert!(thin.avg_keys_per_node() < default.avg_keys_per_node());
+    assert!(default.avg_keys_per_node() < fat.avg_keys_per_node());
+    assert!(thin.height > default.height && default.height > fat.height);
+
+    let plain = tempfile::NamedTempFile::new()?;
+    let tree = MerkleSearchTree::<String, u64>::open(plain.path())?;
+    tree.insert_many(keys.iter().map(|k| (k.clone(), 1)))?;
+    assert_eq!(tree.root_hash(), default_hash);
+    assert_eq!(tree.fanout(), DEFAULT_FANOUT);
+
+    // The fan-out is read back from the file and cannot be changed.
+    let mut tree = MerkleSearchTree::<String, u64>::open(files[2].path())?;
+    assert_eq!(tree.fanout(), 64);
+    tree.insert("extra".to_string(), 2)?;
+    let err = MerkleSearchTree::<String, u64>::open_with_fanout(files[2].path(), 16).err();
+    assert_eq!(err.map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+
+    // Compaction keeps it too.
+    let compacted = tempfile::NamedTempFile::new()?;
+    let root_hash = tree.root_hash();
+    tree.compact(compacted.path())?;
+    drop(tree);
+    let tree = MerkleSearchTree::<String, u64>::open(compacted.path())?;
+    assert_eq!((tree.fanout(), tree.root_hash()), (64, root_hash));
+
+    for fanout in [0, 1, 3, 512] {
+        let file = tempfile::NamedTempFile::new()?;
+        assert!(MerkleSearchTree::<String, u64>::open_with_fanout(file.path(), fanout).is_err());
+    }
+    Ok(())
+}
+
+#[test]
+fn hashes_round_trip_through_hex_and_bytes() -> io::Result<()> {
+    let tree = MerkleSearchTree::<String, u64>::new_temporary()?;
+    tree.insert("a".to_string(), 1)?;
+    let committed = tree.commit()?.hash;
+    let hash = tree.root_hash();
+    assert_eq!(committed, hash);
+
+    let hex = hash.to_string();
+    assert_eq!(hex.len(), 2 * HASH_LEN);
+    assert_eq!(hex.parse::<Hash>()?, hash);
+    assert_eq!(hex.to_uppercase().parse::<Hash>()?, hash);
+    assert_eq!(Hash::from(*hash.as_bytes()), hash);
+    assert_eq!(<[u8; HASH_LEN]>::from(hash), *hash.as_bytes());
+    assert_eq!(format!("{hash:?}"), format!("Hash({hex})"));
+
+    for bad in ["", "00", &hex[1..], &format!("{}zz", &hex[2..]), &format!("{}é", &hex[2..])] {
+        assert_eq!(bad.parse::<Hash>().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+    // Still encoded as 32 plain bytes, as when it was blake3's type.
+    let ours = postcard::to_extend(&hash, Vec::new()).unwrap();
+    let theirs = postcard::to_extend(&blake3::Hash::from(*hash.as_bytes()), Vec::new()).unwrap();
+    assert_eq!(ours, theirs);
+    Ok(())
+}
+
+#[test]
+fn open_at_root_reads_an_older_commit() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let keys = generate_keys(800, 69);
+    let tree = MerkleSearchTree::<String, u64>::open(file.path())?;
+    tree.insert_many(keys.iter().map(|k| (k.clone(), 1)))?;
+    let old_version = tree.commit()?;
+    let (old_offset, old_hash) = old_version.into_parts();
+    for k in &keys[..400] {
+        tree.insert(k.clone(), 2)?;
+    }
+    tree.insert("new".to_string(), 3)?;
+    let new_hash = tree.commit()?.hash;
+
+    // A version survives being written down and parsed back.
+    let parsed: Version = old_version.to_string().parse()?;
+    assert_eq!(parsed, old_version);
+    for invalid in ["12", "x:00"] {
+        let err = invalid.parse::<Version>().unwrap_err();
+        assert!(matches!(err, Error::Parse(_)), "{err}");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    let old = MerkleSearchTree::<String, u64>::open_at_root(file.path(), parsed)?;
+    assert_eq!(old.root_hash(), old_hash);
+    assert_eq!(old.len()?, 800);
+    assert!(!old.contains("new")?);
+    assert!(old.values()?.all(|v| v.is_ok_and(|v| *v == 1)));
+    assert_eq!(old.insert("x".to_string(), 0).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+    // Reopening normally still gets the latest root.
+    drop(tree);
+    let latest = MerkleSearchTree::<String, u64>::open_read_only(file.path())?;
+    assert_eq!(latest.root_hash(), new_hash);
+
+    let version = Version::new(old_offset, new_hash);
+    let err = MerkleSearchTree::<String, u64>::open_at_root(file.path(), version);
+    assert_eq!(err.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    for offset in [0, 100, u64::MAX / 2] {
+        let version = Version::new(offset, old_hash);
+        let err = MerkleSearchTree::<String, u64>::open_at_root(file.path(), version);
+        assert_eq!(err.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+    }
+    Ok(())
+}
+
+#[test]
+fn builder_combines_open_options() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let tree = MerkleSearchTree::<String, u64>::builder()
+        .page_size(1024)
+        .fanout(32)
+        .cache_capacity(4)
+        .sync_policy(SyncPolicy::Never)
+        .open(file.path())?;
+    tree.insert_many((0..500).map(|i| (format!("key-{i}"), i)))?;
+    tree.commit()?;
+    let config = tree.store.config();
+    assert_eq!((config.page_size, tree.fanout()), (1024, 32));
+    assert!(tree.store.cached_nodes() <= 4);
+    assert_eq!(tree.store.syncs.load(std::sync::atomic::Ordering::Relaxed), 0);
+    let root_hash = tree.root_hash();
+    drop(tree);
+
+    let mut options = MerkleSearchTree::<String, u64>::builder();
+    options.read_only(true).verify_on_read(true);
+    let tree = options.open(file.path())?;
+    assert_eq!(tree.root_hash(), root_hash);
+    assert_eq!(tree.get("key-7")?.as_deref(), Some(&7));
+    assert!(tree.store.config().verify_on_read);
+    assert_eq!(tree.remove("key-7").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    assert!(options.clone().read_only(false).fanout(16).open(file.path()).is_err());
+
+    let keyed = OpenOptions::<String, u64, KeyedHasher>::new().fanout(4).create_temporary()?;
+    keyed.insert("a".to_string(), 1)?;
+    assert_eq!(keyed.fanout(), 4);
+    let plain = MerkleSearchTree::<String, u64>::builder().fanout(4).create_temporary()?;
+    plain.insert("a".to_string(), 1)?;
+    assert_ne!(keyed.root_hash(), plain.root_hash());
+    Ok(())
+}
+
+#[test]
+fn removals_leave_no_keyless_nodes() -> io::Result<()> {
+    fn assert_no_keyless_nodes(tree: &MerkleSearchTree<String, u64>) -> io::Result<()> {
+        let mut stack = vec![tree.state.read().unwrap().root.clone()];
+        while let Some(link) = stack.pop() {
+            let node = tree.resolve_link(&link)?;
+            assert!(!node.keys.is_empty() || node.children.is_empty(), "unary node in the tree");
+            stack.extend(node.children.iter().cloned());
+        }
+        Ok(())
+    }
+
+    let mut keys = generate_keys(10_000, 71);
+    let tree = MerkleSearchTree::<String, u64>::new_temporary()?;
+    tree.insert_many(keys.iter().map(|k| (k.clone(), 1)))?;
+    tree.commit()?;
+    keys.sort();
+    let survivors = [keys[17].clone(), keys[5000].clone(), keys[9998].clone()];
+
+    // Leaves gaps of every size between the survivors, through each removal path.
+    tree.remove_range(keys[18].clone()..keys[4000].clone())?;
+    tree.retain(|k, _| k < &keys[4000] || k >= &keys[4999])?;
+    for k in &keys {
+        if !survivors.contains(k) {
+            tree.remove(k)?;
+        }
+    }
+    assert_no_keyless_nodes(&tree)?;
+
+    let fresh = MerkleSearchTree::<String, u64>::new_temporary()?;
+    fresh.insert_many(survivors.iter().map(|k| (k.clone(), 1)))?;
+    assert_eq!(tree.root_hash(), fresh.root_hash());
+    let stats = tree.stats()?;
+    assert_eq!(stats.key_count, 3);
+    assert_eq!(stats.height, fresh.stats()?.height);
+    assert!(stats.height <= 3);
+    Ok(())
+}
+
+/// BLAKE3, except that the key `"tall"` hashes to all zeros and so gets the highest
+/// possible level.
+#[derive(Default)]
+struct TallKeyHasher(Vec<u8>);
+
+impl TreeHasher for TallKeyHasher {
+    fn update(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn finalize(self) -> [u8; 32] {
+        if self.0 == postcard::to_extend("tall", Vec::new()).unwrap() {
+            return [0; 32];
+        }
+        blake3::hash(&self.0).into()
+    }
+}
+
+#[test]
+fn keys_at_the_highest_level_add_a_single_node() -> io::Result<()> {
+    let tall = "tall".to_string();
+    let keys = generate_keys(3000, 72);
+    for fanout in [2, 16, 256] {
+        let max_level = 256 / u32::trailing_zeros(fanout);
+        let level = node::Node::<String, u64>::calc_level::<TallKeyHasher>(&tall, fanout);
+        assert_eq!(level, max_level);
+
+        let tree =
+            OpenOptions::<String, u64, TallKeyHasher>::new().fanout(fanout).create_temporary()?;
+        tree.insert_many(keys[..1500].iter().map(|k| (k.clone(), 1)))?;
+        let before = tree.stats()?;
+        tree.insert(tall.clone(), 0)?;
+        tree.insert_many(keys[1500..].iter().map(|k| (k.clone(), 1)))?;
+        tree.commit()?;
+
+        // The key sits alone in a new root, far above the rest of the tree.
+        let root = tree.resolve_link(&tree.state.read().unwrap().root.clone())?;
+        assert_eq!((root.level, root.keys.len()), (max_level, 1));
+        assert!(tree.stats()?.height <= before.height + 2);
+        assert_eq!(tree.get(&tall)?.as_deref(), Some(&0));
+        assert!(tree.verify()?.is_ok());
+
+        let fresh =
+            OpenOptions::<String, u64, TallKeyHasher>::new().fanout(fanout).create_temporary()?;
+        fresh.insert_many(keys.iter().map(|k| (k.clone(), 1)))?;
+        tree.remove(&tall)?;
+        assert_eq!(tree.root_hash(), fresh.root_hash());
+    }
+    Ok(())
+}
+
+#[test]
+fn splits_keep_subtrees_that_land_on_one_side() -> io::Result<()> {
+    // Every generated key sorts before "tall", so the whole tree goes to its left.
+    let tree = OpenOptions::<String, u64, TallKeyHasher>::new().fanout(16).create_temporary()?;
+    tree.insert_many(generate_keys(2000, 73).into_iter().map(|k| (k, 1)))?;
+    tree.commit()?;
+    let before = tree.stats()?;
+    assert!(before.height > 2);
+
+    tree.insert("tall".to_string(), 0)?;
+    let after = tree.stats()?;
+    // Only the new root, and the old one below it, which it points at in memory.
+    assert_eq!(after.loaded_nodes, 2);
+    assert_eq!(after.node_count, before.node_count + 1);
+    assert_eq!(after.height, before.height + 1);
+    tree.commit()?;
+    assert!(tree.verify()?.is_ok());
+    Ok(())
+}
+
+#[test]
+fn node_dedup_survives_reopening_and_frees_nodes_with_their_last_parent() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let keys = generate_keys(2_000, 66);
+    let open = || MerkleSearchTree::<String, u64>::builder().dedup_nodes(true).open(file.path());
+    let tree = open()?;
+    tree.insert_many(keys.iter().map(|k| (k.clone(), 0)))?;
+    let committed = tree.commit()?;
+    assert!(tree.store.deduplicated_nodes() > 0);
+    drop(tree);
+
+    // A change that is undone rewrites the same nodes, which the reopened tree finds.
+    let tree = open()?;
+    let len = file.as_file().metadata()?.len();
+    tree.insert(keys[0].clone(), 1)?;
+    tree.insert(keys[0].clone(), 0)?;
+    assert_eq!(tree.commit()?, committed);
+    assert_eq!(file.as_file().metadata()?.len(), len);
+
+    // Orphaning every parent of the shared nodes frees them too.
+    for k in &keys {
+        tree.remove(k)?;
+    }
+    tree.commit()?;
+    assert_eq!(tree.store.deduplicated_nodes(), 0);
+    tree.insert_many(keys.iter().map(|k| (k.clone(), 2)))?;
+    tree.commit()?;
+    drop(tree);
+    let tree = MerkleSearchTree::<String, u64>::open_with_verification(file.path())?;
+    assert!(tree.verify()?.is_ok());
+    assert_eq!(tree.get(&keys[0])?.as_deref(), Some(&2));
+    Ok(())
+}
+
+#[test]
+fn node_dedup_shrinks_files_and_never_frees_shared_nodes() -> io::Result<()> {
+    use std::collections::BTreeMap;
+
+    // The workload of `compaction_reduces_file_size_and_preserves_data`.
+    let run = |dedup: bool| -> io::Result<(u64, u64, Hash, tempfile::TempDir)> {
+        let dir = tempfile::tempdir()?;
+        let mut tree = MerkleSearchTree::<String, String>::builder()
+            .dedup_nodes(dedup)
+            .open(dir.path().join("original.mst"))?;
+        for i in 0..2000 {
+            tree.insert(format!("key-{i:04}"), "original-value".to_string())?;
+        }
+        tree.commit()?;
+        for i in 0..500 {
+            tree.insert(format!("key-{i:04}"), "updated-value".to_string())?;
+        }
+        for i in 500..1000 {
+            tree.remove(&format!("key-{i:04}"))?;
+        }
+        tree.commit()?;
+        let fragmented = std::fs::metadata(dir.path().join("original.mst"))?.len();
+        tree.compact(dir.path().join("compacted.mst"))?;
+        let compacted = std::fs::metadata(dir.path().join("compacted.mst"))?.len();
+        Ok((fragmented, compacted, tree.root_hash(), dir))
+    };
+    let (plain_fragmented, plain_compacted, plain_hash, _plain) = run(false)?;
+    let (fragmented, compacted, hash, dir) = run(true)?;
+    println!("Without dedup: {plain_fragmented} bytes, {plain_compacted} compacted");
+    println!("With dedup:    {fragmented} bytes, {compacted} compacted");
+    assert_eq!(hash, plain_hash);
+    assert!(fragmented < plain_fragmented);
+    assert!(compacted < plain_compacted);
+
+    // Churn the compacted file over many commits, with and without deduplication, so
+    // that parents of shared nodes get orphaned and their space reused.
+    let path = dir.path().join("compacted.mst");
+    let mut model: BTreeMap<String, String> = (0..2000)
+        .filter(|i| !(500..1000).contains(i))
+        .map(|i| {
+            let value = if i < 500 { "updated-value" } else { "original-value" };
+            (format!("key-{i:04}"), value.to_string())
+        })
+        .collect();
+    let mut rng = StdRng::seed_from_u64(73);
+    for round in 0..6 {
+        let tree = MerkleSearchTree::<String, String>::builder()
+            .dedup_nodes(round % 2 == 0)
+            .open(&path)?;
+        for _ in 0..5 {
+            for _ in 0..100 {
+                let key = format!("key-{:04}", rng.random_range(0..2500));
+                if rng.random_bool(0.5) {
+                    tree.insert(key.clone(), format!("round-{round}"))?;
+                    model.insert(key, format!("round-{round}"));
+                } else {
+                    tree.remove(&key)?;
+                    model.remove(&key);
+                }
+            }
+            // Rewritten subtrees come out identical to the ones on disk and get shared.
+            for (key, value) in model.iter().step_by(97) {
+                tree.remove(key)?;
+                tree.insert(key.clone(), value.clone())?;
+            }
+            tree.commit()?;
+        }
+        assert!(tree.verify()?.is_ok());
+        let entries: Vec<(String, String)> = tree
+            .iter()?
+            .map(|e| e.map(|(k, v)| ((*k).clone(), (*v).clone())))
+            .collect::<Result<_>>()?;
+        assert!(entries.into_iter().eq(model.clone()));
+    }
+    Ok(())
+}
+
+#[test]
+fn typed_errors_survive_io_error_round_trips() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    MerkleSearchTree::<String, u32>::open(file.path())?.commit()?;
+    let err = MerkleSearchTree::<u32, u32>::open(file.path()).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    // Code written against `io::Result` can still recover the variant.
+    let io_err = io::Error::from(err);
+    assert_eq!(io_err.kind(), io::ErrorKind::InvalidData);
+    assert!(matches!(Error::from(io_err), Error::SchemaMismatch { .. }));
+
+    let tree = MerkleSearchTree::<String, u32>::open_read_only(file.path())?;
+    let err = tree.insert("a".to_string(), 1).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    assert!(matches!(&err, Error::Io(_)));
+    assert_eq!(io::Error::from(err).kind(), io::ErrorKind::PermissionDenied);
+    Ok(())
+}
+
+#[test]
+fn subscribers_receive_the_root_of_each_commit() -> io::Result<()> {
+    let tree: MerkleSearchTree<String, u32> = MerkleSearchTree::new_temporary()?;
+    let mut commits = tree.subscribe();
+    let dropped = tree.subscribe();
+    assert_eq!(*commits.borrow(), Hash::default());
+    drop(dropped);
+
+    tree.insert("a".to_string(), 1)?;
+    let first = tree.commit()?.hash;
+    assert!(commits.has_changed().unwrap());
+    assert_eq!(*commits.borrow_and_update(), first);
+
+    // Nothing new to write, so nothing to announce.
+    tree.commit()?;
+    assert!(!commits.has_changed().unwrap());
+
+    let second = tree.transaction(vec![Op::Insert("b".to_string(), 2)])?.hash;
+    assert_eq!(*commits.borrow_and_update(), second);
+
+    drop(commits);
+    tree.insert("c".to_string(), 3)?;
+    tree.commit()?;
+    Ok(())
+}
+
+#[test]
+fn previews_predict_the_root_without_writing() -> io::Result<()> {
+    let tree = MerkleSearchTree::new_temporary()?;
+    for (i, key) in generate_keys(300, 53).into_iter().enumerate() {
+        tree.insert(key, i as u64)?;
+    }
+    tree.commit()?;
+    let committed = tree.root_hash();
+
+    let predicted = tree.preview_insert("new-key".to_string(), 7)?;
+    assert_ne!(predicted, committed);
+    assert_eq!(tree.root_hash(), committed);
+    assert!(!tree.contains("new-key")?);
+    assert_eq!(tree.preview_insert("new-key".to_string(), 7)?, predicted);
+    tree.insert("new-key".to_string(), 7)?;
+    assert_eq!(tree.root_hash(), predicted);
+
+    let predicted = tree.preview_remove("new-key")?;
+    assert_eq!(predicted, committed);
+    assert_eq!(tree.preview_remove("absent")?, tree.root_hash());
+    assert!(tree.contains("new-key")?);
+    tree.remove("new-key")?;
+    assert_eq!(tree.root_hash(), predicted);
+
+    // The same value again would change nothing.
+    let (key, value) = tree.first_key_value()?.unwrap();
+    assert_eq!(tree.preview_insert((*key).clone(), *value)?, committed);
+    Ok(())
+}
+
+#[test]
+fn commit_if_refuses_when_another_handle_committed() -> io::Result<()> {
+    let keys = generate_keys(1000, 81);
+    let file = tempfile::NamedTempFile::new()?;
+    let ours: MerkleSearchTree<String, u32> = MerkleSearchTree::open(file.path())?;
+    ours.insert_many(keys.iter().map(|k| (k.clone(), 0)))?;
+    ours.commit_if(Hash::default())?.expect("nothing was committed yet");
+    // Rewriting every value twice leaves the first version's space free for reuse.
+    for round in 1..=2 {
+        ours.insert_many(keys.iter().map(|k| (k.clone(), round)))?;
+        ours.commit()?;
+    }
+    let first = ours.root_hash();
+    assert!(ours.store.reclaimable_bytes() > 0);
+    assert_eq!(ours.commit_if(Hash::default())?, Err(first));
+
+    // Another writer, sharing the free list, commits to the same file behind our back.
+    let theirs: MerkleSearchTree<String, u32> = MerkleSearchTree::open(file.path())?;
+    theirs.insert_many(keys.iter().map(|k| (k.clone(), 3)))?;
+    theirs.insert("b".to_string(), 2)?;
+    let second = theirs.commit()?.hash;
+    drop(theirs);
+
+    // Our changes would otherwise be written into the same free space.
+    ours.insert_many(keys.iter().map(|k| (k.clone(), 4)))?;
+    ours.insert("c".to_string(), 3)?;
+    assert_eq!(ours.commit_if(first)?, Err(second));
+    let reopened: MerkleSearchTree<String, u32> =
+        OpenOptions::new().verify_on_read(true).open(file.path())?;
+    assert_eq!(reopened.root_hash(), second);
+    assert!(reopened.verify()?.is_ok());
+    assert_eq!(reopened.get(&keys[7])?.as_deref(), Some(&3));
+    assert!(!reopened.contains("c")?);
+    drop(reopened);
+
+    // The refused commit kept our changes; merged with theirs, expecting their root
+    // lets the commit through.
+    assert_eq!(ours.get(&keys[7])?.as_deref(), Some(&4));
+    ours.insert("b".to_string(), 2)?;
+    let merged = ours.commit_if(second)?.expect("the file still holds their root").hash;
+    let reopened: MerkleSearchTree<String, u32> =
+        OpenOptions::new().verify_on_read(true).open(file.path())?;
+    assert_eq!(reopened.root_hash(), merged);
+    assert!(reopened.verify()?.is_ok());
+    assert_eq!(reopened.len()?, 1002);
+    assert_eq!(reopened.get(&keys[7])?.as_deref(), Some(&4));
+    drop(reopened);
+
+    // A handle that has not seen another's commit goes through when expecting it.
+    let theirs: MerkleSearchTree<String, u32> = MerkleSearchTree::open(file.path())?;
+    theirs.insert("d".to_string(), 5)?;
+    let third = theirs.commit_if(merged)?.expect("nothing else was committed").hash;
+    ours.insert("e".to_string(), 6)?;
+    assert_eq!(ours.commit_if(merged)?, Err(third));
+    let unchanged = theirs.commit_if(third)?.expect("the file holds its root");
+    assert_eq!(unchanged.hash, third);
+    ours.insert("d".to_string(), 5)?;
+    let last = ours.commit_if(third)?.expect("merged with their commit").hash;
+    let reopened: MerkleSearchTree<String, u32> =
+        OpenOptions::new().verify_on_read(true).open(file.path())?;
+    assert_eq!(reopened.root_hash(), last);
+    assert!(reopened.verify()?.is_ok());
+    assert_eq!(reopened.len()?, 1004);
+    Ok(())
+}
+
+#[test]
+fn diff_map_lists_adds_removes_and_changes() -> io::Result<()> {
+    use std::collections::BTreeMap;
+
+    let tree = MerkleSearchTree::new_temporary()?;
+    let mut map = BTreeMap::new();
+    for (i, key) in generate_keys(1000, 55).into_iter().enumerate() {
+        tree.insert(key.clone(), i as u64)?;
+        map.insert(key, i as u64);
+    }
+    tree.commit()?;
+    assert!(tree.diff_map(&map)?.is_empty());
+
+    let keys: Vec<String> = map.keys().cloned().collect();
+    for key in keys.iter().step_by(150) {
+        map.remove(key);
+    }
+    for key in keys.iter().skip(75).step_by(150) {
+        *map.get_mut(key).unwrap() += 1;
+    }
+    for i in 0..6 {
+        map.insert(format!("added-{i}"), i);
+    }
+
+    let differences = tree.diff_map(&map)?;
+    assert_eq!(differences.len(), 20);
+    let key_of = |difference: &Difference<String, u64>| match difference {
+        Difference::Added { key, .. }
+        | Difference::Removed { key, .. }
+        | Difference::Changed { key, .. } => key.clone(),
+    };
+    assert!(differences.windows(2).all(|w| key_of(&w[0]) < key_of(&w[1])));
+
+    for difference in differences {
+        match difference {
+            Difference::Added { key, value } => {
+                assert!(!tree.contains(key.as_str())?);
+                tree.insert((*key).clone(), *value)?;
+            }
+            Difference::Removed { key, value } => {
+                assert!(!map.contains_key(key.as_str()));
+                assert_eq!(tree.get(key.as_str())?, Some(value));
+                tree.remove(key.as_str())?;
+            }
+            Difference::Changed { key, old, new } => {
+                assert_eq!(*new, *old + 1);
+                tree.insert((*key).clone(), *new)?;
+            }
+        }
+    }
+    assert!(tree.diff_map(&map)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn compact_in_place_shrinks_the_file_and_keeps_every_key() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("data.mst");
+    let keys = generate_keys(2000, 56);
+    let mut tree = MerkleSearchTree::open(&path)?;
+    for round in 0..5u64 {
+        for key in &keys {
+            tree.insert(key.clone(), round)?;
+        }
+        tree.commit()?;
+    }
+    let root = tree.root_hash();
+    let fragmented = std::fs::metadata(&path)?.len();
+
+    tree.compact_in_place()?;
+    let compacted = std::fs::metadata(&path)?.len();
+    assert!(compacted < fragmented, "{compacted} >= {fragmented}");
+    assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+
+    // The tree keeps working on the new file.
+    tree.insert("after".to_string(), 9)?;
+    tree.commit()?;
+    drop(tree);
+
+    let tree: MerkleSearchTree<String, u64> = MerkleSearchTree::open(&path)?;
+    assert_eq!(tree.get("after")?.as_deref(), Some(&9));
+    tree.remove("after")?;
+    assert_eq!(tree.root_hash(), root);
+    for key in &keys {
+        assert_eq!(tree.get(key)?.as_deref(), Some(&4));
+    }
+
+    let mut temporary: MerkleSearchTree<String, u64> = MerkleSearchTree::new_temporary()?;
+    let err = temporary.compact_in_place().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn compact_in_place_keeps_the_file_mode() -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("data.mst");
+    let mut tree = MerkleSearchTree::open(&path)?;
+    tree.insert("a".to_string(), 1)?;
+    tree.commit()?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644))?;
+
+    tree.compact_in_place()?;
+    assert_eq!(std::fs::metadata(&path)?.permissions().mode() & 0o777, 0o644);
+    Ok(())
+}
+
+#[test]
+fn tombstones_hide_removed_keys_until_purged() -> io::Result<()> {
+    use std::collections::BTreeMap;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    let keys = generate_keys(500, 59);
+    let tree = OpenOptions::<String, Tombstoned<u64>>::new().tombstones(true).create_temporary()?;
+    let plain = MerkleSearchTree::<String, Tombstoned<u64>>::new_temporary()?;
+    for (i, key) in keys.iter().enumerate() {
+        tree.insert(key.clone(), Tombstoned::Live(i as u64))?;
+        plain.insert(key.clone(), Tombstoned::Live(i as u64))?;
+    }
+    let replica: BTreeMap<String, Tombstoned<u64>> =
+        tree.iter()?.map(|e| e.map(|(k, v)| ((*k).clone(), (*v).clone()))).collect::<Result<_>>()?;
+
+    let before = tree.root_hash();
+    tree.remove(keys[0].as_str())?;
+    tree.transaction(vec![Op::Remove(keys[1].clone())])?;
+    assert_ne!(tree.root_hash(), before);
+    for key in &keys[..2] {
+        assert!(!tree.contains(key.as_str())?);
+        assert_eq!(tree.get(key.as_str())?, None);
+        assert_eq!(tree.with_value(key.as_str(), |_| ())?, None);
+    }
+    assert_eq!(tree.get_many(&[keys[0].as_str(), keys[2].as_str()])?[0], None);
+    assert_eq!(tree.len()?, 500);
+
+    // Removing again keeps the first removal time.
+    let buried = tree.root_hash();
+    tree.remove(keys[0].as_str())?;
+    assert_eq!(tree.root_hash(), buried);
+
+    // The replica learns of the removal instead of seeing a key it has and we lack.
+    let differences = tree.diff_map(&replica)?;
+    assert_eq!(differences.len(), 2);
+    assert!(differences.iter().all(|difference| matches!(
+        difference,
+        Difference::Changed { old, .. } if old.removed_at().is_some()
+    )));
+
+    assert_eq!(tree.purge_tombstones(UNIX_EPOCH)?, 0);
+    let later = SystemTime::now() + Duration::from_secs(1);
+    assert_eq!(tree.purge_tombstones(later)?, 2);
+    assert_eq!(tree.len()?, 498);
+
+    // Without the mode, removing deletes.
+    plain.remove(keys[0].as_str())?;
+    plain.remove(keys[1].as_str())?;
+    assert_eq!(tree.root_hash(), plain.root_hash());
+    Ok(())
+}
+
+#[test]
+fn try_insert_and_entry_treat_removed_keys_as_vacant() -> io::Result<()> {
+    let keys = generate_keys(200, 73);
+    let mut tree =
+        OpenOptions::<String, Tombstoned<u64>>::new().tombstones(true).create_temporary()?;
+    let expected = MerkleSearchTree::<String, Tombstoned<u64>>::new_temporary()?;
+    for (i, key) in keys.iter().enumerate() {
+        tree.insert(key.clone(), Tombstoned::Live(i as u64))?;
+        expected.insert(key.clone(), Tombstoned::Live(i as u64))?;
+    }
+    tree.remove(keys[0].as_str())?;
+    tree.remove(keys[1].as_str())?;
+
+    assert!(matches!(tree.entry(keys[0].clone())?, Entry::Vacant(_)));
+    assert!(matches!(tree.entry(keys[2].clone())?, Entry::Occupied(_)));
+    let inserted = tree.entry(keys[0].clone())?.or_insert(Tombstoned::Live(1000))?;
+    assert_eq!(*inserted, Tombstoned::Live(1000));
+    assert_eq!(tree.try_insert(keys[1].clone(), Tombstoned::Live(1001))?, Ok(()));
+    let refused = tree.try_insert(keys[2].clone(), Tombstoned::Live(1002))?;
+    assert_eq!(refused, Err(Tombstoned::Live(1002)));
+
+    assert_eq!(tree.get(keys[0].as_str())?.as_deref(), Some(&Tombstoned::Live(1000)));
+    assert_eq!(tree.get(keys[1].as_str())?.as_deref(), Some(&Tombstoned::Live(1001)));
+    expected.insert(keys[0].clone(), Tombstoned::Live(1000))?;
+    expected.insert(keys[1].clone(), Tombstoned::Live(1001))?;
+    assert_eq!(tree.root_hash(), expected.root_hash());
+    Ok(())
+}
+
+#[test]
+fn large_values_go_to_blobs_and_read_back_after_reopen() -> io::Result<()> {
+    let files = [tempfile::NamedTempFile::new()?, tempfile::NamedTempFile::new()?];
+    let keys = generate_keys(300, 70);
+    let value = |i: usize| vec![i as u8; if i.is_multiple_of(10) { 10_000 } else { 8 }];
+    let open = |path: &std::path::Path, blobs: bool| {
+        let mut options = MerkleSearchTree::<String, Vec<u8>>::builder();
+        if blobs {
+            options.blob_threshold(1024);
+        }
+        options.open(path)
+    };
+
+    let mut growth = Vec::new();
+    for (file, blobs) in files.iter().zip([true, false]) {
+        let tree = open(file.path(), blobs)?;
+        tree.insert_many(keys.iter().enumerate().map(|(i, k)| (k.clone(), value(i))))?;
+        tree.commit()?;
+        // Only the path to the changed key is written again, without the blobs on it.
+        let before = std::fs::metadata(file.path())?.len();
+        tree.insert(keys[1].clone(), vec![0; 8])?;
+        tree.commit()?;
+        growth.push(std::fs::metadata(file.path())?.len() - before);
+    }
+    assert!(growth[0] < 10_000, "{growth:?}");
+    assert!(growth[1] > 10_000, "{growth:?}");
+    let inline = open(files[1].path(), false)?;
+
+    // Reopened with or without the threshold, the blobs are resolved.
+    for blobs in [true, false] {
+        let tree = open(files[0].path(), blobs)?;
+        assert_eq!(tree.root_hash(), inline.root_hash());
+        assert_eq!(tree.len()?, 300);
+        assert_eq!(tree.get(keys[10].as_str())?.as_deref(), Some(&value(10)));
+        assert_eq!(tree.get(keys[11].as_str())?.as_deref(), Some(&value(11)));
+        assert_eq!(tree.get(keys[1].as_str())?.as_deref(), Some(&vec![0; 8]));
+        assert!(tree.verify()?.is_ok());
+    }
+
+    let mut tree = open(files[0].path(), true)?;
+    let compacted = tempfile::NamedTempFile::new()?;
+    tree.compact(compacted.path())?;
+    drop(tree);
+    let tree = open(compacted.path(), false)?;
+    assert_eq!(tree.root_hash(), inline.root_hash());
+    assert!(tree.iter()?.zip(inline.iter()?).all(|(a, b)| a.ok() == b.ok()));
+    Ok(())
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn parallel_commit_writes_the_same_file_as_a_serial_one() -> io::Result<()> {
+    let files = [tempfile::NamedTempFile::new()?, tempfile::NamedTempFile::new()?];
+    let keys = generate_keys(20_000, 74);
+    let trees = [
+        MerkleSearchTree::<String, u64>::open(files[0].path())?,
+        MerkleSearchTree::<String, u64>::open(files[1].path())?,
+    ];
+    for round in 0..3u64 {
+        for tree in &trees {
+            let changed = keys.iter().skip(round as usize).step_by(7);
+            tree.insert_many(changed.map(|k| (k.clone(), round)))?;
+            tree.remove(keys[round as usize * 100].as_str())?;
+        }
+        let serial = trees[0].commit()?;
+        assert_eq!(trees[1].commit_parallel()?, serial);
+        assert_eq!(std::fs::read(files[0].path())?, std::fs::read(files[1].path())?);
+    }
+    assert_eq!(trees[1].commit_parallel()?, trees[0].commit()?);
+
+    let reopened = MerkleSearchTree::<String, u64>::open(files[1].path())?;
+    assert!(reopened.verify()?.is_ok());
+    assert_eq!(reopened.get(keys[2].as_str())?.as_deref(), Some(&2));
+    Ok(())
+}
+
+#[test]
+fn merkle_set_stores_keys_without_values() -> io::Result<()> {
+    use std::collections::BTreeSet;
+
+    assert!(postcard::to_extend(&(), Vec::new()).map_err(Error::from)?.is_empty());
+    let file = tempfile::NamedTempFile::new()?;
+    let keys = generate_keys(1000, 75);
+    let set = MerkleSet::open(file.path())?;
+    set.insert_many(keys[..900].iter().cloned())?;
+    set.insert(keys[900].clone())?;
+    set.remove(keys[0].as_str())?;
+    assert!(set.contains(keys[1].as_str())? && !set.contains(keys[0].as_str())?);
+    assert_eq!(set.len()?, 900);
+
+    // Same shape and root hash as a tree with unit values.
+    let tree = MerkleSearchTree::<String, ()>::new_temporary()?;
+    tree.insert_many(keys[1..=900].iter().map(|k| (k.clone(), ())))?;
+    assert_eq!(set.root_hash(), tree.root_hash());
+    set.commit()?;
+    drop(set);
+
+    let set = MerkleSet::<String>::open(file.path())?;
+    assert_eq!(set.root_hash(), tree.root_hash());
+    let mut expected = keys[1..=900].to_vec();
+    expected.sort();
+    let stored: Vec<String> = set.iter()?.map(|k| Ok((*k?).clone())).collect::<Result<_>>()?;
+    assert_eq!(stored, expected);
+
+    let other: BTreeSet<String> = keys[..500].iter().cloned().collect();
+    let diff = set.diff(&other)?;
+    assert_eq!(diff.added, vec![&keys[0]]);
+    assert_eq!(diff.removed.len(), 401);
+    assert!(diff.removed.windows(2).all(|pair| pair[0] < pair[1]));
+    Ok(())
+}
+
+#[test]
+fn entries_round_trip_through_an_export() -> io::Result<()> {
+    let count = dump::IMPORT_BATCH_LEN as u32 * 3 + 100;
+    let tree = MerkleSearchTree::<u32, String>::new_temporary()?;
+    tree.insert_many((0..count).map(|i| (i, format!("v{i}"))))?;
+    let mut exported = Vec::new();
+    assert_eq!(tree.export_entries(&mut exported, EntryFormat::Postcard)?, count as u64);
+
+    // More entries than fit in one batch, so the import commits several times.
+    let file = tempfile::NamedTempFile::new()?;
+    let imported = MerkleSearchTree::<u32, String>::import_entries(
+        file.path(),
+        &exported[..],
+        EntryFormat::Postcard,
+    )?;
+    assert_eq!(imported.root_hash(), tree.root_hash());
+    assert!(!imported.is_dirty());
+    drop(imported);
+    let reopened = MerkleSearchTree::<u32, String>::open(file.path())?;
+    assert_eq!(reopened.root_hash(), tree.root_hash());
+
+    // Later entries win, over the input and over what the tree held before.
+    let mut overrides = Vec::new();
+    let small = MerkleSearchTree::<u32, String>::new_temporary()?;
+    small.insert(5, "new".to_string())?;
+    small.export_entries(&mut overrides, EntryFormat::Postcard)?;
+    drop(reopened);
+    let updated = MerkleSearchTree::<u32, String>::import_entries(
+        file.path(),
+        &overrides[..],
+        EntryFormat::Postcard,
+    )?;
+    assert_eq!(updated.get(&5)?.as_deref().map(String::as_str), Some("new"));
+    assert_eq!(updated.len()?, count as u64);
+
+    let cut = tempfile::NamedTempFile::new()?;
+    let truncated = &overrides[..overrides.len() - 1];
+    let err = MerkleSearchTree::<u32, String>::import_entries(
+        cut.path(),
+        truncated,
+        EntryFormat::Postcard,
+    )
+    .err()
+    .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    Ok(())
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn entries_round_trip_through_json_lines() -> io::Result<()> {
+    let tree = MerkleSearchTree::<String, Vec<u32>>::new_temporary()?;
+    for (i, key) in generate_keys(200, 79).into_iter().enumerate() {
+        tree.insert(key, vec![i as u32; i % 4])?;
+    }
+    let mut exported = Vec::new();
+    tree.export_entries(&mut exported, EntryFormat::JsonLines)?;
+    let first_line = exported.split(|b| *b == b'\n').next().unwrap();
+    let first_key = tree.first_key_value()?.unwrap().0;
+    assert!(first_line.starts_with(format!("[\"{first_key}\",").as_bytes()));
+
+    let file = tempfile::NamedTempFile::new()?;
+    let imported = MerkleSearchTree::<String, Vec<u32>>::import_entries(
+        file.path(),
+        &exported[..],
+        EntryFormat::JsonLines,
+    )?;
+    assert_eq!(imported.root_hash(), tree.root_hash());
+    Ok(())
+}
+
+#[test]
+fn live_size_counts_reachable_records_only() -> io::Result<()> {
+    let keys = generate_keys(2000, 81);
+    let file = tempfile::NamedTempFile::new()?;
+    let mut tree = MerkleSearchTree::<String, u32>::open(file.path())?;
+    assert_eq!(tree.disk_size()?, DEFAULT_PAGE_SIZE);
+    assert_eq!(tree.live_size()?, DEFAULT_PAGE_SIZE);
+
+    tree.insert_many(keys.iter().map(|k| (k.clone(), 0)))?;
+    // Uncommitted nodes take no space yet.
+    assert_eq!(tree.live_size()?, DEFAULT_PAGE_SIZE);
+    tree.commit()?;
+    let (live, disk) = (tree.live_size()?, tree.disk_size()?);
+    assert_eq!(disk, std::fs::metadata(file.path())?.len());
+    assert!(live <= disk && live > disk / 2, "{live} of {disk}");
+
+    // Rewriting every value leaves the old nodes behind as garbage.
+    for round in 1..3 {
+        tree.insert_many(keys.iter().map(|k| (k.clone(), round)))?;
+        tree.commit()?;
+    }
+    let (live, disk) = (tree.live_size()?, tree.disk_size()?);
+    assert!(live < disk / 2, "{live} of {disk}");
+
+    // A compacted copy holds the live records and little else. Its nodes can only
+    // shrink, as the offsets of their children take fewer varint bytes.
+    let compacted = tempfile::NamedTempFile::new()?;
+    tree.compact(compacted.path())?;
+    assert!(tree.live_size()? <= live);
+    assert!(tree.disk_size()? - live < live / 4, "{live} of {}", tree.disk_size()?);
+
+    // Blobs count, once each even when values share them.
+    let mut sizes = Vec::new();
+    for distinct in [false, true] {
+        let file = tempfile::NamedTempFile::new()?;
+        let tree = MerkleSearchTree::<String, Vec<u8>>::builder()
+            .blob_threshold(1024)
+            .open(file.path())?;
+        let value = |i: usize| vec![if distinct { i as u8 } else { 0 }; 4000];
+        tree.insert_many(keys[..50].iter().enumerate().map(|(i, k)| (k.clone(), value(i))))?;
+        tree.commit()?;
+        sizes.push(tree.live_size()?);
+    }
+    let extra = sizes[1] - sizes[0];
+    assert!((49 * 4000..50 * 4000).contains(&extra), "{sizes:?}");
+    Ok(())
+}
+
+#[test]
+fn in_memory_trees_commit_and_compact_without_a_file() -> io::Result<()> {
+    let mut tree = MerkleSearchTree::<String, u32>::new_in_memory()?;
+    let keys = generate_keys(2000, 5);
+    tree.insert_many(keys.iter().map(|k| (k.clone(), 0)))?;
+    tree.commit()?;
+    for key in &keys[..1000] {
+        tree.remove(key)?;
+    }
+    tree.commit()?;
+    assert_eq!(tree.len()?, 1000);
+
+    // Trees on disk and in memory agree on everything they hold.
+    let on_disk = MerkleSearchTree::<String, u32>::new_temporary()?;
+    on_disk.insert_many(keys[1000..].iter().map(|k| (k.clone(), 0)))?;
+    on_disk.commit()?;
+    assert_eq!(tree.root_hash(), on_disk.root_hash());
+
+    let fragmented = tree.disk_size()?;
+    tree.compact_in_place()?;
+    assert!(tree.disk_size()? < fragmented);
+    assert_eq!(tree.root_hash(), on_disk.root_hash());
+    assert_eq!(tree.iter()?.count(), 1000);
+
+    // Compacting to a path writes the tree out to a file.
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("copy.mst");
+    tree.compact(&path)?;
+    let reopened = MerkleSearchTree::<String, u32>::open(&path)?;
+    assert_eq!(reopened.root_hash(), on_disk.root_hash());
+
+    let tree = MerkleSearchTree::<String, u32>::default();
+    tree.insert("a".to_string(), 1)?;
+    tree.commit()?;
+    assert_eq!(tree.get("a")?.as_deref(), Some(&1));
+    Ok(())
+}
+
+#[test]
+fn handle_values_hash_as_their_encoding_not_their_cached_content() -> io::Result<()> {
+    /// A reference to content kept elsewhere, which may hold a copy of it.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Handle {
+        content_hash: [u8; 32],
+        #[serde(skip)]
+        cached: Option<Vec<u8>>,
+    }
+    let handle = |i: u8, cached: bool| Handle {
+        content_hash: *blake3::hash(&[i]).as_bytes(),
+        cached: cached.then(|| vec![i; 1 << 20]),
+    };
+
+    let mut resolved = MerkleSearchTree::<u32, Handle>::new_in_memory()?;
+    let bare = MerkleSearchTree::<u32, Handle>::new_in_memory()?;
+    for i in 0..200u8 {
+        resolved.insert(u32::from(i), handle(i, true))?;
+        bare.insert(u32::from(i), handle(i, false))?;
+    }
+    assert_eq!(resolved.root_hash(), bare.root_hash());
+
+    // Only the handle is stored; what it cached is gone once it is read back.
+    resolved.commit()?;
+    // Less than a single cached copy would take.
+    assert!(resolved.disk_size()? < 1 << 20);
+    let file = tempfile::NamedTempFile::new()?;
+    resolved.compact(file.path())?;
+    let reopened = MerkleSearchTree::<u32, Handle>::open(file.path())?;
+    let value = reopened.get(&7)?.unwrap();
+    assert_eq!(value.content_hash, handle(7, false).content_hash);
+    assert!(value.cached.is_none());
+    Ok(())
+}
+
+#[test]
+fn empty_values_are_distinct_from_short_and_missing_ones() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cases: [&[(u32, &[u8])]; 4] = [
+        &[(1, &[]), (2, &[0])],
+        &[(1, &[0]), (2, &[])],
+        &[(1, &[]), (2, &[])],
+        &[(2, &[0])],
+    ];
+    let mut roots = Vec::new();
+    for (i, entries) in cases.iter().enumerate() {
+        let path = dir.path().join(format!("{i}.mst"));
+        let tree = MerkleSearchTree::<u32, Vec<u8>>::open(&path)?;
+        tree.insert_many(entries.iter().map(|&(k, v)| (k, v.to_vec())))?;
+        tree.commit()?;
+        let root = tree.root_hash();
+        drop(tree);
+
+        let tree = MerkleSearchTree::<u32, Vec<u8>>::open(&path)?;
+        assert_eq!(tree.root_hash(), root);
+        for &(key, value) in *entries {
+            assert_eq!(tree.get(&key)?.as_deref().map(Vec::as_slice), Some(value));
+        }
+        assert_eq!(tree.len()?, entries.len() as u64);
+        roots.push(root);
+    }
+    for (i, root) in roots.iter().enumerate() {
+        assert!(!roots[..i].contains(root), "case {i} collides");
+    }
+    Ok(())
+}
+
+#[test]
+fn collated_keys_order_and_match_by_their_collation() -> io::Result<()> {
+    type Name = Collated<String, CaseInsensitive>;
+    let name = |s: &str| Name::new(s.to_string());
+
+    let file = tempfile::NamedTempFile::new()?;
+    let tree = MerkleSearchTree::<Name, u32>::open(file.path())?;
+    for (i, s) in ["banana", "Apple", "CHERRY", "apple"].into_iter().enumerate() {
+        tree.insert(name(s), i as u32)?;
+    }
+    assert_eq!(tree.len()?, 3);
+    assert_eq!(tree.get(&name("APPLE"))?.as_deref(), Some(&3));
+    assert!(tree.contains(&name("Cherry"))?);
+    let keys: Vec<_> = tree.keys()?.map(|k| k.map(|k| k.get().clone())).collect::<Result<_>>()?;
+    assert_eq!(keys, ["apple", "banana", "cherry"]);
+
+    // The casing a key was written in leaves no trace.
+    let other = MerkleSearchTree::<Name, u32>::new_in_memory()?;
+    for (s, i) in [("CHERRY", 2), ("APPLE", 3), ("Banana", 0)] {
+        other.insert(name(s), i)?;
+    }
+    assert_eq!(other.root_hash(), tree.root_hash());
+    tree.commit()?;
+    drop(tree);
+
+    let err = MerkleSearchTree::<String, u32>::open(file.path()).err().unwrap();
+    assert!(matches!(err, Error::SchemaMismatch { .. }));
+
+    // Any order works, even one `Ord` cannot express for the key type.
+    struct Descending;
+    impl Collation<u32> for Descending {
+        fn compare(a: &u32, b: &u32) -> std::cmp::Ordering {
+            b.cmp(a)
+        }
+    }
+    let tree = MerkleSearchTree::<Collated<u32, Descending>, ()>::new_in_memory()?;
+    tree.insert_many((0..100).map(|i| (Collated::new(i), ())))?;
+    let keys: Vec<_> = tree.keys()?.map(|k| k.map(|k| *k.get())).collect::<Result<_>>()?;
+    assert_eq!(keys, (0..100).rev().collect::<Vec<_>>());
+    Ok(())
+}
+
+#[test]
+fn churn_compacts_once_the_policy_finds_enough_garbage() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("churn.mst");
+    let mut tree = MerkleSearchTree::<String, u32>::builder()
+        .compaction_policy(CompactionPolicy::GarbageRatio(0.5))
+        .open(&path)?;
+    let mut never = MerkleSearchTree::<String, u32>::open(dir.path().join("never.mst"))?;
+    let keys = generate_keys(2000, 9);
+
+    let mut peak = 0;
+    let mut compacted_in = None;
+    for round in 0..10 {
+        for tree in [&tree, &never] {
+            tree.insert_many(keys.iter().map(|k| (k.clone(), round)))?;
+            tree.commit()?;
+        }
+        assert!(!never.compact_if_due()?);
+        peak = peak.max(std::fs::metadata(&path)?.len());
+        if tree.compact_if_due()? {
+            compacted_in = Some(round);
+            break;
+        }
+    }
+    let round = compacted_in.expect("never compacted");
+    assert!(round > 0);
+    assert!(std::fs::metadata(&path)?.len() < peak / 2);
+    assert_eq!(tree.root_hash(), never.root_hash());
+    // Right after compacting, too little has changed to measure again.
+    assert!(!tree.compact_if_due()?);
+    drop(tree);
+
+    let tree = MerkleSearchTree::<String, u32>::open(&path)?;
+    assert_eq!(tree.root_hash(), never.root_hash());
+    assert_eq!(tree.get(&keys[0])?.as_deref(), Some(&round));
+    Ok(())
+}
+
+#[test]
+fn expired_entries_are_absent_until_purged() -> io::Result<()> {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    static NOW: AtomicU64 = AtomicU64::new(1_000);
+    let at = |millis: u64| UNIX_EPOCH + Duration::from_millis(millis);
+    fn clock() -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(NOW.load(Ordering::Relaxed))
+    }
+
+    let tree = ExpiringTree::<u32, String>::new_in_memory()?.with_clock(clock);
+    tree.insert(1, "forever".to_string())?;
+    tree.insert_with_ttl(2, "soon".to_string(), at(2_000))?;
+    tree.insert_with_ttl(3, "later".to_string(), at(3_000))?;
+    assert_eq!(tree.len()?, 3);
+    assert_eq!(tree.get(&2)?.as_deref().map(|v| v.as_str()), Some("soon"));
+
+    NOW.store(2_000, Ordering::Relaxed);
+    assert!(tree.get(&2)?.is_none());
+    assert!(!tree.contains(&2)?);
+    assert!(tree.contains(&3)?);
+    assert_eq!(tree.len()?, 2);
+    // Still stored, and still hashed, until purged.
+    assert_eq!(tree.as_tree().len()?, 3);
+
+    let before = tree.root_hash();
+    assert_eq!(tree.purge_expired(at(2_500))?, 1);
+    assert_ne!(tree.root_hash(), before);
+    assert_eq!(tree.as_tree().len()?, 2);
+
+    // Re-inserting without an expiry makes a key permanent again.
+    tree.insert(3, "kept".to_string())?;
+    NOW.store(10_000, Ordering::Relaxed);
+    assert_eq!(tree.len()?, 2);
+    assert_eq!(tree.purge_expired(at(10_000))?, 0);
+    Ok(())
+}
+
+/// Gives key `n` of a `u32` tree level `n` at a fan-out of 2, for `n` up to 256, by
+/// hashing its encoding to exactly `n` leading zero bits. Everything else is hashed
+/// with BLAKE3; node hashes always cover more than a key's 5 bytes.
+#[derive(Default)]
+struct LevelHasher(Vec<u8>);
+
+impl TreeHasher for LevelHasher {
+    fn update(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn finalize(self) -> [u8; 32] {
+        let mut hash = *blake3::hash(&self.0).as_bytes();
+        if let Ok(n @ 0..=256) = postcard::from_bytes::<u32>(&self.0).map(|n| n as usize)
+            && self.0.len() <= 5
+        {
+            for bit in 0..n {
+                hash[bit / 8] &= !(0x80 >> (bit % 8));
+            }
+            if n < 256 {
+                hash[n / 8] |= 0x80 >> (n % 8);
+            }
+        }
+        hash
+    }
+}
+
+#[test]
+fn the_tallest_possible_tree_works_without_overflowing() -> io::Result<()> {
+    let tall = || {
+        OpenOptions::<u32, u32, LevelHasher>::new()
+            .fanout(2)
+            .create_in_memory()
+    };
+    // Every key on a level of its own, one below the other.
+    let mut tree = tall()?;
+    for n in 0..=node::MAX_LEVEL {
+        tree.insert(n, n)?;
+    }
+    assert_eq!(tree.key_level(&256), 256);
+    assert_eq!(tree.stats()?.height, 257);
+    tree.commit()?;
+    tree.compact_in_place()?;
+    for n in (0..=256).step_by(17) {
+        assert_eq!(tree.get(&n)?.as_deref(), Some(&n));
+    }
+    assert!(!tree.contains(&257)?);
+    for n in (0..=256).rev().step_by(2) {
+        tree.remove(&n)?;
+    }
+    assert_eq!(tree.len()?, 128);
+
+    let expected = tall()?;
+    expected.insert_many((1..256).step_by(2).map(|n| (n, n)))?;
+    assert_eq!(tree.root_hash(), expected.root_hash());
+    Ok(())
+}
+
+#[test]
+fn children_not_below_their_parent_are_corrupt() -> io::Result<()> {
+    use std::sync::Arc;
+
+    let tree = OpenOptions::<u32, u32, LevelHasher>::new()
+        .fanout(2)
+        .create_in_memory()?;
+    tree.insert_many((0..4).map(|n| (n, n)))?;
+    let root = tree.commit()?;
+
+    // A node at level 2 whose first child is the root, at level 3.
+    let store = Arc::clone(&tree.store);
+    let empty = node::Node::empty(1);
+    let empty = node::Link::Disk {
+        offset: store.write_node(&empty)?,
+        hash: empty.hash,
+        count: Some(0),
+    };
+    let forged = node::Node {
+        level: 2,
+        keys: vec![Arc::new(10)],
+        values: vec![Arc::new(10)],
+        children: vec![
+            node::Link::Disk { offset: root.offset, hash: root.hash, count: Some(4) },
+            empty,
+        ],
+        hash: Hash::from_bytes([1; 32]),
+        count: Some(5),
+        encoded_values: Arc::default(),
+    };
+    let offset = store.write_node(&forged)?;
+    store.flush()?;
+    store.write_metadata(offset, forged.hash, None)?;
+
+    let tree = MerkleSearchTree::<u32, u32, LevelHasher>::from_store(store)?;
+    assert_eq!(tree.get(&10)?.as_deref(), Some(&10));
+    let err = tree.get(&0).unwrap_err();
+    assert!(matches!(err, Error::Corrupt(_)), "{err}");
+    assert!(tree.insert(1, 1).is_err());
+    Ok(())
+}
+
+#[test]
+fn split_at_matches_halves_built_from_scratch() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let keys = {
+        let mut keys = generate_keys(3000, 11);
+        keys.sort();
+        keys
+    };
+    let build = |keys: &[String]| -> io::Result<MerkleSearchTree<String, usize>> {
+        let tree = MerkleSearchTree::new_in_memory()?;
+        tree.insert_many(keys.iter().map(|k| (k.clone(), k.len())))?;
+        Ok(tree)
+    };
+
+    // Pivots present and absent, and at both ends.
+    let absent = format!("{}~", keys[1234]);
+    let pivots = [keys[1500].clone(), absent, keys[0].clone(), "~".to_string()];
+    for (i, pivot) in pivots.iter().enumerate() {
+        let path = dir.path().join(format!("{i}.mst"));
+        let tree = MerkleSearchTree::open(dir.path().join(format!("left-{i}.mst")))?;
+        tree.insert_many(keys.iter().map(|k| (k.clone(), k.len())))?;
+        tree.commit()?;
+
+        let (left, right) = tree.split_at(pivot, &path)?;
+        let cut = keys.partition_point(|k| k < pivot);
+        assert_eq!(left.root_hash(), build(&keys[..cut])?.root_hash(), "{pivot}");
+        assert_eq!(right.root_hash(), build(&keys[cut..])?.root_hash(), "{pivot}");
+        assert_eq!(left.len()? + right.len()?, keys.len() as u64);
+
+        // The right half is committed in its own file; the left one commits as usual.
+        left.commit()?;
+        let reopened = MerkleSearchTree::<String, usize>::open(&path)?;
+        assert_eq!(reopened.root_hash(), right.root_hash());
+        assert_eq!(reopened.get(pivot)?.is_some(), keys.contains(pivot));
+    }
+    Ok(())
+}
+
+#[test]
+fn values_are_encoded_once_between_insert_and_commit() -> io::Result<()> {
+    use std::cell::Cell;
+
+    thread_local! {
+        static ENCODED: Cell<usize> = const { Cell::new(0) };
+    }
+    /// A value that counts how often it is encoded on this thread.
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Counted(Vec<u8>);
+    impl Serialize for Counted {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            ENCODED.with(|n| n.set(n.get() + 1));
+            self.0.serialize(serializer)
+        }
+    }
+    let encoded_during = |f: &mut dyn FnMut() -> crate::Result<()>| {
+        ENCODED.with(|n| n.set(0));
+        f()?;
+        Ok::<_, io::Error>(ENCODED.with(Cell::get))
+    };
+
+    let keys = generate_keys(300, 90);
+    for blob_threshold in [None, Some(1024)] {
+        let file = tempfile::NamedTempFile::new()?;
+        let mut options = MerkleSearchTree::<String, Counted>::builder();
+        if let Some(threshold) = blob_threshold {
+            options.blob_threshold(threshold);
+        }
+        let tree = options.open(file.path())?;
+        for (i, key) in keys.iter().enumerate() {
+            tree.insert(key.clone(), Counted(vec![i as u8; 4096]))?;
+        }
+
+        // Replacing a value encodes only the new one, and committing encodes none.
+        let replace = &mut || tree.insert(keys[7].clone(), Counted(vec![0; 4096]));
+        assert_eq!(encoded_during(replace)?, 1);
+        assert_eq!(encoded_during(&mut || tree.commit().map(drop))?, 0);
+
+        let root = tree.root_hash();
+        drop(tree);
+        let tree = options.open(file.path())?;
+        assert_eq!(tree.root_hash(), root);
+        assert_eq!(tree.get(&keys[7])?.as_deref(), Some(&Counted(vec![0; 4096])));
+        assert_eq!(tree.get(&keys[8])?.as_deref(), Some(&Counted(vec![8; 4096])));
+        assert!(tree.verify()?.is_ok(), "{blob_threshold:?}");
+    }
+    Ok(())
+}
+
+#[test]
+fn restoring_a_checkpoint_undoes_the_writes_since() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let tree = MerkleSearchTree::<String, u32>::open(file.path())?;
+    let keys = generate_keys(200, 91);
+    for key in &keys[..100] {
+        tree.insert(key.clone(), 1)?;
+    }
+    tree.commit()?;
+    tree.remove(&keys[0])?;
+
+    let checkpoint = tree.checkpoint();
+    assert_eq!(checkpoint.root_hash(), tree.root_hash());
+    for key in &keys[100..] {
+        tree.insert(key.clone(), 2)?;
+    }
+    tree.insert(keys[1].clone(), 2)?;
+    tree.insert(keys[0].clone(), 2)?;
+    tree.restore(&checkpoint)?;
+
+    assert_eq!(tree.root_hash(), checkpoint.root_hash());
+    assert_eq!(tree.len()?, 99);
+    assert!(!tree.contains(&keys[0])?);
+    assert_eq!(tree.get(&keys[1])?.as_deref(), Some(&1));
+    for key in &keys[100..] {
+        assert!(!tree.contains(key)?);
+    }
+
+    // The same checkpoint can be gone back to until the next commit.
+    tree.insert(keys[150].clone(), 3)?;
+    tree.restore(&checkpoint)?;
+    tree.commit()?;
+    let err = tree.restore(&checkpoint).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    let other = MerkleSearchTree::<String, u32>::new_in_memory()?;
+    assert!(other.restore(&tree.checkpoint()).is_err());
+
+    drop(tree);
+    let tree = MerkleSearchTree::<String, u32>::open(file.path())?;
+    assert_eq!(tree.root_hash(), checkpoint.root_hash());
+    Ok(())
+}
+
+#[test]
+fn first_n_and_last_n_read_only_the_edges_of_the_tree() -> io::Result<()> {
+    use std::sync::Arc;
+
+    let file = tempfile::NamedTempFile::new()?;
+    let keys = generate_keys(2000, 92);
+    let tree = MerkleSearchTree::open(file.path())?;
+    for (i, k) in keys.iter().enumerate() {
+        tree.insert(k.clone(), i as u64)?;
+    }
+    tree.commit()?;
+    let entries: Vec<_> = tree.iter()?.map(|e| e.map(|(k, v)| (k, *v))).collect::<Result<_>>()?;
+    let stats = tree.stats()?;
+    drop(tree);
+
+    let tree = MerkleSearchTree::<String, u64>::open(file.path())?;
+    let first = tree.first_n(20)?;
+    let loads = tree.cache_metrics().misses;
+    assert!(loads < stats.node_count / 4, "{loads} of {}", stats.node_count);
+    let last = tree.last_n(20)?;
+    let loads = tree.cache_metrics().misses - loads;
+    assert!(loads < stats.node_count / 4, "{loads} of {}", stats.node_count);
+    let pairs = |found: Vec<(Arc<String>, Arc<u64>)>| -> Vec<(Arc<String>, u64)> {
+        found.into_iter().map(|(k, v)| (k, *v)).collect()
+    };
+    assert_eq!(pairs(first), entries[..20]);
+    let mut tail = entries[entries.len() - 20..].to_vec();
+    tail.reverse();
+    assert_eq!(pairs(last), tail);
+
+    assert!(tree.first_n(0)?.is_empty() && tree.last_n(0)?.is_empty());
+    assert_eq!(pairs(tree.first_n(5000)?), entries);
+    let mut reversed = entries.clone();
+    reversed.reverse();
+    assert_eq!(pairs(tree.last_n(5000)?), reversed);
+    let empty = MerkleSearchTree::<String, u64>::new_in_memory()?;
+    assert!(empty.first_n(3)?.is_empty() && empty.last_n(3)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn iter_rev_and_range_rev_are_iter_and_range_reversed() -> io::Result<()> {
+    use std::ops::Bound;
+
+    let keys = generate_keys(1000, 93);
+    let tree = MerkleSearchTree::new_temporary()?;
+    for (i, k) in keys.iter().enumerate() {
+        tree.insert(k.clone(), i)?;
+    }
+    tree.commit()?;
+
+    let forward: Vec<(String, usize)> = tree
+        .iter()?
+        .map(|e| e.map(|(k, v)| ((*k).clone(), *v)))
+        .collect::<Result<_>>()?;
+    let backward: Vec<(String, usize)> = tree
+        .iter_rev()?
+        .map(|e| e.map(|(k, v)| ((*k).clone(), *v)))
+        .collect::<Result<_>>()?;
+    assert_eq!(forward.len(), 1000);
+    assert!(backward.iter().eq(forward.iter().rev()));
+
+    let (a, b) = (forward[100].0.as_str(), forward[600].0.as_str());
+    let ranges: Vec<(Bound<&str>, Bound<&str>)> = vec![
+        (Bound::Included(a), Bound::Excluded(b)),
+        (Bound::Excluded(a), Bound::Included(b)),
+        (Bound::Included("key-8"), Bound::Unbounded),
+        (Bound::Unbounded, Bound::Excluded("key-1")),
+        (Bound::Included(b), Bound::Excluded(a)),
+        (Bound::Included(a), Bound::Included(a)),
+        (Bound::Excluded(a), Bound::Excluded(a)),
+    ];
+    for range in ranges {
+        let keys = |iter: &mut dyn Iterator<Item = Result<(std::sync::Arc<String>, _)>>| {
+            iter.map(|e| e.map(|(k, _)| (*k).clone())).collect::<Result<Vec<_>>>()
+        };
+        let mut want = keys(&mut tree.range::<str, _>(range)?)?;
+        want.reverse();
+        assert_eq!(keys(&mut tree.range_rev::<str, _>(range)?)?, want, "range {range:?}");
+    }
+
+    let empty = MerkleSearchTree::<String, usize>::new_in_memory()?;
+    assert!(empty.iter_rev()?.next().is_none());
+    Ok(())
+}
+
+/// Levels decide where every key of every file is. These are pinned so a change to
+/// the level function, or to the bytes it hashes, cannot go unnoticed.
+#[test]
+fn key_levels_are_pinned() {
+    let level = |key: &str, fanout| {
+        node::Node::<String, u64>::calc_level::<Blake3Hasher>(&key.to_string(), fanout)
+    };
+    assert_eq!(DEFAULT_FANOUT, 16);
+    for (key, expected) in [("key-0", 0), ("key-1", 1), ("key-396", 2), ("key-2691", 3)] {
+        assert_eq!(level(key, DEFAULT_FANOUT), expected, "{key}");
+    }
+    for (key, expected) in [("key-0", 3), ("key-1", 4), ("key-2", 0)] {
+        assert_eq!(level(key, 2), expected, "{key}");
+    }
+    for (key, expected) in [(0, 2), (1, 1), (42, 0)] {
+        assert_eq!(node::Node::<u64, u64>::calc_level::<Blake3Hasher>(&key, 2), expected);
+    }
+}
+
+#[test]
+fn public_key_levels_follow_the_fanout_distribution() -> io::Result<()> {
+    type Tree = MerkleSearchTree<String, u64>;
+    let keys = generate_keys(20_000, 80);
+    let tree: Tree = OpenOptions::new().fanout(4).create_temporary()?;
+    for key in &keys[..100] {
+        assert_eq!(tree.key_level(key), Tree::level_at_fanout(key, 4));
+        assert_eq!(Tree::level_of(key), Tree::level_at_fanout(key, DEFAULT_FANOUT));
+    }
+
+    // One key in 16 reaches level 1, one in 256 level 2.
+    let at_least = |level| keys.iter().filter(|key| Tree::level_of(key) >= level).count();
+    assert!((1_000..1_500).contains(&at_least(1)), "{}", at_least(1));
+    assert!((40..120).contains(&at_least(2)), "{}", at_least(2));
+    Ok(())
+}
+
+/// `lib.rs` only declares modules and re-exports them, so the tree, its nodes and its
+/// store each have a single implementation. This fails to compile otherwise.
+#[test]
+fn the_exported_tree_is_the_tree_module() -> io::Result<()> {
+    let tree: tree::MerkleSearchTree<String, u64> = crate::MerkleSearchTree::new_in_memory()?;
+    let _: &std::sync::Arc<store::Store<String, u64>> = &tree.store;
+    tree.insert("a".to_string(), 1)?;
+    let root = tree.resolve_link(&tree.snapshot().root)?;
+    let _: &node::Node<String, u64> = &root;
+    assert_eq!(root.hash, tree.root_hash());
+    Ok(())
+}
+
+#[test]
+fn to_dot_draws_every_node_once_with_edges_to_its_children() -> io::Result<()> {
+    let empty = MerkleSearchTree::<String, u32>::new_in_memory()?;
+    assert_eq!(empty.to_dot()?, "digraph mst {\n    node [shape=box];\n}\n");
+
+    let tree = MerkleSearchTree::new_temporary()?;
+    let keys = generate_keys(300, 96);
+    for key in &keys {
+        tree.insert(key.clone(), 0u32)?;
+    }
+    tree.insert("a\"b".to_string(), 0)?;
+    tree.commit()?;
+
+    let dot = tree.to_dot()?;
+    assert!(dot.starts_with("digraph mst {\n") && dot.ends_with("}\n"));
+    let nodes = dot.lines().filter(|line| line.contains("[label=")).count();
+    let edges = dot.lines().filter(|line| line.contains(" -> ")).count();
+    assert!(nodes > 1);
+    assert_eq!(edges, nodes - 1);
+    let root = tree.root_hash();
+    assert!(dot.contains(&format!("\"{root}\" [label=\"level ")));
+    assert!(keys.iter().all(|key| dot.contains(&format!("\\\"{key}\\\""))));
+    assert!(dot.contains(r#"\"a\\\"b\""#));
+    Ok(())
+}
+
+#[test]
+fn read_only_trees_refresh_to_what_another_handle_committed() -> io::Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let writer = MerkleSearchTree::<String, u64>::open(file.path())?;
+    let reader = MerkleSearchTree::<String, u64>::open_read_only(file.path())?;
+    assert!(!reader.refresh()?);
+    let err = writer.refresh().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    let keys = generate_keys(600, 97);
+    let mut commits = reader.subscribe();
+    for (round, chunk) in keys.chunks(100).enumerate() {
+        // Rewriting every value orphans most nodes, whose space later rounds reuse.
+        for key in &keys {
+            if writer.contains(key)? {
+                writer.insert(key.clone(), round as u64)?;
+            }
+        }
+        for key in chunk {
+            writer.insert(key.clone(), round as u64)?;
+        }
+        writer.commit()?;
+        // Cache every node of the previous root before following the writer.
+        assert_eq!(reader.iter()?.count() as u64, 100 * round as u64);
+
+        assert!(reader.refresh()?);
+        assert!(!reader.refresh()?);
+        assert_eq!(reader.root_hash(), writer.root_hash());
+        assert_eq!(*commits.borrow_and_update(), writer.root_hash());
+        for key in &keys[..100 * (round + 1)] {
+            assert_eq!(reader.get(key)?.as_deref(), Some(&(round as u64)), "{round}");
+        }
+        assert!(reader.verify()?.is_ok());
+    }
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::MerkleValue;
+
+/// Values that can mark a removed key, for trees opened with
+/// [`OpenOptions::tombstones`].
+///
+/// In that mode, removing a key stores a tombstone under it instead, so the removal
+/// shows up in the root hash, in proofs and in diffs, and a replica that missed it
+/// cannot bring the key back. The value type needs a variant no live value uses;
+/// [`Tombstoned`] adds one to any type.
+///
+/// [`OpenOptions::tombstones`]: crate::OpenOptions::tombstones
+pub trait Tombstone: MerkleValue {
+    /// The marker for a key removed at `removed_at`, in milliseconds since the Unix
+    /// epoch.
+    fn tombstone(removed_at: u64) -> Self;
+
+    /// When the key was removed, if this value is a tombstone.
+    fn removed_at(&self) -> Option<u64>;
+}
+
+/// A value, or the tombstone of a removed one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Tombstoned<V> {
+    Live(V),
+    /// Removed at this time, in milliseconds since the Unix epoch.
+    Removed(u64),
+}
+
+impl<V: MerkleValue> Tombstone for Tombstoned<V> {
+    fn tombstone(removed_at: u64) -> Self {
+        Tombstoned::Removed(removed_at)
+    }
+
+    fn removed_at(&self) -> Option<u64> {
+        match self {
+            Tombstoned::Live(_) => None,
+            Tombstoned::Removed(at) => Some(*at),
+        }
+    }
+}
+
+/// The [`Tombstone`] functions of a value type, kept by trees in tombstone mode,
+/// whose methods do not otherwise require `V: Tombstone`.
+pub(crate) struct Tombstones<V> {
+    pub(crate) tombstone: fn(u64) -> V,
+    pub(crate) removed_at: fn(&V) -> Option<u64>,
+}
+
+impl<V: Tombstone> Tombstones<V> {
+    pub(crate) fn new() -> Self {
+        Self {
+            tombstone: V::tombstone,
+            removed_at: V::removed_at,
+        }
+    }
+}
+
+impl<V> Tombstones<V> {
+    pub(crate) fn is_tombstone(&self, value: &V) -> bool {
+        (self.removed_at)(value).is_some()
+    }
+}
+
+impl<V> Clone for Tombstones<V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<V> Copy for Tombstones<V> {}
+
+/// `time` in milliseconds since the Unix epoch; 0 for earlier times.
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
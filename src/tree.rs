@@ -1,243 +1,1921 @@
-use blake3::Hash;
+use crate::{Hash, Version};
 
+use crate::diff::{self, Difference};
+use crate::dump::{self, EntryFormat, EntryReader};
+use crate::entry::Entry;
+use crate::export::{self, NodeExport};
+use crate::iter::{Iter, KeysIter, RevIter, ValuesIter};
 use crate::node::{Link, Node};
-use crate::store::Store;
-use crate::{MerkleKey, MerkleValue, NodeId};
+use crate::options::OpenOptions;
+use crate::proof::{Proof, ProofNode};
+use crate::snapshot::{Checkpoint, Snapshot};
+use crate::stats::{self, TreeStats};
+use crate::store::{
+    CompactionPolicy, EncodedEntries, MAX_FANOUT, Store, StoreConfig, SyncPolicy,
+};
+use crate::tombstone::{Tombstone, Tombstones, unix_millis};
+use crate::verify::{self, VerifyReport};
+use crate::walk::{self, DotWriter, NodeVisitor};
+use crate::{
+    Blake3Hasher, CacheMetrics, Compression, DEFAULT_FANOUT, MerkleKey, MerkleValue, NodeId,
+    Result, TreeHasher,
+};
+use serde::{Deserialize, Serialize};
 use std::borrow::Borrow;
-use std::fs::OpenOptions;
-use std::io;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::num::NonZeroU32;
+use std::ops::{Bound, RangeBounds};
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tokio::sync::watch;
 
-pub struct MerkleSearchTree<K: MerkleKey, V: MerkleValue> {
-    pub(crate) root: Link<K, V>,
+/// A single write applied by [`MerkleSearchTree::transaction`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op<K, V> {
+    Insert(K, V),
+    Remove(K),
+}
+
+/// What [`MerkleSearchTree::extend`] does with a key that is already in the tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Replace the value, like `insert`.
+    Overwrite,
+    /// Keep the value already there.
+    KeepExisting,
+    /// Fail with `AlreadyExists`, leaving the tree unchanged.
+    Error,
+}
+
+/// A Merkle Search Tree stored in a file.
+///
+/// `H` is the hash function used for node hashes and key levels. Constructors
+/// without a `_with_hasher` suffix use BLAKE3.
+///
+/// Writes take `&self`, so a tree can be shared between threads, e.g. in an `Arc`.
+/// Mutations, including `commit`, hold a write lock on the root for their whole
+/// duration and are applied one at a time. Lookups hold the read lock while they
+/// descend, so each sees the tree as it was when it took the lock, between two
+/// mutations. Iterators and snapshots keep reading the root they started from.
+pub struct MerkleSearchTree<K: MerkleKey, V: MerkleValue, H: TreeHasher = Blake3Hasher> {
+    pub(crate) state: RwLock<TreeState<K, V>>,
     pub(crate) store: Arc<Store<K, V>>,
+    /// Holds the root hash of the last commit, for [`subscribe`](Self::subscribe).
+    commits: watch::Sender<Hash>,
+    /// Set in tombstone mode; see [`OpenOptions::tombstones`].
+    pub(crate) tombstones: Option<Tombstones<V>>,
+    hasher: PhantomData<fn() -> H>,
+}
+
+/// The nodes on the way to a key, and its value if it was found.
+type ProofPath<K, V> = (Vec<ProofNode<K, V>>, Option<Arc<V>>);
+/// A value and its inclusion proof.
+type ProvenValue<K, V> = (Arc<V>, Proof<K, V>);
+
+pub(crate) struct TreeState<K: MerkleKey, V: MerkleValue> {
+    pub(crate) root: Link<K, V>,
     last_committed: Option<(u64, Hash)>,
+    /// Links from the nodes written since the last commit to nodes already on disk, by
+    /// the offset linked to. Only left over by a commit that failed; see `flush_dirty`.
+    shared: HashMap<NodeId, i64>,
 }
 
 impl<K: MerkleKey, V: MerkleValue> MerkleSearchTree<K, V> {
-    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
-        let store = Store::open(path)?;
-        if let Some((offset, hash)) = store.read_metadata()? {
-            Ok(Self {
-                root: Link::Disk { offset, hash },
-                store,
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_hasher(path)
+    }
+
+    /// Options for opening a tree with any combination of settings. The `open_with_*`
+    /// constructors are shorthands for setting one of them.
+    ///
+    /// Trees with another hasher are opened through [`OpenOptions::new`].
+    pub fn builder() -> OpenOptions<K, V> {
+        OpenOptions::new()
+    }
+
+    /// Opens a tree whose node cache holds at most `cache_capacity` nodes, evicting
+    /// the least recently used ones beyond that.
+    pub fn open_with_cache_capacity<P: AsRef<Path>>(
+        path: P,
+        cache_capacity: usize,
+    ) -> Result<Self> {
+        Self::builder().cache_capacity(cache_capacity).open(path)
+    }
+
+    /// Opens a committed tree without write access. The file is never modified;
+    /// `insert`, `remove`, `commit` and `compact` return `PermissionDenied`.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::builder().read_only(true).open(path)
+    }
+
+    /// Opens a tree, creating the file with the given page size if it does not exist.
+    ///
+    /// The page size must be a power of two of at least 512 bytes. Existing files keep
+    /// the page size they were created with.
+    pub fn open_with_page_size<P: AsRef<Path>>(path: P, page_size: u64) -> Result<Self> {
+        Self::builder().page_size(page_size).open(path)
+    }
+
+    /// Opens a tree that checks every node it loads against the hash recorded by its
+    /// parent, failing with `InvalidData` if the node was corrupted on disk.
+    ///
+    /// Each load recomputes a node hash, so reads cost noticeably more CPU.
+    pub fn open_with_verification<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::builder().verify_on_read(true).open(path)
+    }
+
+    /// Opens a tree whose file is tagged with `schema_id` instead of a fingerprint of
+    /// the key and value type names.
+    ///
+    /// Type names may change between compiler versions or when types are moved, so
+    /// long-lived files should use an explicit id and bump it whenever the key or
+    /// value encoding changes. The id must be non-zero, and a file created with one
+    /// must always be opened with the same id.
+    pub fn open_with_schema_id<P: AsRef<Path>>(path: P, schema_id: u64) -> Result<Self> {
+        Self::builder().schema_id(schema_id).open(path)
+    }
+
+    /// Opens a tree that writes nodes with the given codec. Nodes are stored
+    /// uncompressed whenever compression would not make them smaller.
+    ///
+    /// The codec is recorded per node, so a file can be reopened with any setting.
+    pub fn open_with_compression<P: AsRef<Path>>(
+        path: P,
+        compression: Compression,
+    ) -> Result<Self> {
+        Self::builder().compression(compression).open(path)
+    }
+
+    /// Opens a tree whose commits sync to disk as `sync_policy` dictates. See
+    /// [`SyncPolicy`] for what each mode guarantees after a crash.
+    pub fn open_with_sync_policy<P: AsRef<Path>>(path: P, sync_policy: SyncPolicy) -> Result<Self> {
+        Self::builder().sync_policy(sync_policy).open(path)
+    }
+
+    /// Opens a tree, creating the file with the given fan-out if it does not exist.
+    ///
+    /// A key lands one level higher for every `log2(fanout)` leading zero bits of its
+    /// hash, so nodes hold `fanout - 1` keys on average: larger fan-outs make fewer,
+    /// bigger nodes. The fan-out must be a power of two between 2 and 256. It is part
+    /// of the tree's shape, and so of its root hash, so it is recorded in the file and
+    /// opening a file with a different fan-out fails.
+    pub fn open_with_fanout<P: AsRef<Path>>(path: P, fanout: u32) -> Result<Self> {
+        Self::builder().fanout(fanout).open(path)
+    }
+
+    /// Opens a read-only view of the tree at `version`, as returned by `commit`,
+    /// instead of the latest committed root.
+    ///
+    /// Older roots stay in the file until later commits reuse their space or the file
+    /// is compacted. Every node is checked against its hash as it is loaded, so reads
+    /// through a root whose nodes were since overwritten fail with `InvalidData`
+    /// rather than returning wrong entries.
+    pub fn open_at_root<P: AsRef<Path>>(path: P, version: Version) -> Result<Self> {
+        let config = StoreConfig {
+            read_only: true,
+            verify_on_read: true,
+            ..StoreConfig::default()
+        };
+        Self::from_root(Store::open::<Blake3Hasher, _>(path, config)?, version)
+    }
+
+    /// Creates a new MST backed by a temporary file.
+    pub fn new_temporary() -> Result<Self> {
+        Self::builder().create_temporary()
+    }
+
+    /// Creates a new MST held entirely in memory, with no file at all.
+    pub fn new_in_memory() -> Result<Self> {
+        Self::builder().create_in_memory()
+    }
+}
+
+impl<K: MerkleKey, V: MerkleValue, H: TreeHasher> MerkleSearchTree<K, V, H> {
+    /// Opens a tree that hashes with `H`.
+    pub fn open_with_hasher<P: AsRef<Path>>(path: P) -> Result<Self> {
+        OpenOptions::new().open(path)
+    }
+
+    /// Creates a new MST backed by a temporary file that hashes with `H`.
+    pub fn new_temporary_with_hasher() -> Result<Self> {
+        OpenOptions::new().create_temporary()
+    }
+
+    /// Creates a new MST held in memory that hashes with `H`.
+    pub fn new_in_memory_with_hasher() -> Result<Self> {
+        OpenOptions::new().create_in_memory()
+    }
+
+    pub(crate) fn from_store(store: Arc<Store<K, V>>) -> Result<Self> {
+        let last_committed = store.read_metadata()?;
+        let root = match last_committed {
+            Some((offset, hash)) => Link::Disk {
+                offset,
+                hash,
+                count: None,
+            },
+            None => Link::Loaded(Arc::new(Node::empty(0))),
+        };
+        let committed_hash = last_committed.map_or(Hash::default(), |(_, hash)| hash);
+        Ok(Self {
+            state: RwLock::new(TreeState {
+                root,
+                last_committed,
+                shared: HashMap::new(),
+            }),
+            store,
+            commits: watch::Sender::new(committed_hash),
+            tombstones: None,
+            hasher: PhantomData,
+        })
+    }
+
+    /// Builds a tree on `root` instead of the root the metadata points at, after
+    /// checking that the root node loads and matches `hash`.
+    fn from_root(store: Arc<Store<K, V>>, version: Version) -> Result<Self> {
+        let Version { offset, hash } = version;
+        if offset < store.config().page_size || offset >= store.file_len()? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("offset {offset} is outside the node area of the file"),
+            )
+            .into());
+        }
+        store.load_node(offset, hash)?;
+        Ok(Self {
+            state: RwLock::new(TreeState {
+                root: Link::Disk {
+                    offset,
+                    hash,
+                    count: None,
+                },
                 last_committed: Some((offset, hash)),
-            })
+                shared: HashMap::new(),
+            }),
+            store,
+            commits: watch::Sender::new(hash),
+            tombstones: None,
+            hasher: PhantomData,
+        })
+    }
+
+    pub fn commit(&self) -> Result<Version> {
+        self.ensure_writable()?;
+        self.commit_locked(&mut self.state.write().unwrap())
+    }
+
+    /// Commits only if the root committed in the file is still `expected_prev`, all
+    /// zeros standing for no commit at all. Otherwise nothing is committed and the
+    /// hash of the root found in the file is returned as the error; the tree keeps its
+    /// uncommitted changes, so the caller can merge them with that root and retry.
+    ///
+    /// This lets processes commit to one file optimistically. An exclusive advisory
+    /// lock on the file is held from reading its root to writing the new one, and the
+    /// commit appends everything it writes to the end of the file instead of reusing
+    /// free space, which another process may have taken. Once another handle has
+    /// committed, this tree stops reusing the space freed before, and leaves it to
+    /// `compact`. Plain [`commit`](Self::commit) takes no lock and reuses space, so it
+    /// is only safe while no other process writes the file.
+    ///
+    /// Memory-mapped trees are excluded and fail with `Unsupported`, as the map is
+    /// only sound while no other process writes the file.
+    pub fn commit_if(&self, expected_prev: Hash) -> Result<Result<Version, Hash>> {
+        self.ensure_writable()?;
+        #[cfg(feature = "mmap")]
+        if self.store.config().mmap {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "memory-mapped trees cannot commit alongside other handles",
+            )
+            .into());
+        }
+        self.commit_guarded(
+            &mut self.state.write().unwrap(),
+            Some(expected_prev),
+            None,
+        )
+    }
+
+    /// Like [`commit`](Self::commit), but first encodes the keys and values of every
+    /// changed node in parallel, which pays off for commits that write many nodes.
+    /// The nodes are still written one at a time in the same order, so the file ends
+    /// up exactly as after `commit`.
+    ///
+    /// Files before format version 5, and trees that store values in blobs, are
+    /// committed as by `commit`.
+    #[cfg(feature = "parallel")]
+    pub fn commit_parallel(&self) -> Result<Version>
+    where
+        K: Send + Sync,
+        V: Send + Sync,
+    {
+        use rayon::prelude::*;
+
+        self.ensure_writable()?;
+        let mut state = self.state.write().unwrap();
+        let entries = if self.store.encodes_entries() {
+            let store = &self.store;
+            let nodes = Self::dirty_nodes(&state.root);
+            let entries = nodes.par_iter().map(|node| store.encode_entries(node));
+            Some(entries.collect::<io::Result<Vec<_>>>()?)
         } else {
-            Ok(Self {
-                root: Link::Loaded(Arc::new(Node::empty(0))),
-                store,
-                last_committed: None,
-            })
+            None
+        };
+        match self.commit_guarded(&mut state, None, entries)? {
+            Ok(committed) => Ok(committed),
+            Err(_) => unreachable!("only guarded commits are refused"),
+        }
+    }
+
+    fn commit_locked(&self, state: &mut TreeState<K, V>) -> Result<Version> {
+        match self.commit_guarded(state, None, None)? {
+            Ok(committed) => Ok(committed),
+            Err(_) => unreachable!("only guarded commits are refused"),
         }
     }
 
-    pub fn commit(&mut self) -> io::Result<(u64, Hash)> {
-        // 1. Flush the nodes (recursive)
+    /// `entries` are the encoded keys and values of the nodes `flush_dirty` writes,
+    /// in the order it writes them, if encoded ahead.
+    fn commit_guarded(
+        &self,
+        state: &mut TreeState<K, V>,
+        expected_prev: Option<Hash>,
+        entries: Option<Vec<EncodedEntries>>,
+    ) -> Result<Result<Version, Hash>> {
+        // The lock is held until the metadata is written; see `commit_if`.
+        let mut guarded = None;
+        let mut in_file = state.last_committed;
+        if let Some(expected_prev) = expected_prev {
+            guarded = Some(self.store.begin_guarded_commit()?);
+            in_file = self.store.follow_latest_commit()?;
+            let current = in_file.map_or(Hash::default(), |(_, hash)| hash);
+            if current != expected_prev {
+                return Ok(Err(current));
+            }
+        }
+
+        // 1. Flush the nodes, bottom-up
         // If no changes, this returns the existing Disk offset/hash instantly.
-        let (offset, hash) = self.flush_recursive(&self.root)?;
+        let (offset, hash, deduplicated) =
+            self.flush_dirty(&mut state.root, &mut state.shared, entries)?;
 
-        // 2. Did anything actually change?
-        if let Some((last_off, last_hash)) = self.last_committed
-            && last_off == offset
-            && last_hash == hash
-        {
+        // 2. Did anything actually change? A guarded commit compares with the root in
+        // the file, which another handle may have committed.
+        if in_file == Some((offset, hash)) {
             // Nothing changed. Return early.
-            return Ok((offset, hash));
+            if deduplicated {
+                self.store.remove_parent(offset);
+            }
+            state.shared.clear();
+            return Ok(Ok(Version::new(offset, hash)));
         }
 
-        // 3. Write metadata and sync
-        self.store.write_metadata(offset, hash)?;
-        self.store.flush()?;
-        self.root = Link::Disk { offset, hash };
+        // 3. Find the previous version's nodes that the new root no longer reaches
+        let mut orphaned = Vec::new();
+        let mut reached = HashMap::new();
+        if let Some(last) = state.last_committed {
+            self.collect_orphans(last, &state.shared, &mut orphaned, &mut reached)?;
+        }
+        // Nodes that gained links or lost parents, and did not lose the last one.
+        let mut parents = state.shared.clone();
+        for (offset, reached) in reached {
+            *parents.entry(offset).or_default() -= i64::from(reached);
+        }
 
-        // 4. Update tracker
-        self.last_committed = Some((offset, hash));
+        // 4. Sync the new nodes and free list, then write and sync the root pointer.
+        // The metadata write is the commit point: a crash before it leaves the previous
+        // root in place, and it never refers to nodes that are not yet on disk. The
+        // sync policy may skip both syncs, giving up that guarantee.
+        let retirement = self.store.prepare_retire(&orphaned, &parents)?;
+        let sync = self.store.sync_next_commit();
+        self.store.flush_with(sync)?;
+        self.store
+            .write_metadata(offset, hash, retirement.free_list_offset())?;
+        self.store.flush_with(sync)?;
+        state.shared.clear();
+        self.store.retire_nodes(retirement);
 
-        Ok((offset, hash))
+        // 5. Update tracker and notify subscribers; there may be none.
+        state.last_committed = Some((offset, hash));
+        self.commits.send_replace(hash);
+        drop(guarded);
+
+        Ok(Ok(Version::new(offset, hash)))
     }
 
-    /// Creates a new MST backed by a temporary file.
-    pub fn new_temporary() -> io::Result<Self> {
-        let file = tempfile::tempfile()?;
-        let store = Store::new(file);
+    /// Moves a read-only tree to the root that another handle to its file committed
+    /// last, so readers can follow a single writer without reopening. Returns whether
+    /// the root moved.
+    ///
+    /// Snapshots and iterators taken before keep their root, but the writer does not
+    /// know about them and may reuse the space of their nodes once it has committed
+    /// twice more; reading them then fails, or with `verify_on_read` unset, may even
+    /// return wrong entries.
+    ///
+    /// A refresh sees a commit once its root pointer is written, and relies on the
+    /// writer having written the commit's nodes before it. That holds between
+    /// processes that share one machine's page cache, but not on file systems that
+    /// may reorder writes, such as network ones: there the writer has to sync every
+    /// commit, as the default sync policy does, and readers refresh only after
+    /// learning that a commit returned.
+    ///
+    /// Fails for trees that are not read-only, since their next commit would write
+    /// over the other handle's, and for memory-mapped ones, as the writer would
+    /// change bytes under the map.
+    pub fn refresh(&self) -> Result<bool> {
+        let config = self.store.config();
+        #[cfg(feature = "mmap")]
+        if config.mmap {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "memory-mapped trees cannot follow another handle",
+            )
+            .into());
+        }
+        if !config.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only read-only trees can follow another handle",
+            )
+            .into());
+        }
+        let mut state = self.state.write().unwrap();
+        let committed = self.store.reload_metadata()?;
+        if committed == state.last_committed {
+            return Ok(false);
+        }
+        let Some((offset, hash)) = committed else {
+            return Ok(false);
+        };
+        state.root = Link::Disk {
+            offset,
+            hash,
+            count: None,
+        };
+        state.last_committed = committed;
+        self.commits.send_replace(hash);
+        Ok(true)
+    }
 
-        Ok(Self {
-            root: Link::Loaded(Arc::new(Node::empty(0))),
-            store,
-            last_committed: None,
-        })
+    /// Returns a receiver that observes the root hash of every commit that writes a
+    /// new root, starting from the current committed one, which is all zeros if there
+    /// is none. Commits that change nothing are not announced.
+    ///
+    /// Only the latest hash is kept: a receiver that falls behind skips straight to
+    /// it. Dropping receivers never affects commits.
+    pub fn subscribe(&self) -> watch::Receiver<Hash> {
+        self.commits.subscribe()
     }
 
     /// Inserts a key-value pair into the tree, modifying it in-place.
-    pub fn insert(&mut self, key: K, value: V) -> io::Result<()> {
+    pub fn insert(&self, key: K, value: V) -> Result<()> {
+        self.ensure_writable()?;
+        let mut state = self.state.write().unwrap();
         let key_arc = Arc::new(key);
         let val_arc = Arc::new(value);
 
-        let root_node = self.resolve_link(&self.root)?;
+        let root_node = self.resolve_link(&state.root)?;
+
+        let new_root_node = self.put_entry(&root_node, key_arc, val_arc)?;
+
+        // An identical pair leaves the root as it was, possibly still on disk.
+        if !Arc::ptr_eq(&new_root_node, &root_node) {
+            state.root = Link::Loaded(new_root_node);
+        }
+        Ok(())
+    }
+
+    /// Inserts `key` and `value` unless the key is already in the tree, in which case
+    /// the tree is left unchanged and `value` is given back. In tombstone mode, a
+    /// removed key counts as absent, and its tombstone is replaced.
+    ///
+    /// Unlike [`contains`](Self::contains) followed by [`insert`](Self::insert), this
+    /// descends the tree once, or twice to replace a tombstone.
+    pub fn try_insert(&self, key: K, value: V) -> Result<std::result::Result<(), V>> {
+        self.ensure_writable()?;
+        let mut state = self.state.write().unwrap();
+        let root_node = self.resolve_link(&state.root)?;
+        if let Some(tombstones) = self.tombstones
+            && let Some(existing) = root_node.get(&key, &self.store)?
+        {
+            if !tombstones.is_tombstone(&existing) {
+                return Ok(Err(value));
+            }
+            let new_root = self.put_entry(&root_node, Arc::new(key), Arc::new(value))?;
+            state.root = Link::Loaded(new_root);
+            return Ok(Ok(()));
+        }
+
+        let value = Arc::new(value);
+        let level = self.level_of_entry(&key, &value);
+        let new_root_node =
+            root_node.put_with::<H>(Arc::new(key), value.clone(), level, false, &self.store)?;
+
+        if Arc::ptr_eq(&new_root_node, &root_node) {
+            // The tree did not keep the value, so this is its only reference.
+            return Ok(Err(Arc::into_inner(value).unwrap()));
+        }
+        state.root = Link::Loaded(new_root_node);
+        Ok(Ok(()))
+    }
+
+    /// Returns the root hash the tree would have after inserting `key` and `value`,
+    /// without changing the tree. Equal to [`root_hash`](Self::root_hash) if the
+    /// insert would change nothing.
+    pub fn preview_insert(&self, key: K, value: V) -> Result<Hash> {
+        let state = self.state.read().unwrap();
+        let root = self.resolve_link(&state.root)?;
+        Ok(self.put_entry(&root, Arc::new(key), Arc::new(value))?.hash)
+    }
+
+    /// Inserts a batch of key-value pairs. Produces the same tree as calling `insert`
+    /// for each pair in order, but sorts the batch first and resolves the root once.
+    ///
+    /// If an error occurs, the tree is left unchanged.
+    pub fn insert_many<I: IntoIterator<Item = (K, V)>>(&self, items: I) -> Result<()> {
+        self.ensure_writable()?;
+        let mut items: Vec<(K, V)> = items.into_iter().collect();
+        // Stable sort keeps duplicates in input order, so the last value still wins.
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut state = self.state.write().unwrap();
+        let old_root = self.resolve_link(&state.root)?;
+        let mut root_node = old_root.clone();
+        let mut items = items.into_iter().peekable();
+        while let Some((key, value)) = items.next() {
+            // Of a run of equal keys, only the last value would survive anyway.
+            if items.peek().is_some_and(|(next, _)| *next == key) {
+                continue;
+            }
+            root_node = self.put_entry(&root_node, Arc::new(key), Arc::new(value))?;
+        }
+
+        if !Arc::ptr_eq(&root_node, &old_root) {
+            state.root = Link::Loaded(root_node);
+        }
+        Ok(())
+    }
+
+    /// Inserts a batch of key-value pairs, handling keys already in the tree as
+    /// `on_conflict` says. Pairs are applied as if inserted one at a time in order, so
+    /// a key repeated in the batch conflicts with its earlier pair. Returns how many
+    /// keys were added; keys whose value was replaced are not counted.
+    ///
+    /// Whether a key is present is found out by the descent that inserts it. If an
+    /// error occurs, including a conflict under [`ConflictPolicy::Error`], the tree is
+    /// left unchanged.
+    pub fn extend<I: IntoIterator<Item = (K, V)>>(
+        &self,
+        items: I,
+        on_conflict: ConflictPolicy,
+    ) -> Result<u64> {
+        self.ensure_writable()?;
+        let mut items: Vec<(K, V)> = items.into_iter().collect();
+        // Stable sort keeps duplicates in input order.
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut state = self.state.write().unwrap();
+        let old_root = self.resolve_link(&state.root)?;
+        let mut root_node = old_root.clone();
+        let mut added = 0;
+        let mut items = items.into_iter().peekable();
+        while let Some((key, value)) = items.next() {
+            // Of a run of equal keys, only the last value would survive anyway.
+            if on_conflict == ConflictPolicy::Overwrite
+                && items.peek().is_some_and(|(next, _)| *next == key)
+            {
+                continue;
+            }
+            let (key, value) = (Arc::new(key), Arc::new(value));
+            let level = self.level_of_entry(&key, &value);
+            let new_root =
+                root_node.put_with::<H>(key.clone(), value.clone(), level, false, &self.store)?;
+            if !Arc::ptr_eq(&new_root, &root_node) {
+                added += 1;
+                root_node = new_root;
+                continue;
+            }
+            match on_conflict {
+                ConflictPolicy::Overwrite => {
+                    root_node = self.put_entry(&root_node, key, value)?;
+                }
+                ConflictPolicy::KeepExisting => {}
+                ConflictPolicy::Error => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        "a key of the batch is already in the tree",
+                    )
+                    .into());
+                }
+            }
+        }
+
+        if !Arc::ptr_eq(&root_node, &old_root) {
+            state.root = Link::Loaded(root_node);
+        }
+        Ok(added)
+    }
+
+    /// Applies `ops` in order and commits them as one unit.
+    ///
+    /// The new root only becomes visible through the single metadata write of the
+    /// commit, so after a crash either the whole batch or none of it is on disk. If an
+    /// op fails, the tree is left unchanged; if the commit itself fails, the batch
+    /// stays applied in memory, as with `commit`.
+    pub fn transaction(&self, ops: Vec<Op<K, V>>) -> Result<Version> {
+        self.ensure_writable()?;
+        let mut state = self.state.write().unwrap();
+        let mut root = state.root.clone();
+        for op in ops {
+            let root_node = self.resolve_link(&root)?;
+            match op {
+                Op::Insert(key, value) => {
+                    let new_root = self.put_entry(&root_node, Arc::new(key), Arc::new(value))?;
+                    if !Arc::ptr_eq(&new_root, &root_node) {
+                        root = Link::Loaded(new_root);
+                    }
+                }
+                Op::Remove(key) => {
+                    if let Some(new_root) = self.delete_or_bury(&root_node, &key)? {
+                        root = new_root;
+                    }
+                }
+            }
+        }
+
+        state.root = root;
+        // Still under the same lock, so no other mutation joins the batch.
+        self.commit_locked(&mut state)
+    }
+
+    /// Looks up `key` once and returns an [`Entry`] for reading or updating it in place.
+    pub fn entry(&mut self, key: K) -> Result<Entry<'_, K, V, H>> {
+        self.ensure_writable()?;
+        Ok(Entry::new(self, key)?)
+    }
+
+    /// Checks if a key exists in the tree. In tombstone mode, removed keys do not.
+    pub fn contains<Q>(&self, key: &Q) -> Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        if self.tombstones.is_some() {
+            return Ok(self.get(key)?.is_some());
+        }
+        let state = self.state.read().unwrap();
+        let root = self.resolve_link(&state.root)?;
+        Ok(root.contains(key, &self.store)?)
+    }
+
+    /// Retrieves a value by key. Returns None if the key does not exist, or in
+    /// tombstone mode if it was removed.
+    pub fn get<Q>(&self, key: &Q) -> Result<Option<Arc<V>>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let state = self.state.read().unwrap();
+        let root = self.resolve_link(&state.root)?;
+        Ok(self.live(root.get(key, &self.store)?))
+    }
+
+    /// Calls `f` with a reference to the value stored under `key`, without cloning
+    /// it or its `Arc`. Returns None if the key does not exist, or was removed.
+    pub fn with_value<Q, R>(&self, key: &Q, f: impl FnOnce(&V) -> R) -> Result<Option<R>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let state = self.state.read().unwrap();
+        let mut node = self.resolve_link(&state.root)?;
+        loop {
+            match node
+                .keys
+                .binary_search_by(|probe| probe.as_ref().borrow().cmp(key))
+            {
+                Ok(idx) => return Ok(self.live(Some(&node.values[idx])).map(|v| f(v))),
+                Err(_) if node.children.is_empty() => return Ok(None),
+                Err(idx) => node = self.resolve_link(&node.children[idx])?,
+            }
+        }
+    }
+
+    /// Retrieves the values for a batch of keys in a single descent. Results are
+    /// returned in the order of `keys`.
+    pub fn get_many<Q>(&self, keys: &[&Q]) -> Result<Vec<Option<Arc<V>>>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut queries: Vec<(usize, &Q)> = keys.iter().copied().enumerate().collect();
+        queries.sort_by(|a, b| a.1.cmp(b.1));
+
+        let mut out = vec![None; keys.len()];
+        let state = self.state.read().unwrap();
+        let root = self.resolve_link(&state.root)?;
+        root.get_many(&queries, &self.store, &mut out)?;
+        Ok(out.into_iter().map(|value| self.live(value)).collect())
+    }
+
+    /// Whether all of `keys` are in the tree. Looks them up in a single descent like
+    /// [`get_many`](Self::get_many), stopping at the first one missing.
+    pub fn contains_all<Q>(&self, keys: &[&Q]) -> Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        Ok(!self.lookup_until(keys, |value| value.is_none())?)
+    }
+
+    /// Whether any of `keys` is in the tree. Looks them up in a single descent like
+    /// [`get_many`](Self::get_many), stopping at the first one found.
+    pub fn contains_any<Q>(&self, keys: &[&Q]) -> Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.lookup_until(keys, |value| value.is_some())
+    }
+
+    /// Looks up `keys` in key order, passing whether each is present, and its value
+    /// if so, to `stop` until it returns true. Returns whether it did.
+    fn lookup_until<Q>(
+        &self,
+        keys: &[&Q],
+        mut stop: impl FnMut(Option<&Arc<V>>) -> bool,
+    ) -> Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut keys = keys.to_vec();
+        keys.sort();
+        let state = self.state.read().unwrap();
+        let root = self.resolve_link(&state.root)?;
+        Ok(root.lookup_until(&keys, &self.store, &mut |value| stop(self.live(value)))?)
+    }
+
+    /// Iterates over all entries in key order.
+    ///
+    /// The iterator reads the tree as it is now, like a [`Snapshot`]; later writes are
+    /// not observed.
+    pub fn iter(&self) -> Result<Iter<K, V>> {
+        Ok(Iter::new::<K, _>(self.snapshot(), &..)?)
+    }
+
+    /// Iterates over all keys in order, without decoding values where the file
+    /// allows it. Needs format version 5 to skip the values of nodes read from disk.
+    pub fn keys(&self) -> Result<KeysIter<K, V>> {
+        Ok(KeysIter::new(self.snapshot()))
+    }
+
+    /// Iterates over all values in key order.
+    pub fn values(&self) -> Result<ValuesIter<K, V>> {
+        Ok(ValuesIter::new(self.iter()?))
+    }
+
+    /// Consumes the tree and returns all entries in key order. Uncommitted changes
+    /// are included, and are lost unless committed first.
+    ///
+    /// Entries are moved out of the tree where possible. Those still shared with a
+    /// live [`Snapshot`] or iterator are copied by re-decoding them.
+    pub fn into_entries(self) -> Result<Vec<(K, V)>> {
+        let entries: Vec<_> = self.iter()?.collect::<Result<_>>()?;
+        // Dropping the tree drops the node cache, so the entries are usually no
+        // longer shared.
+        drop(self);
+        entries
+            .into_iter()
+            .map(|(key, value)| Ok((into_owned(key)?, into_owned(value)?)))
+            .collect()
+    }
+
+    /// Iterates over the entries within `range` in key order.
+    pub fn range<Q, R>(&self, range: R) -> Result<Iter<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        Ok(Iter::new(self.snapshot(), &range)?)
+    }
+
+    /// Iterates over all entries in descending key order.
+    pub fn iter_rev(&self) -> Result<RevIter<K, V>> {
+        Ok(RevIter::new::<K, _>(self.snapshot(), &..)?)
+    }
+
+    /// Iterates over the entries within `range` in descending key order.
+    pub fn range_rev<Q, R>(&self, range: R) -> Result<RevIter<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        Ok(RevIter::new(self.snapshot(), &range)?)
+    }
+
+    /// Iterates over the entries whose key starts with `prefix`, in key order.
+    ///
+    /// Only the nodes overlapping the prefix are visited. Requires that `K` orders
+    /// the same way as its bytes, as `String` and `Vec<u8>` do.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Iter<K, V>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let end = prefix_successor(prefix);
+        Ok(Iter::with_bounds(
+            self.snapshot(),
+            |k| k.as_ref() < prefix,
+            |k| end.as_ref().is_some_and(|end| k.as_ref() >= end.as_slice()),
+        )?)
+    }
+
+    /// Lists, in key order, how the entries of `map` differ from those of the tree.
+    ///
+    /// Values count as different when they serialize differently, as they would then
+    /// hash differently. Both sides are walked once, side by side.
+    pub fn diff_map(&self, map: &BTreeMap<K, V>) -> Result<Vec<Difference<K, V>>>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        diff::diff_map(self.iter()?, map)
+    }
 
-        let target_level = Node::<K, V>::calc_level(key_arc.as_ref());
-        let new_root_node = root_node.put(key_arc, val_arc, target_level, &self.store)?;
+    /// Returns a read-only view pinned to the current root, including uncommitted
+    /// changes. It keeps reading the same entries after further writes and commits.
+    pub fn snapshot(&self) -> Snapshot<K, V> {
+        Snapshot::new(self.state.read().unwrap().root.clone(), self.store.clone())
+    }
+
+    /// Marks the current root, uncommitted changes included, so that later writes can
+    /// be undone with [`restore`](Self::restore). Nothing is written to disk.
+    pub fn checkpoint(&self) -> Checkpoint<K, V> {
+        // Read under the lock, so no commit slips in between the root and generation.
+        let state = self.state.read().unwrap();
+        Checkpoint {
+            root: state.root.clone(),
+            store: self.store.clone(),
+            generation: self.store.generation(),
+        }
+    }
 
-        self.root = Link::Loaded(new_root_node);
+    /// Puts the root back to where it was at `checkpoint`, undoing every write since.
+    ///
+    /// Fails if the checkpoint was taken from another tree, or before this one was
+    /// compacted or committed, as the nodes it reaches may have been freed since.
+    pub fn restore(&self, checkpoint: &Checkpoint<K, V>) -> Result<()> {
+        self.ensure_writable()?;
+        let mut state = self.state.write().unwrap();
+        if !Arc::ptr_eq(&checkpoint.store, &self.store) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "checkpoint was taken from another tree, or before compacting",
+            )
+            .into());
+        }
+        if checkpoint.generation != self.store.generation() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "tree has committed since the checkpoint was taken",
+            )
+            .into());
+        }
+        state.root = checkpoint.root.clone();
         Ok(())
     }
 
-    /// Checks if a key exists in the tree.
-    pub fn contains<Q>(&self, key: &Q) -> io::Result<bool>
+    /// Number of keys in the tree. Reads at most the root node, except in files
+    /// written by format version 1, which do not record subtree sizes.
+    pub fn len(&self) -> Result<u64> {
+        self.count_range::<K, _>(..)
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        // Nodes without keys are collapsed into their child, so only an empty tree has
+        // a root without keys.
+        let state = self.state.read().unwrap();
+        Ok(self.resolve_link(&state.root)?.keys.is_empty())
+    }
+
+    /// Counts the keys within `range` without visiting them. Only the nodes along the
+    /// two boundaries of the range are loaded; the subtrees in between are counted
+    /// from the sizes recorded in their parents.
+    pub fn count_range<Q, R>(&self, range: R) -> Result<u64>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        let bounded = |bound: Bound<&Q>| !matches!(bound, Bound::Unbounded);
+        let (check_start, check_end) = (bounded(range.start_bound()), bounded(range.end_bound()));
+        let state = self.state.read().unwrap();
+        if let Some(count) = state.root.count()
+            && !check_start
+            && !check_end
+        {
+            return Ok(count);
+        }
+        Ok(self.resolve_link(&state.root)?.count_range(
+            &range,
+            check_start,
+            check_end,
+            &self.store,
+        )?)
+    }
+
+    /// Returns the entry with the smallest key, or None if the tree is empty.
+    pub fn first_key_value(&self) -> Result<Option<(Arc<K>, Arc<V>)>> {
+        self.boundary_entry(|_| 0, |node| node.children.first())
+    }
+
+    /// Returns the entry with the largest key, or None if the tree is empty.
+    pub fn last_key_value(&self) -> Result<Option<(Arc<K>, Arc<V>)>> {
+        self.boundary_entry(
+            |node| node.keys.len().saturating_sub(1),
+            |node| node.children.last(),
+        )
+    }
+
+    /// The first `n` entries in key order, or all of them if there are fewer. Only
+    /// the nodes along the left edge of the tree that hold them are read.
+    pub fn first_n(&self, n: usize) -> Result<Vec<(Arc<K>, Arc<V>)>> {
+        if n == 0 {
+            return Ok(Vec::new());
+        }
+        self.iter()?.take(n).collect()
+    }
+
+    /// The last `n` entries in descending key order, or all of them if there are
+    /// fewer. Only the nodes along the right edge of the tree that hold them are read.
+    pub fn last_n(&self, n: usize) -> Result<Vec<(Arc<K>, Arc<V>)>> {
+        if n == 0 {
+            return Ok(Vec::new());
+        }
+        self.iter_rev()?.take(n).collect()
+    }
+
+    /// Returns the entry with the greatest key less than or equal to `key`.
+    pub fn floor<Q>(&self, key: &Q) -> Result<Option<(Arc<K>, Arc<V>)>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.nearest_entry(key, |idx, _| idx.checked_sub(1))
+    }
+
+    /// Returns the entry with the least key greater than or equal to `key`.
+    pub fn ceil<Q>(&self, key: &Q) -> Result<Option<(Arc<K>, Arc<V>)>>
     where
         K: Borrow<Q>,
         Q: Ord + ?Sized,
     {
-        let root = self.resolve_link(&self.root)?;
-        root.contains(key, &self.store)
+        self.nearest_entry(key, |idx, len| (idx < len).then_some(idx))
     }
 
-    /// Retrieves a value by key. Returns None if the key does not exist.
-    pub fn get<Q>(&self, key: &Q) -> io::Result<Option<Arc<V>>>
+    /// Searches for `key`, keeping the neighbour picked by `candidate` from each node
+    /// on the path. The child visited next lies between that neighbour and `key`, so
+    /// candidates found deeper are always closer.
+    fn nearest_entry<Q>(
+        &self,
+        key: &Q,
+        candidate: impl Fn(usize, usize) -> Option<usize>,
+    ) -> Result<Option<(Arc<K>, Arc<V>)>>
     where
         K: Borrow<Q>,
         Q: Ord + ?Sized,
     {
-        let root = self.resolve_link(&self.root)?;
-        root.get(key, &self.store)
+        let mut best = None;
+        let state = self.state.read().unwrap();
+        let mut node = self.resolve_link(&state.root)?;
+        loop {
+            match node.keys.binary_search_by(|k| k.as_ref().borrow().cmp(key)) {
+                Ok(idx) => return Ok(Some((node.keys[idx].clone(), node.values[idx].clone()))),
+                Err(idx) => {
+                    if let Some(c) = candidate(idx, node.keys.len()) {
+                        best = Some((node.keys[c].clone(), node.values[c].clone()));
+                    }
+                    match node.children.get(idx) {
+                        Some(child) => node = self.resolve_link(child)?,
+                        None => return Ok(best),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Follows the outermost children on one side, keeping the outermost key seen.
+    /// Keys deeper on that side are always more extreme than their ancestors'.
+    fn boundary_entry(
+        &self,
+        key_index: impl Fn(&Node<K, V>) -> usize,
+        next: impl Fn(&Node<K, V>) -> Option<&Link<K, V>>,
+    ) -> Result<Option<(Arc<K>, Arc<V>)>> {
+        let mut best = None;
+        let state = self.state.read().unwrap();
+        let mut node = self.resolve_link(&state.root)?;
+        loop {
+            if !node.keys.is_empty() {
+                let idx = key_index(&node);
+                best = Some((node.keys[idx].clone(), node.values[idx].clone()));
+            }
+            match next(&node) {
+                Some(child) => node = self.resolve_link(child)?,
+                None => return Ok(best),
+            }
+        }
     }
 
     /// Removes a key from the tree.
-    pub fn remove<Q>(&mut self, key: &Q) -> io::Result<()>
+    ///
+    /// A node left without keys is replaced by the merge of its children, at every
+    /// level, so no chain of keyless nodes remains: the tree has the same shape and
+    /// height as one built from the remaining keys alone.
+    ///
+    /// In tombstone mode, the key keeps a tombstone instead; see
+    /// [`OpenOptions::tombstones`]. `transaction` removes the same way, while
+    /// `remove_range`, `retain`, `clear` and entries always delete.
+    pub fn remove<Q>(&self, key: &Q) -> Result<()>
     where
         K: Borrow<Q>,
         Q: Ord + ?Sized,
     {
-        let root = self.resolve_link(&self.root)?;
+        self.ensure_writable()?;
+        let mut state = self.state.write().unwrap();
+        let root = self.resolve_link(&state.root)?;
 
-        let (new_root, deleted) = root.delete(key, &self.store)?;
+        if let Some(new_root) = self.delete_or_bury(&root, key)? {
+            state.root = new_root;
+        }
 
-        if !deleted {
-            return Ok(());
+        Ok(())
+    }
+
+    /// Deletes `key` below `root` or, in tombstone mode, puts a tombstone in its
+    /// place. Returns the new root, or None if nothing changed.
+    fn delete_or_bury<Q>(&self, root: &Arc<Node<K, V>>, key: &Q) -> io::Result<Option<Link<K, V>>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let Some(tombstones) = self.tombstones else {
+            return root.delete::<H, Q>(key, &self.store);
+        };
+        // The tombstone needs the stored key, since `key` may only borrow it.
+        let mut node = root.clone();
+        let (stored, value) = loop {
+            match node.keys.binary_search_by(|k| k.as_ref().borrow().cmp(key)) {
+                Ok(idx) => break (node.keys[idx].clone(), node.values[idx].clone()),
+                Err(_) if node.children.is_empty() => return Ok(None),
+                Err(idx) => node = self.resolve_link(&node.children[idx])?,
+            }
+        };
+        // A repeated removal keeps the time of the first.
+        if tombstones.is_tombstone(&value) {
+            return Ok(None);
         }
+        let tombstone = Arc::new((tombstones.tombstone)(unix_millis(SystemTime::now())));
+        let new_root = self.put_entry(root, stored, tombstone)?;
+        Ok(Some(Link::Loaded(new_root)))
+    }
 
-        if new_root.keys.is_empty() && !new_root.children.is_empty() {
-            self.root = new_root.children[0].clone();
-        } else {
-            self.root = Link::Loaded(new_root);
+    /// `value`, unless it is a tombstone in tombstone mode.
+    fn live<T: AsRef<V>>(&self, value: Option<T>) -> Option<T> {
+        value.filter(|v| {
+            !self
+                .tombstones
+                .is_some_and(|tombstones| tombstones.is_tombstone(v.as_ref()))
+        })
+    }
+
+    /// Returns the root hash the tree would have after removing `key`, without
+    /// changing the tree. Equal to [`root_hash`](Self::root_hash) if `key` is absent.
+    pub fn preview_remove<Q>(&self, key: &Q) -> Result<Hash>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let state = self.state.read().unwrap();
+        let root = self.resolve_link(&state.root)?;
+        Ok(match self.delete_or_bury(&root, key)? {
+            Some(new_root) => new_root.hash(),
+            None => root.hash,
+        })
+    }
+
+    /// Removes every key within `range` and returns how many were removed.
+    ///
+    /// The whole range is cut out in one descent, but the resulting tree is identical
+    /// to removing each key individually.
+    pub fn remove_range<Q, R>(&self, range: R) -> Result<u64>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        self.ensure_writable()?;
+        let mut state = self.state.write().unwrap();
+        let root = self.resolve_link(&state.root)?;
+
+        match root.delete_range::<H, Q, R>(&range, &self.store)? {
+            Some((new_root, removed)) => {
+                state.root = new_root;
+                Ok(removed)
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Removes every entry for which `f` returns false and returns how many were
+    /// removed. `f` sees every entry once, in key order.
+    ///
+    /// The tree is filtered in a single pass that only rebuilds the nodes that lose
+    /// keys, and the result is identical to removing each key individually.
+    pub fn retain(&self, mut f: impl FnMut(&K, &V) -> bool) -> Result<u64> {
+        self.ensure_writable()?;
+        let mut state = self.state.write().unwrap();
+        let root = self.resolve_link(&state.root)?;
+
+        match root.retain::<H>(&mut f, &self.store)? {
+            Some((new_root, removed)) => {
+                state.root = new_root;
+                Ok(removed)
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Deletes the tombstones of keys removed before `older_than`, and returns how
+    /// many there were. Works whether or not the tree is in tombstone mode.
+    ///
+    /// Once purged, a removal can no longer reach replicas that missed it, so the
+    /// cut-off should lie further back than the longest time a replica stays out of
+    /// sync.
+    pub fn purge_tombstones(&self, older_than: SystemTime) -> Result<u64>
+    where
+        V: Tombstone,
+    {
+        let cutoff = unix_millis(older_than);
+        self.retain(|_, value| value.removed_at().is_none_or(|at| at >= cutoff))
+    }
+
+    /// Removes every entry, leaving an empty tree. Like any other change, this becomes
+    /// persistent only on `commit`.
+    ///
+    /// The old nodes are not reclaimed: the file keeps its size and their space is
+    /// not reused by later commits. Call `compact` to shrink the file.
+    pub fn clear(&self) -> Result<()> {
+        self.ensure_writable()?;
+        let mut state = self.state.write().unwrap();
+        state.root = Link::Loaded(Arc::new(Node::empty(0)));
+        // Forgetting the previous root also skips walking it for orphans on the next
+        // commit, so its nodes are never handed to the free list.
+        state.last_committed = None;
+        Ok(())
+    }
+
+    /// Builds an inclusion proof for `key`. Returns None if the key does not exist.
+    pub fn prove<Q>(&self, key: &Q) -> Result<Option<Proof<K, V>>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let (path, value) = self.proof_path(key)?;
+        Ok(value.map(|_| self.proof(path)))
+    }
+
+    /// Looks up `key` and builds its inclusion proof in the same descent. Returns None
+    /// if the key does not exist.
+    ///
+    /// The proof verifies against [`root_hash`](Self::root_hash) as it was during the
+    /// lookup, like one from [`prove`](Self::prove).
+    pub fn get_with_proof<Q>(&self, key: &Q) -> Result<Option<ProvenValue<K, V>>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let (path, value) = self.proof_path(key)?;
+        Ok(value.map(|value| (value, self.proof(path))))
+    }
+
+    /// Builds a non-existence proof for `key`. Returns None if the key exists.
+    pub fn prove_absence<Q>(&self, key: &Q) -> Result<Option<Proof<K, V>>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let (path, value) = self.proof_path(key)?;
+        Ok(value.is_none().then(|| self.proof(path)))
+    }
+
+    fn proof(&self, path: Vec<ProofNode<K, V>>) -> Proof<K, V> {
+        Proof {
+            path,
+            salt: self.store.domain_salt().copied(),
+        }
+    }
+
+    /// Collects the lookup path for `key`, stopping at the node holding it, whose value
+    /// is returned along with the path, or at the node whose child on the search path
+    /// is empty.
+    fn proof_path<Q>(&self, key: &Q) -> io::Result<ProofPath<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut path = Vec::new();
+        let state = self.state.read().unwrap();
+        let mut node = self.resolve_link(&state.root)?;
+
+        loop {
+            if node.children.is_empty() {
+                return Ok((path, None));
+            }
+            path.push(ProofNode::from_node(&node));
+
+            match node
+                .keys
+                .binary_search_by(|probe| probe.as_ref().borrow().cmp(key))
+            {
+                Ok(idx) => return Ok((path, Some(node.values[idx].clone()))),
+                Err(idx) => node = self.resolve_link(&node.children[idx])?,
+            }
         }
+    }
+
+    /// Exports every node of the tree in a portable encoding, children first, e.g. to
+    /// replicate it with [`import_nodes`](Self::import_nodes). The export reads a
+    /// snapshot, so later writes do not affect it.
+    pub fn export_nodes(&self) -> Result<NodeExport<K, V>> {
+        Ok(NodeExport::new(self.snapshot())?)
+    }
 
+    /// Replaces the tree with the one rooted at `root`, built from nodes produced by
+    /// [`export_nodes`](Self::export_nodes) in the order it yields them.
+    ///
+    /// Nodes this tree already contains may be left out; they are looked up by hash.
+    /// Every imported node is checked against its hash, and if anything is missing or
+    /// corrupt the tree is left unchanged. Imported nodes are held in memory until the
+    /// next `commit` writes them.
+    pub fn import_nodes<I>(&self, root: Hash, nodes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (Hash, Vec<u8>)>,
+    {
+        self.ensure_writable()?;
+        let mut state = self.state.write().unwrap();
+        state.root = export::import_nodes::<K, V, H, I>(root, nodes, &state.root, &self.store)?;
         Ok(())
     }
 
+    /// Writes every entry in key order to `writer` in `format`, for
+    /// [`import_entries`](Self::import_entries) to load into another tree. Returns
+    /// how many entries were written. The export reads a snapshot, so later writes do
+    /// not affect it.
+    pub fn export_entries<W: Write>(&self, writer: W, format: EntryFormat) -> Result<u64> {
+        dump::export_entries(self.iter()?, writer, format)
+    }
+
+    /// Opens the tree at `path`, creating it if needed, and inserts the entries read
+    /// from `reader` in `format`, as written by
+    /// [`export_entries`](Self::export_entries). A key that is already in the tree,
+    /// or repeated in the input, ends up with the value read last.
+    ///
+    /// The input is read in batches, each inserted with
+    /// [`insert_many`](Self::insert_many) and then committed, so inputs far larger
+    /// than memory can be loaded. If reading fails partway, the batches before the
+    /// failure stay committed.
+    pub fn import_entries<P: AsRef<Path>, R: Read>(
+        path: P,
+        reader: R,
+        format: EntryFormat,
+    ) -> Result<Self> {
+        let tree = Self::open_with_hasher(path)?;
+        let mut reader = EntryReader::new(reader, format);
+        let mut batch = Vec::with_capacity(dump::IMPORT_BATCH_LEN);
+        loop {
+            let entry = reader.read_entry()?;
+            let end = entry.is_none();
+            batch.extend(entry);
+            if end || batch.len() == dump::IMPORT_BATCH_LEN {
+                tree.insert_many(batch.drain(..))?;
+                tree.commit()?;
+            }
+            if end {
+                return Ok(tree);
+            }
+        }
+    }
+
+    /// Walks the whole tree and reports its shape. Nodes read from disk for the walk
+    /// are not kept in the cache.
+    pub fn stats(&self) -> Result<TreeStats> {
+        Ok(TreeStats::collect(
+            &self.state.read().unwrap().root,
+            &self.store,
+        )?)
+    }
+
+    /// Length of the file in bytes, including space no node uses anymore. Compare it
+    /// with [`live_size`](Self::live_size) to see how much a compaction would save.
+    pub fn disk_size(&self) -> Result<u64> {
+        Ok(self.store.file_len()?)
+    }
+
+    /// Estimates how many bytes of the file are in use: the header page, and every
+    /// node and blob record reachable from the current root, as stored. The rest of
+    /// [`disk_size`](Self::disk_size) is padding, reclaimable space, and nodes only the
+    /// previous commit still refers to.
+    ///
+    /// This reads every reachable node from disk, so it costs about as much as
+    /// [`stats`](Self::stats). Uncommitted nodes take no space yet and are not
+    /// counted.
+    pub fn live_size(&self) -> Result<u64> {
+        let snapshot = self.snapshot();
+        Ok(stats::live_bytes(&snapshot.root, &snapshot.store)?)
+    }
+
+    /// Reads back every node reachable from the current root and checks that it is
+    /// within the file, decodes, and hashes to what its parent recorded for it. Stops
+    /// at the first problem. Uncommitted nodes are walked through but not checked.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let snapshot = self.snapshot();
+        Ok(verify::verify::<K, V, H>(&snapshot.root, &snapshot.store)?)
+    }
+
+    /// Calls `visitor` for every node of the tree, parents before their children, and
+    /// only goes below a node if the visitor returns [`Visit::Descend`] for it. The walk
+    /// reads a snapshot, so the visitor may use the tree, and later writes do not
+    /// affect it.
+    ///
+    /// [`Visit::Descend`]: crate::Visit::Descend
+    pub fn walk<W: NodeVisitor<K, V> + ?Sized>(&self, visitor: &mut W) -> Result<()> {
+        let snapshot = self.snapshot();
+        Ok(walk::walk(&snapshot.root, &snapshot.store, visitor)?)
+    }
+
+    /// Renders the tree as a Graphviz DOT graph, with a box per node showing its
+    /// level, its keys and the start of its hash, and an edge to each child. Reads
+    /// every node, so it is meant for inspecting small trees.
+    pub fn to_dot(&self) -> Result<String> {
+        let snapshot = self.snapshot();
+        let mut dot = DotWriter::new(snapshot.root.hash());
+        walk::walk(&snapshot.root, &snapshot.store, &mut dot)?;
+        Ok(dot.finish())
+    }
+
     pub fn root_hash(&self) -> Hash {
-        self.root.hash()
+        self.state.read().unwrap().root.hash()
+    }
+
+    /// Whether the tree differs from its last commit, or from an empty tree if it was
+    /// never committed. Changes that were undone, such as inserting a key and
+    /// removing it again, leave the tree clean, so `commit` can be skipped.
+    pub fn is_dirty(&self) -> bool {
+        let state = self.state.read().unwrap();
+        let committed = state.last_committed.map_or(Hash::default(), |(_, hash)| hash);
+        matches!(state.root, Link::Loaded(_)) && state.root.hash() != committed
+    }
+
+    /// Level at which `key` is stored in a tree of the default fan-out, 16; leaves are
+    /// at level 0. This is the number of leading groups of four zero bits in the hash
+    /// of the key's encoding.
+    ///
+    /// A key reaches level `l` or above with probability `16^-l`: about one key in 16
+    /// is at level 1 or above, one in 256 at level 2 or above, and so on, which gives
+    /// nodes about 16 children on average. Keys with known levels, for tests that need
+    /// a key at a given level, can be found by trying candidates.
+    ///
+    /// Trees of another fan-out spread levels the same way in groups of `log2(fanout)`
+    /// bits; see [`key_level`](Self::key_level) and
+    /// [`level_at_fanout`](Self::level_at_fanout).
+    pub fn level_of(key: &K) -> u32 {
+        Self::level_at_fanout(key, DEFAULT_FANOUT)
+    }
+
+    /// Level at which `key` is stored in a tree of fan-out `fanout`, without one at
+    /// hand; see [`level_of`](Self::level_of).
+    ///
+    /// # Panics
+    ///
+    /// If `fanout` is not a power of two between 2 and 256.
+    pub fn level_at_fanout(key: &K, fanout: u32) -> u32 {
+        assert!(
+            fanout.is_power_of_two() && (2..=MAX_FANOUT).contains(&fanout),
+            "invalid fan-out {fanout}"
+        );
+        Node::<K, V>::calc_level::<H>(key, fanout)
+    }
+
+    /// Level at which `key` is stored in this tree, given the fan-out of its file.
+    ///
+    /// Under a [node byte budget](OpenOptions::node_byte_budget), an entry too large
+    /// for it sits higher than this.
+    pub fn key_level(&self, key: &K) -> u32 {
+        Node::<K, V>::calc_level::<H>(key, self.fanout())
+    }
+
+    /// Level at which `key` is stored with `value`: that of
+    /// [`key_level`](Self::key_level), unless the entry is too large for the node byte
+    /// budget, which halves the fan-out it is counted in until it fits or reaches 2.
+    pub(crate) fn level_of_entry(&self, key: &K, value: &V) -> u32 {
+        let Some(budget) = self.node_byte_budget() else {
+            return self.key_level(key);
+        };
+        let encoded_len = |len: postcard::Result<usize>| len.map_or(0, |len| len as u64);
+        let len = encoded_len(postcard::experimental::serialized_size(key))
+            + encoded_len(postcard::experimental::serialized_size(value));
+        let mut fanout = self.fanout();
+        while fanout > 2 && u64::from(fanout) * len > u64::from(budget.get()) {
+            fanout /= 2;
+        }
+        Node::<K, V>::calc_level::<H>(key, fanout)
+    }
+
+    /// Puts `key` and `value` below `root` at the level of the entry. Under a node byte
+    /// budget, a key already stored at another level, because its old value was of
+    /// another size, is taken out first.
+    pub(crate) fn put_entry(
+        &self,
+        root: &Arc<Node<K, V>>,
+        key: Arc<K>,
+        value: Arc<V>,
+    ) -> io::Result<Arc<Node<K, V>>> {
+        let level = self.level_of_entry(&key, &value);
+        if self.node_byte_budget().is_some() {
+            let mut node = root.clone();
+            loop {
+                match node.keys.binary_search_by(|probe| probe.as_ref().cmp(&key)) {
+                    Ok(_) if node.level == level => break,
+                    Ok(_) => {
+                        let without = match root.delete::<H, K>(&key, &self.store)? {
+                            Some(link) => self.resolve_link(&link)?,
+                            None => root.clone(),
+                        };
+                        return without.put::<H>(key, value, level, &self.store);
+                    }
+                    Err(_) if node.children.is_empty() => break,
+                    Err(idx) => node = self.resolve_link(&node.children[idx])?,
+                }
+            }
+        }
+        root.put::<H>(key, value, level, &self.store)
+    }
+
+    fn ensure_writable(&self) -> io::Result<()> {
+        if self.store.config().read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "tree was opened read-only",
+            ));
+        }
+        Ok(())
     }
 
-    fn resolve_link(&self, link: &Link<K, V>) -> io::Result<Arc<Node<K, V>>> {
+    pub(crate) fn resolve_link(&self, link: &Link<K, V>) -> io::Result<Arc<Node<K, V>>> {
         match link {
             Link::Loaded(node) => Ok(node.clone()),
-            Link::Disk { offset, .. } => self.store.load_node(*offset),
+            Link::Disk { offset, hash, .. } => self.store.load_node(*offset, *hash),
         }
     }
 
-    fn flush_recursive(&self, link: &Link<K, V>) -> io::Result<(NodeId, Hash)> {
-        match link {
-            Link::Disk { offset, hash } => Ok((*offset, *hash)),
-            Link::Loaded(node) => {
-                let mut dirty_children = false;
-                for child in &node.children {
-                    if let Link::Loaded(_) = child {
-                        dirty_children = true;
-                        break;
+    /// Writes every loaded node below `root`, bottom-up, turns `root` into a link to
+    /// where it was written and returns that location, and whether the root was
+    /// deduplicated. Links from the new nodes, and from the root, to nodes already on
+    /// disk are counted in `shared`.
+    ///
+    /// Uses an explicit stack instead of recursion: each node is written as soon as
+    /// all its children are on disk, and a parent that nothing else holds, such as a
+    /// snapshot, has its link to the node replaced with one to the disk, so written
+    /// subtrees are freed as the commit goes rather than at its end. If a write fails,
+    /// the nodes not yet written are put back; the written ones stay linked from the
+    /// disk, so `shared` must be kept for the next commit, which does not visit them.
+    fn flush_dirty(
+        &self,
+        root: &mut Link<K, V>,
+        shared: &mut HashMap<NodeId, i64>,
+        entries: Option<Vec<EncodedEntries>>,
+    ) -> io::Result<(NodeId, Hash, bool)> {
+        if let Link::Disk { offset, hash, .. } = root {
+            *shared.entry(*offset).or_default() += 1;
+            return Ok((*offset, *hash, false));
+        }
+        let root_node = take_loaded(root).expect("the root is loaded");
+
+        let mut entries = entries.map(Vec::into_iter);
+        // Each frame is a node, the locations of its children written so far, and those
+        // already on disk before, along with whether a deduplicated write found them.
+        let mut stack = vec![(root_node, Vec::new(), Vec::new())];
+        let err = loop {
+            let (node, written, stored) = stack.last_mut().expect("the root is popped last");
+
+            let idx = written.len();
+            if idx < node.children.len() {
+                let child = match Arc::get_mut(node) {
+                    Some(node) => take_loaded(&mut node.children[idx]),
+                    None => match &node.children[idx] {
+                        Link::Loaded(child) => Some(child.clone()),
+                        Link::Disk { .. } => None,
+                    },
+                };
+                match child {
+                    Some(child) => {
+                        let capacity = child.children.len();
+                        stack.push((child, Vec::with_capacity(capacity), Vec::new()));
+                    }
+                    None => {
+                        let Link::Disk {
+                            offset,
+                            hash,
+                            count,
+                        } = node.children[idx]
+                        else {
+                            unreachable!("loaded children are taken")
+                        };
+                        *shared.entry(offset).or_default() += 1;
+                        written.push((offset, hash, count));
+                        stored.push((offset, false));
+                    }
+                }
+                continue;
+            }
+
+            let children = std::mem::take(written);
+            let (offset, deduplicated) = match self.store.write_disk_node(
+                &node.as_disk_ref_with(children),
+                entries.as_mut().and_then(Iterator::next),
+            ) {
+                Ok(written) => written,
+                Err(err) => break err,
+            };
+            let (node, _, stored) = stack.pop().expect("the root is popped last");
+            // The node already stored links to children of its own instead.
+            if deduplicated {
+                for (child, deduplicated) in stored {
+                    if deduplicated {
+                        self.store.remove_parent(child);
+                    } else {
+                        *shared.entry(child).or_default() -= 1;
+                    }
+                }
+            }
+            let link = Link::Disk {
+                offset,
+                hash: node.hash,
+                count: node.count,
+            };
+            match stack.last_mut() {
+                Some((parent, written, stored)) => {
+                    written.push((offset, node.hash, node.count));
+                    if deduplicated {
+                        stored.push((offset, true));
                     }
+                    if let Some(parent) = Arc::get_mut(parent) {
+                        parent.children[written.len() - 1] = link;
+                    }
+                }
+                None => {
+                    *root = link;
+                    return Ok((offset, node.hash, deduplicated));
                 }
+            }
+        };
 
-                if !dirty_children {
-                    let offset = self.store.write_node(node)?;
-                    return Ok((offset, node.hash));
+        // Put the nodes not written back into their parents, and the root. The next
+        // commit counts the links to the children visited so far again, as they are on
+        // disk by then, so they are taken off now; a link to a node written by this
+        // commit, which nothing else links to, ends up uncounted as it should.
+        let uncount = |node: &Node<K, V>, visited: usize, shared: &mut HashMap<NodeId, i64>| {
+            for child in &node.children[..visited] {
+                if let Link::Disk { offset, .. } = child {
+                    *shared.entry(*offset).or_default() -= 1;
                 }
+            }
+        };
+        let (mut unwritten, ..) = stack.pop().expect("the node that failed is on the stack");
+        uncount(&unwritten, unwritten.children.len(), shared);
+        while let Some((mut parent, written, _)) = stack.pop() {
+            uncount(&parent, written.len(), shared);
+            if let Some(node) = Arc::get_mut(&mut parent) {
+                node.children[written.len()] = Link::Loaded(unwritten);
+            }
+            unwritten = parent;
+        }
+        *root = Link::Loaded(unwritten);
+        Err(err)
+    }
 
-                let mut new_children = Vec::new();
-                for child in &node.children {
-                    let (child_offset, child_hash) = self.flush_recursive(child)?;
-                    new_children.push(Link::Disk {
-                        offset: child_offset,
-                        hash: child_hash,
-                    });
+    /// The nodes below `root` that `flush_dirty` writes, in the order it writes them.
+    #[cfg(feature = "parallel")]
+    fn dirty_nodes(root: &Link<K, V>) -> Vec<Arc<Node<K, V>>> {
+        let mut nodes = Vec::new();
+        let Link::Loaded(root) = root else {
+            return nodes;
+        };
+        // Each frame is a node and the index of the next child to visit.
+        let mut stack = vec![(root.clone(), 0)];
+        while let Some((node, next)) = stack.last_mut() {
+            match node.children.get(*next) {
+                Some(child) => {
+                    *next += 1;
+                    if let Link::Loaded(child) = child {
+                        let child = child.clone();
+                        stack.push((child, 0));
+                    }
                 }
+                None => nodes.push(stack.pop().expect("the frame was just seen").0),
+            }
+        }
+        nodes
+    }
 
-                let mut new_node = (**node).clone();
-                new_node.children = new_children;
-                let offset = self.store.write_node(&new_node)?;
-                Ok((offset, new_node.hash))
+    /// Collects the offsets of nodes under the committed node at `offset` that lose
+    /// their last parent. A node `shared` counts links to from the new nodes, or that
+    /// deduplication gave more than one parent, is only orphaned along with the last of
+    /// its parents and links; until then, `reached` counts the parents orphaned. Nodes
+    /// never orphaned keep their subtrees, which are not visited.
+    fn collect_orphans(
+        &self,
+        (offset, hash): (NodeId, Hash),
+        shared: &HashMap<NodeId, i64>,
+        orphaned: &mut Vec<NodeId>,
+        reached: &mut HashMap<NodeId, u32>,
+    ) -> io::Result<()> {
+        let others = i64::from(self.store.extra_parents(offset))
+            + shared.get(&offset).copied().unwrap_or(0);
+        if others > 0 {
+            let reached = reached.entry(offset).or_default();
+            if i64::from(*reached) < others {
+                *reached += 1;
+                return Ok(());
+            }
+        }
+        orphaned.push(offset);
+        let node = self.store.load_node(offset, hash)?;
+        for child in &node.children {
+            if let Link::Disk { offset, hash, .. } = child {
+                self.collect_orphans((*offset, *hash), shared, orphaned, reached)?;
             }
         }
+        Ok(())
+    }
+
+    /// Average number of children per node, fixed when the file was created.
+    pub fn fanout(&self) -> u32 {
+        self.store.config().fanout.unwrap_or(DEFAULT_FANOUT)
+    }
+
+    /// Size nodes are kept near, if the file was created with one; see
+    /// [`OpenOptions::node_byte_budget`].
+    pub fn node_byte_budget(&self) -> Option<NonZeroU32> {
+        self.store.config().node_byte_budget
+    }
+
+    /// Hits, misses and evictions of the node cache, to help pick a cache capacity.
+    pub fn cache_metrics(&self) -> CacheMetrics {
+        self.store.cache_metrics()
+    }
+
+    /// Number of bytes held by node versions that no committed root reaches anymore.
+    /// New nodes are written into this space before the file is grown.
+    ///
+    /// Space orphaned by a commit becomes reusable only after the following commit,
+    /// because the previous root is kept as a fallback for torn metadata writes.
+    pub fn reclaimable_bytes(&self) -> u64 {
+        self.store.reclaimable_bytes()
     }
 
     /// Compacts the database by copying all reachable nodes to a new file,
     /// eliminating obsolete data and reducing file size.
     ///
     /// This operation effectively "defragments" the storage.
-    pub fn compact<P: AsRef<Path>>(&mut self, new_path: P) -> io::Result<()> {
+    pub fn compact<P: AsRef<Path>>(&mut self, new_path: P) -> Result<()> {
+        self.ensure_writable()?;
+
         // 1. Prepare the new file (Truncate ensures it starts empty)
-        let file = OpenOptions::new()
+        let file = fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
             .truncate(true)
             .open(&new_path)?;
 
-        // Ensure minimum file size for metadata (matching Store::open logic)
-        if file.metadata()?.len() == 0 {
-            file.set_len(crate::PAGE_SIZE)?;
+        let new_store = Store::new::<H>(file, self.store.config())?;
+        let new_root = self.copy_to(&new_store)?;
+        self.switch_to(new_store, new_root);
+        Ok(())
+    }
+
+    /// Compacts the database like [`compact`](Self::compact), but into the file it
+    /// was opened from.
+    ///
+    /// The nodes are copied to a temporary file in the same directory, which is synced
+    /// and then renamed over the original, so the file is replaced either whole or not
+    /// at all. If anything fails before the rename, the original is left untouched and
+    /// the tree keeps using it. Trees held in memory are copied into a fresh buffer.
+    /// Fails with `InvalidInput` for temporary trees.
+    pub fn compact_in_place(&mut self) -> Result<()> {
+        self.ensure_writable()?;
+        if self.store.is_in_memory() {
+            let new_store = Store::in_memory::<H>(self.store.config())?;
+            let new_root = self.copy_to(&new_store)?;
+            self.switch_to(new_store, new_root);
+            return Ok(());
+        }
+        let Some(path) = self.store.path().map(Path::to_path_buf) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a temporary tree has no file to compact in place",
+            )
+            .into());
+        };
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let temp = tempfile::Builder::new()
+            .prefix(".compact-")
+            .tempfile_in(dir)?;
+        // The copy keeps its handle across the rename, and is recorded under the
+        // final path for later compactions.
+        let new_store =
+            Store::from_file::<H>(temp.reopen()?, self.store.config(), Some(path.clone()))?;
+        let new_root = self.copy_to(&new_store)?;
+
+        // The temporary file is created readable by its owner only.
+        temp.as_file().set_permissions(fs::metadata(&path)?.permissions())?;
+        // The temporary file is deleted if it cannot be renamed.
+        temp.persist(&path).map_err(|error| error.error)?;
+        self.switch_to(new_store, new_root);
+        sync_dir(dir)?;
+        Ok(())
+    }
+
+    /// Compacts the tree in place if its [`CompactionPolicy`] finds enough of the file
+    /// to be garbage, and returns whether it did. Async trees call this after every
+    /// commit. Temporary trees, which cannot be compacted in place, never are.
+    ///
+    /// Measuring the live size reads every reachable node, so it is only measured
+    /// again once the file has grown by a tenth since the last time; calls in between
+    /// cost next to nothing and return `false`.
+    pub fn compact_if_due(&mut self) -> Result<bool> {
+        let config = self.store.config();
+        if config.compaction_policy == CompactionPolicy::Never
+            || config.read_only
+            || (self.store.path().is_none() && !self.store.is_in_memory())
+        {
+            return Ok(false);
+        }
+        let disk = self.disk_size()?;
+        if !self.store.take_measurement(disk) {
+            return Ok(false);
+        }
+        let garbage = disk.saturating_sub(self.live_size()?);
+        let due = match config.compaction_policy {
+            CompactionPolicy::Never => false,
+            CompactionPolicy::GarbageRatio(ratio) => garbage as f64 > ratio * disk as f64,
+            CompactionPolicy::GarbageBytes(bytes) => garbage > bytes,
+        };
+        if due {
+            self.compact_in_place()?;
+        }
+        Ok(due)
+    }
+
+    /// Cuts the tree in two at `pivot`. Keys below it stay in this tree, and keys from
+    /// `pivot` on move to a new tree in a file created at `path`, replacing any file
+    /// there. Each half has the root hash of a tree built from its keys alone.
+    ///
+    /// Only the nodes along the cut are rebuilt. The left half is an uncommitted
+    /// change to this tree, like a removal; the right half is copied to its file and
+    /// committed there, like [`compact`](Self::compact) does.
+    pub fn split_at<P: AsRef<Path>>(mut self, pivot: &K, path: P) -> Result<(Self, Self)> {
+        self.ensure_writable()?;
+        let at_pivot = match self.range::<K, _>(pivot..=pivot)?.next() {
+            Some(entry) => Some(entry?),
+            None => None,
+        };
+        let mut root = self.state.get_mut().unwrap().root.clone();
+        // A key equal to the pivot is taken out first and put back on the right, so
+        // the split only ever cuts between keys.
+        if at_pivot.is_some()
+            && let Some(without) = self.resolve_link(&root)?.delete::<H, K>(pivot, &self.store)?
+        {
+            root = without;
+        }
+        let [left_root, mut right_root] = Node::split_link::<H>(&root, pivot, &self.store)?;
+        if let Some((key, value)) = at_pivot {
+            let level = self.level_of_entry(&key, &value);
+            let right_node = self.resolve_link(&right_root)?;
+            right_root = Link::Loaded(right_node.put::<H>(key, value, level, &self.store)?);
         }
 
-        let new_store = Store::new(file);
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        let right_store = Store::from_file::<H>(
+            file,
+            self.store.config(),
+            Some(path.as_ref().to_path_buf()),
+        )?;
+        self.copy_link_to(&right_root, &right_store)?;
+        let mut right = Self::from_store(right_store)?;
+        right.tombstones = self.tombstones;
+
+        self.state.get_mut().unwrap().root = left_root;
+        Ok((self, right))
+    }
+
+    /// Copies the reachable nodes into `new_store` and commits them there. Returns the
+    /// offset, hash and number of keys of the new root.
+    fn copy_to(&mut self, new_store: &Arc<Store<K, V>>) -> Result<(u64, Hash, u64)> {
+        let root = self.state.get_mut().unwrap().root.clone();
+        self.copy_link_to(&root, new_store)
+    }
 
+    /// Like `copy_to`, for the subtree behind `root`, which may differ from the root
+    /// of the tree.
+    fn copy_link_to(
+        &self,
+        root: &Link<K, V>,
+        new_store: &Arc<Store<K, V>>,
+    ) -> Result<(u64, Hash, u64)> {
         // 2. Recursively copy the tree from the old store to the new store.
         // This returns the offset of the root in the NEW file.
-        let (new_root_offset, new_root_hash) = self.copy_recursive(&self.root, &new_store)?;
+        let (new_root_offset, new_root_hash, new_root_count) =
+            self.copy_recursive(root, new_store)?;
 
-        // 3. Write the metadata (Root pointer) to the new store
-        new_store.write_metadata(new_root_offset, new_root_hash)?;
+        // 3. Sync the copied nodes, then write the metadata (Root pointer) to the new store.
+        // Nodes shared through deduplication are saved with the free list.
+        let retirement = new_store.prepare_retire(&[], &HashMap::new())?;
         new_store.flush()?;
+        new_store.write_metadata(
+            new_root_offset,
+            new_root_hash,
+            retirement.free_list_offset(),
+        )?;
+        new_store.flush()?;
+        new_store.retire_nodes(retirement);
+        Ok((new_root_offset, new_root_hash, new_root_count))
+    }
+
+    /// Points the tree at the copy made by `copy_to`.
+    fn switch_to(&mut self, new_store: Arc<Store<K, V>>, new_root: (u64, Hash, u64)) {
+        let (new_root_offset, new_root_hash, new_root_count) = new_root;
 
         // 4. Atomically swap the store in memory
         self.store = new_store;
+        let state = self.state.get_mut().unwrap();
+        state.last_committed = Some((new_root_offset, new_root_hash));
 
         // Update the root link to point to the new disk location
-        self.root = Link::Disk {
+        state.root = Link::Disk {
             offset: new_root_offset,
             hash: new_root_hash,
+            count: Some(new_root_count),
         };
-
-        Ok(())
     }
 
     /// Helper: Recursively loads a node from the old store and writes it to the new store.
-    /// Returns the (Offset, Hash) in the new store.
+    /// Returns the (Offset, Hash) in the new store, and the number of keys in the subtree.
     fn copy_recursive(
         &self,
         link: &Link<K, V>,
         new_store: &Arc<Store<K, V>>,
-    ) -> io::Result<(NodeId, Hash)> {
+    ) -> io::Result<(NodeId, Hash, u64)> {
         // Step A: Resolve the node.
         // If it's on disk, load it from `self.store` (the old store).
         // If it's loaded, use it directly.
         let node = match link {
             Link::Loaded(n) => n.clone(),
-            Link::Disk { offset, .. } => self.store.load_node(*offset)?,
+            Link::Disk { offset, hash, .. } => self.store.load_node(*offset, *hash)?,
         };
 
         // Step B: Recursively process all children first (Bottom-Up).
         // We need to write children first so we know their NEW offsets to put in the parent.
         let mut new_children_links = Vec::with_capacity(node.children.len());
 
+        // Counts are recomputed on the way up, as a version 1 source does not record them.
+        let mut count = node.keys.len() as u64;
+
         for child_link in &node.children {
-            let (child_new_offset, child_hash) = self.copy_recursive(child_link, new_store)?;
+            let (child_new_offset, child_hash, child_count) =
+                self.copy_recursive(child_link, new_store)?;
+            count += child_count;
 
             // The parent must refer to the child by its NEW disk location.
             new_children_links.push(Link::Disk {
                 offset: child_new_offset,
                 hash: child_hash,
+                count: Some(child_count),
             });
         }
 
@@ -246,11 +1924,67 @@ impl<K: MerkleKey, V: MerkleValue> MerkleSearchTree<K, V> {
         // However, we MUST replace the `children` list with the `Link::Disk` variants pointing to the new file.
         let mut new_node = (*node).clone();
         new_node.children = new_children_links;
+        new_node.count = Some(count);
 
         // Step D: Write the node to the new store.
         // Since `new_node` now contains only Link::Disk children, `as_disk_ref` inside `write_node` will succeed.
         let new_offset = new_store.write_node(&new_node)?;
 
-        Ok((new_offset, new_node.hash))
+        Ok((new_offset, new_node.hash, count))
+    }
+}
+
+/// An empty tree held in memory; see [`new_in_memory`](MerkleSearchTree::new_in_memory).
+impl<K: MerkleKey, V: MerkleValue, H: TreeHasher> Default for MerkleSearchTree<K, V, H> {
+    fn default() -> Self {
+        // Only ever writes the header page to a fresh buffer, which cannot fail.
+        Self::new_in_memory_with_hasher().expect("creating an in-memory tree failed")
+    }
+}
+
+/// The smallest byte string greater than every string starting with `prefix`, or
+/// None if there is none (the prefix is empty or all `0xFF`).
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last = prefix.iter().rposition(|&b| b != 0xFF)?;
+    let mut end = prefix[..=last].to_vec();
+    end[last] += 1;
+    Some(end)
+}
+
+/// Takes `value` out of its `Arc`, or copies it through its serialized form if it is
+/// still shared.
+/// Takes the node out of `link` if it is loaded, leaving a link to offset 0 with the
+/// node's hash and count until it is written.
+fn take_loaded<K: MerkleKey, V: MerkleValue>(link: &mut Link<K, V>) -> Option<Arc<Node<K, V>>> {
+    let Link::Loaded(node) = link else {
+        return None;
+    };
+    let unwritten = Link::Disk {
+        offset: 0,
+        hash: node.hash,
+        count: node.count,
+    };
+    match std::mem::replace(link, unwritten) {
+        Link::Loaded(node) => Some(node),
+        Link::Disk { .. } => unreachable!("the link was loaded"),
     }
 }
+
+fn into_owned<T: Serialize + for<'a> Deserialize<'a>>(value: Arc<T>) -> Result<T> {
+    Arc::try_unwrap(value).or_else(|shared| {
+        let bytes = postcard::to_extend(&*shared, Vec::new())?;
+        Ok(postcard::from_bytes(&bytes)?)
+    })
+}
+
+/// Makes a rename within `dir` durable.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Directories cannot be opened for syncing on this platform.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
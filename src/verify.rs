@@ -0,0 +1,122 @@
+use crate::Hash;
+use std::io;
+
+use crate::node::Link;
+use crate::store::Store;
+use crate::{MerkleKey, MerkleValue, NodeId, TreeHasher};
+
+/// Outcome of [`MerkleSearchTree::verify`].
+///
+/// [`MerkleSearchTree::verify`]: crate::MerkleSearchTree::verify
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Nodes read from disk and checked, including empty nodes and the one that failed,
+    /// if any.
+    pub nodes_checked: u64,
+    /// The first problem found; the walk stops there.
+    pub first_error: Option<Corruption>,
+}
+
+impl VerifyReport {
+    /// Whether every reachable node checked out.
+    pub fn is_ok(&self) -> bool {
+        self.first_error.is_none()
+    }
+}
+
+/// A problem with a node on disk, found by [`MerkleSearchTree::verify`].
+///
+/// [`MerkleSearchTree::verify`]: crate::MerkleSearchTree::verify
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Corruption {
+    /// A link points into the header page or past the end of the file.
+    OutOfBounds { offset: NodeId, file_len: u64 },
+    /// The node could not be read or decoded.
+    Unreadable { offset: NodeId, error: String },
+    /// The hash stored with the node differs from the one its parent, or the root
+    /// pointer, recorded for it.
+    ParentMismatch {
+        offset: NodeId,
+        recorded: Hash,
+        stored: Hash,
+    },
+    /// The node's contents do not hash to the hash stored with it.
+    HashMismatch {
+        offset: NodeId,
+        stored: Hash,
+        computed: Hash,
+    },
+}
+
+/// Checks every node on disk reachable from `root`. Nodes only held in memory are
+/// walked through but not checked themselves.
+pub(crate) fn verify<K, V, H>(root: &Link<K, V>, store: &Store<K, V>) -> io::Result<VerifyReport>
+where
+    K: MerkleKey,
+    V: MerkleValue,
+    H: TreeHasher,
+{
+    let file_len = store.file_len()?;
+    let header_len = store.config().page_size;
+    let mut report = VerifyReport::default();
+    let mut stack = vec![root.clone()];
+
+    while let Some(link) = stack.pop() {
+        let node = match link {
+            Link::Loaded(node) => node,
+            Link::Disk { offset, hash, .. } => {
+                report.nodes_checked += 1;
+                let out_of_bounds = Corruption::OutOfBounds { offset, file_len };
+                if offset < header_len || offset + 4 > file_len {
+                    report.first_error = Some(out_of_bounds);
+                    return Ok(report);
+                }
+                match store.record_len(offset) {
+                    Ok(len) if offset + len > file_len => {
+                        report.first_error = Some(out_of_bounds);
+                        return Ok(report);
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        report.first_error = Some(Corruption::Unreadable {
+                            offset,
+                            error: e.to_string(),
+                        });
+                        return Ok(report);
+                    }
+                }
+
+                let node = match store.decode_node(offset) {
+                    Ok(node) => node,
+                    Err(e) => {
+                        report.first_error = Some(Corruption::Unreadable {
+                            offset,
+                            error: e.to_string(),
+                        });
+                        return Ok(report);
+                    }
+                };
+                if node.hash != hash {
+                    report.first_error = Some(Corruption::ParentMismatch {
+                        offset,
+                        recorded: hash,
+                        stored: node.hash,
+                    });
+                    return Ok(report);
+                }
+                let computed = node.hash_with::<H>(store.domain_salt());
+                if computed != node.hash {
+                    report.first_error = Some(Corruption::HashMismatch {
+                        offset,
+                        stored: node.hash,
+                        computed,
+                    });
+                    return Ok(report);
+                }
+                node.into()
+            }
+        };
+        stack.extend(node.children.iter().rev().cloned());
+    }
+    Ok(report)
+}
@@ -0,0 +1,65 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{Error, Hash, Result};
+
+/// A committed version of a tree: where its root node is in the file, and the root
+/// hash. Returned by `commit`, and accepted by
+/// [`MerkleSearchTree::open_at_root`] to read that version again later.
+///
+/// Displayed and parsed as `<offset>:<hash>`, e.g. for logging a version and
+/// reopening it from the log.
+///
+/// [`MerkleSearchTree::open_at_root`]: crate::MerkleSearchTree::open_at_root
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, std::hash::Hash, Serialize, Deserialize,
+)]
+pub struct Version {
+    /// Offset of the root node in the file.
+    pub offset: u64,
+    pub hash: Hash,
+}
+
+impl Version {
+    pub const fn new(offset: u64, hash: Hash) -> Self {
+        Self { offset, hash }
+    }
+
+    /// The offset and hash, in that order.
+    pub const fn into_parts(self) -> (u64, Hash) {
+        (self.offset, self.hash)
+    }
+}
+
+impl From<(u64, Hash)> for Version {
+    fn from((offset, hash): (u64, Hash)) -> Self {
+        Self { offset, hash }
+    }
+}
+
+impl From<Version> for (u64, Hash) {
+    fn from(version: Version) -> Self {
+        version.into_parts()
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.offset, self.hash)
+    }
+}
+
+impl FromStr for Version {
+    type Err = Error;
+
+    /// Parses `<offset>:<hash>`, with the offset in decimal and the hash in hex.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::Parse(format!("invalid version {s:?}: expected <offset>:<hash>"));
+        let (offset, hash) = s.split_once(':').ok_or_else(invalid)?;
+        Ok(Self {
+            offset: offset.parse().map_err(|_| invalid())?,
+            hash: hash.parse().map_err(|_| invalid())?,
+        })
+    }
+}
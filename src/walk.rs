@@ -0,0 +1,134 @@
+use std::fmt::{self, Write};
+use std::io;
+use std::sync::Arc;
+
+use crate::hash::{HASH_LEN, Hash};
+use crate::node::Link;
+use crate::store::Store;
+use crate::{MerkleKey, MerkleValue};
+
+/// Whether [`MerkleSearchTree::walk`] goes on into the children of a node.
+///
+/// [`MerkleSearchTree::walk`]: crate::MerkleSearchTree::walk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visit {
+    /// Visit the children of the node next.
+    Descend,
+    /// Leave out the subtree below the node.
+    Skip,
+}
+
+/// Callbacks for [`MerkleSearchTree::walk`].
+///
+/// [`MerkleSearchTree::walk`]: crate::MerkleSearchTree::walk
+pub trait NodeVisitor<K, V> {
+    /// Called for each node before its children. `keys` and `values` are in order,
+    /// and `child_hashes` holds one hash per gap between keys, all zeros where the
+    /// gap is empty; it has no entries for a leaf.
+    fn visit_node(
+        &mut self,
+        level: u32,
+        keys: &[Arc<K>],
+        values: &[Arc<V>],
+        child_hashes: &[Hash],
+    ) -> Visit;
+}
+
+/// Visits the tree below `root` in pre-order, left to right. Empty nodes, which only
+/// mark the absence of a subtree, are not visited.
+pub(crate) fn walk<K, V, W>(
+    root: &Link<K, V>,
+    store: &Store<K, V>,
+    visitor: &mut W,
+) -> io::Result<()>
+where
+    K: MerkleKey,
+    V: MerkleValue,
+    W: NodeVisitor<K, V> + ?Sized,
+{
+    let empty = Hash::from_bytes([0u8; HASH_LEN]);
+    let mut stack = vec![root.clone()];
+    let mut child_hashes = Vec::new();
+
+    while let Some(link) = stack.pop() {
+        if link.hash() == empty {
+            continue;
+        }
+        let node = match &link {
+            Link::Loaded(node) => node.clone(),
+            Link::Disk { offset, hash, .. } => store.load_node(*offset, *hash)?,
+        };
+
+        child_hashes.clear();
+        child_hashes.extend(node.children.iter().map(Link::hash));
+        let visit = visitor.visit_node(node.level, &node.keys, &node.values, &child_hashes);
+        if visit == Visit::Descend {
+            stack.extend(node.children.iter().rev().cloned());
+        }
+    }
+    Ok(())
+}
+
+/// Builds a Graphviz DOT graph of the tree for [`MerkleSearchTree::to_dot`], one box
+/// per node with its level, keys and a short hash, and an edge to each child. Nodes
+/// are named by their hash, which no two nodes of a tree share, as their keys differ.
+///
+/// [`MerkleSearchTree::to_dot`]: crate::MerkleSearchTree::to_dot
+pub(crate) struct DotWriter {
+    out: String,
+    /// Hashes of the nodes still to be visited, the next one last. The walk only
+    /// passes the hashes of a node's children, so the visitor follows its order to
+    /// know which node it is at.
+    pending: Vec<Hash>,
+}
+
+impl DotWriter {
+    pub(crate) fn new(root: Hash) -> Self {
+        Self {
+            out: String::from("digraph mst {\n    node [shape=box];\n"),
+            pending: vec![root],
+        }
+    }
+
+    pub(crate) fn finish(mut self) -> String {
+        self.out.push_str("}\n");
+        self.out
+    }
+}
+
+impl<K: fmt::Debug, V> NodeVisitor<K, V> for DotWriter {
+    fn visit_node(
+        &mut self,
+        level: u32,
+        keys: &[Arc<K>],
+        _values: &[Arc<V>],
+        child_hashes: &[Hash],
+    ) -> Visit {
+        let empty = Hash::from_bytes([0u8; HASH_LEN]);
+        let hash = self.pending.pop().expect("every node visited was pending");
+
+        let keys = keys
+            .iter()
+            .map(|key| format!("{key:?}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        // Writing to a String cannot fail.
+        let _ = writeln!(
+            self.out,
+            "    \"{hash}\" [label=\"level {level}\\n{}\\n{}\"];",
+            escape(&keys),
+            &hash.to_hex()[..8],
+        );
+        for child in child_hashes.iter().filter(|&&child| child != empty) {
+            let _ = writeln!(self.out, "    \"{hash}\" -> \"{child}\";");
+        }
+        self.pending
+            .extend(child_hashes.iter().rev().filter(|&&child| child != empty));
+        Visit::Descend
+    }
+}
+
+/// `text` with the characters that end or escape a DOT string escaped.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
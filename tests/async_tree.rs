@@ -1,5 +1,5 @@
-use blake3::Hash;
-use file_mst::AsyncMerkleSearchTree;
+use file_mst::Hash;
+use file_mst::{AsyncMerkleSearchTree, CompactionPolicy, MerkleSearchTree, Version};
 use tempfile::tempdir;
 
 #[tokio::test]
@@ -31,12 +31,28 @@ async fn contains_and_remove() {
     assert!(!tree.contains(10).await.unwrap());
 }
 
+#[tokio::test]
+async fn borrowed_lookups() {
+    let tree = AsyncMerkleSearchTree::new_temporary().unwrap();
+    tree.insert("alpha".to_string(), 1u32).await.unwrap();
+
+    let val = tree.get_borrowed("alpha").await.unwrap();
+    assert_eq!(val.as_deref(), Some(&1));
+    assert!(tree.get_borrowed("beta").await.unwrap().is_none());
+    assert!(tree.contains_borrowed("alpha").await.unwrap());
+    assert!(!tree.contains_borrowed("beta").await.unwrap());
+
+    let bytes = AsyncMerkleSearchTree::new_temporary().unwrap();
+    bytes.insert(vec![1u8, 2, 3], ()).await.unwrap();
+    assert!(bytes.contains_borrowed(&[1u8, 2, 3][..]).await.unwrap());
+}
+
 #[tokio::test]
 async fn commit() {
     let tree = AsyncMerkleSearchTree::new_temporary().unwrap();
 
     tree.insert(5, "five".to_string()).await.unwrap();
-    let (offset, hash) = tree.commit().await.unwrap();
+    let Version { offset, hash } = tree.commit().await.unwrap();
 
     // Root hash should be non-zero
     assert_ne!(hash, Hash::from([0u8; 32]));
@@ -67,6 +83,34 @@ async fn compact() {
     }
 }
 
+#[tokio::test]
+async fn churn_auto_compacts_on_the_worker() {
+    let temp_dir = tempdir().unwrap();
+    let file_path = temp_dir.path().join("churn.mst");
+    let tree: MerkleSearchTree<u32, u32> = MerkleSearchTree::builder()
+        .compaction_policy(CompactionPolicy::GarbageRatio(0.5))
+        .open(&file_path)
+        .unwrap();
+    let tree = AsyncMerkleSearchTree::from(tree);
+
+    let mut peak = 0;
+    let mut shrunk = false;
+    for round in 0..10 {
+        tree.insert_many((0..2000).map(|i| (i, round)).collect()).await.unwrap();
+        tree.commit().await.unwrap();
+        // Waits for any compaction the commit started.
+        assert_eq!(tree.get(0).await.unwrap().as_deref(), Some(&round));
+        let len = std::fs::metadata(&file_path).unwrap().len();
+        if len < peak {
+            shrunk = true;
+            break;
+        }
+        peak = peak.max(len);
+    }
+    assert!(shrunk, "the file never shrank from {peak} bytes");
+    assert!(tree.contains(1999).await.unwrap());
+}
+
 #[tokio::test]
 async fn multiple_operations() {
     let tree = AsyncMerkleSearchTree::new_temporary().unwrap();
@@ -96,5 +140,104 @@ async fn multiple_operations() {
     }
 
     // Commit after all operations
-    let (_offset, _hash) = tree.commit().await.unwrap();
+    tree.commit().await.unwrap();
+}
+
+#[tokio::test]
+async fn close_commits_pending_changes() {
+    let temp_dir = tempdir().unwrap();
+    let file_path = temp_dir.path().join("close.mst");
+
+    let tree = AsyncMerkleSearchTree::open(&file_path).unwrap();
+    let other = tree.clone();
+    for i in 0..10 {
+        tree.insert(i, format!("v{}", i)).await.unwrap();
+    }
+    tree.close().await.unwrap();
+    assert!(other.get(1).await.is_err());
+
+    let reopened: MerkleSearchTree<i32, String> = MerkleSearchTree::open(&file_path).unwrap();
+    assert_eq!(reopened.get(&7).unwrap().unwrap().as_ref(), "v7");
+}
+
+#[tokio::test]
+async fn streams_entries_in_order() {
+    let tree = AsyncMerkleSearchTree::new_temporary().unwrap();
+    for i in 0..1000u32 {
+        tree.insert(i, i * 2).await.unwrap();
+    }
+
+    let mut stream = tree.iter().await.unwrap();
+    // The stream reads a snapshot, so later writes neither block on it nor show up.
+    tree.insert(1000, 0).await.unwrap();
+    let mut expected = 0;
+    while let Some(entry) = stream.next().await {
+        let (key, value) = entry.unwrap();
+        assert_eq!((*key, *value), (expected, expected * 2));
+        expected += 1;
+    }
+    assert_eq!(expected, 1000);
+
+    let mut stream = tree.range(250..260).await.unwrap();
+    let mut keys = Vec::new();
+    while let Some(entry) = stream.next().await {
+        keys.push(*entry.unwrap().0);
+    }
+    assert_eq!(keys, (250..260).collect::<Vec<_>>());
+
+    // Dropping a stream early stops its producer.
+    let mut stream = tree.iter().await.unwrap();
+    assert_eq!(*stream.next().await.unwrap().unwrap().0, 0);
+    drop(stream);
+    assert!(tree.range(2000..).await.unwrap().next().await.is_none());
+}
+
+#[tokio::test]
+async fn batched_inserts_and_lookups() {
+    let tree = AsyncMerkleSearchTree::new_temporary().unwrap();
+    let items: Vec<(u32, u32)> = (0..1000).rev().map(|i| (i, i + 1)).collect();
+    tree.insert_many(items).await.unwrap();
+    tree.insert(5, 0).await.unwrap();
+
+    let found = tree.get_many(vec![5, 2000, 999, 0]).await.unwrap();
+    let found: Vec<_> = found.iter().map(|v| v.as_deref().copied()).collect();
+    assert_eq!(found, [Some(0), None, Some(1000), Some(1)]);
+    assert!(tree.get_many(Vec::new()).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn subscribers_see_new_roots() {
+    let tree = AsyncMerkleSearchTree::new_temporary().unwrap();
+    let mut commits = tree.subscribe();
+    assert_eq!(*commits.borrow(), Hash::default());
+
+    tree.insert(1, "one".to_string()).await.unwrap();
+    assert!(!commits.has_changed().unwrap());
+    let root = tree.commit().await.unwrap().hash;
+    commits.changed().await.unwrap();
+    assert_eq!(*commits.borrow_and_update(), root);
+
+    // Subscribing later starts from the latest commit.
+    let late = tree.clone().subscribe();
+    assert!(!late.has_changed().unwrap());
+    assert_eq!(*late.borrow(), root);
+}
+
+#[tokio::test]
+async fn snapshot_reads_without_the_worker() {
+    let tree = AsyncMerkleSearchTree::new_temporary().unwrap();
+    tree.insert_many((0..100u32).map(|i| (i, i * 3)).collect())
+        .await
+        .unwrap();
+
+    let snapshot = tree.snapshot().await.unwrap();
+    tree.insert(100, 0).await.unwrap();
+    tree.remove(0).await.unwrap();
+    // Closing the tree stops the worker; the snapshot keeps reading the store.
+    tree.close().await.unwrap();
+
+    for i in 0..100u32 {
+        assert_eq!(*snapshot.get(&i).unwrap().unwrap(), i * 3);
+    }
+    assert!(!snapshot.contains(&100).unwrap());
 }
@@ -0,0 +1,97 @@
+use file_mst::Hash;
+use file_mst::{MerkleSearchTree, SharedAsyncMerkleSearchTree};
+use tempfile::tempdir;
+
+#[tokio::test]
+async fn insert_get_and_remove() {
+    let tree = SharedAsyncMerkleSearchTree::new_temporary().unwrap();
+
+    tree.insert(1, "value1".to_string()).await.unwrap();
+    tree.insert(2, "value2".to_string()).await.unwrap();
+
+    let val = tree.get(1).await.unwrap();
+    assert_eq!(val.unwrap().as_ref(), &"value1".to_string());
+    assert!(tree.contains(2).await.unwrap());
+    assert!(tree.get(3).await.unwrap().is_none());
+
+    tree.remove(1).await.unwrap();
+    assert!(!tree.contains(1).await.unwrap());
+}
+
+#[tokio::test]
+async fn borrowed_lookups() {
+    let tree = SharedAsyncMerkleSearchTree::new_temporary().unwrap();
+    tree.insert("alpha".to_string(), 1u32).await.unwrap();
+
+    let val = tree.get_borrowed("alpha").await.unwrap();
+    assert_eq!(val.as_deref(), Some(&1));
+    assert!(tree.get_borrowed("beta").await.unwrap().is_none());
+    assert!(tree.contains_borrowed("alpha").await.unwrap());
+    assert!(!tree.contains_borrowed("beta").await.unwrap());
+}
+
+#[tokio::test]
+async fn commit_and_reopen() {
+    let temp_dir = tempdir().unwrap();
+    let file_path = temp_dir.path().join("shared.mst");
+
+    let tree = SharedAsyncMerkleSearchTree::open(&file_path).unwrap();
+    for i in 0..50 {
+        tree.insert(i, format!("v{}", i)).await.unwrap();
+    }
+    let hash = tree.commit().await.unwrap().hash;
+    assert_ne!(hash, Hash::from([0u8; 32]));
+    drop(tree);
+
+    let reopened: MerkleSearchTree<i32, String> = MerkleSearchTree::open(&file_path).unwrap();
+    assert_eq!(reopened.root_hash(), hash);
+}
+
+#[tokio::test]
+async fn compact() {
+    let temp_dir = tempdir().unwrap();
+    let file_path = temp_dir.path().join("compact.mst");
+
+    let tree = SharedAsyncMerkleSearchTree::new_temporary().unwrap();
+    for i in 0..10 {
+        tree.insert(i, format!("val{}", i)).await.unwrap();
+    }
+    tree.compact(file_path.to_str().unwrap().to_string())
+        .await
+        .unwrap();
+
+    for i in 0..10 {
+        let val = tree.get(i).await.unwrap();
+        assert_eq!(val.unwrap().as_ref(), &format!("val{}", i));
+    }
+}
+
+#[tokio::test]
+async fn concurrent_reads_and_writes() {
+    let tree = SharedAsyncMerkleSearchTree::new_temporary().unwrap();
+    for i in 0..200u64 {
+        tree.insert(i, i * 2).await.unwrap();
+    }
+    tree.commit().await.unwrap();
+
+    let mut handles = Vec::new();
+    for i in 0..200u64 {
+        let tree = tree.clone();
+        handles.push(tokio::spawn(async move {
+            if i % 10 == 0 {
+                tree.insert(1000 + i, i).await.unwrap();
+                tree.commit().await.unwrap();
+            }
+            // Keys below 200 are never touched, whatever the writes interleave with.
+            let val = tree.get(i).await.unwrap();
+            assert_eq!(val.as_deref(), Some(&(i * 2)));
+        }));
+    }
+    for handle in handles {
+        handle.await.unwrap();
+    }
+
+    for i in (0..200u64).step_by(10) {
+        assert!(tree.contains(1000 + i).await.unwrap());
+    }
+}